@@ -0,0 +1,198 @@
+//! Helpers for paying for this server's x402 routes: read the 402 body, pick one of the
+//! advertised requirements, open a 4mica tab, and encode the payment header.
+//!
+//! Signing is left to the caller (the 4mica SDK's `X402Flow` does it for 4mica claims);
+//! these helpers only cover the parts every integration repeats.
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use reqwest::{Client, Response, StatusCode};
+use serde::Serialize;
+use url::Url;
+
+pub use x402_common::{
+    FacilitatorTabResponse, PaymentEnvelope, PaymentRequiredResponse, PaymentRequirements,
+    X402_VERSION,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    #[error("request failed: {0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("expected a 402 response, got {0}")]
+    NotPaymentRequired(StatusCode),
+
+    #[error("server answered {status}: {body}")]
+    Status { status: StatusCode, body: String },
+
+    #[error("payment requirements name no tab endpoint")]
+    MissingTabEndpoint,
+}
+
+/// Reads the body of a 402 response.
+pub async fn payment_required(response: Response) -> Result<PaymentRequiredResponse, ClientError> {
+    let status = response.status();
+    if status != StatusCode::PAYMENT_REQUIRED {
+        return Err(ClientError::NotPaymentRequired(status));
+    }
+    Ok(response.json().await?)
+}
+
+/// The requirement whose scheme is `preferred_scheme` (ignoring case), else the first
+/// one advertised.
+pub fn choose_requirement<'a>(
+    accepts: &'a [PaymentRequirements],
+    preferred_scheme: &str,
+) -> Option<&'a PaymentRequirements> {
+    accepts
+        .iter()
+        .find(|requirement| requirement.scheme.eq_ignore_ascii_case(preferred_scheme))
+        .or_else(|| accepts.first())
+}
+
+/// The `tabEndpoint` a 4mica requirement advertises in its `extra`.
+pub fn tab_endpoint(requirement: &PaymentRequirements) -> Option<Url> {
+    requirement
+        .extra
+        .as_ref()?
+        .get("tabEndpoint")?
+        .as_str()?
+        .parse()
+        .ok()
+}
+
+/// Body of `POST /tab`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TabRequest<'a> {
+    user_address: &'a str,
+    payment_requirements: &'a PaymentRequirements,
+}
+
+/// Opens (or reuses) the 4mica tab a payment is made against.
+#[derive(Clone, Debug, Default)]
+pub struct TabClient {
+    http: Client,
+}
+
+impl TabClient {
+    pub fn new(http: Client) -> Self {
+        Self { http }
+    }
+
+    /// Asks `tab_endpoint` for the tab `user` pays `requirement` into.
+    pub async fn open_tab(
+        &self,
+        tab_endpoint: &Url,
+        user: &str,
+        requirement: &PaymentRequirements,
+    ) -> Result<FacilitatorTabResponse, ClientError> {
+        let response = self
+            .http
+            .post(tab_endpoint.clone())
+            .json(&TabRequest {
+                user_address: user,
+                payment_requirements: requirement,
+            })
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(ClientError::Status { status, body });
+        }
+        Ok(response.json().await?)
+    }
+
+    /// [`TabClient::open_tab`] at the endpoint `requirement` advertises.
+    pub async fn open_advertised_tab(
+        &self,
+        user: &str,
+        requirement: &PaymentRequirements,
+    ) -> Result<FacilitatorTabResponse, ClientError> {
+        let endpoint = tab_endpoint(requirement).ok_or(ClientError::MissingTabEndpoint)?;
+        self.open_tab(&endpoint, user, requirement).await
+    }
+}
+
+/// The base64 value of the `x-payment` header carrying `envelope`.
+pub fn build_payment_header(envelope: &PaymentEnvelope) -> String {
+    // An envelope is strings and a JSON value, which always serialize.
+    let json = serde_json::to_vec(envelope).unwrap_or_default();
+    BASE64_STANDARD.encode(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn requirement(scheme: &str, extra: Option<serde_json::Value>) -> PaymentRequirements {
+        PaymentRequirements {
+            scheme: scheme.to_string(),
+            network: "polygon-amoy".to_string(),
+            max_amount_required: "100".to_string(),
+            resource: None,
+            description: None,
+            mime_type: None,
+            output_schema: None,
+            pay_to: "0x00000000000000000000000000000000000000ab".to_string(),
+            max_timeout_seconds: Some(300),
+            asset: "0x41E94Eb019C0762f9Bfcf9Fb1E58725BfB0e7582".to_string(),
+            extra,
+        }
+    }
+
+    #[test]
+    fn preferred_scheme_is_chosen_else_the_first() {
+        let accepts = [
+            requirement("exact", None),
+            requirement("4mica-credit", None),
+        ];
+        assert_eq!(
+            choose_requirement(&accepts, "4MICA-CREDIT").unwrap().scheme,
+            "4mica-credit"
+        );
+        assert_eq!(
+            choose_requirement(&accepts, "upto").unwrap().scheme,
+            "exact"
+        );
+        assert!(choose_requirement(&[], "exact").is_none());
+    }
+
+    #[test]
+    fn tab_endpoint_comes_from_extra() {
+        let advertised = requirement(
+            "4mica-credit",
+            Some(json!({ "tabEndpoint": "http://localhost:3000/tab" })),
+        );
+        assert_eq!(
+            tab_endpoint(&advertised).unwrap().as_str(),
+            "http://localhost:3000/tab"
+        );
+        assert!(tab_endpoint(&requirement("exact", None)).is_none());
+        let relative = requirement("4mica-credit", Some(json!({ "tabEndpoint": "/tab" })));
+        assert!(tab_endpoint(&relative).is_none());
+    }
+
+    #[test]
+    fn payment_header_is_the_base64_envelope() {
+        let envelope = PaymentEnvelope::new(
+            &requirement("4mica-credit", None),
+            json!({ "signature": "0x01" }),
+        );
+        let decoded = BASE64_STANDARD
+            .decode(build_payment_header(&envelope))
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&decoded).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "x402Version": X402_VERSION,
+                "scheme": "4mica-credit",
+                "network": "polygon-amoy",
+                "payload": { "signature": "0x01" },
+            })
+        );
+    }
+}
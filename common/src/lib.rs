@@ -0,0 +1,96 @@
+//! Wire types shared by the x402 server and its clients.
+
+pub use sdk_4mica::x402::PaymentRequirements;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::ToSchema;
+
+/// x402 version of the 402 responses and payment envelopes built here.
+pub const X402_VERSION: u64 = 1;
+
+/// Body of every 402 response.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymentRequiredResponse {
+    pub x402_version: u64,
+    /// Payment options the resource accepts; pay with any one of them.
+    #[schema(value_type = Vec<PaymentRequirementsSchema>)]
+    pub accepts: Vec<PaymentRequirements>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Machine-readable reason for `error`, from the x402 `invalidReason` codes:
+    /// `invalid_payload`, `invalid_scheme`, `invalid_x402_version`,
+    /// `invalid_payment_requirements`, `insufficient_funds`, `invalid_transaction_state`,
+    /// `unexpected_verify_error`, `unexpected_settle_error`, or a facilitator's own code.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "insufficient_funds")]
+    pub error_code: Option<String>,
+}
+
+/// Documents the serialized shape of the SDK's [`PaymentRequirements`], which can't
+/// derive [`ToSchema`] itself.
+#[allow(dead_code)] // Only used as a `value_type`.
+#[derive(ToSchema)]
+#[schema(as = PaymentRequirements)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymentRequirementsSchema {
+    scheme: String,
+    network: String,
+    /// Price in the asset's smallest unit, as a decimal string.
+    max_amount_required: String,
+    resource: Option<String>,
+    description: Option<String>,
+    mime_type: Option<String>,
+    output_schema: Option<Value>,
+    pay_to: String,
+    max_timeout_seconds: Option<u64>,
+    asset: String,
+    /// Scheme-specific data: the `tabEndpoint` for 4mica, the EIP-712 domain for `exact`.
+    extra: Option<Value>,
+}
+
+/// A v1 payment as carried, base64 encoded, in the `x-payment` header.
+///
+/// The payload is scheme-specific: signed claims for 4mica, a transaction reference or
+/// EIP-3009 authorization for `exact`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymentEnvelope {
+    pub x402_version: u64,
+    pub scheme: String,
+    pub network: String,
+    pub payload: Value,
+}
+
+impl PaymentEnvelope {
+    /// An envelope paying `requirements` with `payload`.
+    pub fn new(requirements: &PaymentRequirements, payload: Value) -> Self {
+        Self {
+            x402_version: X402_VERSION,
+            scheme: requirements.scheme.clone(),
+            network: requirements.network.clone(),
+            payload,
+        }
+    }
+}
+
+/// Tab opened by the facilitator, passed through unchanged by `POST /tab`.
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct FacilitatorTabResponse {
+    pub tab_id: String,
+    pub user_address: String,
+    pub recipient_address: String,
+    pub asset_address: String,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        alias = "nextReqId",
+        alias = "next_req_id",
+        alias = "reqId",
+        alias = "req_id"
+    )]
+    pub next_req_id: Option<String>,
+    pub start_timestamp: i64,
+    pub ttl_seconds: i64,
+}
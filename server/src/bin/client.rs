@@ -0,0 +1,165 @@
+//! Command-line client that buys a paid stream from this server, for manual testing
+//! and CI smoke tests.
+//!
+//! `4mica` payments are signed with `4MICA_WALLET_PRIVATE_KEY` (other `4MICA_*`
+//! variables are honoured as in the SDK); `exact` payments reference a transaction
+//! that was already broadcast.
+
+use anyhow::{Context, anyhow, bail};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use clap::{Parser, ValueEnum};
+use reqwest::{Client, Response, StatusCode};
+use sdk_4mica::{Client as FourMicaClient, ConfigBuilder, X402Flow};
+use serde_json::json;
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+use tokio::io::AsyncWriteExt;
+use url::Url;
+use x402_client::{PaymentEnvelope, PaymentRequiredResponse, build_payment_header};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Scheme {
+    #[value(name = "4mica")]
+    FourMica,
+    Exact,
+}
+
+#[derive(Debug, Parser)]
+#[command(about = "Fetch a paid stream, paying through the x402 flow")]
+struct Args {
+    /// Base URL of the server.
+    #[arg(long, default_value = "http://localhost:3000")]
+    server: Url,
+
+    /// File under `/stream/` to fetch.
+    filename: String,
+
+    /// Payment scheme to use.
+    #[arg(long, value_enum, default_value = "4mica")]
+    scheme: Scheme,
+
+    /// Hash of the broadcast payment transaction (exact scheme).
+    #[arg(long, required_if_eq("scheme", "exact"))]
+    tx_hash: Option<String>,
+
+    /// Address that sent the payment, required for ERC20 transfers (exact scheme).
+    #[arg(long)]
+    payer: Option<String>,
+
+    /// Where to write the body; defaults to the file name in the current directory.
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = Args::parse();
+    match run(&args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(args: &Args) -> anyhow::Result<()> {
+    let url = args
+        .server
+        .join(&format!("stream/{}", args.filename))
+        .context("invalid stream URL")?;
+    let http = Client::new();
+
+    let response = http.get(url.clone()).send().await?;
+    let response = match response.status() {
+        StatusCode::PAYMENT_REQUIRED => {
+            let required = x402_client::payment_required(response)
+                .await
+                .context("failed to parse 402 response")?;
+            let header = payment_header(args, required).await?;
+            http.get(url).header("x-payment", header).send().await?
+        }
+        _ => response,
+    };
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        bail!("server answered {status}: {body}");
+    }
+    if let Some(settlement) = response
+        .headers()
+        .get("payment-response")
+        .and_then(|value| BASE64_STANDARD.decode(value.as_bytes()).ok())
+    {
+        eprintln!("settlement: {}", String::from_utf8_lossy(&settlement));
+    }
+
+    let output = args.output.clone().unwrap_or_else(|| {
+        PathBuf::from(&args.filename)
+            .file_name()
+            .map(PathBuf::from)
+            .unwrap_or_default()
+    });
+    let bytes = write_body(response, &output).await?;
+    eprintln!("wrote {bytes} bytes to {}", output.display());
+    Ok(())
+}
+
+async fn payment_header(args: &Args, required: PaymentRequiredResponse) -> anyhow::Result<String> {
+    let requirements = required
+        .accepts
+        .into_iter()
+        .find(|requirements| match args.scheme {
+            Scheme::FourMica => requirements.scheme.to_lowercase().contains("4mica"),
+            Scheme::Exact => requirements.scheme.eq_ignore_ascii_case("exact"),
+        })
+        .ok_or_else(|| {
+            anyhow!(
+                "server does not accept the {:?} scheme{}",
+                args.scheme,
+                required
+                    .error
+                    .map(|error| format!(" ({error})"))
+                    .unwrap_or_default()
+            )
+        })?;
+
+    match args.scheme {
+        Scheme::FourMica => {
+            let config = ConfigBuilder::default()
+                .from_env()
+                .build()
+                .context("failed to configure the 4mica client (set 4MICA_WALLET_PRIVATE_KEY)")?;
+            let user_address = config.wallet_private_key.address().to_string();
+            let flow = X402Flow::new(FourMicaClient::new(config).await?)?;
+            let signed = flow.sign_payment(requirements, user_address).await?;
+            Ok(signed.header)
+        }
+        Scheme::Exact => {
+            let payload = json!({
+                "txHash": args.tx_hash,
+                "payer": args.payer,
+            });
+            Ok(build_payment_header(&PaymentEnvelope::new(
+                &requirements,
+                payload,
+            )))
+        }
+    }
+}
+
+async fn write_body(mut response: Response, output: &Path) -> anyhow::Result<u64> {
+    let mut file = tokio::fs::File::create(output)
+        .await
+        .with_context(|| format!("failed to create {}", output.display()))?;
+    let mut written = 0;
+    while let Some(chunk) = response.chunk().await? {
+        file.write_all(&chunk).await?;
+        written += chunk.len() as u64;
+    }
+    file.flush().await?;
+    Ok(written)
+}
@@ -0,0 +1,43 @@
+//! Prints an `X-PAYMENT` header for a server running with `X402_MODE=sandbox`, so player
+//! and UI work can pay without a facilitator, wallet, or RPC:
+//!
+//! ```text
+//! curl -H "X-PAYMENT: $(cargo run -q --bin sandbox_header)" localhost:3000/stream/seg.ts
+//! ```
+
+use clap::Parser;
+use std::process::ExitCode;
+
+#[derive(Debug, Parser)]
+#[command(about = "Print a sandbox x402 payment header")]
+struct Args {
+    /// Network the payment claims to be on.
+    #[arg(long, default_value = "polygon-amoy")]
+    network: String,
+
+    /// Absolute resource URL to bind the payment to; any resource accepts it when unset.
+    #[arg(long)]
+    resource: Option<String>,
+
+    /// Produce a payment the server declines, to exercise the failed-payment UI.
+    #[arg(long)]
+    decline: bool,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    match server::x402::sandbox_payment_header(
+        &args.network,
+        args.resource.as_deref(),
+        !args.decline,
+    ) {
+        Ok(header) => {
+            println!("{header}");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! Measures how fast local files stream at different `STREAM_BUFFER_BYTES` values, reading
+//! them through the same storage backend `/stream/{filename}` uses:
+//!
+//! ```text
+//! cargo run --release --bin stream_bench -- --size-mb 256 --buffer 4096 --buffer 65536
+//! ```
+
+use axum::body::HttpBody;
+use clap::Parser;
+use server::io::{LocalStorage, StorageBackend};
+use std::{
+    io::Write,
+    process::ExitCode,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Parser)]
+#[command(about = "Compare file streaming throughput across read buffer sizes")]
+struct Args {
+    /// Size of the file streamed, in MiB.
+    #[arg(long, default_value_t = 64)]
+    size_mb: usize,
+
+    /// Read buffer sizes to compare, in bytes.
+    #[arg(long = "buffer", default_values_t = [4096, 65536])]
+    buffers: Vec<usize>,
+
+    /// Times the file is streamed per buffer size; the fastest run is reported.
+    #[arg(long, default_value_t = 5)]
+    runs: usize,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = Args::parse();
+    match run(&args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(args: &Args) -> anyhow::Result<()> {
+    let directory = std::env::temp_dir().join(format!("stream_bench_{}", std::process::id()));
+    std::fs::create_dir_all(&directory)?;
+    let result = bench(args, &directory).await;
+    std::fs::remove_dir_all(&directory)?;
+    result
+}
+
+async fn bench(args: &Args, directory: &std::path::Path) -> anyhow::Result<()> {
+    let mut file = std::fs::File::create(directory.join("segment.ts"))?;
+    let chunk = vec![0x47u8; 1024 * 1024];
+    for _ in 0..args.size_mb {
+        file.write_all(&chunk)?;
+    }
+    file.sync_all()?;
+
+    for &buffer_bytes in &args.buffers {
+        let storage = LocalStorage::new(directory)?.with_buffer_bytes(buffer_bytes);
+        let info = storage.verify("segment.ts").await?;
+        let mut fastest = Duration::MAX;
+        let mut chunks = 0;
+        for _ in 0..args.runs.max(1) {
+            let started = Instant::now();
+            let mut body = storage.open_stream(&info, None).await?;
+            chunks = 0;
+            let mut bytes = 0;
+            while let Some(frame) =
+                std::future::poll_fn(|cx| std::pin::Pin::new(&mut body).poll_frame(cx)).await
+            {
+                if let Ok(data) = frame?.into_data() {
+                    chunks += 1;
+                    bytes += data.len() as u64;
+                }
+            }
+            anyhow::ensure!(bytes == info.len, "streamed {bytes} of {} bytes", info.len);
+            fastest = fastest.min(started.elapsed());
+        }
+        let mib_per_second = args.size_mb as f64 / fastest.as_secs_f64();
+        println!(
+            "{buffer_bytes:>8} B buffer: {mib_per_second:>9.1} MiB/s, {chunks} chunks, \
+             fastest of {} runs {fastest:?}",
+            args.runs.max(1)
+        );
+    }
+    Ok(())
+}
@@ -1,6 +1,11 @@
-use std::path::PathBuf;
+use sdk_4mica::U256;
+use std::{path::PathBuf, time::Duration};
 use thiserror::Error;
 
+use crate::x402::{
+    CertificateError, FacilitatorClientError, FacilitatorErrorBody, SUPPORTED_X402_VERSIONS,
+};
+
 #[derive(Error, Debug)]
 pub enum FileStreamError {
     #[error("File not found: {0}")]
@@ -12,10 +17,42 @@ pub enum FileStreamError {
     #[error("Access denied: path is outside allowed directory")]
     AccessDenied,
 
+    /// The file is still being written; it may be served after `retry_after`.
+    #[error("File is still being written: {path}")]
+    NotReady {
+        path: PathBuf,
+        retry_after: Duration,
+    },
+
     #[error("Failed to open file: {0}")]
     IoError(#[from] std::io::Error),
 }
 
+#[derive(Error, Debug)]
+pub enum RemoteFetchError {
+    #[error("Failed to fetch remote file: {0}")]
+    Request(#[from] reqwest::Error),
+
+    /// The upstream answered, but not with a success status.
+    #[error("Failed to fetch remote file: HTTP {0}")]
+    Status(reqwest::StatusCode),
+
+    #[error("Remote file is larger than {max_bytes} bytes")]
+    TooLarge { max_bytes: u64 },
+
+    #[error("Remote file is empty")]
+    Empty,
+}
+
+#[derive(Error, Debug)]
+pub enum IpfsPathError {
+    #[error("Invalid CID: {0}")]
+    InvalidCid(String),
+
+    #[error("Invalid IPFS path segment: {0:?}")]
+    InvalidSegment(String),
+}
+
 #[derive(Error, Debug)]
 pub enum PaymentError {
     #[error("Failed to decode payment header: {0}")]
@@ -24,24 +61,162 @@ pub enum PaymentError {
     #[error("Failed to parse payment envelope: {0}")]
     JsonParse(#[from] serde_json::Error),
 
+    #[error("Malformed payment header: {0}")]
+    MalformedEnvelope(&'static str),
+
     #[error("Facilitator error: {0}")]
-    Facilitator(#[from] crate::x402::FacilitatorClientError),
+    Facilitator(#[from] FacilitatorClientError),
+
+    #[error("Payment verification failed: {0}")]
+    VerificationFailed(String),
 
     #[error("Settlement failed: {0}")]
     SettlementFailed(String),
 
-    #[error("No matching payment requirements found for scheme: {scheme}, network: {network}")]
-    NoMatchingRequirements { scheme: String, network: String },
+    /// `accepted` lists each offered scheme with every identifier of its network.
+    #[error(
+        "No matching payment requirements found for scheme: {scheme}, network: {network}; \
+         accepted: {}",
+        accepted.join(", ")
+    )]
+    NoMatchingRequirements {
+        scheme: String,
+        network: String,
+        accepted: Vec<String>,
+    },
+
+    #[error(
+        "Unsupported x402 version {0}; supported versions: {versions}",
+        versions = supported_versions()
+    )]
+    UnsupportedVersion(u64),
 
     #[error("Unsupported payment scheme: {0}")]
     UnsupportedScheme(String),
 
+    #[error("Payment is for resource {paid}, not {requested}")]
+    ResourceMismatch { paid: String, requested: String },
+
+    #[error("Payment claims pay {claimed}, not {expected}")]
+    RecipientMismatch { claimed: String, expected: String },
+
+    #[error("Payment claims are in asset {claimed}, not {expected}")]
+    AssetMismatch { claimed: String, expected: String },
+
+    #[error("Payment amount {provided} is below the required {required}")]
+    InsufficientAmount { required: U256, provided: U256 },
+
+    #[error("Payment claims are missing {0}")]
+    MissingClaim(&'static str),
+
     #[error("Missing transaction hash for direct settlement")]
     MissingTxHash,
 
+    #[error("Missing payer address (payload `payer` or `from`) for ERC20 direct settlement")]
+    MissingPayer,
+
+    #[error("Payment header was already used for an earlier purchase")]
+    PaymentReplayed,
+
+    /// The transaction is mined but not yet buried deep enough; retrying shortly may succeed.
+    #[error("insufficient confirmations: have {have}, need {need}")]
+    InsufficientConfirmations { have: u64, need: u64 },
+
+    /// The tab's guarantees, less what its user already paid, don't cover the price.
+    #[error("Tab guarantee headroom {headroom} is below the price {price}")]
+    InsufficientGuarantee { headroom: U256, price: U256 },
+
+    /// The payment transaction was mined longer ago than payments stay valid.
+    #[error("payment transaction too old: mined {age}s ago, limit {max_age}s")]
+    TransactionTooOld { age: u64, max_age: u64 },
+
+    /// The tab was charged too much on this server recently; see `X402_TAB_SPEND_CAP`.
+    #[error(
+        "Tab {tab_id} reached its spend cap of {cap} per {window_seconds}s; open a new tab or \
+         retry in {retry_after}s"
+    )]
+    TabSpendCapExceeded {
+        tab_id: String,
+        cap: U256,
+        window_seconds: u64,
+        retry_after: u64,
+    },
+
+    #[error("Tab guarantee check failed: {0}")]
+    GuaranteeCheckFailed(String),
+
     #[error("On-chain settlement failed: {0}")]
     Onchain(String),
 
+    #[error("Settlement certificate rejected: {0}")]
+    Certificate(#[from] CertificateError),
+
     #[error("{0}")]
     Other(String),
 }
+
+impl PaymentError {
+    /// The x402 `invalidReason` code reported alongside this error in a 402 body's
+    /// `errorCode`. A facilitator's own reason is passed through when it is already a code.
+    pub fn reason_code(&self) -> &str {
+        match self {
+            Self::Base64Decode(_) | Self::JsonParse(_) | Self::MalformedEnvelope(_) => {
+                "invalid_payload"
+            }
+            Self::Facilitator(FacilitatorClientError::HttpStatus {
+                error:
+                    Some(FacilitatorErrorBody {
+                        invalid_reason: Some(reason),
+                        ..
+                    }),
+                ..
+            }) => facilitator_reason(reason).unwrap_or("unexpected_settle_error"),
+            Self::Facilitator(_) | Self::Certificate(_) | Self::Other(_) => {
+                "unexpected_settle_error"
+            }
+            Self::VerificationFailed(reason) => {
+                facilitator_reason(reason).unwrap_or("unexpected_verify_error")
+            }
+            Self::SettlementFailed(reason) => {
+                facilitator_reason(reason).unwrap_or("unexpected_settle_error")
+            }
+            Self::NoMatchingRequirements { .. } => "invalid_payment_requirements",
+            Self::UnsupportedVersion(_) => "invalid_x402_version",
+            Self::UnsupportedScheme(_) => "invalid_scheme",
+            Self::ResourceMismatch { .. }
+            | Self::RecipientMismatch { .. }
+            | Self::AssetMismatch { .. }
+            | Self::MissingClaim(_)
+            | Self::MissingTxHash
+            | Self::MissingPayer => "invalid_payload",
+            Self::InsufficientAmount { .. } | Self::InsufficientGuarantee { .. } => {
+                "insufficient_funds"
+            }
+            Self::PaymentReplayed
+            | Self::InsufficientConfirmations { .. }
+            | Self::TransactionTooOld { .. }
+            | Self::Onchain(_) => "invalid_transaction_state",
+            Self::GuaranteeCheckFailed(_) => "unexpected_verify_error",
+            Self::TabSpendCapExceeded { .. } => "tab_spend_cap_exceeded",
+        }
+    }
+}
+
+/// `reason` if it reads as a snake_case code (`insufficient_funds`) rather than prose.
+fn facilitator_reason(reason: &str) -> Option<&str> {
+    let reason = reason.trim();
+    let is_code = reason.starts_with(|c: char| c.is_ascii_lowercase())
+        && reason
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    is_code.then_some(reason)
+}
+
+/// [`SUPPORTED_X402_VERSIONS`] as a comma-separated list, for error messages.
+fn supported_versions() -> String {
+    SUPPORTED_X402_VERSIONS
+        .iter()
+        .map(u64::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
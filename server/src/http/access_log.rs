@@ -0,0 +1,331 @@
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use chrono::Utc;
+use http_body::{Frame, SizeHint};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::{
+    fs::OpenOptions,
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    thread,
+    time::Instant,
+};
+use tokio::sync::mpsc;
+use tower_http::request_id::RequestId;
+use tracing::{info, warn};
+
+use crate::http::{
+    audit::{AuditEntry, Decision},
+    client_ip::request_client_ip,
+    router::AppState,
+};
+
+/// Lines that may wait for the file writer before new ones are dropped.
+const QUEUE_SIZE: usize = 4096;
+
+/// What the paywall made of a request, as the access log reports it. Never holds the
+/// payment itself.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymentContext {
+    pub decision: Decision,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scheme: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount: Option<String>,
+}
+
+/// Request extension the access log adds and the paywall fills in with its decision, read
+/// back once the response is done.
+#[derive(Clone, Default)]
+pub struct PaymentLog(Arc<Mutex<Option<PaymentContext>>>);
+
+impl PaymentLog {
+    /// Records the decision `entry` describes, replacing any earlier one.
+    pub fn record(&self, entry: &AuditEntry) {
+        *self.0.lock() = Some(PaymentContext {
+            decision: entry.decision,
+            scheme: entry.scheme.clone(),
+            payer: entry.payer.clone(),
+            amount: entry.amount.clone(),
+        });
+    }
+
+    fn take(&self) -> Option<PaymentContext> {
+        self.0.lock().take()
+    }
+}
+
+/// One request, logged once its response body ended or was dropped.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AccessEntry {
+    timestamp: String,
+    method: String,
+    /// The path without its query, which may carry a payment.
+    path: String,
+    status: u16,
+    /// Response body bytes the client was sent, after any compression.
+    bytes_sent: u64,
+    duration_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payment: Option<PaymentContext>,
+}
+
+/// `ACCESS_LOG`: one line per request with its status, size, duration, client, and the
+/// paywall's decision, through the logger (target `access_log`) or, with
+/// `ACCESS_LOG_PATH`, as JSON lines appended to a file by a writer thread.
+#[derive(Clone)]
+pub struct AccessLog {
+    file: Option<mpsc::Sender<AccessEntry>>,
+}
+
+impl AccessLog {
+    /// Logs through the logger.
+    pub fn logger() -> Self {
+        Self { file: None }
+    }
+
+    /// Appends to `path`, creating it if needed.
+    pub fn spawn(path: PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let (tx, mut rx) = mpsc::channel::<AccessEntry>(QUEUE_SIZE);
+        thread::Builder::new()
+            .name("access-log".into())
+            .spawn(move || {
+                let mut writer = BufWriter::new(file);
+                while let Some(entry) = rx.blocking_recv() {
+                    let mut written = write_line(&mut writer, &entry);
+                    while let Ok(entry) = rx.try_recv() {
+                        written = written.and_then(|()| write_line(&mut writer, &entry));
+                    }
+                    if let Err(e) = written.and_then(|()| writer.flush()) {
+                        warn!("Failed to write access log {}: {}", path.display(), e);
+                    }
+                }
+            })?;
+        Ok(Self { file: Some(tx) })
+    }
+
+    fn log(&self, entry: AccessEntry) {
+        let Some(file) = &self.file else {
+            let payment = entry.payment.as_ref();
+            info!(
+                target: "access_log",
+                method = %entry.method,
+                path = %entry.path,
+                status = entry.status,
+                bytes_sent = entry.bytes_sent,
+                duration_ms = entry.duration_ms,
+                client_ip = entry.client_ip,
+                request_id = entry.request_id,
+                decision = payment.map(|payment| payment.decision.as_str()),
+                scheme = payment.and_then(|payment| payment.scheme.as_deref()),
+                payer = payment.and_then(|payment| payment.payer.as_deref()),
+                amount = payment.and_then(|payment| payment.amount.as_deref()),
+                "{} {} {}",
+                entry.method,
+                entry.path,
+                entry.status
+            );
+            return;
+        };
+        if let Err(e) = file.try_send(entry) {
+            warn!("Dropping access log line: {}", e);
+        }
+    }
+}
+
+fn write_line(writer: &mut impl Write, entry: &AccessEntry) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, entry)?;
+    writer.write_all(b"\n")
+}
+
+/// Adds the [`PaymentLog`] extension and logs the request once its response body is done,
+/// whatever answered it.
+pub async fn access_log(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(log) = state.access_log.clone() else {
+        return next.run(request).await;
+    };
+    let started = Instant::now();
+    let payment = PaymentLog::default();
+    request.extensions_mut().insert(payment.clone());
+    let entry = AccessEntry {
+        timestamp: Utc::now().to_rfc3339(),
+        method: request.method().to_string(),
+        path: request.uri().path().to_string(),
+        status: 0,
+        bytes_sent: 0,
+        duration_ms: 0,
+        client_ip: request_client_ip(&state, &request).map(|ip| ip.to_string()),
+        request_id: request
+            .extensions()
+            .get::<RequestId>()
+            .and_then(|id| id.header_value().to_str().ok())
+            .map(str::to_string),
+        payment: None,
+    };
+    let resp = next.run(request).await;
+    let entry = AccessEntry {
+        status: resp.status().as_u16(),
+        ..entry
+    };
+    resp.map(|body| {
+        Body::new(LoggedBody {
+            inner: body,
+            pending: Some(Pending {
+                log,
+                entry,
+                payment,
+                started,
+            }),
+        })
+    })
+}
+
+/// What [`LoggedBody`] logs once the body is done.
+struct Pending {
+    log: AccessLog,
+    entry: AccessEntry,
+    payment: PaymentLog,
+    started: Instant,
+}
+
+/// Response body that counts the data it yields and logs the request exactly once: when
+/// the body ends, fails, or is dropped early.
+struct LoggedBody {
+    inner: Body,
+    pending: Option<Pending>,
+}
+
+impl LoggedBody {
+    fn finish(&mut self) {
+        if let Some(Pending {
+            log,
+            entry,
+            payment,
+            started,
+        }) = self.pending.take()
+        {
+            log.log(AccessEntry {
+                duration_ms: started.elapsed().as_millis() as u64,
+                payment: payment.take(),
+                ..entry
+            });
+        }
+    }
+}
+
+impl HttpBody for LoggedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let polled = Pin::new(&mut self.inner).poll_frame(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(frame))) => {
+                let len = frame.data_ref().map_or(0, |data| data.len() as u64);
+                if let Some(pending) = &mut self.pending {
+                    pending.entry.bytes_sent += len;
+                }
+                // The server may stop polling once the body says it is done.
+                if self.inner.is_end_stream() {
+                    self.finish();
+                }
+            }
+            Poll::Ready(Some(Err(_)) | None) => self.finish(),
+            Poll::Pending => {}
+        }
+        polled
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{Value, json};
+
+    fn line(payment: &PaymentLog) -> Value {
+        let entry = AccessEntry {
+            timestamp: "2024-01-01T00:00:00+00:00".into(),
+            method: "GET".into(),
+            path: "/stream/a.ts".into(),
+            status: 200,
+            bytes_sent: 13,
+            duration_ms: 4,
+            client_ip: Some("203.0.113.7".into()),
+            request_id: None,
+            payment: payment.take(),
+        };
+        let mut written = Vec::new();
+        write_line(&mut written, &entry).unwrap();
+        assert_eq!(written.pop(), Some(b'\n'));
+        serde_json::from_slice(&written).unwrap()
+    }
+
+    #[test]
+    fn line_carries_the_recorded_payment() {
+        let payment = PaymentLog::default();
+        payment.record(&AuditEntry::new(Decision::PaymentRequired, "/stream/a.ts"));
+        payment.record(&AuditEntry {
+            scheme: Some("4mica-credit".into()),
+            payer: Some("0x00000000000000000000000000000000000000ef".into()),
+            amount: Some("100".into()),
+            ..AuditEntry::new(Decision::Settled, "/stream/a.ts")
+        });
+        assert_eq!(
+            line(&payment),
+            json!({
+                "timestamp": "2024-01-01T00:00:00+00:00",
+                "method": "GET",
+                "path": "/stream/a.ts",
+                "status": 200,
+                "bytesSent": 13,
+                "durationMs": 4,
+                "clientIp": "203.0.113.7",
+                "payment": {
+                    "decision": "settled",
+                    "scheme": "4mica-credit",
+                    "payer": "0x00000000000000000000000000000000000000ef",
+                    "amount": "100",
+                },
+            })
+        );
+        assert!(line(&PaymentLog::default()).get("payment").is_none());
+    }
+}
@@ -0,0 +1,309 @@
+use axum::{
+    Json, Router,
+    extract::{Path, Query, Request, State},
+    http::{HeaderMap, StatusCode, header::AUTHORIZATION},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get},
+};
+use serde::{Deserialize, Serialize};
+use server::x402::{SpendSummary, TabCacheEntry, fetch_tab_snapshot, parse_u256_value};
+use tracing::{info, warn};
+
+use crate::http::{delivery::DeliverySummary, model::ApiError, router::AppState};
+
+/// Operator-only routes, all behind the `ADMIN_TOKEN` bearer check.
+pub fn router(state: AppState) -> Router<AppState> {
+    Router::new()
+        .route("/tabs/summary", get(handle_tab_summary))
+        .route("/tabs/{tab_id}", get(handle_tab_snapshot))
+        .route("/settlements", get(handle_settlements))
+        .route("/deferred", get(handle_deferred))
+        .route("/unsettled", get(handle_unsettled))
+        .route("/recovery", get(handle_recovery))
+        .route("/spend", get(handle_top_spenders))
+        .route("/spend/{address}", get(handle_spend))
+        .route("/splits", get(handle_splits))
+        .route(
+            "/tab-cache",
+            get(handle_tab_cache).delete(handle_clear_tab_cache),
+        )
+        .route("/tab-cache/{user_address}", delete(handle_evict_tab_cache))
+        .route_layer(middleware::from_fn_with_state(state, require_admin_token))
+}
+
+async fn require_admin_token(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !bearer_matches(request.headers(), state.config.admin_token.as_deref()) {
+        return ApiError::new(StatusCode::UNAUTHORIZED, "unauthorized", "Unauthorized")
+            .into_response();
+    }
+    next.run(request).await
+}
+
+/// Whether `headers` carry `Authorization: Bearer <expected>`; never when `expected` is unset.
+pub(super) fn bearer_matches(headers: &HeaderMap, expected: Option<&str>) -> bool {
+    let presented = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match (expected, presented) {
+        (Some(expected), Some(presented)) => {
+            constant_time_eq(expected.as_bytes(), presented.as_bytes())
+        }
+        _ => false,
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn handle_tab_snapshot(
+    State(state): State<AppState>,
+    Path(tab_id): Path<String>,
+) -> Response {
+    let tab_id = match parse_u256_value(&tab_id) {
+        Ok(tab_id) => tab_id,
+        Err(e) => {
+            return ApiError::new(StatusCode::BAD_REQUEST, "invalid_tab_id", e).into_response();
+        }
+    };
+
+    let Some(snapshot) = fetch_tab_snapshot(tab_id, &state.config.x402).await else {
+        warn!("Admin tab snapshot requested but the 4mica SDK client is unavailable");
+        return ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "sdk_unavailable",
+            "4mica SDK client unavailable",
+        )
+        .into_response();
+    };
+    if snapshot.is_missing() {
+        return ApiError::new(StatusCode::NOT_FOUND, "tab_not_found", "Tab not found")
+            .into_response();
+    }
+    (StatusCode::OK, Json(snapshot)).into_response()
+}
+
+async fn handle_tab_summary(State(state): State<AppState>) -> Response {
+    let Some(audit) = &state.tab_audit else {
+        return ApiError::new(
+            StatusCode::NOT_FOUND,
+            "tab_audit_disabled",
+            "Tab audit is not enabled",
+        )
+        .into_response();
+    };
+    match audit.latest() {
+        Some(summary) => (StatusCode::OK, Json(summary)).into_response(),
+        None => ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "tab_audit_pending",
+            "The first tab audit has not finished yet",
+        )
+        .into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TopSpendersQuery {
+    /// Rank by this asset's total instead of all assets combined.
+    asset: Option<String>,
+    #[serde(default = "default_top_spenders_limit")]
+    limit: usize,
+}
+
+fn default_top_spenders_limit() -> usize {
+    10
+}
+
+async fn handle_deferred(State(state): State<AppState>) -> Response {
+    match &state.deferred {
+        Some(deferred) => (StatusCode::OK, Json(deferred.snapshot())).into_response(),
+        None => ApiError::new(
+            StatusCode::NOT_FOUND,
+            "deferred_disabled",
+            "Deferred settlement is not enabled",
+        )
+        .into_response(),
+    }
+}
+
+async fn handle_unsettled(State(state): State<AppState>) -> Response {
+    match &state.settle_queue {
+        Some(queue) => (StatusCode::OK, Json(queue.snapshot())).into_response(),
+        None => ApiError::new(
+            StatusCode::NOT_FOUND,
+            "async_settle_disabled",
+            "Async settlement is not enabled",
+        )
+        .into_response(),
+    }
+}
+
+async fn handle_recovery(State(state): State<AppState>) -> Response {
+    match &state.pending_settlements {
+        Some(journal) => (StatusCode::OK, Json(journal.snapshot())).into_response(),
+        None => ApiError::new(
+            StatusCode::NOT_FOUND,
+            "pending_settlements_disabled",
+            "X402_PENDING_SETTLEMENTS_PATH is not configured",
+        )
+        .into_response(),
+    }
+}
+
+async fn handle_top_spenders(
+    State(state): State<AppState>,
+    Query(query): Query<TopSpendersQuery>,
+) -> Response {
+    let spenders = state
+        .ledger
+        .top_spenders(query.asset.as_deref(), query.limit);
+    (StatusCode::OK, Json(spenders)).into_response()
+}
+
+/// An address's purchases alongside the bytes it was actually sent.
+#[derive(Serialize)]
+struct SpendResponse {
+    #[serde(flatten)]
+    summary: SpendSummary,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delivered: Option<DeliverySummary>,
+}
+
+async fn handle_spend(State(state): State<AppState>, Path(address): Path<String>) -> Response {
+    match state.ledger.summary(&address) {
+        Some(summary) => {
+            let delivered = state.deliveries.payer(&address);
+            (StatusCode::OK, Json(SpendResponse { summary, delivered })).into_response()
+        }
+        None => ApiError::new(
+            StatusCode::NOT_FOUND,
+            "address_not_found",
+            "No purchases recorded for address",
+        )
+        .into_response(),
+    }
+}
+
+async fn handle_splits(State(state): State<AppState>) -> Response {
+    if state.config.x402.pay_to_split.is_none() {
+        return ApiError::new(
+            StatusCode::NOT_FOUND,
+            "split_disabled",
+            "X402_PAY_TO_SPLIT is not configured",
+        )
+        .into_response();
+    }
+    (StatusCode::OK, Json(state.ledger.recipients())).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct TabCacheQuery {
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_tab_cache_limit")]
+    limit: usize,
+}
+
+fn default_tab_cache_limit() -> usize {
+    100
+}
+
+/// One page of the tab cache, ordered by user address.
+#[derive(Serialize)]
+struct TabCachePage {
+    total: usize,
+    entries: Vec<TabCacheEntry>,
+}
+
+/// How many cached tabs a flush dropped.
+#[derive(Serialize)]
+struct TabCacheEviction {
+    evicted: usize,
+}
+
+async fn handle_tab_cache(
+    State(state): State<AppState>,
+    Query(query): Query<TabCacheQuery>,
+) -> Response {
+    let entries = state
+        .facilitator
+        .as_ref()
+        .map(|facilitator| facilitator.tab_cache_entries())
+        .unwrap_or_default();
+    let total = entries.len();
+    let entries = entries
+        .into_iter()
+        .skip(query.offset)
+        .take(query.limit.min(1000))
+        .collect();
+    (StatusCode::OK, Json(TabCachePage { total, entries })).into_response()
+}
+
+async fn handle_clear_tab_cache(State(state): State<AppState>) -> Response {
+    let evicted = state
+        .facilitator
+        .as_ref()
+        .map_or(0, |facilitator| facilitator.clear_tab_cache());
+    info!(evicted, "Tab cache cleared");
+    (StatusCode::OK, Json(TabCacheEviction { evicted })).into_response()
+}
+
+async fn handle_evict_tab_cache(
+    State(state): State<AppState>,
+    Path(user_address): Path<String>,
+) -> Response {
+    let evicted = state.facilitator.as_ref().map_or(0, |facilitator| {
+        facilitator.evict_cached_tabs(&user_address)
+    });
+    info!(user_address, evicted, "Tab cache entries evicted");
+    (StatusCode::OK, Json(TabCacheEviction { evicted })).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct SettlementsQuery {
+    /// Unix timestamp in seconds; records before it are skipped.
+    #[serde(default)]
+    since: i64,
+    #[serde(default = "default_settlements_limit")]
+    limit: u32,
+}
+
+fn default_settlements_limit() -> u32 {
+    100
+}
+
+async fn handle_settlements(
+    State(state): State<AppState>,
+    Query(query): Query<SettlementsQuery>,
+) -> Response {
+    let Some(store) = &state.settlement_store else {
+        return ApiError::new(
+            StatusCode::NOT_FOUND,
+            "database_disabled",
+            "DATABASE_PATH is not configured",
+        )
+        .into_response();
+    };
+    match store.query(query.since, query.limit).await {
+        Some(Ok(records)) => (StatusCode::OK, Json(records)).into_response(),
+        Some(Err(e)) => ApiError::internal(
+            "settlement_query_failed",
+            "Failed to read settlement records",
+            e,
+        )
+        .into_response(),
+        None => ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "settlement_store_unavailable",
+            "Settlement store unavailable",
+        )
+        .into_response(),
+    }
+}
@@ -0,0 +1,254 @@
+use chrono::Utc;
+use serde::Serialize;
+use server::io::FsyncPolicy;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    thread,
+};
+use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
+
+/// Entries that may wait for the writer before new ones are dropped.
+const QUEUE_SIZE: usize = 4096;
+
+/// What the paywall did with a request.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub enum Decision {
+    /// Let through without a new settlement: a payment session or the free preview.
+    #[serde(rename = "served_free")]
+    ServedFree,
+    #[serde(rename = "402_issued")]
+    PaymentRequired,
+    #[serde(rename = "settled")]
+    Settled,
+    #[serde(rename = "settlement_failed")]
+    SettlementFailed,
+    /// Let through on a verified metered payment, which settles once delivery ends.
+    #[serde(rename = "verified")]
+    Verified,
+}
+
+impl Decision {
+    /// The name the logs give the decision.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::ServedFree => "served_free",
+            Self::PaymentRequired => "402_issued",
+            Self::Settled => "settled",
+            Self::SettlementFailed => "settlement_failed",
+            Self::Verified => "verified",
+        }
+    }
+}
+
+/// One paywall decision.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+    pub timestamp: String,
+    pub resource: String,
+    pub decision: Decision,
+    /// The requesting client, as resolved through `TRUSTED_PROXIES`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scheme: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// An audit log line: the entry plus `prevHash`, the SHA-256 of the previous line, so
+/// removing or editing a line breaks the chain.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ChainedEntry<'a> {
+    #[serde(flatten)]
+    entry: &'a AuditEntry,
+    prev_hash: &'a str,
+}
+
+impl AuditEntry {
+    pub fn new(decision: Decision, resource: &str) -> Self {
+        Self {
+            timestamp: Utc::now().to_rfc3339(),
+            resource: resource.to_string(),
+            decision,
+            client_ip: None,
+            scheme: None,
+            payer: None,
+            amount: None,
+            error: None,
+        }
+    }
+}
+
+enum Command {
+    Write(Box<AuditEntry>),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Appends paywall decisions to `AUDIT_LOG_PATH` as JSON lines.
+///
+/// A single writer thread owns the file, so lines never interleave. When the file
+/// would exceed `max_bytes` it is rotated to `<path>.1`, shifting older files up to
+/// `<path>.<keep>`. Write failures are logged and retried on the next line; they
+/// never fail the request.
+#[derive(Clone)]
+pub struct AuditLog {
+    tx: mpsc::Sender<Command>,
+}
+
+impl AuditLog {
+    pub fn spawn(
+        path: PathBuf,
+        max_bytes: u64,
+        keep: usize,
+        fsync: FsyncPolicy,
+    ) -> io::Result<Self> {
+        let (tx, mut rx) = mpsc::channel::<Command>(QUEUE_SIZE);
+        let mut writer = Writer {
+            prev_hash: last_line_hash(&path),
+            path,
+            max_bytes,
+            keep,
+            fsync,
+            file: None,
+        };
+        thread::Builder::new()
+            .name("audit-log".into())
+            .spawn(move || {
+                while let Some(command) = rx.blocking_recv() {
+                    match command {
+                        Command::Write(entry) => writer.write(*entry),
+                        Command::Flush(reply) => {
+                            if let Some((file, _)) = &writer.file
+                                && let Err(e) = file.sync_data()
+                            {
+                                warn!("Failed to sync audit log: {}", e);
+                            }
+                            let _ = reply.send(());
+                        }
+                    }
+                }
+            })?;
+        Ok(Self { tx })
+    }
+
+    pub fn log(&self, entry: AuditEntry) {
+        if let Err(e) = self.tx.try_send(Command::Write(Box::new(entry))) {
+            warn!("Dropping audit log entry: {}", e);
+        }
+    }
+
+    /// Waits until every entry queued so far has been written and synced.
+    pub async fn flush(&self) {
+        let (reply, rx) = oneshot::channel();
+        if self.tx.send(Command::Flush(reply)).await.is_ok() {
+            let _ = rx.await;
+        }
+    }
+}
+
+struct Writer {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    fsync: FsyncPolicy,
+    /// The open log and its current size.
+    file: Option<(File, u64)>,
+    prev_hash: String,
+}
+
+impl Writer {
+    fn write(&mut self, entry: AuditEntry) {
+        let chained = ChainedEntry {
+            entry: &entry,
+            prev_hash: &self.prev_hash,
+        };
+        let mut line = match serde_json::to_vec(&chained) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to serialize audit log entry: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        match self.append(&line) {
+            Ok(()) => self.prev_hash = format!("{:x}", Sha256::digest(&line)),
+            Err(e) => {
+                warn!("Failed to write audit log {}: {}", self.path.display(), e);
+                // Reopen on the next entry; the path may have become writable again.
+                self.file = None;
+            }
+        }
+    }
+
+    fn append(&mut self, line: &[u8]) -> io::Result<()> {
+        let len = line.len() as u64;
+        if let Some((_, size)) = &self.file
+            && *size > 0
+            && size + len > self.max_bytes
+        {
+            self.file = None;
+            self.rotate()?;
+        }
+        let (file, size) = match &mut self.file {
+            Some(open) => open,
+            None => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?;
+                let size = file.metadata()?.len();
+                self.file.insert((file, size))
+            }
+        };
+        file.write_all(line)?;
+        *size += len;
+        if self.fsync == FsyncPolicy::Always {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        if self.keep == 0 {
+            return fs::remove_file(&self.path);
+        }
+        for index in (1..self.keep).rev() {
+            let from = rotated_path(&self.path, index);
+            if from.exists() {
+                fs::rename(&from, rotated_path(&self.path, index + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated_path(&self.path, 1))
+    }
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{index}"));
+    PathBuf::from(rotated)
+}
+
+/// Hash of the last line already logged, so a restart (or rotation) continues the chain.
+fn last_line_hash(path: &Path) -> String {
+    [path.to_path_buf(), rotated_path(path, 1)]
+        .iter()
+        .find_map(|path| {
+            let contents = fs::read(path).ok()?;
+            contents
+                .split_inclusive(|byte| *byte == b'\n')
+                .rfind(|line| line.ends_with(b"\n"))
+                .map(|line| format!("{:x}", Sha256::digest(line)))
+        })
+        .unwrap_or_default()
+}
@@ -0,0 +1,363 @@
+use async_zip::{Compression, ZipDateTime, ZipEntryBuilder, tokio::write::ZipFileWriter};
+use axum::{
+    Extension, Router,
+    body::Body,
+    extract::{Path, State},
+    http::{
+        HeaderValue, StatusCode, Uri,
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+    },
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use chrono::{DateTime, Datelike, Utc};
+use futures_util::{AsyncWriteExt, StreamExt, future, stream};
+use sdk_4mica::U256;
+use serde::Deserialize;
+use serde_json::json;
+use server::io::{FileInfo, StorageBackend};
+use std::{collections::BTreeMap, io, str::FromStr, sync::Arc};
+use tokio::io::DuplexStream;
+use tokio_util::io::ReaderStream;
+use tracing::warn;
+
+use crate::http::{
+    cache_policy,
+    model::{ApiError, ApiErrorBody, PaymentRequiredResponse},
+    mounts::Mount,
+    openapi,
+    pricing::PricedRoute,
+    rate_limit::rate_limit,
+    router::AppState,
+    x402::{self, PaidRequest, Paywall},
+};
+
+/// Archive bytes buffered between the zip writer and the response body.
+const ARCHIVE_BUFFER_BYTES: usize = 64 * 1024;
+
+/// A `BUNDLES` entry as written: `files` sold together at `price`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawBundle {
+    price: String,
+    files: Vec<String>,
+}
+
+/// One validated `BUNDLES` entry.
+#[derive(Debug, Clone)]
+pub struct Bundle {
+    pub name: String,
+    /// Price of the whole bundle, in place of its files' prices.
+    pub price: U256,
+    /// Paths as `/stream/{filename}` takes them, in archive order.
+    pub files: Vec<String>,
+}
+
+/// `BUNDLES`: a JSON object mapping each bundle name to `{price, files}`, or a path to a
+/// file holding one.
+#[derive(Debug, Clone)]
+pub struct BundleList(pub Vec<Arc<Bundle>>);
+
+impl FromStr for BundleList {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let raw = raw.trim();
+        let json = if raw.starts_with('{') {
+            raw.to_string()
+        } else {
+            std::fs::read_to_string(raw).map_err(|e| format!("failed to read {raw}: {e}"))?
+        };
+        let bundles: BTreeMap<String, RawBundle> =
+            serde_json::from_str(&json).map_err(|e| format!("invalid bundle list: {e}"))?;
+        let mut entries = Vec::with_capacity(bundles.len());
+        for (name, bundle) in bundles {
+            // Also the archive's file name, so it must be safe in `Content-Disposition`.
+            let valid_name = !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+            if !valid_name || name.starts_with('.') {
+                return Err(format!("invalid bundle name {name:?}"));
+            }
+            let price = bundle
+                .price
+                .parse::<U256>()
+                .map_err(|e| format!("invalid price of bundle {name:?}: {e}"))?;
+            if bundle.files.is_empty() {
+                return Err(format!("bundle {name:?} lists no files"));
+            }
+            let mut files: Vec<String> = Vec::with_capacity(bundle.files.len());
+            for file in bundle.files {
+                let path = file.trim_start_matches('/');
+                let invalid = path
+                    .split('/')
+                    .any(|segment| matches!(segment, "" | "." | "..") || segment.contains('\\'));
+                if invalid {
+                    return Err(format!("invalid path {file:?} in bundle {name:?}"));
+                }
+                if files.iter().any(|listed| listed == path) {
+                    return Err(format!("bundle {name:?} lists {path:?} twice"));
+                }
+                files.push(path.to_string());
+            }
+            entries.push(Arc::new(Bundle { name, price, files }));
+        }
+        Ok(Self(entries))
+    }
+}
+
+impl BundleList {
+    fn get(&self, name: &str) -> Option<&Arc<Bundle>> {
+        self.0.iter().find(|bundle| bundle.name == name)
+    }
+}
+
+/// A bundle whose files were all found, in `Bundle::files` order. Inserted before the
+/// paywall, which charges its price.
+#[derive(Clone)]
+pub struct VerifiedBundle {
+    pub bundle: Arc<Bundle>,
+    members: Arc<[Member]>,
+}
+
+/// A bundled file and the `MOUNTS` entry it was found under, if any.
+struct Member {
+    path: String,
+    file: FileInfo,
+    mount: Option<Arc<Mount>>,
+}
+
+impl Member {
+    fn storage<'a>(&'a self, state: &'a AppState) -> &'a dyn StorageBackend {
+        match &self.mount {
+            Some(mount) => &mount.storage,
+            None => state.storage.as_ref(),
+        }
+    }
+}
+
+/// `/bundle/{name}`, when `BUNDLES` is set.
+pub fn router(state: &AppState) -> Router<AppState> {
+    if state.config.bundles.is_none() {
+        return Router::new();
+    }
+    Router::new().route(
+        "/bundle/{name}",
+        get(handle_bundle)
+            .route_layer(middleware::from_fn_with_state(
+                Paywall::new(state.clone(), PricedRoute::Bundle),
+                x402::require_payment,
+            ))
+            // Runs before the paywall so nobody pays for a bundle with a missing file.
+            .route_layer(middleware::from_fn_with_state(state.clone(), verify_bundle))
+            .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit)),
+    )
+}
+
+async fn verify_bundle(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    mut request: axum::extract::Request,
+    next: Next,
+) -> Response {
+    let Some(bundle) = state
+        .config
+        .bundles
+        .as_ref()
+        .and_then(|bundles| bundles.get(&name))
+    else {
+        return ApiError::new(
+            StatusCode::NOT_FOUND,
+            "bundle_not_found",
+            "Bundle not found",
+        )
+        .into_response();
+    };
+    let mut members = Vec::with_capacity(bundle.files.len());
+    for path in &bundle.files {
+        let verified = match state.mounts.resolve(path) {
+            Some((mount, rest)) => {
+                let file = mount.storage.verify(rest).await;
+                file.map(|file| (file, Some(mount.clone())))
+            }
+            None => state.storage.verify(path).await.map(|file| (file, None)),
+        };
+        match verified {
+            Ok((file, mount)) => members.push(Member {
+                path: path.clone(),
+                file,
+                mount,
+            }),
+            Err(e) => {
+                warn!("Bundle {} file {} is unavailable: {}", bundle.name, path, e);
+                return ApiError::from(e)
+                    .with_details(json!({ "file": path }))
+                    .into_response();
+            }
+        }
+    }
+    request.extensions_mut().insert(VerifiedBundle {
+        bundle: bundle.clone(),
+        members: members.into(),
+    });
+    next.run(request).await
+}
+
+/// Streams a bundle's files as one zip archive once the bundle is paid for. Entries are
+/// stored uncompressed, since the media in them already is, and the archive is written as
+/// it is sent.
+#[utoipa::path(
+    get,
+    path = "/bundle/{name}",
+    tag = "stream",
+    params(
+        ("name" = String, Path, description = "Name of a `BUNDLES` entry"),
+        openapi::PaymentHeaders,
+        openapi::PaymentQuery,
+    ),
+    responses(
+        (status = 200, description = "Zip archive of the bundle's files, as `attachment; filename=\"{name}.zip\"`; a fresh payment adds `payment-response`", content_type = "application/zip", body = Vec<u8>),
+        (status = 402, description = "Payment for the whole bundle is required; v2 clients also get the base64 `payment-required` header", body = PaymentRequiredResponse),
+        (status = 403, description = "`access_denied`: a file leaves the storage root; `details.file` names it", body = ApiErrorBody),
+        (status = 404, description = "`bundle_not_found`: no such bundle; `file_not_found`: a file of the bundle is missing, named by `details.file`", body = ApiErrorBody),
+        (status = 429, description = "`rate_limited`: retry after `Retry-After` seconds", body = ApiErrorBody),
+        (status = 503, description = "`file_not_ready`: a file of the bundle is still being written; `settlement_busy`: too many payments are settling", body = ApiErrorBody),
+    )
+)]
+pub(super) async fn handle_bundle(
+    State(state): State<AppState>,
+    Extension(bundle): Extension<VerifiedBundle>,
+    paid: Option<Extension<PaidRequest>>,
+    uri: Uri,
+) -> Response {
+    let (writer, reader) = tokio::io::duplex(ARCHIVE_BUFFER_BYTES);
+    let written = tokio::spawn(write_archive(state.clone(), bundle.clone(), writer));
+    // A failure ends the body with an error, so the client sees a broken download rather
+    // than a truncated archive.
+    let failed = async move {
+        match written.await {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(Err(e)),
+            Err(e) => Some(Err(io::Error::other(e))),
+        }
+    };
+    let archive = ReaderStream::with_capacity(reader, ARCHIVE_BUFFER_BYTES)
+        .chain(stream::once(failed).filter_map(future::ready));
+    let payer = paid.as_ref().and_then(|Extension(paid)| paid.payer());
+    let body = state.deliveries.count(
+        Body::from_stream(archive),
+        uri.path().to_string(),
+        payer.map(str::to_string),
+    );
+
+    let mut resp = (StatusCode::OK, body).into_response();
+    let headers = resp.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/zip"));
+    let disposition = format!("attachment; filename=\"{}.zip\"", bundle.bundle.name);
+    if let Ok(disposition) = HeaderValue::from_str(&disposition) {
+        headers.insert(CONTENT_DISPOSITION, disposition);
+    }
+    cache_policy::apply(&mut resp, &state.config, uri.path(), paid.is_some());
+    if let Some(Extension(paid)) = paid {
+        paid.attach(&mut resp);
+    }
+    resp
+}
+
+/// Writes `bundle` to `writer` as a zip archive, one entry per file under its bundle path.
+async fn write_archive(
+    state: AppState,
+    bundle: VerifiedBundle,
+    writer: DuplexStream,
+) -> io::Result<()> {
+    let mut zip = ZipFileWriter::with_tokio(writer);
+    for member in bundle.members.iter() {
+        let body = member
+            .storage(&state)
+            .open_stream(&member.file, None)
+            .await
+            .map_err(io::Error::other)?;
+        let mut entry = ZipEntryBuilder::new(member.path.clone().into(), Compression::Stored);
+        // Zip timestamps start in 1980.
+        if let Some(modified) = member
+            .file
+            .modified
+            .map(DateTime::<Utc>::from)
+            .filter(|modified| modified.year() >= 1980)
+        {
+            entry = entry.last_modification_date(ZipDateTime::from_chrono(&modified));
+        }
+        let mut entry = zip
+            .write_entry_stream(entry)
+            .await
+            .map_err(io::Error::other)?;
+        let mut data = body.into_data_stream();
+        while let Some(chunk) = data.next().await {
+            entry.write_all(&chunk.map_err(io::Error::other)?).await?;
+        }
+        entry.close().await.map_err(io::Error::other)?;
+    }
+    zip.close().await.map_err(io::Error::other)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<BundleList, String> {
+        raw.parse()
+    }
+
+    #[test]
+    fn bundles_parse_in_name_order() {
+        let bundles = parse(
+            r#"{"pack": {"price": "250", "files": ["/a.ts", "captions/a.vtt"]},
+                "extras": {"price": "10", "files": ["b.ts"]}}"#,
+        )
+        .unwrap();
+        let names: Vec<&str> = bundles
+            .0
+            .iter()
+            .map(|bundle| bundle.name.as_str())
+            .collect();
+        assert_eq!(names, ["extras", "pack"]);
+        let pack = bundles.get("pack").unwrap();
+        assert_eq!(pack.price, U256::from(250));
+        assert_eq!(pack.files, ["a.ts", "captions/a.vtt"]);
+        assert!(bundles.get("Pack").is_none());
+    }
+
+    #[test]
+    fn bundles_load_from_a_file() {
+        let file = std::env::temp_dir().join(format!("bundles-{}.json", std::process::id()));
+        std::fs::write(&file, r#"{"pack": {"price": "1", "files": ["a.ts"]}}"#).unwrap();
+        let bundles = parse(&file.display().to_string()).unwrap();
+        assert_eq!(bundles.0.len(), 1);
+        std::fs::remove_file(&file).unwrap();
+        assert!(parse(&file.display().to_string()).is_err());
+    }
+
+    #[test]
+    fn invalid_bundles_are_rejected() {
+        for raw in [
+            r#"{"": {"price": "1", "files": ["a.ts"]}}"#,
+            r#"{".hidden": {"price": "1", "files": ["a.ts"]}}"#,
+            r#"{"a\"b": {"price": "1", "files": ["a.ts"]}}"#,
+            r#"{"a b": {"price": "1", "files": ["a.ts"]}}"#,
+            r#"{"pack": {"price": "-1", "files": ["a.ts"]}}"#,
+            r#"{"pack": {"price": "1", "files": []}}"#,
+            r#"{"pack": {"price": "1", "files": ["../a.ts"]}}"#,
+            r#"{"pack": {"price": "1", "files": ["a/./b.ts"]}}"#,
+            r#"{"pack": {"price": "1", "files": ["a//b.ts"]}}"#,
+            r#"{"pack": {"price": "1", "files": ["a\\b.ts"]}}"#,
+            r#"{"pack": {"price": "1", "files": ["a.ts", "/a.ts"]}}"#,
+            r#"{"pack": {"price": "1", "files": ["a.ts"], "free": true}}"#,
+            r#"["a.ts"]"#,
+        ] {
+            assert!(parse(raw).is_err(), "{raw}");
+        }
+    }
+}
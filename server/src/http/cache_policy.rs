@@ -0,0 +1,129 @@
+use axum::http::{HeaderValue, header::CACHE_CONTROL};
+use axum::response::Response;
+
+use super::{config::Config, pricing::is_playlist};
+
+/// Extensions of content that never changes once published, so caches may keep it.
+const IMMUTABLE_EXTENSIONS: [&str; 3] = ["ts", "m4s", "mp4"];
+
+/// Sets `Cache-Control` on a response streaming `name`, a path or URL.
+///
+/// Segments and MP4s are cached for `SEGMENT_MAX_AGE` and playlists for `PLAYLIST_MAX_AGE`.
+/// Responses behind the paywall are also `private`, so shared caches don't serve what was
+/// paid for to others, unless `CDN_MODE` says the CDN enforces payment itself. Other files
+/// keep whatever `Cache-Control` the response already has.
+pub(super) fn apply(resp: &mut Response, config: &Config, name: &str, paywalled: bool) {
+    if let Some(value) = cache_control(config, name, paywalled) {
+        resp.headers_mut().insert(CACHE_CONTROL, value);
+    }
+}
+
+fn cache_control(config: &Config, name: &str, paywalled: bool) -> Option<HeaderValue> {
+    let path = name.split(['?', '#']).next().unwrap_or(name);
+    let policy = if is_playlist(path) {
+        match config.playlist_max_age {
+            0 => "no-cache".to_string(),
+            max_age => format!("max-age={max_age}"),
+        }
+    } else if is_immutable(path) {
+        format!("max-age={}, immutable", config.segment_max_age)
+    } else if paywalled && !config.cdn_mode {
+        return Some(HeaderValue::from_static("private"));
+    } else {
+        return None;
+    };
+    let scope = if paywalled && !config.cdn_mode {
+        "private"
+    } else {
+        "public"
+    };
+    HeaderValue::from_str(&format!("{scope}, {policy}")).ok()
+}
+
+fn is_immutable(path: &str) -> bool {
+    path.rsplit_once('.').is_some_and(|(_, ext)| {
+        IMMUTABLE_EXTENSIONS
+            .iter()
+            .any(|immutable| ext.eq_ignore_ascii_case(immutable))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use envconfig::Envconfig;
+    use std::collections::HashMap;
+
+    fn config(settings: &[(&str, &str)]) -> Config {
+        let values: HashMap<String, String> = [("X402_PAY_TO", "0xab")]
+            .iter()
+            .chain(settings)
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        Config::init_from_hashmap(&values).unwrap()
+    }
+
+    fn policy(config: &Config, name: &str, paywalled: bool) -> Option<String> {
+        cache_control(config, name, paywalled).map(|value| value.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn segments_are_immutable() {
+        let defaults = config(&[]);
+        for name in [
+            "seg_00001.ts",
+            "show/720p/chunk.M4S",
+            "init.mp4?session=abc",
+        ] {
+            assert_eq!(
+                policy(&defaults, name, false).as_deref(),
+                Some("public, max-age=31536000, immutable"),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn playlists_get_a_short_max_age() {
+        let defaults = config(&[]);
+        assert_eq!(
+            policy(&defaults, "show/index.m3u8", false).as_deref(),
+            Some("public, max-age=2")
+        );
+        assert_eq!(
+            policy(&defaults, "https://cdn.test/manifest.mpd#t=1", false).as_deref(),
+            Some("public, max-age=2")
+        );
+
+        let uncached = config(&[("PLAYLIST_MAX_AGE", "0")]);
+        assert_eq!(
+            policy(&uncached, "index.m3u8", false).as_deref(),
+            Some("public, no-cache")
+        );
+    }
+
+    #[test]
+    fn paywalled_responses_are_private() {
+        let defaults = config(&[]);
+        assert_eq!(
+            policy(&defaults, "seg_00001.ts", true).as_deref(),
+            Some("private, max-age=31536000, immutable")
+        );
+        assert_eq!(
+            policy(&defaults, "index.m3u8", true).as_deref(),
+            Some("private, max-age=2")
+        );
+        assert_eq!(
+            policy(&defaults, "notes.txt", true).as_deref(),
+            Some("private")
+        );
+        assert_eq!(policy(&defaults, "notes.txt", false), None);
+
+        let cdn = config(&[("CDN_MODE", "true")]);
+        assert_eq!(
+            policy(&cdn, "seg_00001.ts", true).as_deref(),
+            Some("public, max-age=31536000, immutable")
+        );
+        assert_eq!(policy(&cdn, "notes.txt", true), None);
+    }
+}
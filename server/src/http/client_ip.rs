@@ -0,0 +1,334 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use crate::http::router::AppState;
+
+/// A network in `TRUSTED_PROXIES`: an address and how many leading bits must match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IpRange {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match raw.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (raw, None),
+        };
+        let network = address
+            .parse::<IpAddr>()
+            .map_err(|e| format!("invalid address in trusted proxy {raw:?}: {e}"))?
+            .to_canonical();
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("invalid prefix length in trusted proxy {raw:?}"))?,
+            None => max,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+/// `TRUSTED_PROXIES`: comma-separated addresses and CIDR ranges, e.g.
+/// `127.0.0.1,10.0.0.0/8,fd00::/8`, whose forwarded headers name the real client.
+#[derive(Debug, Clone)]
+pub struct TrustedProxies(Vec<IpRange>);
+
+impl FromStr for TrustedProxies {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let ranges = raw
+            .split(',')
+            .map(str::trim)
+            .filter(|range| !range.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<_>, String>>()?;
+        if ranges.is_empty() {
+            return Err("trusted proxy list is empty".into());
+        }
+        Ok(Self(ranges))
+    }
+}
+
+impl TrustedProxies {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|range| range.contains(ip))
+    }
+
+    /// The client behind `peer`: the nearest hop in the forwarded headers that is not a
+    /// trusted proxy, found by walking them from the right. Headers are ignored unless
+    /// `peer` is itself trusted; `None` for the peer means a Unix socket, which only local
+    /// processes can reach.
+    ///
+    /// `Forwarded` wins over `X-Forwarded-For` when both are set. A hop that is not an
+    /// address (`unknown`, an obfuscated name) stops the walk at the last proxy before it,
+    /// and if every hop is trusted the leftmost is taken.
+    pub fn client_ip(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        let peer = peer.map(|ip| ip.to_canonical());
+        if let Some(peer) = peer
+            && !self.contains(peer)
+        {
+            return Some(peer);
+        }
+        let mut client = peer;
+        for hop in forwarded_hops(headers).into_iter().rev() {
+            let Some(ip) = hop else { break };
+            client = Some(ip);
+            if !self.contains(ip) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+/// The hops forwarded headers list, from the original client to the nearest proxy;
+/// `None` for an entry that is not an address.
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .collect::<Vec<_>>()
+    };
+    let forwarded = values("forwarded");
+    if !forwarded.is_empty() {
+        return forwarded
+            .into_iter()
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                    .and_then(|(_, node)| parse_node(node))
+            })
+            .collect();
+    }
+    values("x-forwarded-for")
+        .into_iter()
+        .map(parse_node)
+        .collect()
+}
+
+/// An address as forwarded headers write it: bare, quoted, `[v6]` or with a port.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip.to_canonical());
+    }
+    node.parse::<SocketAddr>()
+        .ok()
+        .map(|addr| addr.ip().to_canonical())
+}
+
+/// The address a request came from, after [`resolve_client_ip`] read any forwarded headers
+/// from trusted proxies; absent on a Unix socket without `TRUSTED_PROXIES` or forwarded
+/// headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+/// The address `request` came from, through `TRUSTED_PROXIES` when set.
+pub fn request_client_ip(state: &AppState, request: &Request) -> Option<IpAddr> {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    match &state.config.trusted_proxies {
+        Some(proxies) => proxies.client_ip(peer, request.headers()),
+        None => peer.map(|ip| ip.to_canonical()),
+    }
+}
+
+/// What per-client limits count a request against: its [`ClientIp`], empty when it has
+/// none. Never the payer a payment header claims, which is unverified until settled and
+/// could name a new payer in every request to start afresh.
+pub fn client_key(request: &Request) -> String {
+    request
+        .extensions()
+        .get::<ClientIp>()
+        .map(|ClientIp(ip)| ip.to_string())
+        .unwrap_or_default()
+}
+
+/// Adds the [`ClientIp`] extension, and records it on the request span.
+pub async fn resolve_client_ip(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if let Some(ip) = request_client_ip(&state, &request) {
+        tracing::Span::current().record("client_ip", tracing::field::display(ip));
+        request.extensions_mut().insert(ClientIp(ip));
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn proxies() -> TrustedProxies {
+        "127.0.0.1,10.0.0.0/8,fd00::/8".parse().unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn ip(raw: &str) -> IpAddr {
+        raw.parse().unwrap()
+    }
+
+    #[test]
+    fn ranges_parse_and_match() {
+        let proxies = proxies();
+        assert!(proxies.contains(ip("127.0.0.1")));
+        assert!(proxies.contains(ip("10.255.0.1")));
+        assert!(proxies.contains(ip("fd12::1")));
+        assert!(proxies.contains(ip("::ffff:10.0.0.1")));
+        assert!(!proxies.contains(ip("127.0.0.2")));
+        assert!(!proxies.contains(ip("11.0.0.1")));
+        assert!(!proxies.contains(ip("fe80::1")));
+
+        let everything: TrustedProxies = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(ip("203.0.113.9")));
+        for raw in [
+            "",
+            " , ",
+            "10.0.0.0/33",
+            "fd00::/129",
+            "not-an-ip",
+            "10.0.0.0/x",
+        ] {
+            assert!(raw.parse::<TrustedProxies>().is_err(), "{raw:?}");
+        }
+    }
+
+    #[test]
+    fn untrusted_peer_headers_are_ignored() {
+        let spoofed = headers(&[
+            ("x-forwarded-for", "198.51.100.7"),
+            ("forwarded", "for=198.51.100.8"),
+        ]);
+        assert_eq!(
+            proxies().client_ip(Some(ip("203.0.113.9")), &spoofed),
+            Some(ip("203.0.113.9"))
+        );
+    }
+
+    #[test]
+    fn multi_hop_chain_is_walked_from_the_right() {
+        // client, then a spoofed entry it added, then two of our proxies.
+        let chain = headers(&[("x-forwarded-for", "198.51.100.7, 203.0.113.9, 10.0.0.2")]);
+        assert_eq!(
+            proxies().client_ip(Some(ip("10.0.0.1")), &chain),
+            Some(ip("203.0.113.9"))
+        );
+
+        // The same chain split over several header lines.
+        let split = headers(&[
+            ("x-forwarded-for", "198.51.100.7"),
+            ("x-forwarded-for", "203.0.113.9, 10.0.0.2"),
+        ]);
+        assert_eq!(
+            proxies().client_ip(Some(ip("10.0.0.1")), &split),
+            Some(ip("203.0.113.9"))
+        );
+
+        let all_trusted = headers(&[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]);
+        assert_eq!(
+            proxies().client_ip(Some(ip("10.0.0.1")), &all_trusted),
+            Some(ip("10.0.0.3"))
+        );
+    }
+
+    #[test]
+    fn forwarded_wins_over_x_forwarded_for() {
+        let both = headers(&[
+            ("x-forwarded-for", "198.51.100.7"),
+            (
+                "forwarded",
+                "for=\"[2001:db8::1]:4711\";proto=https, for=10.0.0.2",
+            ),
+        ]);
+        assert_eq!(
+            proxies().client_ip(Some(ip("127.0.0.1")), &both),
+            Some(ip("2001:db8::1"))
+        );
+        let with_port = headers(&[("forwarded", "for=192.0.2.60:8080;by=10.0.0.1")]);
+        assert_eq!(
+            proxies().client_ip(Some(ip("127.0.0.1")), &with_port),
+            Some(ip("192.0.2.60"))
+        );
+    }
+
+    #[test]
+    fn unknown_hop_stops_at_the_last_proxy() {
+        let obfuscated = headers(&[("x-forwarded-for", "198.51.100.7, unknown, 10.0.0.2")]);
+        assert_eq!(
+            proxies().client_ip(Some(ip("10.0.0.1")), &obfuscated),
+            Some(ip("10.0.0.2"))
+        );
+    }
+
+    #[test]
+    fn unix_socket_peers_trust_their_headers() {
+        let forwarded = headers(&[("x-forwarded-for", "198.51.100.7")]);
+        assert_eq!(
+            proxies().client_ip(None, &forwarded),
+            Some(ip("198.51.100.7"))
+        );
+        assert_eq!(proxies().client_ip(None, &HeaderMap::new()), None);
+        assert_eq!(
+            proxies().client_ip(Some(ip("10.0.0.1")), &HeaderMap::new()),
+            Some(ip("10.0.0.1"))
+        );
+    }
+}
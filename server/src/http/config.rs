@@ -1,30 +1,777 @@
+use crate::http::{bundle::BundleList, client_ip::TrustedProxies, mounts::MountList};
 use envconfig::Envconfig;
-use server::x402::X402Config;
+use server::{
+    io::{FileStability, FsyncPolicy, IpfsGateways, RemoteConfig, S3Config},
+    x402::{BASIS_POINTS, SANDBOX_ACK, X402Config, X402Mode},
+};
+use std::{collections::HashMap, path::Path, str::FromStr, time::Duration};
 use url::Url;
 
-#[derive(Envconfig, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("invalid log format {other}, expected text or json")),
+        }
+    }
+}
+
+/// Which [`server::io::StorageBackend`] serves `/stream/{filename}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+    Local,
+    S3,
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "local" => Ok(StorageKind::Local),
+            "s3" => Ok(StorageKind::S3),
+            other => Err(format!(
+                "invalid storage backend {other}, expected local or s3"
+            )),
+        }
+    }
+}
+
+/// Permission bits for the Unix socket, written in octal (`660`, `0o660`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketMode(pub u32);
+
+impl FromStr for SocketMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits = s.trim().trim_start_matches("0o");
+        match u32::from_str_radix(digits, 8) {
+            Ok(mode) if mode <= 0o777 => Ok(SocketMode(mode)),
+            _ => Err(format!(
+                "invalid socket mode {s}, expected octal permissions like 660"
+            )),
+        }
+    }
+}
+
+/// Why the layered configuration could not be loaded.
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read config file {path}: {source}")]
+    Read {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("failed to parse config file {path}: {source}")]
+    Parse {
+        path: String,
+        source: toml::de::Error,
+    },
+
+    #[error("config file key {0} must be a string, number, boolean, or list of those")]
+    Unsupported(String),
+
+    #[error(transparent)]
+    Env(#[from] envconfig::Error),
+
+    #[error("invalid price: {0}")]
+    Price(String),
+}
+
+#[derive(Envconfig, Clone, Debug)]
 pub struct Config {
     #[envconfig(from = "LOG_LEVEL", default = "info")]
-    pub log_level: log::Level,
+    pub log_level: tracing::Level,
 
+    #[envconfig(from = "LOG_FORMAT", default = "text")]
+    pub log_format: LogFormat,
+
+    #[envconfig(from = "STORAGE_BACKEND", default = "local")]
+    pub storage_backend: StorageKind,
+
+    /// Content root for the `local` storage backend.
     #[envconfig(from = "FILE_DIRECTORY", default = "./data/hls")]
     pub file_directory: String,
 
+    /// Directories served under `/stream/` URL prefixes with their own pay-to address and
+    /// price, see [`MountList`]; other paths fall back to the storage backend.
+    #[envconfig(from = "MOUNTS")]
+    pub mounts: Option<MountList>,
+
+    /// Files sold together as one zip archive at `/bundle/{name}`, see [`BundleList`].
+    #[envconfig(from = "BUNDLES")]
+    pub bundles: Option<BundleList>,
+
+    /// Largest read of a local file, and size remote bodies' small chunks are merged up to,
+    /// when streaming.
+    #[envconfig(from = "STREAM_BUFFER_BYTES", default = "65536")]
+    pub stream_buffer_bytes: usize,
+
+    /// Local files modified less than this many milliseconds ago are still being written:
+    /// they answer 503 with `Retry-After` and generated playlists leave them out. 0 is off.
+    #[envconfig(from = "MIN_FILE_AGE_MS", default = "0")]
+    pub min_file_age_ms: u64,
+
+    /// Comma-separated name endings of local files still being written, which answer 404;
+    /// empty serves every file.
+    #[envconfig(from = "IGNORE_FILE_SUFFIXES", default = ".tmp,.part")]
+    pub ignore_file_suffixes: String,
+
+    #[envconfig(nested)]
+    pub s3: S3Config,
+
+    #[envconfig(nested)]
+    pub remote: RemoteConfig,
+
+    /// Path gateways `/stream/ipfs/{cid}` fetches through, in order; the route is off when
+    /// unset.
+    #[envconfig(from = "IPFS_GATEWAYS")]
+    pub ipfs_gateways: Option<IpfsGateways>,
+
     #[envconfig(from = "SERVER_PORT", default = "3000")]
     pub server_port: u16,
 
     #[envconfig(from = "SERVER_HOST", default = "0.0.0.0")]
     pub server_host: String,
 
+    /// Public origin of this server, joined into resource URLs and the `tabEndpoint`. Behind a
+    /// proxy (always the case with `unix_socket`) it must be the proxy's URL.
     #[envconfig(from = "SERVER_ADVERTISED_URL", default = "http://localhost:3000")]
     pub server_advertised_url: Url,
 
+    /// Serves on a Unix socket at this path instead of `SERVER_HOST:SERVER_PORT`. Clients
+    /// have no remote IP there unless `TRUSTED_PROXIES` is set, so previews and rate limits
+    /// otherwise key them by payer only.
+    #[envconfig(from = "SERVER_UNIX_SOCKET")]
+    pub unix_socket: Option<String>,
+
+    /// Permissions of the Unix socket; the umask decides them when unset.
+    #[envconfig(from = "SERVER_UNIX_SOCKET_MODE")]
+    pub unix_socket_mode: Option<SocketMode>,
+
+    /// Peers whose `Forwarded`/`X-Forwarded-For` headers name the client; headers from
+    /// anyone else are ignored, and all of them are when unset. A Unix socket peer is
+    /// trusted whenever this is set.
+    #[envconfig(from = "TRUSTED_PROXIES")]
+    pub trusted_proxies: Option<TrustedProxies>,
+
+    /// PEM certificate chain; TLS is served when this and `tls_key_path` are both set.
+    #[envconfig(from = "TLS_CERT_PATH")]
+    pub tls_cert_path: Option<String>,
+
+    #[envconfig(from = "TLS_KEY_PATH")]
+    pub tls_key_path: Option<String>,
+
+    /// Enables payment sessions: after one settlement, clients may stream sibling
+    /// resources until the session expires by presenting the minted token.
+    #[envconfig(from = "SESSION_SECRET")]
+    pub session_secret: Option<String>,
+
+    #[envconfig(from = "SESSION_DURATION_SECONDS", default = "300")]
+    pub session_duration_seconds: u64,
+
+    /// Trailing path components stripped from the paid resource to get the session prefix;
+    /// it never covers a whole route.
+    #[envconfig(from = "SESSION_PREFIX_STRIP_SEGMENTS", default = "1")]
+    pub session_prefix_strip_segments: usize,
+
+    /// Receives a JSON event for every successful settlement.
+    #[envconfig(from = "WEBHOOK_URL")]
+    pub webhook_url: Option<Url>,
+
+    /// Signs webhook bodies with HMAC-SHA256 in the `x-webhook-signature` header.
+    #[envconfig(from = "WEBHOOK_SECRET")]
+    pub webhook_secret: Option<String>,
+
+    #[envconfig(from = "WEBHOOK_QUEUE_SIZE", default = "1024")]
+    pub webhook_queue_size: usize,
+
+    /// Serves the OpenAPI spec at `/openapi.json` and a Swagger UI at `/docs`.
+    #[envconfig(from = "API_DOCS_ENABLED", default = "false")]
+    pub api_docs_enabled: bool,
+
+    /// Gzip/brotli-compresses playlists, JSON and text bodies for clients that accept it.
+    #[envconfig(from = "COMPRESSION_ENABLED", default = "false")]
+    pub compression_enabled: bool,
+
+    /// Bearer token guarding `/admin` routes; they answer 401 when unset.
+    #[envconfig(from = "ADMIN_TOKEN")]
+    pub admin_token: Option<String>,
+
+    /// Bearer token guarding `PUT /upload/{filename}`; uploads answer 401 when unset.
+    #[envconfig(from = "UPLOAD_TOKEN")]
+    pub upload_token: Option<String>,
+
+    /// Largest upload accepted, in bytes.
+    #[envconfig(from = "UPLOAD_MAX_BYTES", default = "536870912")]
+    pub upload_max_bytes: u64,
+
+    /// Comma-separated file extensions uploads may have.
+    #[envconfig(from = "UPLOAD_EXTENSIONS", default = "m3u8,mpd,ts,mp4,m4s")]
+    pub upload_extensions: String,
+
+    /// Append-only JSON lines log of every paywall decision; disabled when unset.
+    #[envconfig(from = "AUDIT_LOG_PATH")]
+    pub audit_log_path: Option<String>,
+
+    /// Size at which the audit log is rotated.
+    #[envconfig(from = "AUDIT_LOG_MAX_BYTES", default = "10485760")]
+    pub audit_log_max_bytes: u64,
+
+    /// Rotated audit logs kept as `<path>.1` .. `<path>.N`.
+    #[envconfig(from = "AUDIT_LOG_KEEP", default = "5")]
+    pub audit_log_keep: usize,
+
+    #[envconfig(from = "AUDIT_LOG_FSYNC", default = "never")]
+    pub audit_log_fsync: FsyncPolicy,
+
+    /// One log line per request, with the paywall's decision on paid routes.
+    #[envconfig(from = "ACCESS_LOG", default = "false")]
+    pub access_log: bool,
+
+    /// Writes the access log to this file as JSON lines instead of through the logger.
+    #[envconfig(from = "ACCESS_LOG_PATH")]
+    pub access_log_path: Option<String>,
+
+    /// SQLite database every settlement attempt is recorded in; nothing is persisted when unset.
+    #[envconfig(from = "DATABASE_PATH")]
+    pub database_path: Option<String>,
+
+    /// Settlement records that may wait for the database writer before new ones are dropped.
+    #[envconfig(from = "DATABASE_QUEUE_SIZE", default = "1024")]
+    pub database_queue_size: usize,
+
+    /// JSON lines file the spend ledger is replayed from and appended to; in-memory only when unset.
+    #[envconfig(from = "SPEND_LEDGER_PATH")]
+    pub spend_ledger_path: Option<String>,
+
+    /// Recent purchases kept per address for `/admin/spend/{address}`.
+    #[envconfig(from = "SPEND_LEDGER_RECENT", default = "20")]
+    pub spend_ledger_recent: usize,
+
+    /// Sustained requests per second each client may make to paid routes; unlimited when unset.
+    #[envconfig(from = "RATE_LIMIT_RPS")]
+    pub rate_limit_rps: Option<f64>,
+
+    /// Requests a client may make in a burst above `RATE_LIMIT_RPS`.
+    #[envconfig(from = "RATE_LIMIT_BURST", default = "20")]
+    pub rate_limit_burst: u32,
+
+    /// `POST /tab` requests each user address may make per minute, not counting ones
+    /// answered from the tab cache; unlimited when unset.
+    #[envconfig(from = "TAB_RATE_LIMIT_PER_MINUTE")]
+    pub tab_rate_limit_per_minute: Option<u32>,
+
+    /// How often the tabs `POST /tab` opened are checked through the 4mica SDK and counted
+    /// as open, paid, expired, or unknown; off when unset. Needs `4MICA_WALLET_PRIVATE_KEY`.
+    #[envconfig(from = "TAB_AUDIT_INTERVAL_SECONDS")]
+    pub tab_audit_interval_seconds: Option<u64>,
+
+    /// How long a remote `.m3u8` or `.mpd` fetched through `/stream/remote` is served from
+    /// memory; zero disables the cache.
+    #[envconfig(from = "REMOTE_PLAYLIST_CACHE_SECONDS", default = "2")]
+    pub remote_playlist_cache_seconds: u64,
+
+    /// Total playlist bytes the remote playlist cache holds before evicting the least
+    /// recently used.
+    #[envconfig(from = "REMOTE_PLAYLIST_CACHE_BYTES", default = "8388608")]
+    pub remote_playlist_cache_bytes: usize,
+
+    /// Seconds `.ts`, `.m4s`, and `.mp4` responses may be cached, as `immutable`.
+    #[envconfig(from = "SEGMENT_MAX_AGE", default = "31536000")]
+    pub segment_max_age: u64,
+
+    /// Seconds `.m3u8` and `.mpd` responses may be cached; zero sends `no-cache`.
+    #[envconfig(from = "PLAYLIST_MAX_AGE", default = "2")]
+    pub playlist_max_age: u64,
+
+    /// Lets shared caches store paid responses, for a CDN that enforces payment upstream;
+    /// otherwise those are `Cache-Control: private`.
+    #[envconfig(from = "CDN_MODE", default = "false")]
+    pub cdn_mode: bool,
+
+    /// How long in-flight requests may keep running after a shutdown signal.
+    #[envconfig(from = "SHUTDOWN_GRACE_SECONDS", default = "30")]
+    pub shutdown_grace_seconds: u64,
+
     #[envconfig(nested)]
     pub x402: X402Config,
 }
 
 impl Config {
-    pub fn from_env() -> Result<Self, envconfig::Error> {
-        Config::init_from_env()
+    /// Loads the configuration, each layer overriding the one before: field defaults, the
+    /// TOML `file` if any, then environment variables.
+    ///
+    /// A file key names the environment variable it stands for: the key path joined with
+    /// `_` and uppercased, so `[x402] pay_to` is `X402_PAY_TO` and `[server] port` is
+    /// `SERVER_PORT`. Values go through the same parsing as the environment; lists are
+    /// joined with commas. Keys that match no variable are ignored, so keep secrets in the
+    /// environment and everything reviewable in the file.
+    pub fn load(file: Option<&Path>) -> Result<Self, ConfigError> {
+        Self::load_with(file, std::env::vars())
+    }
+
+    /// [`Config::load`] with `env` standing in for the process environment.
+    fn load_with(
+        file: Option<&Path>,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ConfigError> {
+        let mut values = HashMap::new();
+        if let Some(file) = file {
+            let path = file.display().to_string();
+            let contents = std::fs::read_to_string(file).map_err(|source| ConfigError::Read {
+                path: path.clone(),
+                source,
+            })?;
+            let table = contents
+                .parse::<toml::Table>()
+                .map_err(|source| ConfigError::Parse { path, source })?;
+            flatten_toml("", &table, &mut values)?;
+        }
+        values.extend(env);
+        Ok(Config::init_from_hashmap(&values)?)
+    }
+
+    /// A copy with every secret replaced, for logging.
+    pub fn redacted(&self) -> Self {
+        fn redact(secret: &mut Option<String>) {
+            if secret.is_some() {
+                *secret = Some("<redacted>".to_string());
+            }
+        }
+        let mut config = self.clone();
+        redact(&mut config.session_secret);
+        redact(&mut config.webhook_secret);
+        redact(&mut config.admin_token);
+        redact(&mut config.upload_token);
+        redact(&mut config.x402.facilitator_api_key);
+        redact(&mut config.x402.facilitator_hmac_key);
+        redact(&mut config.s3.access_key_id);
+        redact(&mut config.s3.secret_access_key);
+        redact(&mut config.s3.session_token);
+        config
+    }
+
+    /// Whether `extension` is one of `UPLOAD_EXTENSIONS`, ignoring case and leading dots.
+    pub fn upload_extension_allowed(&self, extension: &str) -> bool {
+        self.upload_extensions
+            .split(',')
+            .map(|allowed| allowed.trim().trim_start_matches('.'))
+            .any(|allowed| !allowed.is_empty() && allowed.eq_ignore_ascii_case(extension))
+    }
+
+    /// When local files are still being written, from `MIN_FILE_AGE_MS` and
+    /// `IGNORE_FILE_SUFFIXES`.
+    pub fn file_stability(&self) -> FileStability {
+        FileStability {
+            min_age: Duration::from_millis(self.min_file_age_ms),
+            ignore_suffixes: self
+                .ignore_file_suffixes
+                .split(',')
+                .map(str::trim)
+                .filter(|suffix| !suffix.is_empty())
+                .map(str::to_string)
+                .collect(),
+        }
+    }
+
+    /// Checks settings that parse but would only fail later, during payment or
+    /// streaming, and returns every violation found.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+
+        for network in self.x402.networks() {
+            for (name, value) in [
+                ("pay-to address", &network.pay_to),
+                ("asset address", &network.asset),
+            ] {
+                if !is_hex_address(value) {
+                    errors.push(format!(
+                        "{name} {value:?} of network {} is not a 20-byte hex address",
+                        network.name
+                    ));
+                }
+            }
+        }
+
+        if let Some(split) = &self.x402.pay_to_split {
+            for share in &split.0 {
+                if !is_hex_address(&share.address) {
+                    errors.push(format!(
+                        "X402_PAY_TO_SPLIT recipient {:?} is not a 20-byte hex address",
+                        share.address
+                    ));
+                }
+            }
+            let total = split.total_basis_points();
+            if total != u64::from(BASIS_POINTS) {
+                errors.push(format!(
+                    "X402_PAY_TO_SPLIT shares add up to {total} basis points, not {BASIS_POINTS}"
+                ));
+            }
+        }
+
+        for mount in self.mounts.iter().flat_map(|MountList(mounts)| mounts) {
+            if !is_hex_address(&mount.pay_to) {
+                errors.push(format!(
+                    "pay-to address {:?} of mount {:?} is not a 20-byte hex address",
+                    mount.pay_to, mount.url_prefix
+                ));
+            }
+            if !mount.directory.is_dir() {
+                errors.push(format!(
+                    "directory {} of mount {:?} does not exist or is not a directory",
+                    mount.directory.display(),
+                    mount.url_prefix
+                ));
+            }
+        }
+
+        if self.stream_buffer_bytes == 0 {
+            errors.push("STREAM_BUFFER_BYTES must be greater than 0".to_string());
+        }
+
+        if self.tab_audit_interval_seconds == Some(0) {
+            errors.push("TAB_AUDIT_INTERVAL_SECONDS must be greater than 0".to_string());
+        }
+
+        if self.x402.mode == X402Mode::Sandbox
+            && self.x402.sandbox_ack.as_deref() != Some(SANDBOX_ACK)
+        {
+            errors.push(format!(
+                "X402_MODE=sandbox accepts payments that settle nothing; set \
+                 X402_SANDBOX_ACK={SANDBOX_ACK} to confirm"
+            ));
+        }
+
+        let advertised = &self.server_advertised_url;
+        if advertised.host_str().is_none_or(str::is_empty) {
+            errors.push(format!("SERVER_ADVERTISED_URL {advertised} has no host"));
+        }
+        if !matches!(advertised.path(), "" | "/")
+            || advertised.query().is_some()
+            || advertised.fragment().is_some()
+        {
+            // Resource and tab URLs are joined onto it as absolute paths.
+            errors.push(format!(
+                "SERVER_ADVERTISED_URL {advertised} must not have a path, query, or fragment"
+            ));
+        }
+
+        match &self.unix_socket {
+            Some(socket) if socket.trim().is_empty() => {
+                errors.push("SERVER_UNIX_SOCKET must not be empty".to_string());
+            }
+            Some(_) if cfg!(not(unix)) => {
+                errors.push("SERVER_UNIX_SOCKET is only supported on Unix".to_string());
+            }
+            // A TLS proxy in front of the socket terminates TLS itself.
+            Some(_) if self.tls_cert_path.is_some() || self.tls_key_path.is_some() => {
+                errors.push(
+                    "SERVER_UNIX_SOCKET cannot be combined with TLS_CERT_PATH/TLS_KEY_PATH"
+                        .to_string(),
+                );
+            }
+            None if self.unix_socket_mode.is_some() => {
+                errors
+                    .push("SERVER_UNIX_SOCKET_MODE is set without SERVER_UNIX_SOCKET".to_string());
+            }
+            _ => {}
+        }
+
+        if self.storage_backend == StorageKind::Local && !Path::new(&self.file_directory).is_dir() {
+            errors.push(format!(
+                "FILE_DIRECTORY {} does not exist or is not a directory",
+                self.file_directory
+            ));
+        }
+
+        if self.x402.tx_wait_seconds > 0 && self.x402.tx_poll_interval_ms == 0 {
+            errors.push(
+                "X402_TX_POLL_INTERVAL_MS must be positive when X402_TX_WAIT_SECONDS is set"
+                    .to_string(),
+            );
+        }
+
+        if self.x402.accept_native {
+            if !self.x402.direct_settlement {
+                errors.push("X402_ACCEPT_NATIVE needs X402_DIRECT_SETTLEMENT".to_string());
+            }
+            if self.x402.native_price.is_none() {
+                errors.push("X402_ACCEPT_NATIVE needs X402_NATIVE_PRICE".to_string());
+            }
+        }
+
+        if self.x402.enabled && !self.x402.enable_4mica && !self.x402.offers_exact() {
+            errors.push(
+                "X402_ENABLED needs a payment scheme: X402_ENABLE_4MICA, or X402_ENABLE_EXACT \
+                 with X402_DIRECT_SETTLEMENT or X402_EXACT_FACILITATOR"
+                    .to_string(),
+            );
+        }
+
+        // The RPC endpoints only matter to payments checked on-chain.
+        if self.x402.enable_exact && self.x402.direct_settlement {
+            for network in self.x402.networks() {
+                if self.x402.rpc_urls_for(&network.name).is_empty() {
+                    errors.push(format!(
+                        "X402_DIRECT_SETTLEMENT needs an RPC URL for network {}",
+                        network.name
+                    ));
+                }
+            }
+        }
+
+        let facilitator_var = if self.x402.facilitator_urls.is_some() {
+            "X402_FACILITATOR_URLS entry"
+        } else {
+            "X402_FACILITATOR_URL"
+        };
+        for facilitator in self.x402.facilitators() {
+            if !matches!(facilitator.scheme(), "http" | "https") {
+                errors.push(format!(
+                    "{facilitator_var} {facilitator} must use http or https"
+                ));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// Adds the scalar values of `table` to `out`, keyed by their uppercased key path
+/// under `prefix`.
+fn flatten_toml(
+    prefix: &str,
+    table: &toml::Table,
+    out: &mut HashMap<String, String>,
+) -> Result<(), ConfigError> {
+    for (key, value) in table {
+        let name = format!("{prefix}{}", key.to_ascii_uppercase());
+        let value = match value {
+            toml::Value::Table(table) => {
+                flatten_toml(&format!("{name}_"), table, out)?;
+                continue;
+            }
+            toml::Value::Array(items) => items
+                .iter()
+                .map(toml_scalar)
+                .collect::<Option<Vec<_>>>()
+                .map(|items| items.join(",")),
+            scalar => toml_scalar(scalar),
+        };
+        let value = value.ok_or_else(|| ConfigError::Unsupported(name.clone()))?;
+        out.insert(name, value);
+    }
+    Ok(())
+}
+
+/// A scalar as the string its environment variable would hold.
+fn toml_scalar(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(s) => Some(s.clone()),
+        toml::Value::Integer(i) => Some(i.to_string()),
+        toml::Value::Float(f) => Some(f.to_string()),
+        toml::Value::Boolean(b) => Some(b.to_string()),
+        toml::Value::Datetime(d) => Some(d.to_string()),
+        toml::Value::Array(_) | toml::Value::Table(_) => None,
+    }
+}
+
+/// 40 hex digits, with or without a `0x` prefix.
+pub(super) fn is_hex_address(value: &str) -> bool {
+    let digits = value.strip_prefix("0x").unwrap_or(value);
+    digits.len() == 40 && digits.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const PAY_TO: &str = "0x00000000000000000000000000000000000000ab";
+
+    /// A config that passes [`Config::validate`], with `overrides` applied.
+    fn config(overrides: &[(&str, &str)]) -> Config {
+        let directory = std::env::temp_dir();
+        let values: HashMap<String, String> = [
+            ("X402_PAY_TO", PAY_TO),
+            ("FILE_DIRECTORY", directory.to_str().unwrap()),
+        ]
+        .into_iter()
+        .chain(overrides.iter().copied())
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+        Config::init_from_hashmap(&values).unwrap()
+    }
+
+    fn violations(overrides: &[(&str, &str)]) -> Vec<String> {
+        config(overrides).validate().err().unwrap_or_default()
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert_eq!(violations(&[]), Vec::<String>::new());
+    }
+
+    #[test]
+    fn addresses_must_be_20_byte_hex() {
+        assert!(is_hex_address(PAY_TO));
+        assert!(is_hex_address(PAY_TO.trim_start_matches("0x")));
+        assert!(is_hex_address("0x41E94Eb019C0762f9Bfcf9Fb1E58725BfB0e7582"));
+        assert!(!is_hex_address("0x00000000000000000000000000000000000000a"));
+        assert!(!is_hex_address(
+            "0x00000000000000000000000000000000000000abc"
+        ));
+        assert!(!is_hex_address(
+            "0x00000000000000000000000000000000000000zz"
+        ));
+        assert!(!is_hex_address(""));
+
+        let errors = violations(&[("X402_PAY_TO", "0xab")]);
+        assert_eq!(errors.len(), 1);
+        assert!(
+            errors[0].starts_with("pay-to address \"0xab\""),
+            "{errors:?}"
+        );
+
+        let errors = violations(&[("X402_ASSET", "0x41E94Eb019C0762f9Bfcf9Fb1E58725BfB0e758")]);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("asset address"), "{errors:?}");
+    }
+
+    #[test]
+    fn advertised_url_needs_a_host_and_no_path() {
+        let errors = violations(&[("SERVER_ADVERTISED_URL", "https://cdn.example/app")]);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("must not have a path"), "{errors:?}");
+
+        let errors = violations(&[("SERVER_ADVERTISED_URL", "https://cdn.example/?a=1")]);
+        assert!(errors[0].contains("must not have a path"), "{errors:?}");
+
+        let errors = violations(&[("SERVER_ADVERTISED_URL", "file:///")]);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("has no host"), "{errors:?}");
+
+        assert!(violations(&[("SERVER_ADVERTISED_URL", "https://cdn.example/")]).is_empty());
+    }
+
+    #[test]
+    fn file_directory_must_exist() {
+        let missing = std::env::temp_dir().join("config-test-missing-directory");
+        let errors = violations(&[("FILE_DIRECTORY", missing.to_str().unwrap())]);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("FILE_DIRECTORY"), "{errors:?}");
+    }
+
+    #[test]
+    fn facilitator_must_use_http() {
+        let errors = violations(&[("X402_FACILITATOR_URL", "ftp://facilitator.example/")]);
+        assert_eq!(
+            errors,
+            ["X402_FACILITATOR_URL ftp://facilitator.example/ must use http or https"]
+        );
+        assert!(violations(&[("X402_FACILITATOR_URL", "http://localhost:8080/")]).is_empty());
+    }
+
+    #[test]
+    fn every_violation_is_reported() {
+        let missing = std::env::temp_dir().join("config-test-missing-directory");
+        let errors = violations(&[
+            ("X402_PAY_TO", "0xab"),
+            ("SERVER_ADVERTISED_URL", "https://cdn.example/app"),
+            ("FILE_DIRECTORY", missing.to_str().unwrap()),
+            ("X402_FACILITATOR_URL", "ftp://facilitator.example/"),
+        ]);
+        assert_eq!(errors.len(), 4, "{errors:?}");
+    }
+
+    /// Loads `toml` as the config file under `env`, which always names the payee.
+    fn load(toml: Option<&str>, env: &[(&str, &str)]) -> Config {
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let file = toml.map(|toml| {
+            let file = std::env::temp_dir().join(format!(
+                "config-test-{}-{}.toml",
+                std::process::id(),
+                FILES.fetch_add(1, Ordering::SeqCst)
+            ));
+            std::fs::write(&file, toml).unwrap();
+            file
+        });
+        let env = [("X402_PAY_TO", PAY_TO)]
+            .iter()
+            .chain(env)
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<Vec<_>>();
+        let config = Config::load_with(file.as_deref(), env).unwrap();
+        if let Some(file) = file {
+            std::fs::remove_file(file).unwrap();
+        }
+        config
+    }
+
+    const FILE: &str = r#"
+log_level = "debug"
+
+[server]
+advertised_url = "https://file.example/"
+
+[x402]
+max_timeout_seconds = 60
+"#;
+
+    #[test]
+    fn defaults_apply_without_file_or_env() {
+        let config = load(None, &[]);
+        assert_eq!(config.log_level, tracing::Level::INFO);
+        assert_eq!(
+            config.server_advertised_url.as_str(),
+            "http://localhost:3000/"
+        );
+        assert_eq!(config.x402.max_timeout_seconds, 300);
+    }
+
+    #[test]
+    fn file_overrides_defaults() {
+        let config = load(Some(FILE), &[]);
+        assert_eq!(config.log_level, tracing::Level::DEBUG);
+        assert_eq!(
+            config.server_advertised_url.as_str(),
+            "https://file.example/"
+        );
+        assert_eq!(config.x402.max_timeout_seconds, 60);
+    }
+
+    #[test]
+    fn env_overrides_the_file() {
+        let config = load(
+            Some(FILE),
+            &[
+                ("LOG_LEVEL", "warn"),
+                ("SERVER_ADVERTISED_URL", "https://env.example/"),
+                ("X402_MAX_TIMEOUT_SECONDS", "30"),
+            ],
+        );
+        assert_eq!(config.log_level, tracing::Level::WARN);
+        assert_eq!(
+            config.server_advertised_url.as_str(),
+            "https://env.example/"
+        );
+        assert_eq!(config.x402.max_timeout_seconds, 30);
     }
 }
@@ -0,0 +1,207 @@
+use axum::body::{Body, Bytes, HttpBody};
+use http_body::{Frame, SizeHint};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+};
+use tracing::debug;
+
+/// Bytes one payer was actually sent, across every streamed response.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeliverySummary {
+    pub bytes_sent: u64,
+    /// Responses streamed to the end.
+    pub completed: u64,
+    /// Responses the client abandoned, or that failed, before the end.
+    pub aborted: u64,
+}
+
+impl DeliverySummary {
+    fn add(&mut self, bytes: u64, completed: bool) {
+        self.bytes_sent += bytes;
+        if completed {
+            self.completed += 1;
+        } else {
+            self.aborted += 1;
+        }
+    }
+}
+
+/// Tallies what streamed responses delivered, as reported by [`DeliveryStats::count`].
+///
+/// Totals cover every response; the per-payer breakdown only those whose payment settled
+/// on the request, since a payment session does not name its payer.
+#[derive(Default)]
+pub struct DeliveryStats {
+    bytes_sent: AtomicU64,
+    completed: AtomicU64,
+    aborted: AtomicU64,
+    payers: Mutex<HashMap<String, DeliverySummary>>,
+}
+
+impl DeliveryStats {
+    /// Wraps `body` so the bytes it yields are recorded against `resource` and `payer`
+    /// once it ends or is dropped. Chunks pass through as they arrive.
+    pub fn count(self: &Arc<Self>, body: Body, resource: String, payer: Option<String>) -> Body {
+        Body::new(CountingBody {
+            inner: body,
+            stats: self.clone(),
+            resource,
+            payer: payer.map(|payer| payer.to_lowercase()),
+            bytes: 0,
+            reported: false,
+        })
+    }
+
+    pub fn record(&self, resource: &str, payer: Option<&str>, bytes: u64, completed: bool) {
+        debug!(resource, payer, bytes, completed, "Response body delivered");
+        self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+        let outcome = if completed {
+            &self.completed
+        } else {
+            &self.aborted
+        };
+        outcome.fetch_add(1, Ordering::Relaxed);
+        if let Some(payer) = payer {
+            self.payers
+                .lock()
+                .entry(payer.to_lowercase())
+                .or_default()
+                .add(bytes, completed);
+        }
+    }
+
+    /// What streamed responses delivered in total.
+    pub fn totals(&self) -> DeliverySummary {
+        DeliverySummary {
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            completed: self.completed.load(Ordering::Relaxed),
+            aborted: self.aborted.load(Ordering::Relaxed),
+        }
+    }
+
+    /// What was delivered to `address`, if it was ever sent a paid response.
+    pub fn payer(&self, address: &str) -> Option<DeliverySummary> {
+        self.payers.lock().get(&address.to_lowercase()).cloned()
+    }
+}
+
+/// Response body that counts the data it yields and reports the count to
+/// [`DeliveryStats`] exactly once: when the body ends, fails, or is dropped early.
+struct CountingBody {
+    inner: Body,
+    stats: Arc<DeliveryStats>,
+    resource: String,
+    payer: Option<String>,
+    bytes: u64,
+    reported: bool,
+}
+
+impl CountingBody {
+    fn report(&mut self, completed: bool) {
+        if !self.reported {
+            self.reported = true;
+            self.stats
+                .record(&self.resource, self.payer.as_deref(), self.bytes, completed);
+        }
+    }
+}
+
+impl HttpBody for CountingBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let polled = Pin::new(&mut self.inner).poll_frame(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    self.bytes += data.len() as u64;
+                }
+                // The server may stop polling once the body says it is done.
+                if self.inner.is_end_stream() {
+                    self.report(true);
+                }
+            }
+            Poll::Ready(Some(Err(_))) => self.report(false),
+            Poll::Ready(None) => self.report(true),
+            Poll::Pending => {}
+        }
+        polled
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for CountingBody {
+    fn drop(&mut self) {
+        let completed = self.inner.is_end_stream();
+        self.report(completed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+
+    const PAYER: &str = "0x00000000000000000000000000000000000000EF";
+
+    /// A body yielding `chunks` of 100 bytes each.
+    fn body(chunks: usize) -> Body {
+        let chunks = (0..chunks).map(|_| Ok::<_, std::io::Error>(Bytes::from(vec![0; 100])));
+        Body::from_stream(futures_util::stream::iter(chunks))
+    }
+
+    #[tokio::test]
+    async fn body_dropped_half_read_records_what_was_sent() {
+        let stats = Arc::new(DeliveryStats::default());
+        let counted = stats.count(body(4), "/stream/a.ts".into(), Some(PAYER.into()));
+        let mut data = counted.into_data_stream();
+        for _ in 0..2 {
+            assert_eq!(data.next().await.unwrap().unwrap().len(), 100);
+        }
+        assert_eq!(stats.totals().aborted, 0, "reported before the body ended");
+        drop(data);
+
+        let totals = stats.totals();
+        assert_eq!(
+            (totals.bytes_sent, totals.completed, totals.aborted),
+            (200, 0, 1)
+        );
+        let payer = stats.payer(&PAYER.to_lowercase()).unwrap();
+        assert_eq!((payer.bytes_sent, payer.aborted), (200, 1));
+    }
+
+    #[tokio::test]
+    async fn body_read_to_the_end_is_reported_once_as_completed() {
+        let stats = Arc::new(DeliveryStats::default());
+        let counted = stats.count(body(3), "/stream/a.ts".into(), None);
+        let bytes = axum::body::to_bytes(counted, usize::MAX).await.unwrap();
+        assert_eq!(bytes.len(), 300);
+
+        let totals = stats.totals();
+        assert_eq!(
+            (totals.bytes_sent, totals.completed, totals.aborted),
+            (300, 1, 0)
+        );
+        assert!(stats.payer(PAYER).is_none());
+    }
+}
@@ -0,0 +1,185 @@
+use axum::http::Uri;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use percent_encoding::percent_decode_str;
+use server::x402::X402Config;
+use tracing::warn;
+use url::{Url, form_urlencoded};
+
+use crate::http::pricing::PricedRoute;
+
+/// Patterns used when `X402_FREE_PATHS` is unset: HLS playlists and DASH manifests are free,
+/// everything else is paid. `X402_CHARGE_PLAYLISTS` drops them, so nothing is free by default.
+const DEFAULT_FREE_PATHS: &str = "*.m3u8,*.mpd";
+
+/// Paths a pattern must not all match; one that does almost certainly frees every resource.
+const PAID_PROBES: [&str; 4] = ["seg_00000.ts", "video/720p/seg_00001.m4s", "init.mp4", "a"];
+
+/// Resources served without payment, compiled once from `X402_FREE_PATHS`.
+///
+/// `*` in a pattern also matches across `/`, so `*.vtt` frees subtitles in any directory.
+/// Extensions compare case-insensitively on both sides; the rest of the path does not.
+#[derive(Clone)]
+pub struct FreePaths {
+    set: GlobSet,
+}
+
+impl FreePaths {
+    /// Compiles the patterns, warning about any that would make every resource free.
+    pub fn from_config(config: &X402Config) -> Result<Self, globset::Error> {
+        let patterns = config
+            .free_paths
+            .as_deref()
+            .filter(|patterns| !patterns.trim().is_empty())
+            .unwrap_or(if config.charge_playlists {
+                ""
+            } else {
+                DEFAULT_FREE_PATHS
+            });
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let glob = GlobBuilder::new(&lowercase_extension(pattern))
+                .literal_separator(false)
+                .build()?;
+            let matcher = glob.compile_matcher();
+            if PAID_PROBES.iter().all(|probe| matcher.is_match(probe)) {
+                warn!(
+                    pattern,
+                    "X402_FREE_PATHS pattern matches every resource; the paywall is effectively off"
+                );
+            }
+            builder.add(glob);
+        }
+        Ok(Self {
+            set: builder.build()?,
+        })
+    }
+
+    /// Whether `path`, a decoded file path without a leading `/`, is served for free.
+    pub fn is_free_resource(&self, path: &str) -> bool {
+        self.set.is_match(lowercase_extension(path))
+    }
+
+    /// Whether the resource `uri` asks for on `route` is served for free: the file path
+    /// under `/stream/`, or the path of the remote `url` query parameter.
+    pub fn is_free_request(&self, route: PricedRoute, uri: &Uri) -> bool {
+        resource_path(route, uri).is_some_and(|path| self.is_free_resource(&path))
+    }
+}
+
+/// The decoded file path `uri` asks for on `route`, without a leading `/`.
+pub fn resource_path(route: PricedRoute, uri: &Uri) -> Option<String> {
+    let encoded = match route {
+        PricedRoute::Stream => uri.path().strip_prefix("/stream/")?.to_string(),
+        PricedRoute::Ipfs => uri.path().strip_prefix("/stream/ipfs/")?.to_string(),
+        // A bundle is sold whole, whatever its files are.
+        PricedRoute::Bundle => return None,
+        PricedRoute::Remote => {
+            let url = form_urlencoded::parse(uri.query()?.as_bytes())
+                .find(|(key, _)| key == "url")?
+                .1;
+            match Url::parse(&url) {
+                Ok(url) => url.path().trim_start_matches('/').to_string(),
+                Err(_) => url.split(['?', '#']).next().unwrap_or_default().to_string(),
+            }
+        }
+    };
+    Some(
+        percent_decode_str(&encoded)
+            .decode_utf8_lossy()
+            .into_owned(),
+    )
+}
+
+/// `path` with the part after its last `.` lowercased, if that part names an extension.
+fn lowercase_extension(path: &str) -> String {
+    match path.rsplit_once('.') {
+        Some((stem, ext)) if !ext.contains('/') => format!("{}.{}", stem, ext.to_lowercase()),
+        _ => path.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use envconfig::Envconfig;
+    use std::collections::HashMap;
+
+    fn config(settings: &[(&str, &str)]) -> X402Config {
+        let values: HashMap<String, String> = [("X402_PAY_TO", "0xab")]
+            .iter()
+            .chain(settings)
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        X402Config::init_from_hashmap(&values).unwrap()
+    }
+
+    fn free_paths(settings: &[(&str, &str)]) -> FreePaths {
+        FreePaths::from_config(&config(settings)).unwrap()
+    }
+
+    #[test]
+    fn playlists_and_manifests_are_free_by_default() {
+        let free = free_paths(&[]);
+        assert!(free.is_free_resource("index.m3u8"));
+        assert!(free.is_free_resource("show/720p/index.m3u8"));
+        assert!(free.is_free_resource("show/manifest.mpd"));
+        assert!(!free.is_free_resource("show/seg_00001.ts"));
+        assert!(!free.is_free_resource("show/init.mp4"));
+
+        let charged = free_paths(&[("X402_CHARGE_PLAYLISTS", "true")]);
+        assert!(!charged.is_free_resource("index.m3u8"));
+        assert!(!charged.is_free_resource("show/manifest.mpd"));
+    }
+
+    #[test]
+    fn custom_patterns_replace_the_defaults() {
+        let free = free_paths(&[("X402_FREE_PATHS", "*.vtt, trailers/*")]);
+        assert!(free.is_free_resource("show/en/subs.vtt"));
+        assert!(free.is_free_resource("trailers/teaser/seg_00000.ts"));
+        assert!(!free.is_free_resource("index.m3u8"));
+        assert!(!free.is_free_resource("show/seg_00000.ts"));
+
+        let blank = free_paths(&[("X402_FREE_PATHS", "  ")]);
+        assert!(blank.is_free_resource("index.m3u8"));
+    }
+
+    #[test]
+    fn extensions_compare_case_insensitively() {
+        let free = free_paths(&[("X402_FREE_PATHS", "*.VTT,Trailers/*.ts")]);
+        assert!(free.is_free_resource("subs.vtt"));
+        assert!(free.is_free_resource("subs.Vtt"));
+        assert!(free.is_free_resource("Trailers/a.TS"));
+        assert!(!free.is_free_resource("trailers/a.ts"));
+        assert!(free_paths(&[]).is_free_resource("INDEX.M3U8"));
+    }
+
+    #[test]
+    fn invalid_pattern_is_rejected() {
+        let config = config(&[("X402_FREE_PATHS", "*.vtt,[abc")]);
+        assert!(FreePaths::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn requests_are_matched_by_their_resource_path() {
+        let free = free_paths(&[]);
+        let stream: Uri = "/stream/show%20one/index.m3u8?session=1".parse().unwrap();
+        assert_eq!(
+            resource_path(PricedRoute::Stream, &stream).as_deref(),
+            Some("show one/index.m3u8")
+        );
+        assert!(free.is_free_request(PricedRoute::Stream, &stream));
+
+        let remote: Uri =
+            "/stream/remote?url=https%3A%2F%2Fcdn.test%2Flive%2Findex.m3u8%3Ftoken%3D1"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            resource_path(PricedRoute::Remote, &remote).as_deref(),
+            Some("live/index.m3u8")
+        );
+        assert!(free.is_free_request(PricedRoute::Remote, &remote));
+
+        let bundle: Uri = "/bundle/index.m3u8".parse().unwrap();
+        assert!(!free.is_free_request(PricedRoute::Bundle, &bundle));
+    }
+}
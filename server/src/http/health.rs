@@ -0,0 +1,44 @@
+use axum::{Json, extract::State};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::http::router::AppState;
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Health {
+    /// `ok`, or `degraded` when a dependency is unavailable.
+    pub status: &'static str,
+    /// Whether the facilitator answered the startup probe; absent when there is none.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub facilitator_reachable: Option<bool>,
+    /// Host of the facilitator calls go to first; a fallback after a failover.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub active_facilitator: Option<String>,
+}
+
+/// Liveness of the server, with the state of its dependencies.
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "health",
+    responses((status = 200, description = "The server is up", body = Health))
+)]
+pub async fn handle_health(State(state): State<AppState>) -> Json<Health> {
+    Json(Health {
+        status: if state.facilitator_reachable {
+            "ok"
+        } else {
+            "degraded"
+        },
+        facilitator_reachable: state
+            .facilitator
+            .as_ref()
+            .map(|_| state.facilitator_reachable),
+        active_facilitator: state
+            .facilitator
+            .as_ref()
+            .and_then(|facilitator| facilitator.failover_status())
+            .map(|status| status.label()),
+    })
+}
@@ -0,0 +1,162 @@
+use axum::{
+    Extension, Router,
+    extract::{Path, State},
+    http::{HeaderMap, header::CONTENT_TYPE},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use serde::Deserialize;
+use server::io::{IpfsGateways, IpfsPath};
+use tracing::warn;
+
+use crate::http::{
+    cache_policy,
+    model::{ApiError, ApiErrorBody, PaymentRequiredResponse},
+    openapi,
+    pricing::PricedRoute,
+    rate_limit::rate_limit,
+    router::{AppState, content_type_for, head_response},
+    x402::{self, PaidRequest, Paywall},
+};
+
+/// `/stream/ipfs/{cid}` and `/stream/ipfs/{cid}/{path}`, when `IPFS_GATEWAYS` is set.
+pub fn router(state: &AppState) -> Router<AppState> {
+    let Some(gateways) = state.config.ipfs_gateways.clone() else {
+        return Router::new();
+    };
+    let routes = || {
+        get(handle_ipfs_stream)
+            .head(handle_ipfs_stream_head)
+            .route_layer(middleware::from_fn_with_state(
+                Paywall::new(state.clone(), PricedRoute::Ipfs),
+                x402::require_payment,
+            ))
+            // Runs before the paywall so nobody is charged for a malformed CID.
+            .route_layer(middleware::from_fn(verify_ipfs_path))
+            .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit))
+    };
+    Router::new()
+        .route("/stream/ipfs/{cid}", routes())
+        .route("/stream/ipfs/{cid}/{*path}", routes())
+        .layer(Extension(gateways))
+}
+
+#[derive(Debug, Deserialize)]
+struct IpfsParams {
+    cid: String,
+    path: Option<String>,
+}
+
+async fn verify_ipfs_path(
+    Path(params): Path<IpfsParams>,
+    mut request: axum::extract::Request,
+    next: Next,
+) -> Response {
+    match IpfsPath::new(&params.cid, params.path.as_deref()) {
+        Ok(path) => {
+            request.extensions_mut().insert(path);
+            next.run(request).await
+        }
+        Err(e) => {
+            warn!("Rejected IPFS path: {}", e);
+            ApiError::from(e).into_response()
+        }
+    }
+}
+
+/// Fetches IPFS content through the first `IPFS_GATEWAYS` entry that serves it, and streams
+/// it back once it is paid for. `Range`, `If-None-Match`, `If-Modified-Since`, and `Accept`
+/// are forwarded to the gateway.
+#[utoipa::path(
+    get,
+    path = "/stream/ipfs/{cid}/{path}",
+    tag = "stream",
+    params(
+        ("cid" = String, Path, description = "CIDv0 or CIDv1 of the content"),
+        ("path" = String, Path, description = "Optional path inside a UnixFS directory"),
+        openapi::PaymentHeaders,
+        openapi::PaymentQuery,
+    ),
+    responses(
+        (status = 200, description = "The content with the gateway's `Content-Type`, `Content-Length`, and validators, and a `Cache-Control` chosen by file type; a fresh payment adds `payment-response`", content_type = "application/octet-stream", body = Vec<u8>),
+        (status = 206, description = "The part of the content the forwarded `Range` asked for", content_type = "application/octet-stream", body = Vec<u8>),
+        (status = 304, description = "The gateway answered a forwarded `If-None-Match` or `If-Modified-Since`: not modified"),
+        (status = 400, description = "`invalid_cid` or `invalid_ipfs_path`: checked before any payment or fetch", body = ApiErrorBody),
+        (status = 402, description = "Payment is required for `ipfs://{cid}/{path}`; v2 clients also get the base64 `payment-required` header", body = PaymentRequiredResponse),
+        (status = 404, description = "`remote_not_found`: the last gateway tried answered 404", body = ApiErrorBody),
+        (status = 416, description = "`range_not_satisfiable`: the gateway rejected the forwarded `Range`", body = ApiErrorBody),
+        (status = 429, description = "`rate_limited`: retry after `Retry-After` seconds", body = ApiErrorBody),
+        (status = 502, description = "`remote_fetch_failed` or `remote_empty`: no gateway served the content; `remote_too_large`: it is above `REMOTE_MAX_BYTES`", body = ApiErrorBody),
+        (status = 503, description = "`settlement_busy`: too many payments are settling; retry after `Retry-After` seconds", body = ApiErrorBody),
+    )
+)]
+pub(super) async fn handle_ipfs_stream(
+    State(state): State<AppState>,
+    Extension(gateways): Extension<IpfsGateways>,
+    Extension(path): Extension<IpfsPath>,
+    paid: Option<Extension<PaidRequest>>,
+    request_headers: HeaderMap,
+) -> Response {
+    // The paywall already charged once, whether the gateway answers in part or full.
+    match gateways
+        .stream(&state.remote, &path, Some(&request_headers))
+        .await
+    {
+        Ok(remote) => {
+            let payer = paid.as_ref().and_then(|Extension(paid)| paid.payer());
+            let body =
+                state
+                    .deliveries
+                    .count(remote.body, path.resource(), payer.map(str::to_string));
+            let mut resp = (remote.status, remote.headers, body).into_response();
+            cache_policy::apply(&mut resp, &state.config, path.file_name(), paid.is_some());
+            if let Some(Extension(paid)) = paid {
+                paid.attach(&mut resp);
+            }
+            if !resp.headers().contains_key(CONTENT_TYPE)
+                && let Some(ct) = content_type_for(path.file_name())
+            {
+                resp.headers_mut().insert(CONTENT_TYPE, ct);
+            }
+            resp
+        }
+        Err(e) => ApiError::from(e).into_response(),
+    }
+}
+
+/// Describes IPFS content's size and type without paying for it (unless
+/// `X402_FREE_HEAD=false`).
+#[utoipa::path(
+    head,
+    path = "/stream/ipfs/{cid}/{path}",
+    tag = "stream",
+    params(
+        ("cid" = String, Path, description = "CIDv0 or CIDv1 of the content"),
+        ("path" = String, Path, description = "Optional path inside a UnixFS directory"),
+        openapi::PaymentHeaders,
+        openapi::PaymentQuery,
+    ),
+    responses(
+        (status = 200, description = "`Content-Length` and `Content-Type` of the content"),
+        (status = 400, description = "`invalid_cid` or `invalid_ipfs_path`", body = ApiErrorBody),
+        (status = 402, description = "Payment is required and no payment session covers the content"),
+        (status = 404, description = "The last gateway tried answered 404"),
+        (status = 502, description = "No gateway could be reached"),
+    )
+)]
+pub(super) async fn handle_ipfs_stream_head(
+    State(state): State<AppState>,
+    Extension(gateways): Extension<IpfsGateways>,
+    Extension(path): Extension<IpfsPath>,
+) -> Response {
+    match gateways.head(&state.remote, &path).await {
+        Ok(remote) => head_response(
+            remote.content_length,
+            remote
+                .content_type
+                .or_else(|| content_type_for(path.file_name())),
+        ),
+        Err(e) => ApiError::from(e).into_response(),
+    }
+}
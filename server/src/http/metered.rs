@@ -0,0 +1,99 @@
+use axum::body::{Body, Bytes, HttpBody};
+use http_body::{Frame, SizeHint};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tracing::warn;
+
+/// Called once with the bytes a metered body delivered.
+type OnDelivered = Box<dyn FnOnce(u64) + Send>;
+
+/// Wraps `body` so it yields at most `max_bytes` and reports what it delivered to
+/// `on_delivered` once it ends, fails, or is dropped. Data beyond the cap ends the
+/// response with an error instead of being sent.
+pub fn meter(body: Body, max_bytes: u64, on_delivered: impl FnOnce(u64) + Send + 'static) -> Body {
+    Body::new(MeteredBody {
+        inner: body,
+        max_bytes,
+        bytes: 0,
+        capped: false,
+        on_delivered: Some(Box::new(on_delivered)),
+    })
+}
+
+struct MeteredBody {
+    inner: Body,
+    max_bytes: u64,
+    bytes: u64,
+    /// Set once data was cut at the cap; the next poll fails the body.
+    capped: bool,
+    on_delivered: Option<OnDelivered>,
+}
+
+impl MeteredBody {
+    fn report(&mut self) {
+        if let Some(on_delivered) = self.on_delivered.take() {
+            on_delivered(self.bytes);
+        }
+    }
+}
+
+impl HttpBody for MeteredBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if self.capped {
+            self.report();
+            return Poll::Ready(Some(Err(axum::Error::new("metered payment cap reached"))));
+        }
+        let frame = match Pin::new(&mut self.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => frame,
+            Poll::Ready(Some(Err(e))) => {
+                self.report();
+                return Poll::Ready(Some(Err(e)));
+            }
+            Poll::Ready(None) => {
+                self.report();
+                return Poll::Ready(None);
+            }
+            Poll::Pending => return Poll::Pending,
+        };
+        let mut data = match frame.into_data() {
+            Ok(data) => data,
+            Err(frame) => return Poll::Ready(Some(Ok(frame))),
+        };
+        let remaining = self.max_bytes - self.bytes;
+        if data.len() as u64 > remaining {
+            warn!(
+                max_bytes = self.max_bytes,
+                "Metered response reached its cap; ending it"
+            );
+            data.truncate(remaining as usize);
+            self.capped = true;
+        }
+        self.bytes += data.len() as u64;
+        if !self.capped && self.inner.is_end_stream() {
+            self.report();
+        }
+        Poll::Ready(Some(Ok(Frame::data(data))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        !self.capped && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for MeteredBody {
+    fn drop(&mut self) {
+        self.report();
+    }
+}
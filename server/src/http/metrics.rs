@@ -0,0 +1,137 @@
+use axum::{
+    extract::State,
+    http::{HeaderValue, header},
+    response::{IntoResponse, Response},
+};
+use std::fmt::{Display, Write as _};
+
+use crate::http::router::AppState;
+
+/// Operational gauges and counters in the Prometheus text format.
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "health",
+    responses(
+        (status = 200, description = "Prometheus text exposition", content_type = "text/plain", body = String),
+    )
+)]
+pub async fn handle_metrics(State(state): State<AppState>) -> Response {
+    let mut out = String::new();
+    let delivered = state.deliveries.totals();
+    metric(
+        &mut out,
+        "x402_bytes_sent_total",
+        "counter",
+        "Body bytes streamed to clients.",
+        delivered.bytes_sent,
+    );
+    metric(
+        &mut out,
+        "x402_streams_completed_total",
+        "counter",
+        "Streamed responses sent to the end.",
+        delivered.completed,
+    );
+    metric(
+        &mut out,
+        "x402_streams_aborted_total",
+        "counter",
+        "Streamed responses abandoned or failed before the end.",
+        delivered.aborted,
+    );
+    if let Some(failover) = state
+        .facilitator
+        .as_ref()
+        .and_then(|facilitator| facilitator.failover_status())
+    {
+        let _ = writeln!(
+            out,
+            "# HELP x402_facilitator_active The facilitator calls go to first.\n\
+             # TYPE x402_facilitator_active gauge\n\
+             x402_facilitator_active{{facilitator=\"{}\",priority=\"{}\"}} 1",
+            failover.label(),
+            failover.priority
+        );
+        metric(
+            &mut out,
+            "x402_facilitator_failovers_total",
+            "counter",
+            "Times facilitator calls moved to another facilitator.",
+            failover.failovers,
+        );
+    }
+    if let Some(limiter) = &state.settlement_limiter {
+        metric(
+            &mut out,
+            "x402_settlement_slots",
+            "gauge",
+            "Settlements allowed to run at once.",
+            limiter.max(),
+        );
+        metric(
+            &mut out,
+            "x402_settlements_in_flight",
+            "gauge",
+            "Settlements currently running.",
+            limiter.in_flight(),
+        );
+        metric(
+            &mut out,
+            "x402_settlements_queued",
+            "gauge",
+            "Paid requests waiting for a settlement slot.",
+            limiter.queued(),
+        );
+        metric(
+            &mut out,
+            "x402_settlements_rejected_total",
+            "counter",
+            "Paid requests answered 503 after waiting for a settlement slot.",
+            limiter.rejected(),
+        );
+    }
+    if let Some(audit) = &state.tab_audit {
+        metric(
+            &mut out,
+            "x402_tabs_tracked",
+            "gauge",
+            "Tabs opened through POST /tab that the tab audit tracks.",
+            audit.tracked(),
+        );
+        if let Some(summary) = audit.latest() {
+            let _ = writeln!(
+                out,
+                "# HELP x402_tabs Tracked tabs by state at the last tab audit.\n\
+                 # TYPE x402_tabs gauge\n\
+                 x402_tabs{{state=\"open\"}} {}\n\
+                 x402_tabs{{state=\"paid\"}} {}\n\
+                 x402_tabs{{state=\"expired\"}} {}\n\
+                 x402_tabs{{state=\"unknown\"}} {}",
+                summary.open, summary.paid, summary.expired, summary.unknown
+            );
+            metric(
+                &mut out,
+                "x402_tab_audit_timestamp_seconds",
+                "gauge",
+                "Unix time the last tab audit finished.",
+                summary.checked_at,
+            );
+        }
+    }
+    (
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4"),
+        )],
+        out,
+    )
+        .into_response()
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: impl Display) {
+    let _ = writeln!(
+        out,
+        "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}"
+    );
+}
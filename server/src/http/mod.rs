@@ -1,6 +1,37 @@
+pub mod access_log;
+pub mod admin;
+pub mod audit;
+mod bundle;
+mod cache_policy;
+pub mod client_ip;
 pub mod config;
+pub mod delivery;
+pub mod free_paths;
+pub mod health;
+mod ipfs;
+mod metered;
+pub mod metrics;
 mod model;
+pub mod mounts;
+mod openapi;
+pub mod playlist;
+pub mod playlist_cache;
+pub mod preflight;
+pub mod preview;
+pub mod pricing;
+pub mod rate_limit;
 pub mod router;
+pub mod session;
+pub mod settlement_limit;
+pub mod settlement_store;
+pub mod shutdown;
+pub mod tab_spend;
+pub mod tls;
+#[cfg(unix)]
+pub mod unix_socket;
+mod upload;
+mod verify;
+pub mod webhook;
 mod x402;
 
 pub use config::Config;
@@ -1,24 +1,236 @@
+use axum::{
+    Json,
+    http::{HeaderValue, StatusCode, header::RETRY_AFTER},
+    response::{IntoResponse, Response},
+};
 use sdk_4mica::x402::PaymentRequirements;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Value, json};
+use server::{
+    FileStreamError, IpfsPathError, RemoteFetchError,
+    x402::{Network, PaymentDiagnosis},
+};
+use std::{fmt::Display, time::Duration};
+use tracing::{error, warn};
+use utoipa::ToSchema;
+pub use x402_common::{PaymentRequiredResponse, PaymentRequirementsSchema};
 
-#[derive(Debug, Clone, Serialize)]
+use crate::http::config::is_hex_address;
+
+/// Error answered by every endpoint except 402s, whose body the x402 spec defines.
+///
+/// Serialized as [`ApiErrorBody`]. Server errors carry only a generic message; build
+/// them with [`ApiError::internal`] so the cause is logged instead.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    details: Option<Value>,
+    retry_after: Option<u64>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+            details: None,
+            retry_after: None,
+        }
+    }
+
+    /// A 500 whose `cause` is logged but not sent.
+    pub fn internal(code: &'static str, message: &str, cause: impl Display) -> Self {
+        error!(code, "{}: {}", message, cause);
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, code, message)
+    }
+
+    /// Attaches structured context for the client, merged into any already attached;
+    /// dropped from server errors.
+    pub fn with_details(mut self, details: Value) -> Self {
+        self.details = match (self.details.take(), details) {
+            (Some(Value::Object(mut attached)), Value::Object(details)) => {
+                attached.extend(details);
+                Some(Value::Object(attached))
+            }
+            (_, details) => Some(details),
+        };
+        self
+    }
+
+    /// Tells the client to retry after `wait`, rounded up to whole seconds, in
+    /// `Retry-After` and the `retryAfterSeconds` detail.
+    pub fn with_retry_after(self, wait: Duration) -> Self {
+        let seconds = wait.as_secs_f64().ceil().max(1.0) as u64;
+        let mut error = self.with_details(json!({ "retryAfterSeconds": seconds }));
+        error.retry_after = Some(seconds);
+        error
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let details = self.details.filter(|_| !self.status.is_server_error());
+        let body = ApiErrorBody {
+            error: ApiErrorDetail {
+                code: self.code,
+                message: self.message,
+                details,
+            },
+        };
+        let mut resp = (self.status, Json(body)).into_response();
+        if let Some(seconds) = self.retry_after {
+            resp.headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        resp
+    }
+}
+
+impl From<FileStreamError> for ApiError {
+    fn from(e: FileStreamError) -> Self {
+        match e {
+            FileStreamError::NotFound(_) => {
+                Self::new(StatusCode::NOT_FOUND, "file_not_found", "File not found")
+            }
+            FileStreamError::NotAFile(_) => {
+                Self::new(StatusCode::BAD_REQUEST, "not_a_file", "Not a file")
+            }
+            FileStreamError::AccessDenied => {
+                Self::new(StatusCode::FORBIDDEN, "access_denied", "Access denied")
+            }
+            FileStreamError::NotReady { retry_after, .. } => Self::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "file_not_ready",
+                "File is still being written",
+            )
+            .with_retry_after(retry_after),
+            FileStreamError::IoError(e) => {
+                Self::internal("file_read_failed", "Failed to read file", e)
+            }
+        }
+    }
+}
+
+impl From<RemoteFetchError> for ApiError {
+    fn from(e: RemoteFetchError) -> Self {
+        warn!("Remote fetch failed: {}", e);
+        match e {
+            RemoteFetchError::Status(status) if status == StatusCode::NOT_FOUND => Self::new(
+                StatusCode::NOT_FOUND,
+                "remote_not_found",
+                "Remote file not found",
+            ),
+            RemoteFetchError::Status(status) if status == StatusCode::RANGE_NOT_SATISFIABLE => {
+                Self::new(
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    "range_not_satisfiable",
+                    "Requested range is outside the remote file",
+                )
+            }
+            RemoteFetchError::TooLarge { max_bytes } => Self::new(
+                StatusCode::BAD_GATEWAY,
+                "remote_too_large",
+                format!("Remote file is larger than the {max_bytes} byte limit"),
+            ),
+            RemoteFetchError::Empty => Self::new(
+                StatusCode::BAD_GATEWAY,
+                "remote_empty",
+                "Remote file is empty",
+            ),
+            _ => Self::new(
+                StatusCode::BAD_GATEWAY,
+                "remote_fetch_failed",
+                "Failed to fetch remote file",
+            ),
+        }
+    }
+}
+
+impl From<IpfsPathError> for ApiError {
+    fn from(e: IpfsPathError) -> Self {
+        match e {
+            IpfsPathError::InvalidCid(_) => {
+                Self::new(StatusCode::BAD_REQUEST, "invalid_cid", e.to_string())
+            }
+            IpfsPathError::InvalidSegment(_) => {
+                Self::new(StatusCode::BAD_REQUEST, "invalid_ipfs_path", e.to_string())
+            }
+        }
+    }
+}
+
+/// `{"error": {"code", "message", "details"}}`.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiErrorBody {
+    pub error: ApiErrorDetail,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiErrorDetail {
+    /// Stable machine-readable code, e.g. `file_not_found`.
+    #[schema(value_type = String)]
+    pub code: &'static str,
+    /// Human-readable description; generic for server errors.
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+}
+
+/// Body of `GET /price/...`: what the matching stream route would charge right now.
+#[derive(Debug, Clone, Serialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
-pub struct PaymentRequiredResponse {
+pub struct PriceQuote {
     pub x402_version: u64,
+    /// The URL a payment must name, as the 402 would advertise it.
+    pub resource: String,
+    /// Hex amount in the asset's base units; `0x0` when free.
+    pub price: String,
+    /// Served without payment, e.g. playlists or with x402 disabled.
+    pub free: bool,
+    /// The payment options a 402 would list; empty when free.
+    #[schema(value_type = Vec<PaymentRequirementsSchema>)]
     pub accepts: Vec<PaymentRequirements>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub error: Option<String>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// Body of `POST /x402/verify`.
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymentVerifyRequest {
+    /// The base64 payment header, as sent in `X-PAYMENT` or `payment-signature`.
+    pub payment_header: String,
+    /// What the payment is for: the absolute URL or path of a `/stream/...` route, or an
+    /// `ipfs://{cid}/{path}` resource.
+    pub resource: String,
+}
+
+/// Body of a `POST /x402/verify` answer: how the paywall would judge the payment.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymentVerifyResponse {
+    /// The URL the payment must name.
+    pub resource: String,
+    /// Hex amount in the asset's base units; `0x0` when free.
+    pub price: String,
+    /// Served without payment, so there was nothing to check.
+    pub free: bool,
+    /// `valid`, the decoded `envelope`, the `matchedRequirement`, each of the `checks` in
+    /// order with its `passed`, `message`, and `errorCode`, and the `facilitator`'s
+    /// `isValid` and `invalidReason`; absent when free.
+    #[schema(value_type = Option<Object>)]
+    pub diagnosis: Option<PaymentDiagnosis>,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct TabRequestParams {
     pub user_address: String,
     pub payment_requirements: TabPaymentRequirements,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct TabPaymentRequirements {
     pub scheme: String,
@@ -43,6 +255,61 @@ pub struct TabPaymentRequirements {
     pub extra: Option<Value>,
 }
 
+impl TabRequestParams {
+    /// Checks the tab would be opened for a real address and pays us on one of our
+    /// `networks`, or one of the `mount_pay_tos` on any of them, so the facilitator is
+    /// never asked for anything else.
+    pub fn validate(&self, networks: &[Network], mount_pay_tos: &[&str]) -> Result<(), ApiError> {
+        if !is_hex_address(&self.user_address) {
+            return Err(invalid_tab_field(
+                "userAddress",
+                "is not a 20-byte hex address",
+            ));
+        }
+        let requirements = &self.payment_requirements;
+        let Some(network) = networks.iter().find(|network| {
+            network.name == requirements.network
+                || network.network_v2.as_deref() == Some(requirements.network.as_str())
+        }) else {
+            return Err(invalid_tab_field(
+                "paymentRequirements.network",
+                "is not a network this server accepts",
+            ));
+        };
+        let pays_us = std::iter::once(network.pay_to.as_str())
+            .chain(mount_pay_tos.iter().copied())
+            .any(|pay_to| same_address(&requirements.pay_to, pay_to));
+        if !pays_us {
+            return Err(invalid_tab_field(
+                "paymentRequirements.payTo",
+                "is not this server's pay-to address",
+            ));
+        }
+        if !same_address(&requirements.asset, &network.asset) {
+            return Err(invalid_tab_field(
+                "paymentRequirements.asset",
+                "is not an asset this server accepts on the network",
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn invalid_tab_field(field: &str, problem: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "invalid_tab_request",
+        format!("{field} {problem}"),
+    )
+    .with_details(json!({ "field": field }))
+}
+
+/// Whether two hex addresses are equal, ignoring case and the `0x` prefix.
+fn same_address(a: &str, b: &str) -> bool {
+    let digits = |address: &str| address.trim_start_matches("0x").to_ascii_lowercase();
+    digits(a) == digits(b)
+}
+
 impl TabPaymentRequirements {
     pub fn into_payment_requirements(self) -> PaymentRequirements {
         let max_amount_required = self
@@ -65,3 +332,93 @@ impl TabPaymentRequirements {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAY_TO: &str = "0x00000000000000000000000000000000000000ab";
+    const MOUNT_PAY_TO: &str = "0x00000000000000000000000000000000000000ac";
+    const ASSET: &str = "0x41E94Eb019C0762f9Bfcf9Fb1E58725BfB0e7582";
+
+    fn networks() -> Vec<Network> {
+        vec![Network {
+            name: "polygon-amoy".to_string(),
+            network_v2: Some("eip155:80002".to_string()),
+            rpc_url: "http://localhost:8545".to_string(),
+            asset: ASSET.to_string(),
+            pay_to: PAY_TO.to_string(),
+            asset_name: "USDC".to_string(),
+            asset_version: "2".to_string(),
+            asset_decimals: Some(6),
+        }]
+    }
+
+    fn tab_request(change: impl FnOnce(&mut Value)) -> TabRequestParams {
+        let mut body = json!({
+            "userAddress": "0x00000000000000000000000000000000000000ef",
+            "paymentRequirements": {
+                "scheme": "4mica-credit",
+                "network": "polygon-amoy",
+                "maxAmountRequired": "100",
+                "payTo": PAY_TO,
+                "asset": ASSET,
+            },
+        });
+        change(&mut body);
+        serde_json::from_value(body).unwrap()
+    }
+
+    /// The field a rejected request names, or `None` when it is accepted.
+    async fn rejected_field(request: TabRequestParams) -> Option<String> {
+        let error = request.validate(&networks(), &[MOUNT_PAY_TO]).err()?;
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&bytes).unwrap();
+        Some(
+            body["error"]["details"]["field"]
+                .as_str()
+                .unwrap()
+                .to_string(),
+        )
+    }
+
+    #[tokio::test]
+    async fn tab_requests_for_our_addresses_are_accepted() {
+        assert_eq!(rejected_field(tab_request(|_| {})).await, None);
+        let v2 = tab_request(|body| body["paymentRequirements"]["network"] = json!("eip155:80002"));
+        assert_eq!(rejected_field(v2).await, None);
+        let mount = tab_request(|body| body["paymentRequirements"]["payTo"] = json!(MOUNT_PAY_TO));
+        assert_eq!(rejected_field(mount).await, None);
+        let upper = tab_request(|body| {
+            body["paymentRequirements"]["payTo"] = json!(PAY_TO.to_uppercase().replace("0X", "0x"));
+            body["paymentRequirements"]["asset"] = json!(ASSET.trim_start_matches("0x"));
+        });
+        assert_eq!(rejected_field(upper).await, None);
+    }
+
+    #[tokio::test]
+    async fn each_bad_field_is_named() {
+        for (field, value) in [
+            ("userAddress", "0xnot-an-address"),
+            ("paymentRequirements.network", "base"),
+            (
+                "paymentRequirements.payTo",
+                "0x00000000000000000000000000000000000000ad",
+            ),
+            (
+                "paymentRequirements.asset",
+                "0x00000000000000000000000000000000000000cd",
+            ),
+        ] {
+            let request = tab_request(|body| match field.split_once('.') {
+                Some((_, key)) => body["paymentRequirements"][key] = json!(value),
+                None => body[field] = json!(value),
+            });
+            assert_eq!(rejected_field(request).await.as_deref(), Some(field));
+        }
+    }
+}
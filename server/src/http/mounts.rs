@@ -0,0 +1,148 @@
+use sdk_4mica::U256;
+use serde::Deserialize;
+use server::io::LocalStorage;
+use std::{path::PathBuf, str::FromStr, sync::Arc};
+
+use crate::http::config::Config;
+
+/// A `MOUNTS` entry as written: files under `urlPrefix` come from `directory`, paid to
+/// `payTo`, at `price` if it is set.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct RawMount {
+    url_prefix: String,
+    directory: PathBuf,
+    pay_to: String,
+    price: Option<String>,
+}
+
+/// One validated `MOUNTS` entry.
+#[derive(Debug, Clone)]
+pub struct MountEntry {
+    /// Path segments under `/stream/`, joined by `/`, without leading or trailing slashes.
+    pub url_prefix: String,
+    pub directory: PathBuf,
+    pub pay_to: String,
+    /// Price of every paid file under the mount, in place of the route's.
+    pub price: Option<U256>,
+}
+
+/// `MOUNTS`: a JSON array of `{urlPrefix, directory, payTo, price}` objects, or a path to a
+/// file holding one.
+#[derive(Debug, Clone)]
+pub struct MountList(pub Vec<MountEntry>);
+
+impl FromStr for MountList {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let raw = raw.trim();
+        let json = if raw.starts_with('[') {
+            raw.to_string()
+        } else {
+            std::fs::read_to_string(raw).map_err(|e| format!("failed to read {raw}: {e}"))?
+        };
+        let mounts: Vec<RawMount> =
+            serde_json::from_str(&json).map_err(|e| format!("invalid mount list: {e}"))?;
+        let mut entries: Vec<MountEntry> = Vec::with_capacity(mounts.len());
+        for mount in mounts {
+            let url_prefix = mount.url_prefix.trim_matches('/');
+            let invalid = url_prefix
+                .split('/')
+                .any(|segment| matches!(segment, "" | "." | "..") || segment.contains('\\'));
+            if invalid {
+                return Err(format!("invalid mount URL prefix {:?}", mount.url_prefix));
+            }
+            if entries.iter().any(|entry| entry.url_prefix == url_prefix) {
+                return Err(format!("mount URL prefix {url_prefix:?} is listed twice"));
+            }
+            let price = mount
+                .price
+                .map(|price| {
+                    price
+                        .parse::<U256>()
+                        .map_err(|e| format!("invalid price of mount {url_prefix:?}: {e}"))
+                })
+                .transpose()?;
+            entries.push(MountEntry {
+                url_prefix: url_prefix.to_string(),
+                directory: mount.directory,
+                pay_to: mount.pay_to,
+                price,
+            });
+        }
+        Ok(Self(entries))
+    }
+}
+
+/// A directory served under a URL prefix, opened from its [`MountEntry`].
+pub struct Mount {
+    pub url_prefix: String,
+    pub storage: LocalStorage,
+    pub pay_to: String,
+    pub price: Option<U256>,
+}
+
+/// The `MOUNTS` directories `/stream/{filename}` reads from instead of the storage
+/// backend, for files under their URL prefixes.
+#[derive(Clone, Default)]
+pub struct Mounts {
+    /// Longest prefix first, so the most specific mount matches.
+    mounts: Arc<[Arc<Mount>]>,
+}
+
+impl Mounts {
+    /// Opens every configured mount directory, which must exist.
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        let Some(MountList(entries)) = &config.mounts else {
+            return Ok(Self::default());
+        };
+        let mut mounts = entries
+            .iter()
+            .map(|entry| {
+                let storage = LocalStorage::new(&entry.directory)
+                    .map_err(|e| {
+                        format!(
+                            "failed to open mount directory {}: {e}",
+                            entry.directory.display()
+                        )
+                    })?
+                    .with_buffer_bytes(config.stream_buffer_bytes)
+                    .with_stability(config.file_stability());
+                Ok(Arc::new(Mount {
+                    url_prefix: entry.url_prefix.clone(),
+                    storage,
+                    pay_to: entry.pay_to.clone(),
+                    price: entry.price,
+                }))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        mounts.sort_by_key(|mount| std::cmp::Reverse(mount.url_prefix.len()));
+        Ok(Self {
+            mounts: mounts.into(),
+        })
+    }
+
+    /// The mount `filename` (a `/stream/` path) falls under, by whole path segments, and
+    /// the rest of the path inside it. The rest is resolved within the mount's directory,
+    /// so `..` can't leave it.
+    pub fn resolve<'a>(&self, filename: &'a str) -> Option<(&Arc<Mount>, &'a str)> {
+        let filename = filename.trim_start_matches('/');
+        self.mounts.iter().find_map(|mount| {
+            let rest = filename.strip_prefix(mount.url_prefix.as_str())?;
+            match rest.strip_prefix('/') {
+                Some(rest) => Some((mount, rest)),
+                None if rest.is_empty() => Some((mount, rest)),
+                None => None,
+            }
+        })
+    }
+
+    /// Pay-to addresses of the mounts, which tabs may also be opened for.
+    pub fn pay_tos(&self) -> Vec<&str> {
+        self.mounts
+            .iter()
+            .map(|mount| mount.pay_to.as_str())
+            .collect()
+    }
+}
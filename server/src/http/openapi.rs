@@ -0,0 +1,124 @@
+use axum::{
+    Json, Router,
+    response::{Html, IntoResponse, Response},
+    routing::get,
+};
+use utoipa::{IntoParams, OpenApi};
+
+use super::{
+    bundle,
+    config::Config,
+    health::{self, Health},
+    ipfs, metrics,
+    model::{
+        ApiErrorBody, ApiErrorDetail, PaymentRequiredResponse, PaymentRequirementsSchema,
+        PaymentVerifyRequest, PaymentVerifyResponse, PriceQuote, TabPaymentRequirements,
+        TabRequestParams,
+    },
+    playlist,
+    router::{self, AppState},
+    verify,
+};
+
+/// Spec of the public routes, generated from the handler annotations in [`router`].
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "4mica demo stream server",
+        description = "Pay-per-segment HLS streaming over x402."
+    ),
+    paths(
+        health::handle_health,
+        metrics::handle_metrics,
+        router::handle_tab,
+        router::handle_stream,
+        router::handle_stream_head,
+        router::handle_remote_stream,
+        router::handle_remote_stream_head,
+        ipfs::handle_ipfs_stream,
+        ipfs::handle_ipfs_stream_head,
+        playlist::handle_playlist,
+        bundle::handle_bundle,
+        router::handle_tab_status,
+        router::handle_price,
+        router::handle_remote_price,
+        verify::handle_verify,
+    ),
+    components(schemas(
+        ApiErrorBody,
+        ApiErrorDetail,
+        Health,
+        PaymentRequiredResponse,
+        PaymentRequirementsSchema,
+        PaymentVerifyRequest,
+        PaymentVerifyResponse,
+        PriceQuote,
+        TabRequestParams,
+        TabPaymentRequirements,
+        server::x402::FacilitatorTabResponse,
+        server::x402::TabStatus,
+    ))
+)]
+struct ApiDoc;
+
+/// Request headers the paywall reads on paid routes.
+#[allow(dead_code)] // Only describes the headers; the paywall reads them directly.
+#[derive(IntoParams)]
+#[into_params(parameter_in = Header)]
+pub(super) struct PaymentHeaders {
+    /// Base64 JSON x402 v1 payment envelope, or a v2 payload.
+    #[param(rename = "x-payment")]
+    x_payment: Option<String>,
+    /// x402 v2 payment payload; takes precedence over `x-payment`.
+    #[param(rename = "payment-signature")]
+    payment_signature: Option<String>,
+    /// Session token from an earlier paid response, when payment sessions are enabled.
+    #[param(rename = "x-payment-session")]
+    x_payment_session: Option<String>,
+}
+
+/// Query parameter carrying the payment for players that cannot set request headers.
+#[allow(dead_code)] // Only describes the parameter; the paywall reads it directly.
+#[derive(IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(super) struct PaymentQuery {
+    /// URL-safe base64 payment, read only with `X402_ALLOW_QUERY_PAYMENT` and when no
+    /// payment header is present.
+    payment: Option<String>,
+}
+
+/// Swagger UI rendering `/openapi.json`, with its assets loaded from a CDN.
+const SWAGGER_UI: &str = r##"<!doctype html>
+<html lang="en">
+<head>
+  <meta charset="utf-8" />
+  <title>4mica demo API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css" />
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js" crossorigin></script>
+  <script>
+    window.ui = SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });
+  </script>
+</body>
+</html>
+"##;
+
+/// `/openapi.json` and `/docs`, or no routes unless `API_DOCS_ENABLED` is set.
+pub fn router(config: &Config) -> Router<AppState> {
+    if !config.api_docs_enabled {
+        return Router::new();
+    }
+    Router::new()
+        .route("/openapi.json", get(handle_spec))
+        .route("/docs", get(handle_docs))
+}
+
+async fn handle_spec() -> Response {
+    Json(ApiDoc::openapi()).into_response()
+}
+
+async fn handle_docs() -> Html<&'static str> {
+    Html(SWAGGER_UI)
+}
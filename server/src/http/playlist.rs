@@ -0,0 +1,530 @@
+use axum::{
+    Router,
+    extract::{Path, State},
+    http::{HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use parking_lot::Mutex;
+use serde_json::json;
+use server::io::FileStability;
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    path::{Component, Path as FsPath, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use crate::http::{
+    config::{Config, StorageKind},
+    model::{ApiError, ApiErrorBody},
+    router::AppState,
+};
+
+/// Optional file in an asset directory listing `<segment> <seconds>` per line, for
+/// segments whose duration can't be probed (fMP4) or shouldn't be.
+const DURATIONS_FILE: &str = "durations.txt";
+
+/// fMP4 initialization segment, advertised with `#EXT-X-MAP` when present.
+const FMP4_INIT_SEGMENT: &str = "init.mp4";
+
+/// MPEG-TS clock rate of PES timestamps.
+const TS_CLOCK_HZ: f64 = 90_000.0;
+const TS_PACKET_LEN: usize = 188;
+
+/// Media playlists generated for asset directories, reused until the directory or its
+/// durations file changes. Segments still being written are left out, and a playlist
+/// missing any is generated again next time.
+#[derive(Debug, Default)]
+pub struct GeneratedPlaylists {
+    playlists: Mutex<HashMap<PathBuf, Generated>>,
+    stability: FileStability,
+}
+
+#[derive(Debug)]
+struct Generated {
+    stamp: Stamp,
+    playlist: Arc<str>,
+}
+
+/// Modification times of an asset directory and its durations file.
+type Stamp = (Option<SystemTime>, Option<SystemTime>);
+
+/// Why a playlist could not be generated.
+#[derive(Debug)]
+enum PlaylistError {
+    NoSegments,
+    MixedSegments,
+    UnknownDuration(String),
+    Io(std::io::Error),
+}
+
+impl From<PlaylistError> for ApiError {
+    fn from(e: PlaylistError) -> Self {
+        match e {
+            PlaylistError::NoSegments => ApiError::new(
+                StatusCode::NOT_FOUND,
+                "no_segments",
+                "Asset has no numbered .ts or .m4s segments",
+            ),
+            PlaylistError::MixedSegments => ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "mixed_segments",
+                "Asset mixes .ts and .m4s segments",
+            ),
+            PlaylistError::UnknownDuration(segment) => ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "segment_duration_unknown",
+                format!("Duration of {segment} is unknown; list it in {DURATIONS_FILE}"),
+            )
+            .with_details(json!({ "segment": segment })),
+            PlaylistError::Io(e) => {
+                ApiError::internal("playlist_generation_failed", "Failed to read asset", e)
+            }
+        }
+    }
+}
+
+/// Routes for generated playlists, mounted only for the `local` storage backend.
+pub fn router(config: &Config) -> Router<AppState> {
+    if config.storage_backend != StorageKind::Local {
+        return Router::new();
+    }
+    Router::new().route("/playlist/{asset}", get(handle_playlist))
+}
+
+/// Returns a VOD media playlist for the numbered segments in `FILE_DIRECTORY/{asset}/`,
+/// pointing at their paywalled `/stream/{asset}/{segment}` routes. Free, like every
+/// playlist.
+#[utoipa::path(
+    get,
+    path = "/playlist/{asset}",
+    tag = "stream",
+    params(("asset" = String, Path, description = "Directory under the storage root, optionally suffixed `.m3u8`")),
+    responses(
+        (status = 200, description = "The generated media playlist", content_type = "application/vnd.apple.mpegurl", body = String),
+        (status = 403, description = "`access_denied`: the asset is not a directory directly under the storage root", body = ApiErrorBody),
+        (status = 404, description = "`asset_not_found` or `no_segments`", body = ApiErrorBody),
+        (status = 422, description = "`segment_duration_unknown` or `mixed_segments`", body = ApiErrorBody),
+    )
+)]
+pub(super) async fn handle_playlist(
+    State(state): State<AppState>,
+    Path(asset): Path<String>,
+) -> Result<Response, ApiError> {
+    let asset = asset.strip_suffix(".m3u8").unwrap_or(&asset).to_string();
+    let mut components = FsPath::new(&asset).components();
+    if !matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "access_denied",
+            "Access denied",
+        ));
+    }
+    let base = FsPath::new(&state.config.file_directory);
+    let Ok(directory) = base.join(&asset).canonicalize() else {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "asset_not_found",
+            "Asset not found",
+        ));
+    };
+    // A symlink may not expose a directory outside the root.
+    if !base
+        .canonicalize()
+        .is_ok_and(|base| directory.starts_with(base))
+    {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "access_denied",
+            "Access denied",
+        ));
+    }
+    if !directory.is_dir() {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "asset_not_found",
+            "Asset not found",
+        ));
+    }
+
+    let playlists = state.playlists.clone();
+    let playlist = tokio::task::spawn_blocking(move || playlists.get(&directory, &asset))
+        .await
+        .map_err(|e| ApiError::internal("playlist_generation_failed", "Failed to read asset", e))?
+        .map_err(ApiError::from)?;
+    Ok((
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/vnd.apple.mpegurl"),
+        )],
+        playlist.to_string(),
+    )
+        .into_response())
+}
+
+impl GeneratedPlaylists {
+    pub fn new(stability: FileStability) -> Self {
+        Self {
+            playlists: Mutex::default(),
+            stability,
+        }
+    }
+
+    fn get(&self, directory: &FsPath, asset: &str) -> Result<Arc<str>, PlaylistError> {
+        let stamp = (
+            modified(directory),
+            modified(&directory.join(DURATIONS_FILE)),
+        );
+        if let Some(cached) = self.playlists.lock().get(directory)
+            && cached.stamp == stamp
+        {
+            return Ok(cached.playlist.clone());
+        }
+
+        let (playlist, complete) = generate(directory, asset, &self.stability)?;
+        let playlist: Arc<str> = playlist.into();
+        if !complete {
+            return Ok(playlist);
+        }
+        self.playlists.lock().insert(
+            directory.to_path_buf(),
+            Generated {
+                stamp,
+                playlist: playlist.clone(),
+            },
+        );
+        Ok(playlist)
+    }
+}
+
+fn modified(path: &FsPath) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+/// The playlist, and whether it lists every segment rather than leaving out fresh ones.
+fn generate(
+    directory: &FsPath,
+    asset: &str,
+    stability: &FileStability,
+) -> Result<(String, bool), PlaylistError> {
+    let mut segments = numbered_segments(directory).map_err(PlaylistError::Io)?;
+    let found = segments.len();
+    segments.retain(|(_, name)| {
+        !stability.is_ignored(name) && stability.wait(modified(&directory.join(name))).is_none()
+    });
+    let complete = segments.len() == found;
+    let Some((_, first)) = segments.first() else {
+        return Err(PlaylistError::NoSegments);
+    };
+    let fmp4 = first.ends_with(".m4s");
+    if segments
+        .iter()
+        .any(|(_, name)| name.ends_with(".m4s") != fmp4)
+    {
+        return Err(PlaylistError::MixedSegments);
+    }
+
+    let sidecar = match std::fs::read_to_string(directory.join(DURATIONS_FILE)) {
+        Ok(sidecar) => parse_durations(&sidecar),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+        Err(e) => return Err(PlaylistError::Io(e)),
+    };
+    let probed: Vec<Option<(u64, u64)>> = segments
+        .iter()
+        .map(|(_, name)| {
+            if fmp4 || sidecar.contains_key(name) {
+                return Ok(None);
+            }
+            std::fs::read(directory.join(name)).map(|bytes| probe_ts_pts(&bytes))
+        })
+        .collect::<Result<_, _>>()
+        .map_err(PlaylistError::Io)?;
+
+    let mut durations = Vec::with_capacity(segments.len());
+    for (i, (_, name)) in segments.iter().enumerate() {
+        let duration = match (sidecar.get(name), probed[i]) {
+            (Some(seconds), _) => *seconds,
+            // The next segment's first timestamp also covers this one's last frame,
+            // unless a discontinuity separates them.
+            (None, Some((first, last))) => {
+                let end = probed
+                    .get(i + 1)
+                    .copied()
+                    .flatten()
+                    .map(|(next, _)| next)
+                    .filter(|next| *next > first && *next <= last + TS_CLOCK_HZ as u64)
+                    .unwrap_or(last);
+                (end - first) as f64 / TS_CLOCK_HZ
+            }
+            (None, None) => 0.0,
+        };
+        if duration <= 0.0 {
+            return Err(PlaylistError::UnknownDuration(name.clone()));
+        }
+        durations.push(duration);
+    }
+
+    let target = durations.iter().fold(1.0_f64, |max, d| max.max(d.round()));
+    let mut playlist = String::from("#EXTM3U\n");
+    let _ = writeln!(playlist, "#EXT-X-VERSION:{}", if fmp4 { 7 } else { 3 });
+    let _ = writeln!(playlist, "#EXT-X-TARGETDURATION:{}", target as u64);
+    playlist.push_str("#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n");
+    if fmp4 && directory.join(FMP4_INIT_SEGMENT).is_file() {
+        let _ = writeln!(
+            playlist,
+            "#EXT-X-MAP:URI=\"/stream/{asset}/{FMP4_INIT_SEGMENT}\""
+        );
+    }
+    for ((_, name), duration) in segments.iter().zip(durations) {
+        let _ = writeln!(playlist, "#EXTINF:{duration:.6},\n/stream/{asset}/{name}");
+    }
+    playlist.push_str("#EXT-X-ENDLIST\n");
+    Ok((playlist, complete))
+}
+
+/// `.ts`/`.m4s` files whose name ends in a number, ordered by that number.
+fn numbered_segments(directory: &FsPath) -> std::io::Result<Vec<(u64, String)>> {
+    let mut segments = Vec::new();
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let Some(stem) = name
+            .strip_suffix(".ts")
+            .or_else(|| name.strip_suffix(".m4s"))
+        else {
+            continue;
+        };
+        let digits = stem.len() - stem.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+        if let Ok(number) = stem[stem.len() - digits..].parse() {
+            segments.push((number, name));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+/// `<segment> <seconds>` lines; blank lines, `#` comments, and malformed lines are skipped.
+fn parse_durations(sidecar: &str) -> HashMap<String, f64> {
+    sidecar
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let (name, seconds) = line.split_once(char::is_whitespace)?;
+            let seconds: f64 = seconds.trim().parse().ok()?;
+            (seconds.is_finite() && seconds > 0.0).then(|| (name.to_string(), seconds))
+        })
+        .collect()
+}
+
+/// Lowest and highest PES presentation timestamp of the first timestamped stream in an
+/// MPEG-TS segment, in 90 kHz ticks.
+fn probe_ts_pts(bytes: &[u8]) -> Option<(u64, u64)> {
+    let mut stream_pid = None;
+    let mut range: Option<(u64, u64)> = None;
+    for packet in bytes.chunks_exact(TS_PACKET_LEN) {
+        let payload_start = packet[1] & 0x40 != 0;
+        let pid = (u16::from(packet[1] & 0x1f) << 8) | u16::from(packet[2]);
+        if packet[0] != 0x47 || !payload_start || stream_pid.is_some_and(|p| p != pid) {
+            continue;
+        }
+        let adaptation = (packet[3] >> 4) & 0x3;
+        if adaptation & 0x1 == 0 {
+            continue;
+        }
+        let offset = if adaptation & 0x2 != 0 {
+            5 + usize::from(packet[4])
+        } else {
+            4
+        };
+        let Some(pes) = packet.get(offset..) else {
+            continue;
+        };
+        // PES start code, then the PTS flag and the 5-byte PTS.
+        if pes.len() < 14 || pes[..3] != [0, 0, 1] || pes[7] & 0x80 == 0 {
+            continue;
+        }
+        let pts = (u64::from(pes[9] >> 1) & 0x7) << 30
+            | u64::from(pes[10]) << 22
+            | u64::from(pes[11] >> 1) << 15
+            | u64::from(pes[12]) << 7
+            | u64::from(pes[13] >> 1);
+        stream_pid = Some(pid);
+        range = Some(match range {
+            Some((low, high)) => (low.min(pts), high.max(pts)),
+            None => (pts, pts),
+        });
+    }
+    range
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use m3u8_rs::{MediaPlaylist, MediaPlaylistType};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// An empty asset directory of its own.
+    fn asset_directory() -> PathBuf {
+        static ASSETS: AtomicUsize = AtomicUsize::new(0);
+        let directory = std::env::temp_dir().join(format!(
+            "playlist-test-{}-{}",
+            std::process::id(),
+            ASSETS.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    /// An MPEG-TS segment holding one video PES packet per timestamp in `pts`.
+    fn ts_segment(pts: &[u64]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for &pts in pts {
+            let mut packet = vec![0x47, 0x41, 0x00, 0x10];
+            packet.extend([0, 0, 1, 0xe0, 0, 0, 0x80, 0x80, 5]);
+            packet.extend([
+                0x21 | ((pts >> 29) & 0x0e) as u8,
+                (pts >> 22) as u8,
+                ((pts >> 14) & 0xfe) as u8 | 1,
+                (pts >> 7) as u8,
+                ((pts << 1) & 0xfe) as u8 | 1,
+            ]);
+            packet.resize(TS_PACKET_LEN, 0xff);
+            bytes.extend(packet);
+        }
+        bytes
+    }
+
+    fn parse(playlist: &str) -> MediaPlaylist {
+        m3u8_rs::parse_media_playlist_res(playlist.as_bytes()).unwrap()
+    }
+
+    fn segments(playlist: &MediaPlaylist) -> Vec<(&str, f32)> {
+        playlist
+            .segments
+            .iter()
+            .map(|segment| (segment.uri.as_str(), segment.duration))
+            .collect()
+    }
+
+    #[test]
+    fn sidecar_durations_describe_fmp4_segments() {
+        let directory = asset_directory();
+        for name in [
+            "init.mp4",
+            "seg_2.m4s",
+            "seg_10.m4s",
+            "seg_1.m4s",
+            "notes.txt",
+        ] {
+            std::fs::write(directory.join(name), b"fmp4").unwrap();
+        }
+        std::fs::write(
+            directory.join(DURATIONS_FILE),
+            "# seconds per segment\nseg_1.m4s 4.004\nseg_2.m4s 4.5\n\nseg_10.m4s 2\n",
+        )
+        .unwrap();
+
+        let (playlist, complete) = generate(&directory, "show", &FileStability::default()).unwrap();
+        assert!(complete);
+        let playlist = parse(&playlist);
+        assert_eq!(playlist.version, Some(7));
+        assert_eq!(playlist.target_duration, 5);
+        assert_eq!(playlist.playlist_type, Some(MediaPlaylistType::Vod));
+        assert!(playlist.end_list);
+        assert_eq!(
+            segments(&playlist),
+            [
+                ("/stream/show/seg_1.m4s", 4.004),
+                ("/stream/show/seg_2.m4s", 4.5),
+                ("/stream/show/seg_10.m4s", 2.0),
+            ]
+        );
+        let map = playlist.segments[0].map.as_ref().unwrap();
+        assert_eq!(map.uri, "/stream/show/init.mp4");
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn ts_durations_are_probed_from_timestamps() {
+        let directory = asset_directory();
+        let second = TS_CLOCK_HZ as u64;
+        // 25 fps: the first segment ends where the second begins; the last ends at its
+        // last frame.
+        let frames = |from: u64, count: u64| -> Vec<u64> {
+            (0..count).map(|i| from + i * second / 25).collect()
+        };
+        std::fs::write(directory.join("seg_0.ts"), ts_segment(&frames(0, 100))).unwrap();
+        std::fs::write(
+            directory.join("seg_1.ts"),
+            ts_segment(&frames(4 * second, 151)),
+        )
+        .unwrap();
+
+        let (playlist, _) = generate(&directory, "show", &FileStability::default()).unwrap();
+        let playlist = parse(&playlist);
+        assert_eq!(playlist.version, Some(3));
+        assert_eq!(
+            segments(&playlist),
+            [
+                ("/stream/show/seg_0.ts", 4.0),
+                ("/stream/show/seg_1.ts", 6.0)
+            ]
+        );
+        assert_eq!(playlist.target_duration, 6);
+        assert!(
+            playlist
+                .segments
+                .iter()
+                .all(|segment| segment.map.is_none())
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn target_duration_is_the_longest_rounded_duration() {
+        for (durations, target) in [
+            ("seg_1.m4s 4.4\nseg_2.m4s 3.9\n", 4),
+            ("seg_1.m4s 4.4\nseg_2.m4s 4.5\n", 5),
+            ("seg_1.m4s 0.2\nseg_2.m4s 0.4\n", 1),
+        ] {
+            let directory = asset_directory();
+            for name in ["seg_1.m4s", "seg_2.m4s"] {
+                std::fs::write(directory.join(name), b"fmp4").unwrap();
+            }
+            std::fs::write(directory.join(DURATIONS_FILE), durations).unwrap();
+
+            let (playlist, _) = generate(&directory, "show", &FileStability::default()).unwrap();
+            assert_eq!(parse(&playlist).target_duration, target, "{durations}");
+            std::fs::remove_dir_all(directory).unwrap();
+        }
+    }
+
+    #[test]
+    fn segments_without_a_duration_are_rejected() {
+        let directory = asset_directory();
+        std::fs::write(directory.join("seg_1.m4s"), b"fmp4").unwrap();
+        assert!(matches!(
+            generate(&directory, "show", &FileStability::default()),
+            Err(PlaylistError::UnknownDuration(segment)) if segment == "seg_1.m4s"
+        ));
+        std::fs::write(directory.join("seg_2.ts"), ts_segment(&[0])).unwrap();
+        assert!(matches!(
+            generate(&directory, "show", &FileStability::default()),
+            Err(PlaylistError::MixedSegments)
+        ));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
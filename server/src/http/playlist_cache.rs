@@ -0,0 +1,155 @@
+use parking_lot::Mutex;
+use server::{
+    RemoteFetchError,
+    io::{RemoteFetcher, RemoteFile},
+};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::OnceCell;
+use tracing::debug;
+
+use super::{config::Config, pricing::is_playlist};
+
+/// A fetched playlist and when it was fetched.
+struct Fetched {
+    at: Instant,
+    file: RemoteFile,
+}
+
+/// One URL's cache slot; concurrent misses share `fetched` so only one of them fetches.
+#[derive(Default)]
+struct Slot {
+    fetched: OnceCell<Fetched>,
+}
+
+struct Entry {
+    slot: Arc<Slot>,
+    last_used: Instant,
+}
+
+/// Short-lived in-memory copies of remote `.m3u8` playlists and `.mpd` manifests served by
+/// `/stream/remote`, so every viewer polling a live playlist doesn't reach the origin.
+///
+/// Entries expire after `ttl`, and the least recently used are evicted once the cached
+/// bodies exceed `max_bytes`. Failed fetches are never cached.
+#[derive(Clone)]
+pub struct RemotePlaylistCache {
+    ttl: Duration,
+    max_bytes: usize,
+    remote: Arc<RemoteFetcher>,
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl RemotePlaylistCache {
+    /// Returns `None` when `REMOTE_PLAYLIST_CACHE_SECONDS` is zero.
+    pub fn from_config(config: &Config, remote: Arc<RemoteFetcher>) -> Option<Self> {
+        if config.remote_playlist_cache_seconds == 0 {
+            return None;
+        }
+        Some(Self {
+            ttl: Duration::from_secs(config.remote_playlist_cache_seconds),
+            max_bytes: config.remote_playlist_cache_bytes,
+            remote,
+            entries: Arc::default(),
+        })
+    }
+
+    /// Whether `url` is cached here rather than streamed.
+    pub fn caches(url: &str) -> bool {
+        url.split(['?', '#']).next().is_some_and(is_playlist)
+    }
+
+    /// The playlist at `url`, from cache while it is fresh.
+    pub async fn get(&self, url: &str) -> Result<RemoteFile, RemoteFetchError> {
+        let slot = {
+            let mut entries = self.entries.lock();
+            let entry = entries.entry(url.to_string()).or_insert_with(|| Entry {
+                slot: Arc::default(),
+                last_used: Instant::now(),
+            });
+            if entry
+                .slot
+                .fetched
+                .get()
+                .is_some_and(|fetched| fetched.at.elapsed() >= self.ttl)
+            {
+                entry.slot = Arc::default();
+            }
+            entry.last_used = Instant::now();
+            entry.slot.clone()
+        };
+
+        if let Some(fetched) = slot.fetched.get() {
+            debug!(url, "Serving remote playlist from cache");
+            return Ok(fetched.file.clone());
+        }
+        let fetched = slot
+            .fetched
+            .get_or_try_init(|| async {
+                let file = self.remote.fetch(url).await?;
+                Ok::<_, RemoteFetchError>(Fetched {
+                    at: Instant::now(),
+                    file,
+                })
+            })
+            .await;
+        match fetched {
+            Ok(fetched) => {
+                let file = fetched.file.clone();
+                self.evict();
+                Ok(file)
+            }
+            Err(e) => {
+                // Drop the empty slot unless a newer one replaced it meanwhile.
+                let mut entries = self.entries.lock();
+                if entries
+                    .get(url)
+                    .is_some_and(|entry| Arc::ptr_eq(&entry.slot, &slot))
+                    && slot.fetched.get().is_none()
+                {
+                    entries.remove(url);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Drops expired entries, then least recently used ones until the cached bodies fit
+    /// in `max_bytes`.
+    fn evict(&self) {
+        let mut entries = self.entries.lock();
+        entries.retain(|_, entry| {
+            entry
+                .slot
+                .fetched
+                .get()
+                .is_none_or(|fetched| fetched.at.elapsed() < self.ttl)
+        });
+        let mut total: usize = entries.values().map(Entry::len).sum();
+        while total > self.max_bytes {
+            let Some((url, len)) = entries
+                .iter()
+                .filter(|(_, entry)| entry.slot.fetched.initialized())
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(url, entry)| (url.clone(), entry.len()))
+            else {
+                break;
+            };
+            debug!(url, len, "Evicting remote playlist from cache");
+            entries.remove(&url);
+            total -= len;
+        }
+    }
+}
+
+impl Entry {
+    fn len(&self) -> usize {
+        self.slot
+            .fetched
+            .get()
+            .map_or(0, |fetched| fetched.file.bytes.len())
+    }
+}
@@ -0,0 +1,145 @@
+use server::{
+    io::StorageBackend,
+    x402::{FacilitatorClient, chain_id, check_fourmica_client, rpc_client},
+};
+use std::{fmt::Display, sync::Arc};
+
+use crate::{
+    FACILITATOR_PROBE_TIMEOUT,
+    http::{
+        config::{Config, StorageKind},
+        mounts::{MountList, Mounts},
+    },
+};
+
+/// The `--check` report, printed a line per check as each one finishes.
+#[derive(Default)]
+struct Report {
+    failed: usize,
+}
+
+impl Report {
+    fn pass(&mut self, check: &str, detail: impl Display) {
+        println!("PASS {check:<12} {detail}");
+    }
+
+    fn fail(&mut self, check: &str, detail: impl Display) {
+        self.failed += 1;
+        println!("FAIL {check:<12} {detail}");
+    }
+
+    fn skip(&mut self, check: &str, detail: impl Display) {
+        println!("SKIP {check:<12} {detail}");
+    }
+
+    fn record(&mut self, check: &str, result: Result<String, String>) {
+        match result {
+            Ok(detail) => self.pass(check, detail),
+            Err(detail) => self.fail(check, detail),
+        }
+    }
+}
+
+/// Runs the `--check` pre-flight with the `facilitator` and `storage` the server would serve
+/// with: the configuration, then storage and `MOUNTS`, the facilitator's `/supported` when
+/// one is used, the chain id of each network's RPC endpoints when `exact` payments are
+/// enabled, and the 4mica SDK client when a wallet key is set. Returns whether every check
+/// passed; skipped checks don't fail it.
+pub async fn run(
+    config: &Config,
+    validated: Result<(), Vec<String>>,
+    facilitator: Option<&FacilitatorClient>,
+    storage: Result<Arc<dyn StorageBackend>, String>,
+) -> bool {
+    let mut report = Report::default();
+    match validated {
+        Ok(()) => report.pass("config", "settings are valid"),
+        Err(errors) => {
+            for error in errors {
+                report.fail("config", error);
+            }
+        }
+    }
+
+    report.record("storage", check_storage(config, storage.map(drop)));
+    if let Some(MountList(entries)) = &config.mounts {
+        let opened = Mounts::from_config(config).map(|_| format!("{} opened", entries.len()));
+        report.record("mounts", opened);
+    }
+
+    match facilitator {
+        Some(facilitator) => match facilitator.probe(FACILITATOR_PROBE_TIMEOUT).await {
+            Ok(latency) => report.pass(
+                "facilitator",
+                format!(
+                    "{} answered GET /supported in {} ms",
+                    facilitator.base_url(),
+                    latency.as_millis()
+                ),
+            ),
+            Err(e) => report.fail("facilitator", e),
+        },
+        None => report.skip("facilitator", "no enabled scheme settles through one"),
+    }
+
+    let networks = if config.x402.enable_exact {
+        config.x402.networks()
+    } else {
+        report.skip("rpc", "X402_ENABLE_EXACT is off");
+        Vec::new()
+    };
+    let rpc = rpc_client(&config.x402);
+    for network in networks {
+        let expected = network
+            .network_v2
+            .as_deref()
+            .and_then(|chain| chain.strip_prefix("eip155:"))
+            .and_then(|id| id.parse::<u64>().ok());
+        match (chain_id(&rpc, &config.x402, &network.name).await, expected) {
+            (Ok(id), Some(expected)) if id != expected => report.fail(
+                "rpc",
+                format!(
+                    "{}: RPC is on chain {id}, expected {expected}",
+                    network.name
+                ),
+            ),
+            (Ok(id), _) => report.pass("rpc", format!("{}: chain {id}", network.name)),
+            (Err(e), _) => report.fail("rpc", format!("{}: {e}", network.name)),
+        }
+    }
+
+    if std::env::var_os("4MICA_WALLET_PRIVATE_KEY").is_some() {
+        let built = check_fourmica_client(&config.x402).await;
+        report.record("4mica", built.map(|()| "SDK client built".to_string()));
+    } else {
+        report.skip("4mica", "4MICA_WALLET_PRIVATE_KEY is unset");
+    }
+
+    match report.failed {
+        0 => println!("All checks passed"),
+        failed => println!("{failed} check(s) failed"),
+    }
+    report.failed == 0
+}
+
+/// Local storage must be a readable, non-empty directory; S3 is only configured, not
+/// contacted.
+fn check_storage(config: &Config, opened: Result<(), String>) -> Result<String, String> {
+    opened?;
+    match config.storage_backend {
+        StorageKind::S3 => Ok(format!(
+            "S3 bucket {} configured",
+            config.s3.bucket.as_deref().unwrap_or_default()
+        )),
+        StorageKind::Local => {
+            let directory = &config.file_directory;
+            let mut entries = std::fs::read_dir(directory)
+                .map_err(|e| format!("FILE_DIRECTORY {directory} is not readable: {e}"))?;
+            match entries.next() {
+                Some(Ok(_)) => Ok(format!("FILE_DIRECTORY {directory} is readable")),
+                Some(Err(e)) => Err(format!("FILE_DIRECTORY {directory} is not readable: {e}")),
+                None => Err(format!("FILE_DIRECTORY {directory} is empty")),
+            }
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use parking_lot::Mutex;
+use server::x402::X402Config;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Free quota a client has used since its window opened.
+struct Usage {
+    since: Instant,
+    segments: u32,
+    bytes: u64,
+}
+
+/// Lets each client stream a few paid resources for free before the paywall applies.
+///
+/// Clients are identified by IP (see [`client_key`](super::client_ip::client_key)), never
+/// by the payer a payment header claims, so a new claim doesn't bring a new quota. A
+/// client's window opens on its first free request and its quota refreshes once the window
+/// elapses.
+#[derive(Clone)]
+pub struct PreviewQuota {
+    segment_count: Option<u32>,
+    byte_budget: Option<u64>,
+    window: Duration,
+    usage: Arc<Mutex<HashMap<String, Usage>>>,
+}
+
+impl PreviewQuota {
+    /// Returns `None` unless `X402_FREE_SEGMENT_COUNT` or `X402_FREE_BYTE_BUDGET` is set.
+    pub fn from_config(config: &X402Config) -> Option<Self> {
+        if config.free_segment_count.is_none() && config.free_byte_budget.is_none() {
+            return None;
+        }
+        Some(Self {
+            segment_count: config.free_segment_count,
+            byte_budget: config.free_byte_budget,
+            window: Duration::from_secs(config.free_preview_window_seconds),
+            usage: Arc::default(),
+        })
+    }
+
+    /// Charges one request of `bytes` (when known) against `client`'s quota, returning
+    /// `false` without charging anything if it doesn't fit.
+    ///
+    /// With a byte budget, responses of unknown size are never free.
+    pub fn try_consume(&self, client: &str, bytes: Option<u64>) -> bool {
+        let mut usage = self.usage.lock();
+        usage.retain(|_, used| used.since.elapsed() < self.window);
+        let used = usage.entry(client.to_string()).or_insert_with(|| Usage {
+            since: Instant::now(),
+            segments: 0,
+            bytes: 0,
+        });
+
+        if let Some(count) = self.segment_count
+            && used.segments >= count
+        {
+            return false;
+        }
+        let bytes = match (self.byte_budget, bytes) {
+            (Some(budget), Some(bytes)) if used.bytes.saturating_add(bytes) <= budget => bytes,
+            (Some(_), _) => return false,
+            (None, bytes) => bytes.unwrap_or(0),
+        };
+        used.segments += 1;
+        used.bytes = used.bytes.saturating_add(bytes);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quota(segment_count: Option<u32>, byte_budget: Option<u64>) -> PreviewQuota {
+        PreviewQuota {
+            segment_count,
+            byte_budget,
+            window: Duration::from_secs(3600),
+            usage: Arc::default(),
+        }
+    }
+
+    #[test]
+    fn segment_quota_is_exhausted() {
+        let quota = quota(Some(2), None);
+        assert!(quota.try_consume("10.0.0.1", Some(100)));
+        assert!(quota.try_consume("10.0.0.1", None));
+        assert!(!quota.try_consume("10.0.0.1", Some(100)));
+        // Another client has a quota of its own.
+        assert!(quota.try_consume("10.0.0.2", Some(100)));
+    }
+
+    #[test]
+    fn request_crossing_the_byte_budget_is_paid() {
+        let quota = quota(None, Some(1000));
+        assert!(quota.try_consume("10.0.0.1", Some(600)));
+        // Crosses from free to paid: it doesn't fit, so it is charged and nothing is used.
+        assert!(!quota.try_consume("10.0.0.1", Some(500)));
+        assert!(quota.try_consume("10.0.0.1", Some(400)));
+        assert!(!quota.try_consume("10.0.0.1", Some(1)));
+    }
+
+    #[test]
+    fn unknown_sizes_are_never_free_with_a_byte_budget() {
+        let quota = quota(None, Some(1000));
+        assert!(!quota.try_consume("10.0.0.1", None));
+        assert!(quota.try_consume("10.0.0.1", Some(1000)));
+    }
+
+    #[test]
+    fn quota_refreshes_once_the_window_elapses() {
+        let quota = PreviewQuota {
+            window: Duration::from_millis(20),
+            ..quota(Some(1), None)
+        };
+        assert!(quota.try_consume("10.0.0.1", Some(1)));
+        assert!(!quota.try_consume("10.0.0.1", Some(1)));
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(quota.try_consume("10.0.0.1", Some(1)));
+    }
+}
@@ -0,0 +1,468 @@
+use parking_lot::{Mutex, RwLock};
+use sdk_4mica::U256;
+use server::{io::FileInfo, x402::X402Config};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info};
+
+use crate::http::config::{Config, ConfigError};
+
+/// How often the config file's modification time is checked for a price change.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Media segment extensions priced by duration when `X402_PRICE_PER_SECOND` is set.
+const SEGMENT_EXTENSIONS: [&str; 2] = ["ts", "m4s"];
+
+/// Paid routes that can carry their own price.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PricedRoute {
+    Stream,
+    Remote,
+    Ipfs,
+    /// Always charged its `BUNDLES` price.
+    Bundle,
+}
+
+/// Resolves what a request costs: a per-route override if configured, else the
+/// global `X402_PRICE`.
+///
+/// With `X402_PRICE_PER_SECOND`, local media segments instead cost their `#EXTINF`
+/// duration times that rate, falling back to the flat price for segments no sibling
+/// playlist describes. Playlists charged under `X402_CHARGE_PLAYLISTS` cost
+/// `X402_PLAYLIST_PRICE` when it is set.
+#[derive(Clone, Debug)]
+pub struct PriceResolver {
+    default: U256,
+    stream: Option<U256>,
+    remote: Option<U256>,
+    ipfs: Option<U256>,
+    per_second: Option<U256>,
+    playlist: Option<U256>,
+    durations: Arc<SegmentDurations>,
+}
+
+impl PriceResolver {
+    /// The prices of `config`; `decimals` of the asset scale `X402_PRICE_HUMAN`.
+    pub fn from_config(config: &X402Config, decimals: Option<u8>) -> Result<Self, String> {
+        Ok(Self {
+            default: config.default_price(decimals)?,
+            stream: config.price_stream,
+            remote: config.price_remote,
+            ipfs: config.price_ipfs,
+            per_second: config.price_per_second,
+            playlist: config.playlist_price,
+            durations: Arc::default(),
+        })
+    }
+
+    /// The prices of `config`, keeping the segment durations already parsed.
+    fn reconfigured(&self, config: &X402Config, decimals: Option<u8>) -> Result<Self, String> {
+        Ok(Self {
+            durations: self.durations.clone(),
+            ..Self::from_config(config, decimals)?
+        })
+    }
+
+    /// Price of `route`; `file` is the verified local file for `/stream/{filename}` and
+    /// `playlist` whether the resource is a `.m3u8` playlist or `.mpd` manifest.
+    pub fn price(&self, route: PricedRoute, file: Option<&FileInfo>, playlist: bool) -> U256 {
+        if playlist && let Some(price) = self.playlist {
+            return price;
+        }
+        if route == PricedRoute::Stream
+            && let (Some(per_second), Some(file)) = (self.per_second, file)
+            && is_segment(&file.path)
+            && let Some(millis) = self.durations.duration_millis(&file.path)
+        {
+            // Rounded up so a segment is never cheaper than the time it plays.
+            let price = per_second
+                .saturating_mul(U256::from(millis))
+                .saturating_add(U256::from(999))
+                / U256::from(1000);
+            debug!(segment = %file.path.display(), millis, %price, "Priced segment by duration");
+            return price;
+        }
+        let route_price = match route {
+            PricedRoute::Stream => self.stream,
+            PricedRoute::Remote => self.remote,
+            PricedRoute::Ipfs => self.ipfs,
+            PricedRoute::Bundle => None,
+        };
+        route_price.unwrap_or(self.default)
+    }
+
+    /// Duration of a local media segment according to its sibling playlists.
+    pub fn segment_millis(&self, file: &FileInfo) -> Option<u64> {
+        is_segment(&file.path)
+            .then(|| self.durations.duration_millis(&file.path))
+            .flatten()
+    }
+}
+
+/// The [`PriceResolver`] in effect, swapped whole when the prices are reloaded.
+///
+/// Prices are reloaded from the configuration file (`--config` / `CONFIG_FILE`) when its
+/// modification time changes, and on SIGHUP. The environment still overrides the file, and
+/// settings other than prices only take effect on restart. A file that fails to load
+/// leaves the previous prices in place.
+pub struct PriceTable {
+    current: RwLock<Arc<PriceResolver>>,
+    file: Option<PathBuf>,
+    modified: Mutex<Option<SystemTime>>,
+    /// Asset decimals resolved at startup, reused unless a reload sets them.
+    decimals: Option<u8>,
+}
+
+impl PriceTable {
+    pub fn new(resolver: PriceResolver, file: Option<PathBuf>, decimals: Option<u8>) -> Self {
+        let modified = file.as_deref().and_then(modified_time);
+        Self {
+            current: RwLock::new(Arc::new(resolver)),
+            file,
+            modified: Mutex::new(modified),
+            decimals,
+        }
+    }
+
+    /// The prices to use for one request; later reloads don't change a snapshot.
+    pub fn current(&self) -> Arc<PriceResolver> {
+        self.current.read().clone()
+    }
+
+    /// Re-reads the configuration and swaps in its prices.
+    pub fn reload(&self) -> Result<(), ConfigError> {
+        *self.modified.lock() = self.file.as_deref().and_then(modified_time);
+        let config = Config::load(self.file.as_deref())?;
+        let decimals = config.x402.asset_decimals.or(self.decimals);
+        let mut current = self.current.write();
+        let resolver = current
+            .reconfigured(&config.x402, decimals)
+            .map_err(ConfigError::Price)?;
+        *current = Arc::new(resolver);
+        Ok(())
+    }
+
+    /// Reloads until `shutdown` whenever the file changes or SIGHUP arrives.
+    pub async fn watch(self: Arc<Self>, shutdown: CancellationToken) {
+        #[cfg(unix)]
+        let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        {
+            Ok(signal) => Some(signal),
+            Err(e) => {
+                error!("Failed to listen for SIGHUP: {}", e);
+                None
+            }
+        };
+        let mut check = tokio::time::interval(RELOAD_CHECK_INTERVAL);
+        loop {
+            #[cfg(unix)]
+            let hangup = async {
+                match &mut hangup {
+                    Some(signal) => signal.recv().await,
+                    None => std::future::pending().await,
+                }
+            };
+            #[cfg(not(unix))]
+            let hangup = std::future::pending::<Option<()>>();
+
+            let trigger = tokio::select! {
+                _ = shutdown.cancelled() => return,
+                _ = hangup => "SIGHUP",
+                _ = check.tick() => {
+                    let modified = self.file.as_deref().and_then(modified_time);
+                    if self.file.is_none() || modified == *self.modified.lock() {
+                        continue;
+                    }
+                    "config file change"
+                }
+            };
+            match self.reload() {
+                Ok(()) => info!(trigger, "Reloaded prices"),
+                Err(e) => {
+                    error!(
+                        trigger,
+                        "Failed to reload prices, keeping the previous ones: {}", e
+                    )
+                }
+            }
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+/// Extensions of HLS playlists and DASH manifests.
+const PLAYLIST_EXTENSIONS: [&str; 2] = ["m3u8", "mpd"];
+
+/// Whether `path` names an HLS playlist or a DASH manifest.
+pub fn is_playlist(path: &str) -> bool {
+    path.rsplit_once('.').is_some_and(|(_, ext)| {
+        PLAYLIST_EXTENSIONS
+            .iter()
+            .any(|playlist| ext.eq_ignore_ascii_case(playlist))
+    })
+}
+
+fn is_segment(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| SEGMENT_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+/// Segment durations parsed from the `.m3u8` playlists of each directory, reparsed when
+/// a playlist is added, removed, or modified.
+#[derive(Debug, Default)]
+struct SegmentDurations {
+    directories: Mutex<HashMap<PathBuf, DirectoryDurations>>,
+}
+
+#[derive(Debug)]
+struct DirectoryDurations {
+    /// Playlists the durations were parsed from, with their modification times.
+    playlists: Vec<(PathBuf, Option<SystemTime>)>,
+    /// Duration in milliseconds by segment path.
+    segments: HashMap<PathBuf, u64>,
+}
+
+impl SegmentDurations {
+    fn duration_millis(&self, segment: &Path) -> Option<u64> {
+        let directory = segment.parent()?;
+        let playlists = playlist_stamps(directory);
+        if let Some(cached) = self.directories.lock().get(directory)
+            && cached.playlists == playlists
+        {
+            return cached.segments.get(segment).copied();
+        }
+
+        // Parsed without holding the lock; concurrent misses may both parse, and
+        // either result is current.
+        let segments: HashMap<PathBuf, u64> = playlists
+            .iter()
+            .filter_map(|(path, _)| std::fs::read_to_string(path).ok())
+            .flat_map(|playlist| parse_media_playlist(&playlist, directory))
+            .collect();
+        let millis = segments.get(segment).copied();
+        self.directories.lock().insert(
+            directory.to_path_buf(),
+            DirectoryDurations {
+                playlists,
+                segments,
+            },
+        );
+        millis
+    }
+}
+
+/// The `.m3u8` files in `directory` and their modification times, in a stable order.
+fn playlist_stamps(directory: &Path) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let Ok(entries) = std::fs::read_dir(directory) else {
+        return Vec::new();
+    };
+    let mut playlists: Vec<_> = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "m3u8"))
+        .map(|path| {
+            let modified = std::fs::metadata(&path)
+                .and_then(|meta| meta.modified())
+                .ok();
+            (path, modified)
+        })
+        .collect();
+    playlists.sort();
+    playlists
+}
+
+/// `(segment path, duration in milliseconds)` for every `#EXTINF`-tagged segment of a
+/// media playlist whose URI is relative to `directory`. Consecutive byte ranges of one
+/// file (`#EXT-X-BYTERANGE`) add up to a single entry, since the file is sold whole.
+fn parse_media_playlist(playlist: &str, directory: &Path) -> Vec<(PathBuf, u64)> {
+    let mut segments = Vec::new();
+    let mut duration = None;
+    for line in playlist.lines().map(str::trim) {
+        if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            duration = parse_extinf_millis(extinf);
+            continue;
+        }
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some(millis) = duration.take() else {
+            continue;
+        };
+        let uri = line.split(['?', '#']).next().unwrap_or_default();
+        if uri.contains("://") || uri.starts_with('/') {
+            continue;
+        }
+        let path = directory.join(uri);
+        match segments.last_mut() {
+            Some((last, total)) if *last == path => *total += millis,
+            _ => segments.push((path, millis)),
+        }
+    }
+    segments
+}
+
+/// The duration of `#EXTINF:<duration>,[<title>]`; zero and malformed durations are ignored.
+fn parse_extinf_millis(extinf: &str) -> Option<u64> {
+    let seconds: f64 = extinf.split(',').next()?.trim().parse().ok()?;
+    if !seconds.is_finite() || seconds <= 0.0 {
+        return None;
+    }
+    let millis = (seconds * 1000.0).round() as u64;
+    (millis > 0).then_some(millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use envconfig::Envconfig;
+
+    /// A media playlist as packagers write them: fractional durations, tags and comments
+    /// between segments, query strings, byte ranges, and segments served from elsewhere.
+    const PLAYLIST: &str = "#EXTM3U
+#EXT-X-VERSION:4
+#EXT-X-TARGETDURATION:7
+#EXT-X-MEDIA-SEQUENCE:0
+#EXT-X-PLAYLIST-TYPE:VOD
+# Packaged for the pricing tests
+#EXTINF:6.006,
+seg_00000.ts
+#EXTINF:5.5,title with, a comma
+#EXT-X-PROGRAM-DATE-TIME:2024-01-01T00:00:06.006Z
+seg_00001.ts?token=abc
+#EXT-X-DISCONTINUITY
+#EXTINF:4.0006,
+
+seg_00002.ts
+#EXTINF:2.002,
+#EXT-X-BYTERANGE:1000@0
+media.ts
+#EXTINF:2.002,
+#EXT-X-BYTERANGE:800
+media.ts
+#EXTINF:0,
+seg_zero.ts
+#EXTINF:bogus,
+seg_bogus.ts
+#EXTINF:6.0,
+https://cdn.test/seg_remote.ts
+#EXTINF:6.0,
+/absolute/seg.ts
+#EXT-X-ENDLIST
+";
+
+    #[test]
+    fn media_playlist_segments_and_durations() {
+        let directory = Path::new("/media/show");
+        assert_eq!(
+            parse_media_playlist(PLAYLIST, directory),
+            [
+                (directory.join("seg_00000.ts"), 6006),
+                (directory.join("seg_00001.ts"), 5500),
+                (directory.join("seg_00002.ts"), 4001),
+                (directory.join("media.ts"), 4004),
+            ]
+        );
+    }
+
+    #[test]
+    fn extinf_durations_round_to_milliseconds() {
+        assert_eq!(parse_extinf_millis("8.333333,"), Some(8333));
+        assert_eq!(parse_extinf_millis(" 10 ,Title"), Some(10_000));
+        assert_eq!(parse_extinf_millis("0.0004,"), None);
+        assert_eq!(parse_extinf_millis("-1,"), None);
+        assert_eq!(parse_extinf_millis("inf,"), None);
+        assert_eq!(parse_extinf_millis("NaN,"), None);
+        assert_eq!(parse_extinf_millis(""), None);
+    }
+
+    #[test]
+    fn segments_cost_their_duration() {
+        let directory = std::env::temp_dir().join(format!("pricing-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("index.m3u8"), PLAYLIST).unwrap();
+
+        let values = [
+            ("X402_PAY_TO", "0xab"),
+            ("X402_PRICE", "7"),
+            ("X402_PRICE_PER_SECOND", "1000"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+        let config = X402Config::init_from_hashmap(&values).unwrap();
+        let resolver = PriceResolver::from_config(&config, None).unwrap();
+        let price = |name: &str| {
+            let file = FileInfo {
+                path: directory.join(name),
+                len: 0,
+                modified: None,
+                mime: None,
+            };
+            resolver.price(PricedRoute::Stream, Some(&file), false)
+        };
+
+        // 1000 per second is one unit per millisecond played.
+        assert_eq!(price("seg_00000.ts"), U256::from(6006));
+        assert_eq!(price("seg_00001.ts"), U256::from(5500));
+        assert_eq!(price("seg_00002.ts"), U256::from(4001));
+        assert_eq!(price("media.ts"), U256::from(4004));
+        // Not described by the playlist, or not a segment: the flat price.
+        assert_eq!(price("seg_zero.ts"), U256::from(7));
+        assert_eq!(price("seg_09999.ts"), U256::from(7));
+        assert_eq!(price("poster.jpg"), U256::from(7));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn watch_reloads_a_rewritten_price_file() {
+        let directory =
+            std::env::temp_dir().join(format!("pricing-watch-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let file = directory.join("config.toml");
+        let write_price = |price: u64, modified: SystemTime| {
+            let toml = format!("[x402]\npay_to = \"0xab\"\nprice = {price}\n");
+            std::fs::write(&file, toml).unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(&file)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        };
+        let start = SystemTime::now() - Duration::from_secs(60);
+        write_price(100, start);
+
+        let config = Config::load(Some(&file)).unwrap();
+        let resolver = PriceResolver::from_config(&config.x402, None).unwrap();
+        let table = Arc::new(PriceTable::new(resolver, Some(file.clone()), None));
+        let quote = |table: &PriceTable| table.current().price(PricedRoute::Stream, None, false);
+        assert_eq!(quote(&table), U256::from(100));
+
+        let shutdown = CancellationToken::new();
+        let watcher = tokio::spawn(table.clone().watch(shutdown.clone()));
+        write_price(250, start + Duration::from_secs(1));
+        let deadline = tokio::time::Instant::now() + 3 * RELOAD_CHECK_INTERVAL;
+        while quote(&table) != U256::from(250) {
+            assert!(
+                tokio::time::Instant::now() < deadline,
+                "price was not reloaded"
+            );
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        shutdown.cancel();
+        watcher.await.unwrap();
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
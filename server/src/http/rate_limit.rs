@@ -0,0 +1,123 @@
+use axum::{
+    extract::{Request, State},
+    http::{HeaderValue, StatusCode, header::RETRY_AFTER},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use parking_lot::Mutex;
+use serde_json::json;
+use std::{
+    collections::{HashMap, hash_map::DefaultHasher},
+    hash::{Hash, Hasher},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+use crate::http::{client_ip::client_key, model::ApiError, router::AppState};
+
+const SHARDS: usize = 16;
+
+/// How often a shard drops buckets that have refilled completely.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Shard {
+    buckets: HashMap<String, Bucket>,
+    pruned: Instant,
+}
+
+/// Per-client token buckets: each client gets `burst` requests up front, refilled at
+/// `rps` per second. Buckets are spread over a fixed set of locks so concurrent
+/// clients rarely contend.
+#[derive(Clone)]
+pub struct RateLimiter {
+    rps: f64,
+    burst: f64,
+    shards: Arc<[Mutex<Shard>]>,
+}
+
+impl RateLimiter {
+    /// Returns `None` unless `rps` is a positive rate.
+    pub fn new(rps: Option<f64>, burst: u32) -> Option<Self> {
+        let rps = rps.filter(|rps| *rps > 0.0)?;
+        let shards = (0..SHARDS)
+            .map(|_| {
+                Mutex::new(Shard {
+                    buckets: HashMap::new(),
+                    pruned: Instant::now(),
+                })
+            })
+            .collect();
+        Some(Self {
+            rps,
+            burst: f64::from(burst.max(1)),
+            shards,
+        })
+    }
+
+    /// Takes a token from `client`'s bucket, or returns how long until one is available.
+    pub fn check(&self, client: &str) -> Result<(), Duration> {
+        let mut hasher = DefaultHasher::new();
+        client.hash(&mut hasher);
+        let mut shard = self.shards[hasher.finish() as usize % SHARDS].lock();
+
+        let now = Instant::now();
+        // A bucket idle long enough to refill is indistinguishable from a new one.
+        let refill = Duration::from_secs_f64(self.burst / self.rps);
+        if now.duration_since(shard.pruned) >= PRUNE_INTERVAL {
+            shard
+                .buckets
+                .retain(|_, bucket| now.duration_since(bucket.updated) < refill);
+            shard.pruned = now;
+        }
+
+        let bucket = shard.buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rps).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rps))
+        }
+    }
+}
+
+/// Answers 429 with `Retry-After` once a client exceeds `RATE_LIMIT_RPS`. Add it as
+/// the outermost layer of paid routes so limited requests never reach the
+/// facilitator or RPC; unpaid routes such as health checks are not limited.
+pub async fn rate_limit(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let Some(limiter) = &state.rate_limiter else {
+        return next.run(request).await;
+    };
+    let client = client_key(&request);
+    if let Err(wait) = limiter.check(&client) {
+        warn!(client = %client, "Rate limit exceeded");
+        return too_many_requests(wait);
+    }
+    next.run(request).await
+}
+
+/// 429 telling the client to retry after `wait`, rounded up to whole seconds.
+pub fn too_many_requests(wait: Duration) -> Response {
+    let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+    let mut resp = ApiError::new(
+        StatusCode::TOO_MANY_REQUESTS,
+        "rate_limited",
+        "Too many requests",
+    )
+    .with_details(json!({ "retryAfterSeconds": retry_after }))
+    .into_response();
+    resp.headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+    resp
+}
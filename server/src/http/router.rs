@@ -1,194 +1,765 @@
-use crate::http::{model::TabRequestParams, x402};
+use crate::http::{
+    model::{ApiError, ApiErrorBody, PaymentRequiredResponse, PriceQuote, TabRequestParams},
+    x402::{self, PAYMENT_RESPONSE_HEADER, PaidRequest, Paywall},
+};
 use axum::{
-    Json, Router,
-    extract::{Path, Query, State},
-    http::{HeaderMap, HeaderName, HeaderValue, StatusCode, Uri},
+    Extension, Json, Router,
+    body::Body,
+    extract::{MatchedPath, Path, Query, State, rejection::JsonRejection},
+    http::{
+        HeaderMap, HeaderName, HeaderValue, Request, StatusCode, Uri, Version,
+        header::{CONTENT_RANGE, CONTENT_TYPE},
+    },
+    middleware::{self, Next},
     response::{IntoResponse, Response},
     routing::{get, post},
 };
-use log::error;
+use chrono::{DateTime, Utc};
 use reqwest::Client;
-use sdk_4mica::U256;
 use serde::Deserialize;
 use serde_json::Value;
-use server::x402::FacilitatorClient;
+use server::{
+    io::{FileInfo, RemoteFetcher, StorageBackend},
+    x402::{
+        AsyncSettler, DeferredSettler, Facilitator, FacilitatorTabResponse, PendingSettlements,
+        SettlementCache, SpendLedger, TabAudit, TabStatus, TabStatusError, fetch_tab_status,
+        parse_u256_value,
+    },
+};
 use std::sync::Arc;
-use tower_http::cors::CorsLayer;
+use tokio_util::sync::CancellationToken;
+use tower_http::{
+    compression::{
+        CompressionLayer,
+        predicate::{Predicate, SizeAbove},
+    },
+    cors::CorsLayer,
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, RequestId, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+use tracing::{error, field, info_span, warn};
+use utoipa::IntoParams;
+
+use super::{
+    access_log::{AccessLog, access_log},
+    admin,
+    audit::AuditLog,
+    bundle, cache_policy,
+    client_ip::resolve_client_ip,
+    config::Config,
+    delivery::DeliveryStats,
+    free_paths::FreePaths,
+    health, ipfs, metrics,
+    mounts::{Mount, Mounts},
+    openapi,
+    playlist::{self, GeneratedPlaylists},
+    playlist_cache::RemotePlaylistCache,
+    preview::PreviewQuota,
+    pricing::{PriceTable, PricedRoute},
+    rate_limit::{RateLimiter, rate_limit, too_many_requests},
+    session::{SESSION_HEADER, SessionSigner},
+    settlement_limit::SettlementLimiter,
+    settlement_store::SettlementStore,
+    shutdown::{InFlight, track_in_flight},
+    tab_spend::TabSpendCap,
+    upload, verify,
+    webhook::WebhookNotifier,
+};
 
-use super::config::Config;
+/// Whether `POST /tab` answered from the tab cache: `hit` or `miss`.
+const X_CACHE: &str = "x-cache";
 
 #[derive(Clone)]
 pub struct AppState {
     pub config: Arc<Config>,
-    pub facilitator: Arc<FacilitatorClient>,
+    /// Absent when neither 4mica nor facilitator `exact` payments are enabled.
+    pub facilitator: Option<Arc<dyn Facilitator>>,
+    /// Cancelled when the server starts shutting down; background tasks should stop on it.
+    #[allow(dead_code)] // Not every build spawns background tasks.
+    pub shutdown: CancellationToken,
+    pub in_flight: InFlight,
+    /// Whether the facilitator answered the startup probe; true when there is none.
+    pub facilitator_reachable: bool,
+    pub pricing: Arc<PriceTable>,
+    /// Resources `X402_FREE_PATHS` serves without payment.
+    pub free_paths: FreePaths,
+    /// Free preview quota, when `X402_FREE_SEGMENT_COUNT` or `X402_FREE_BYTE_BUDGET` is set.
+    pub previews: Option<PreviewQuota>,
+    /// Per-client limit on paid routes, when `RATE_LIMIT_RPS` is set.
+    pub rate_limiter: Option<RateLimiter>,
+    /// Per-user limit on `POST /tab`, when `TAB_RATE_LIMIT_PER_MINUTE` is set.
+    pub tab_rate_limiter: Option<RateLimiter>,
+    /// Audit of the tabs `POST /tab` opened, when `TAB_AUDIT_INTERVAL_SECONDS` is set.
+    pub tab_audit: Option<Arc<TabAudit>>,
+    /// Per-tab spending limit, when `X402_TAB_SPEND_CAP` is set.
+    pub tab_spend: Option<TabSpendCap>,
+    /// Bound on concurrent settlements, unless `X402_MAX_CONCURRENT_SETTLEMENTS=0`.
+    pub settlement_limiter: Option<SettlementLimiter>,
+    pub storage: Arc<dyn StorageBackend>,
+    /// `MOUNTS` directories, read instead of `storage` for paths under their prefixes.
+    pub mounts: Mounts,
+    /// Remote `.m3u8` responses, unless `REMOTE_PLAYLIST_CACHE_SECONDS=0`.
+    pub remote_playlists: Option<RemotePlaylistCache>,
+    /// Fetches `/stream/remote` files within the `REMOTE_*` timeouts and size cap.
+    pub remote: Arc<RemoteFetcher>,
+    /// Calls the `X402_RPC_URL` endpoints within `X402_RPC_TIMEOUT_SECONDS`: on-chain payment
+    /// checks and `POST /rpc`.
+    pub rpc: Client,
+    /// Playlists generated by `/playlist/{asset}`.
+    pub playlists: Arc<GeneratedPlaylists>,
+    pub sessions: Option<SessionSigner>,
+    pub settlements: Arc<SettlementCache>,
+    pub ledger: Arc<SpendLedger>,
+    /// Bytes each streamed response actually delivered.
+    pub deliveries: Arc<DeliveryStats>,
+    /// Background settler for 4mica payments when `X402_SETTLEMENT_MODE=deferred`.
+    pub deferred: Option<Arc<DeferredSettler>>,
+    /// Background settlement queue when `X402_ASYNC_SETTLE` is set.
+    pub settle_queue: Option<Arc<AsyncSettler>>,
+    /// Journal of payments served before settling, when `X402_PENDING_SETTLEMENTS_PATH` is set.
+    pub pending_settlements: Option<Arc<PendingSettlements>>,
+    /// SQLite record of every settlement attempt, when `DATABASE_PATH` is set.
+    pub settlement_store: Option<SettlementStore>,
+    pub webhook: Option<WebhookNotifier>,
+    /// Paywall decision log, when `AUDIT_LOG_PATH` is set.
+    pub audit_log: Option<AuditLog>,
+    /// Per-request log, when `ACCESS_LOG` is set.
+    pub access_log: Option<AccessLog>,
 }
 
-#[derive(Debug, Deserialize)]
-struct RemoteStreamQuery {
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(super) struct RemoteStreamQuery {
+    /// Absolute URL of the file to fetch and stream.
     url: String,
 }
 
 pub fn build_router(state: AppState) -> Router {
-    Router::new()
+    let compression_enabled = state.config.compression_enabled;
+    let access_log_layer = middleware::from_fn_with_state(state.clone(), access_log);
+    let router = Router::new()
+        .route("/healthz", get(health::handle_health))
+        .route("/metrics", get(metrics::handle_metrics))
         .route("/tab", post(handle_tab))
+        .route("/tab/{tab_id}", get(handle_tab_status))
         .route("/rpc", post(handle_rpc_proxy))
-        .route("/stream/remote", get(handle_remote_stream))
-        .route("/stream/{filename}", get(handle_stream))
-        .with_state(state)
-        .layer(
-            CorsLayer::permissive().expose_headers([
-                HeaderName::from_static("payment-required"),
-                HeaderName::from_static("payment-response"),
-                HeaderName::from_static("x-payment"),
-            ]),
+        .route(
+            "/stream/remote",
+            get(handle_remote_stream)
+                .head(handle_remote_stream_head)
+                .route_layer(middleware::from_fn_with_state(
+                    Paywall::new(state.clone(), PricedRoute::Remote),
+                    x402::require_payment,
+                ))
+                .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit)),
         )
+        // A wildcard so assets in subdirectories, as `/playlist/{asset}` lists them, resolve.
+        .route(
+            "/stream/{*filename}",
+            get(handle_stream)
+                .head(handle_stream_head)
+                .route_layer(middleware::from_fn_with_state(
+                    Paywall::new(state.clone(), PricedRoute::Stream),
+                    x402::require_payment,
+                ))
+                // Runs before the paywall so nobody is charged for a missing file.
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    verify_stream_file,
+                ))
+                .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit)),
+        )
+        .route("/price/remote", get(handle_remote_price))
+        .route("/price/{*filename}", get(handle_price))
+        .nest("/admin", admin::router(state.clone()))
+        .merge(bundle::router(&state))
+        .merge(ipfs::router(&state))
+        .merge(playlist::router(&state.config))
+        .merge(upload::router(&state.config))
+        .merge(verify::router(&state))
+        .merge(openapi::router(&state.config))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            track_in_flight,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            resolve_client_ip,
+        ))
+        .with_state(state);
+    let router = if compression_enabled {
+        router.layer(compression_layer())
+    } else {
+        router
+    };
+    router
+        // Outside compression, so it counts the bytes actually sent.
+        .layer(access_log_layer)
+        .layer(TraceLayer::new_for_http().make_span_with(make_request_span))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        // Keeps a client's `x-request-id`, else assigns a UUID, before the span is opened.
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        .layer(CorsLayer::permissive().expose_headers([
+            HeaderName::from_static("payment-required"),
+            HeaderName::from_static(PAYMENT_RESPONSE_HEADER),
+            HeaderName::from_static("x-payment"),
+            HeaderName::from_static(SESSION_HEADER),
+            HeaderName::from_static("x-request-id"),
+            HeaderName::from_static(X_CACHE),
+        ]))
+}
+
+/// Compresses playlists, JSON and text for clients that accept it; segments are already
+/// compressed video, and range responses keep their byte offsets, so neither is touched.
+///
+/// The layer drops `Content-Length` from what it encodes and adds `Vary: accept-encoding`.
+/// Our ETags are weak, so they still hold for the encoded body.
+fn compression_layer() -> CompressionLayer<impl Predicate> {
+    CompressionLayer::new().compress_when(SizeAbove::default().and(is_compressible))
+}
+
+/// Content types worth compressing, besides any `text/*`.
+const COMPRESSIBLE_TYPES: [&str; 4] = [
+    "application/vnd.apple.mpegurl",
+    "application/x-mpegurl",
+    "application/dash+xml",
+    "application/json",
+];
+
+fn is_compressible(
+    _: StatusCode,
+    _: Version,
+    headers: &HeaderMap,
+    _: &axum::http::Extensions,
+) -> bool {
+    if headers.contains_key(CONTENT_RANGE) {
+        return false;
+    }
+    let Some(content_type) = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    mime.starts_with("text/") || COMPRESSIBLE_TYPES.contains(&mime.as_str())
+}
+
+/// Every request runs inside this span, so each log line of a payment carries its
+/// `request_id`; `client_ip` is filled in by [`resolve_client_ip`], and the paywall fills in
+/// `resource` once it is known.
+fn make_request_span(request: &Request<Body>) -> tracing::Span {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(MatchedPath::as_str)
+        .unwrap_or_default();
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .unwrap_or_default();
+    info_span!(
+        "http_request",
+        method = %request.method(),
+        route,
+        uri = %request.uri(),
+        request_id,
+        client_ip = field::Empty,
+        resource = field::Empty,
+    )
 }
 
-async fn handle_tab(State(state): State<AppState>, Json(body): Json<TabRequestParams>) -> Response {
+/// Opens a 4mica tab for the payer, as advertised in the `tabEndpoint` of 4mica requirements.
+#[utoipa::path(
+    post,
+    path = "/tab",
+    tag = "payments",
+    request_body = TabRequestParams,
+    responses(
+        (status = 200, description = "The tab to pay into", body = FacilitatorTabResponse),
+        (status = 400, description = "`invalid_tab_request`: the body is not valid JSON, or `details.field` names a user address that is malformed or requirements that don't pay this server", body = ApiErrorBody),
+        (status = 404, description = "`tabs_disabled`: `X402_ENABLE_4MICA` is off", body = ApiErrorBody),
+        (status = 422, description = "`invalid_tab_request`: the body is missing fields", body = ApiErrorBody),
+        (status = 429, description = "`rate_limited`: the user asked for too many new tabs; retry after `Retry-After` seconds", body = ApiErrorBody),
+        (status = 500, description = "`tab_request_failed`: the facilitator could not open a tab", body = ApiErrorBody),
+    )
+)]
+pub(super) async fn handle_tab(
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+    body: Result<Json<TabRequestParams>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let facilitator = tab_facilitator(&state)?;
+    let Json(body) = body.map_err(|rejection| {
+        ApiError::new(
+            rejection.status(),
+            "invalid_tab_request",
+            rejection.body_text(),
+        )
+    })?;
+    body.validate(&state.config.x402.networks(), &state.mounts.pay_tos())?;
+    let requirements = body.payment_requirements.into_payment_requirements();
+    // Repeats of a recent request are cheap, so only new tabs count against the limit.
+    if let Some(tab) =
+        server::x402::cached_tab(&body.user_address, &requirements, facilitator.as_ref())
+    {
+        return Ok(([(X_CACHE, HeaderValue::from_static("hit"))], Json(tab)).into_response());
+    }
+    if let Some(limiter) = &state.tab_rate_limiter
+        && let Err(wait) = limiter.check(&body.user_address.to_lowercase())
+    {
+        warn!(user = %body.user_address, "Tab rate limit exceeded");
+        return Ok(too_many_requests(wait));
+    }
     let tab = server::x402::request_tab(
         body.user_address,
-        body.payment_requirements.into_payment_requirements(),
-        &state.facilitator,
+        requirements,
+        facilitator
+            .with_request_id(request_id.into_header_value())
+            .as_ref(),
+    )
+    .await
+    .map_err(|e| ApiError::internal("tab_request_failed", "Failed to request tab", e))?;
+    if let Some(audit) = &state.tab_audit {
+        audit.track(&tab);
+    }
+    Ok(([(X_CACHE, HeaderValue::from_static("miss"))], Json(tab)).into_response())
+}
+
+/// The facilitator 4mica tabs are opened through, or a 404 when this server takes no 4mica
+/// payments.
+fn tab_facilitator(state: &AppState) -> Result<&Arc<dyn Facilitator>, ApiError> {
+    state
+        .facilitator
+        .as_ref()
+        .filter(|_| state.config.x402.enable_4mica)
+        .ok_or_else(tabs_disabled)
+}
+
+fn tabs_disabled() -> ApiError {
+    ApiError::new(
+        StatusCode::NOT_FOUND,
+        "tabs_disabled",
+        "4mica payments are disabled",
+    )
+}
+
+/// The path and query of the stream route a `/price/...` request quotes.
+fn quoted_target(uri: &Uri) -> String {
+    let target = uri.path_and_query().map_or("", |target| target.as_str());
+    format!("/stream{}", target.strip_prefix("/price").unwrap_or(target))
+}
+
+/// Quotes `/stream/{filename}` without paying: the price and the requirements its 402
+/// would list.
+#[utoipa::path(
+    get,
+    path = "/price/{filename}",
+    tag = "payments",
+    params(("filename" = String, Path, description = "Path of the file under the storage root, or under a `MOUNTS` directory after its prefix")),
+    responses(
+        (status = 200, description = "What streaming the file costs; free files quote `0x0`", body = PriceQuote),
+        (status = 400, description = "`not_a_file`: the path names a directory", body = ApiErrorBody),
+        (status = 403, description = "`access_denied`: the path leaves the storage root", body = ApiErrorBody),
+        (status = 404, description = "`file_not_found`: no such file", body = ApiErrorBody),
     )
-    .await;
-    match tab {
-        Ok(tab) => (StatusCode::OK, Json(tab)).into_response(),
+)]
+pub(super) async fn handle_price(
+    State(state): State<AppState>,
+    Path(filename): Path<String>,
+    uri: Uri,
+) -> Result<Json<PriceQuote>, ApiError> {
+    let (file, mount) = match state.mounts.resolve(&filename) {
+        Some((mount, path)) => (mount.storage.verify(path).await?, Some(mount)),
+        None => (state.storage.verify(&filename).await?, None),
+    };
+    let target = quoted_target(&uri);
+    x402::quote(
+        &state,
+        PricedRoute::Stream,
+        &target,
+        Some(&file),
+        mount.map(AsRef::as_ref),
+    )
+    .map(Json)
+}
+
+/// Quotes `/stream/remote` for `url` without paying.
+#[utoipa::path(
+    get,
+    path = "/price/remote",
+    tag = "payments",
+    params(RemoteStreamQuery),
+    responses(
+        (status = 200, description = "What streaming the remote file costs; free resources quote `0x0`", body = PriceQuote),
+    )
+)]
+pub(super) async fn handle_remote_price(
+    State(state): State<AppState>,
+    // Only extracted to reject a missing `url`; the quote reads the raw query.
+    _query: Query<RemoteStreamQuery>,
+    uri: Uri,
+) -> Result<Json<PriceQuote>, ApiError> {
+    x402::quote(
+        &state,
+        PricedRoute::Remote,
+        &quoted_target(&uri),
+        None,
+        None,
+    )
+    .map(Json)
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(super) struct TabStatusQuery {
+    /// The tab's user; other addresses get 404 as if the tab didn't exist.
+    #[serde(alias = "userAddress")]
+    user_address: String,
+}
+
+/// Reports a tab's state and usage to its user.
+#[utoipa::path(
+    get,
+    path = "/tab/{tab_id}",
+    tag = "payments",
+    params(("tab_id" = String, Path, description = "Tab id, hex or decimal"), TabStatusQuery),
+    responses(
+        (status = 200, description = "The tab", body = TabStatus),
+        (status = 400, description = "`invalid_tab_id`", body = ApiErrorBody),
+        (status = 404, description = "`tab_not_found`: no such tab for this user; `tabs_disabled`: `X402_ENABLE_4MICA` is off", body = ApiErrorBody),
+        (status = 502, description = "`tab_lookup_failed`: the 4mica API request failed", body = ApiErrorBody),
+        (status = 503, description = "`sdk_unavailable`: no 4mica wallet key is configured", body = ApiErrorBody),
+    )
+)]
+pub(super) async fn handle_tab_status(
+    State(state): State<AppState>,
+    Path(tab_id): Path<String>,
+    Query(query): Query<TabStatusQuery>,
+) -> Result<Json<TabStatus>, ApiError> {
+    if !state.config.x402.enable_4mica {
+        return Err(tabs_disabled());
+    }
+    let tab_id = parse_u256_value(&tab_id)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, "invalid_tab_id", e))?;
+    let not_found = || ApiError::new(StatusCode::NOT_FOUND, "tab_not_found", "Tab not found");
+    match fetch_tab_status(tab_id, &state.config.x402).await {
+        Ok(tab)
+            if tab
+                .user_address
+                .eq_ignore_ascii_case(query.user_address.trim()) =>
+        {
+            Ok(Json(tab))
+        }
+        Ok(_) | Err(TabStatusError::NotFound) => Err(not_found()),
+        Err(e @ TabStatusError::Unavailable) => {
+            warn!("Tab status requested but {}", e);
+            Err(ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "sdk_unavailable",
+                "Tab lookups are unavailable: this server has no 4mica wallet key configured",
+            ))
+        }
         Err(e) => {
-            error!("Failed to request tab: {}", e);
-            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+            error!("Tab status lookup failed: {}", e);
+            Err(ApiError::new(
+                StatusCode::BAD_GATEWAY,
+                "tab_lookup_failed",
+                "Failed to look up tab",
+            ))
         }
     }
 }
 
-async fn handle_stream(
+async fn verify_stream_file(
     State(state): State<AppState>,
     Path(filename): Path<String>,
-    uri: Uri,
-    headers: HeaderMap,
+    mut request: axum::extract::Request,
+    next: Next,
 ) -> Response {
-    // Verify the file path before charging for the file
-    let file_path = match server::io::verify_file(&state.config.file_directory, &filename) {
-        Ok(file_path) => file_path,
-        Err(e) => {
-            error!("Failed to verify file path: {}", e);
-            return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+    let verified = match state.mounts.resolve(&filename) {
+        Some((mount, path)) => {
+            let mount = mount.clone();
+            let file = mount.storage.verify(path).await;
+            file.map(|file| (file, Some(mount)))
         }
+        None => state
+            .storage
+            .verify(&filename)
+            .await
+            .map(|file| (file, None)),
     };
-
-    let resource = match state.config.server_advertised_url.join(&uri.to_string()) {
-        Ok(resource) => resource,
+    match verified {
+        Ok((file, mount)) => {
+            request.extensions_mut().insert(file);
+            if let Some(mount) = mount {
+                request.extensions_mut().insert(mount);
+            }
+            next.run(request).await
+        }
         Err(e) => {
-            error!("Failed to construct resource URL: {}", e);
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to construct resource URL",
-            )
-                .into_response();
+            warn!("Failed to verify file path: {}", e);
+            ApiError::from(e).into_response()
         }
-    };
-    // We don't want to charge for playlist files
-    let is_playlist = filename.ends_with(".m3u8");
-    if state.config.x402.enabled
-        && !is_playlist
-        && let Err(err) =
-            x402::handle_x402_paywall(&state, U256::from(100), resource.to_string(), headers).await
-    {
-        return err;
     }
+}
 
-    match server::io::stream_file(&file_path).await {
+/// Streams a file from the storage backend once it is paid for.
+#[utoipa::path(
+    get,
+    path = "/stream/{filename}",
+    tag = "stream",
+    params(
+        ("filename" = String, Path, description = "Path of the file under the storage root, or under a `MOUNTS` directory after its prefix"),
+        openapi::PaymentHeaders,
+        openapi::PaymentQuery,
+    ),
+    responses(
+        (status = 200, description = "The file; a fresh payment adds `payment-response`", content_type = "application/octet-stream", body = Vec<u8>),
+        (status = 304, description = "The cached copy named by `If-None-Match` or `If-Modified-Since` is current"),
+        (status = 400, description = "`not_a_file`: the path names a directory", body = ApiErrorBody),
+        (status = 402, description = "Payment is required; v2 clients also get the base64 `payment-required` header", body = PaymentRequiredResponse),
+        (status = 403, description = "`access_denied`: the path leaves the storage root", body = ApiErrorBody),
+        (status = 404, description = "`file_not_found`: no such file, or its name ends in one of `IGNORE_FILE_SUFFIXES`", body = ApiErrorBody),
+        (status = 429, description = "`rate_limited`: retry after `Retry-After` seconds", body = ApiErrorBody),
+        (status = 500, description = "`file_read_failed`: the file could not be read", body = ApiErrorBody),
+        (status = 503, description = "`settlement_busy`: too many payments are settling; `file_not_ready`: the file changed within `MIN_FILE_AGE_MS`. Retry after `Retry-After` seconds", body = ApiErrorBody),
+    )
+)]
+pub(super) async fn handle_stream(
+    State(state): State<AppState>,
+    Extension(file): Extension<FileInfo>,
+    mount: Option<Extension<Arc<Mount>>>,
+    paid: Option<Extension<PaidRequest>>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Response {
+    // Runs after the paywall, so a 304 never reveals paid content for free.
+    if is_not_modified(&headers, &file) {
+        let mut resp = StatusCode::NOT_MODIFIED.into_response();
+        attach_validators(&mut resp, &file);
+        cache_policy::apply(&mut resp, &state.config, uri.path(), paid.is_some());
+        return resp;
+    }
+
+    let storage = match &mount {
+        Some(Extension(mount)) => &mount.storage as &dyn StorageBackend,
+        None => state.storage.as_ref(),
+    };
+    match storage.open_stream(&file, None).await {
         Ok(body) => {
+            let payer = paid.as_ref().and_then(|Extension(paid)| paid.payer());
+            let body =
+                state
+                    .deliveries
+                    .count(body, uri.path().to_string(), payer.map(str::to_string));
             let mut resp = (StatusCode::OK, body).into_response();
-            if is_playlist {
-                resp.headers_mut().insert(
-                    axum::http::header::CONTENT_TYPE,
-                    HeaderValue::from_static("application/vnd.apple.mpegurl"),
-                );
-            } else if filename.ends_with(".ts") {
+            attach_validators(&mut resp, &file);
+            cache_policy::apply(&mut resp, &state.config, uri.path(), paid.is_some());
+            if let Some(Extension(paid)) = paid {
+                paid.attach(&mut resp);
+            }
+            if let Some(mime) = file.mime {
                 resp.headers_mut().insert(
                     axum::http::header::CONTENT_TYPE,
-                    HeaderValue::from_static("video/mp2t"),
+                    HeaderValue::from_static(mime),
                 );
             }
             resp
         }
-        Err(e) => {
-            use server::FileStreamError;
-
-            let (status, message) = match e {
-                FileStreamError::NotFound(_) => (StatusCode::NOT_FOUND, "File not found"),
-                FileStreamError::NotAFile(_) => (StatusCode::BAD_REQUEST, "Not a file"),
-                FileStreamError::AccessDenied => (StatusCode::FORBIDDEN, "Access denied"),
-                FileStreamError::IoError(_) => {
-                    error!("Failed to stream file: {}", e);
-                    (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read file")
-                }
-            };
-
-            (status, message).into_response()
-        }
+        Err(e) => ApiError::from(e).into_response(),
     }
 }
 
-async fn handle_remote_stream(
+/// Fetches a remote file and streams it back once it is paid for. `Range`, `If-None-Match`,
+/// `If-Modified-Since`, and `Accept` are forwarded to the upstream.
+#[utoipa::path(
+    get,
+    path = "/stream/remote",
+    tag = "stream",
+    params(RemoteStreamQuery, openapi::PaymentHeaders, openapi::PaymentQuery),
+    responses(
+        (status = 200, description = "The remote file with its upstream `Content-Type`, `Content-Length`, `Last-Modified`, `ETag`, and `Accept-Ranges`, and a `Cache-Control` chosen by file type; a fresh payment adds `payment-response`", content_type = "application/octet-stream", body = Vec<u8>),
+        (status = 206, description = "The part of the remote file the forwarded `Range` asked for, with the upstream `Content-Range`", content_type = "application/octet-stream", body = Vec<u8>),
+        (status = 304, description = "The upstream answered a forwarded `If-None-Match` or `If-Modified-Since`: not modified"),
+        (status = 402, description = "Payment is required; v2 clients also get the base64 `payment-required` header", body = PaymentRequiredResponse),
+        (status = 404, description = "`remote_not_found`: the upstream answered 404", body = ApiErrorBody),
+        (status = 416, description = "`range_not_satisfiable`: the upstream rejected the forwarded `Range`", body = ApiErrorBody),
+        (status = 429, description = "`rate_limited`: retry after `Retry-After` seconds", body = ApiErrorBody),
+        (status = 502, description = "`remote_fetch_failed`: the upstream failed or could not be reached; `remote_too_large`: its `Content-Length` is above `REMOTE_MAX_BYTES`", body = ApiErrorBody),
+        (status = 503, description = "`settlement_busy`: too many payments are settling; retry after `Retry-After` seconds", body = ApiErrorBody),
+    )
+)]
+pub(super) async fn handle_remote_stream(
     State(state): State<AppState>,
     Query(query): Query<RemoteStreamQuery>,
-    uri: Uri,
-    headers: HeaderMap,
+    paid: Option<Extension<PaidRequest>>,
+    request_headers: HeaderMap,
 ) -> Response {
     let url = query.url;
 
-    let resource = match state.config.server_advertised_url.join(&uri.to_string()) {
-        Ok(resource) => resource,
-        Err(e) => {
-            error!("Failed to construct resource URL: {}", e);
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to construct resource URL",
-            )
-                .into_response();
-        }
+    let remote = match &state.remote_playlists {
+        Some(cache) if RemotePlaylistCache::caches(&url) => cache
+            .get(&url)
+            .await
+            .map(|file| (StatusCode::OK, file.headers, Body::from(file.bytes))),
+        // The paywall already charged once, whether the upstream answers in part or full.
+        _ => state
+            .remote
+            .stream(&url, Some(&request_headers))
+            .await
+            .map(|remote| (remote.status, remote.headers, remote.body)),
     };
-    // We don't want to charge for playlist files
-    let is_playlist = url.ends_with(".m3u8");
-    if state.config.x402.enabled
-        && !is_playlist
-        && let Err(err) =
-            x402::handle_x402_paywall(&state, U256::from(100), resource.to_string(), headers).await
-    {
-        return err;
-    }
-
-    match server::io::stream_remote_file(&url).await {
-        Ok(remote) => {
-            let mut resp = (StatusCode::OK, remote.body).into_response();
-            if let Some(ct) = remote.content_type {
+    match remote {
+        Ok((status, headers, body)) => {
+            let payer = paid.as_ref().and_then(|Extension(paid)| paid.payer());
+            let body = state
+                .deliveries
+                .count(body, url.clone(), payer.map(str::to_string));
+            let mut resp = (status, headers, body).into_response();
+            cache_policy::apply(&mut resp, &state.config, &url, paid.is_some());
+            if let Some(Extension(paid)) = paid {
+                paid.attach(&mut resp);
+            }
+            if !resp
+                .headers()
+                .contains_key(axum::http::header::CONTENT_TYPE)
+                && let Some(ct) = content_type_for(&url)
+            {
                 resp.headers_mut()
                     .insert(axum::http::header::CONTENT_TYPE, ct);
-            } else if is_playlist {
-                resp.headers_mut().insert(
-                    axum::http::header::CONTENT_TYPE,
-                    HeaderValue::from_static("application/vnd.apple.mpegurl"),
-                );
             }
             resp
         }
-        Err(e) => {
-            error!("Failed to stream remote file: {}, Error: {}", url, e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to fetch remote file",
-            )
-                .into_response()
-        }
+        Err(e) => ApiError::from(e).into_response(),
+    }
+}
+
+/// Content type for HLS files, by extension.
+pub(super) fn content_type_for(name: &str) -> Option<HeaderValue> {
+    server::io::mime_type_for(name).map(HeaderValue::from_static)
+}
+
+/// Empty-bodied response describing a file's size and type.
+pub(super) fn head_response(
+    content_length: Option<u64>,
+    content_type: Option<HeaderValue>,
+) -> Response {
+    let mut resp = StatusCode::OK.into_response();
+    let headers = resp.headers_mut();
+    if let Some(len) = content_length {
+        headers.insert(axum::http::header::CONTENT_LENGTH, HeaderValue::from(len));
+    }
+    if let Some(ct) = content_type {
+        headers.insert(axum::http::header::CONTENT_TYPE, ct);
+    }
+    headers.insert(
+        axum::http::header::ACCEPT_RANGES,
+        HeaderValue::from_static("none"),
+    );
+    resp
+}
+
+/// Describes a file's size and type without paying for it (unless `X402_FREE_HEAD=false`).
+#[utoipa::path(
+    head,
+    path = "/stream/{filename}",
+    tag = "stream",
+    params(
+        ("filename" = String, Path, description = "Path of the file under the storage root, or under a `MOUNTS` directory after its prefix"),
+        openapi::PaymentHeaders,
+        openapi::PaymentQuery,
+    ),
+    responses(
+        (status = 200, description = "`Content-Length`, `Content-Type`, and validators of the file"),
+        (status = 402, description = "Payment is required and no payment session covers the file"),
+        (status = 404, description = "No such file"),
+    )
+)]
+pub(super) async fn handle_stream_head(Extension(file): Extension<FileInfo>) -> Response {
+    let mut resp = head_response(Some(file.len), file.mime.map(HeaderValue::from_static));
+    attach_validators(&mut resp, &file);
+    resp
+}
+
+/// Adds `ETag` and `Last-Modified` for a local file.
+fn attach_validators(resp: &mut Response, file: &FileInfo) {
+    if let Ok(etag) = HeaderValue::from_str(&file.etag()) {
+        resp.headers_mut().insert(axum::http::header::ETAG, etag);
+    }
+    if let Some(modified) = file.modified_secs().and_then(http_date)
+        && let Ok(modified) = HeaderValue::from_str(&modified)
+    {
+        resp.headers_mut()
+            .insert(axum::http::header::LAST_MODIFIED, modified);
+    }
+}
+
+/// Evaluates `If-None-Match`, falling back to `If-Modified-Since` only when no
+/// `If-None-Match` was sent (RFC 9110 section 13.2.2).
+fn is_not_modified(headers: &HeaderMap, file: &FileInfo) -> bool {
+    if let Some(if_none_match) = headers.get(axum::http::header::IF_NONE_MATCH) {
+        let Ok(if_none_match) = if_none_match.to_str() else {
+            return false;
+        };
+        let etag = file.etag();
+        // Weak comparison: `W/` prefixes are ignored on both sides.
+        let opaque = etag.trim_start_matches("W/");
+        return if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == opaque);
+    }
+
+    let Some(since) = headers
+        .get(axum::http::header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| DateTime::parse_from_rfc2822(value).ok())
+    else {
+        return false;
+    };
+    file.modified_secs()
+        .is_some_and(|modified| modified as i64 <= since.timestamp())
+}
+
+fn http_date(secs: u64) -> Option<String> {
+    let date = DateTime::<Utc>::from_timestamp(secs as i64, 0)?;
+    Some(date.format("%a, %d %b %Y %H:%M:%S GMT").to_string())
+}
+
+/// Describes a remote file's size and type without paying for it (unless `X402_FREE_HEAD=false`).
+#[utoipa::path(
+    head,
+    path = "/stream/remote",
+    tag = "stream",
+    params(RemoteStreamQuery, openapi::PaymentHeaders, openapi::PaymentQuery),
+    responses(
+        (status = 200, description = "`Content-Length` and `Content-Type` of the remote file"),
+        (status = 402, description = "Payment is required and no payment session covers the file"),
+        (status = 404, description = "The upstream answered 404"),
+        (status = 502, description = "The upstream failed or could not be reached"),
+    )
+)]
+pub(super) async fn handle_remote_stream_head(
+    State(state): State<AppState>,
+    Query(query): Query<RemoteStreamQuery>,
+) -> Response {
+    match state.remote.head(&query.url).await {
+        Ok(remote) => head_response(
+            remote.content_length,
+            remote.content_type.or_else(|| content_type_for(&query.url)),
+        ),
+        Err(e) => ApiError::from(e).into_response(),
     }
 }
 
 async fn handle_rpc_proxy(State(state): State<AppState>, Json(body): Json<Value>) -> Response {
-    let client = Client::new();
-    let upstream = state.config.x402.rpc_url.clone();
+    let upstream = state.config.x402.primary_rpc_url();
 
-    match client.post(upstream).json(&body).send().await {
+    match state.rpc.post(upstream).json(&body).send().await {
         Ok(resp) => {
             let status = resp.status();
             let text = resp
@@ -204,11 +775,886 @@ async fn handle_rpc_proxy(State(state): State<AppState>, Json(body): Json<Value>
         }
         Err(e) => {
             error!("RPC proxy request failed: {}", e);
-            (
+            ApiError::new(
                 StatusCode::BAD_GATEWAY,
-                format!("RPC proxy request failed: {e}"),
+                "rpc_proxy_failed",
+                "RPC proxy request failed",
             )
-                .into_response()
+            .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{access_log::AccessLog, pricing::PriceResolver};
+    use envconfig::Envconfig;
+    use serde_json::json;
+    use std::{
+        collections::HashMap,
+        future::IntoFuture,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+    use wiremock::{
+        Mock, MockServer, ResponseTemplate,
+        matchers::{method, path},
+    };
+
+    const PAY_TO: &str = "0x00000000000000000000000000000000000000ab";
+    const PAYER: &str = "0x00000000000000000000000000000000000000ef";
+    const SEGMENT: &[u8] = b"segment bytes";
+
+    /// Serves the router over a `FILE_DIRECTORY` holding `a.ts`, with `facilitator` as its
+    /// only facilitator; returns the server's base URL.
+    async fn serve(facilitator: &MockServer) -> String {
+        serve_with(facilitator, &[], &[]).await
+    }
+
+    /// Like [`serve`], with more `files` below `FILE_DIRECTORY` and more `settings`.
+    async fn serve_with(
+        facilitator: &MockServer,
+        files: &[(&str, &[u8])],
+        settings: &[(&str, &str)],
+    ) -> String {
+        static SERVERS: AtomicUsize = AtomicUsize::new(0);
+        let directory = std::env::temp_dir().join(format!(
+            "router-test-{}-{}",
+            std::process::id(),
+            SERVERS.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("a.ts"), SEGMENT).unwrap();
+        for (name, contents) in files {
+            let file = directory.join(name);
+            std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+            std::fs::write(file, contents).unwrap();
+        }
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let values: HashMap<String, String> = [
+            ("X402_PAY_TO", PAY_TO.to_string()),
+            ("X402_TAB_SNAPSHOT", "off".to_string()),
+            ("X402_FACILITATOR_URL", format!("{}/", facilitator.uri())),
+            ("X402_FACILITATOR_MAX_ATTEMPTS", "1".to_string()),
+            ("FILE_DIRECTORY", directory.display().to_string()),
+            ("SERVER_ADVERTISED_URL", base.clone()),
+        ]
+        .into_iter()
+        .chain(
+            settings
+                .iter()
+                .map(|&(key, value)| (key, value.to_string())),
+        )
+        .map(|(key, value)| (key.to_string(), value))
+        .collect();
+        let config = Arc::new(Config::init_from_hashmap(&values).unwrap());
+
+        let facilitator: Arc<dyn Facilitator> =
+            Arc::new(crate::facilitator_client(&config).unwrap());
+        let resolver = PriceResolver::from_config(&config.x402, None).unwrap();
+        let state = AppState {
+            config: config.clone(),
+            facilitator: Some(facilitator),
+            shutdown: CancellationToken::new(),
+            in_flight: InFlight::default(),
+            facilitator_reachable: true,
+            pricing: Arc::new(PriceTable::new(resolver, None, None)),
+            free_paths: FreePaths::from_config(&config.x402).unwrap(),
+            previews: None,
+            rate_limiter: None,
+            tab_rate_limiter: None,
+            tab_audit: None,
+            tab_spend: None,
+            settlement_limiter: None,
+            storage: crate::open_storage(&config).unwrap(),
+            mounts: Mounts::default(),
+            remote_playlists: None,
+            remote: Arc::new(RemoteFetcher::new(&config.remote).unwrap()),
+            rpc: server::x402::rpc_client(&config.x402),
+            playlists: Arc::new(GeneratedPlaylists::new(config.file_stability())),
+            sessions: None,
+            settlements: Arc::new(SettlementCache::new(
+                Duration::from_secs(config.x402.settlement_cache_seconds),
+                config.x402.replay_retention(),
+            )),
+            ledger: Arc::new(SpendLedger::new(config.spend_ledger_recent)),
+            deliveries: Arc::default(),
+            deferred: None,
+            settle_queue: None,
+            pending_settlements: None,
+            settlement_store: None,
+            webhook: None,
+            audit_log: None,
+            access_log: config
+                .access_log_path
+                .as_ref()
+                .map(|path| AccessLog::spawn(path.into()).unwrap()),
+        };
+        let app = build_router(state).into_make_service();
+        tokio::spawn(axum::serve(listener, app).into_future());
+        base
+    }
+
+    /// A 4mica payment for the default price, as an x402 v1 client sends it.
+    fn payment_header() -> String {
+        use base64::{Engine, prelude::BASE64_STANDARD};
+        let envelope = json!({
+            "x402Version": 1,
+            "scheme": "4mica-credit",
+            "network": "polygon-amoy",
+            "payload": {
+                "claims": {
+                    "userAddress": PAYER,
+                    "recipientAddress": PAY_TO,
+                    "assetAddress": "0x41E94Eb019C0762f9Bfcf9Fb1E58725BfB0e7582",
+                    "amount": "100",
+                    "tab_id": "7",
+                    "req_id": "0",
+                },
+                "signature": "0x01",
+            },
+        });
+        BASE64_STANDARD.encode(envelope.to_string())
+    }
+
+    async fn mock_settle(facilitator: &MockServer, response: ResponseTemplate) {
+        Mock::given(method("POST"))
+            .and(path("/settle"))
+            .respond_with(response)
+            .expect(1)
+            .mount(facilitator)
+            .await;
+    }
+
+    async fn get_paid(base: &str) -> reqwest::Response {
+        Client::new()
+            .get(format!("{base}/stream/a.ts"))
+            .header("x-payment", payment_header())
+            .send()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn unpaid_request_is_asked_to_pay() {
+        let facilitator = MockServer::start().await;
+        let base = serve(&facilitator).await;
+
+        let response = Client::new()
+            .get(format!("{base}/stream/a.ts"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+        let body: Value = response.json().await.unwrap();
+        let accepts = body["accepts"].as_array().unwrap();
+        let fourmica = accepts
+            .iter()
+            .find(|accept| accept["scheme"] == "4mica-credit")
+            .unwrap();
+        assert_eq!(fourmica["payTo"], PAY_TO);
+        assert_eq!(fourmica["maxAmountRequired"], "100");
+        assert_eq!(fourmica["resource"], format!("{base}/stream/a.ts"));
+        assert_eq!(fourmica["extra"]["tabEndpoint"], format!("{base}/tab"));
+    }
+
+    #[tokio::test]
+    async fn payment_required_body_describes_the_resource() {
+        let facilitator = MockServer::start().await;
+        let base = serve(&facilitator).await;
+
+        let response = Client::new()
+            .get(format!("{base}/stream/a.ts"))
+            .send()
+            .await
+            .unwrap();
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(
+            body["accepts"][0],
+            json!({
+                "scheme": "4mica-credit",
+                "network": "polygon-amoy",
+                "maxAmountRequired": "100",
+                "resource": format!("{base}/stream/a.ts"),
+                "description": "Media segment a.ts",
+                "mimeType": "video/mp2t",
+                "outputSchema": null,
+                "payTo": PAY_TO,
+                "maxTimeoutSeconds": 300,
+                "asset": "0x41E94Eb019C0762f9Bfcf9Fb1E58725BfB0e7582",
+                "extra": { "tabEndpoint": format!("{base}/tab") },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn settled_4mica_payment_is_served() {
+        let facilitator = MockServer::start().await;
+        mock_settle(
+            &facilitator,
+            ResponseTemplate::new(200).set_body_json(json!({
+                "success": true,
+                "txHash": "0xabc",
+                "networkId": "polygon-amoy",
+            })),
+        )
+        .await;
+        let base = serve(&facilitator).await;
+
+        let response = get_paid(&base).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().contains_key(PAYMENT_RESPONSE_HEADER));
+        assert_eq!(response.bytes().await.unwrap().as_ref(), SEGMENT);
+    }
+
+    #[tokio::test]
+    async fn failed_guarantee_check_rejects_before_settling() {
+        let facilitator = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/settle"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&facilitator)
+            .await;
+        // No 4mica wallet is configured, so the headroom lookup can't run and fails closed.
+        let base = serve_with(&facilitator, &[], &[("X402_REQUIRE_GUARANTEE", "true")]).await;
+
+        let response = get_paid(&base).await;
+        assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["errorCode"], "unexpected_verify_error");
+    }
+
+    #[tokio::test]
+    async fn failed_settlement_is_asked_to_pay_again() {
+        let facilitator = MockServer::start().await;
+        mock_settle(
+            &facilitator,
+            ResponseTemplate::new(200).set_body_json(json!({
+                "success": false,
+                "error": "tab is closed",
+            })),
+        )
+        .await;
+        let base = serve(&facilitator).await;
+
+        let response = get_paid(&base).await;
+        assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+        let body: Value = response.json().await.unwrap();
+        assert!(body["error"].as_str().unwrap().contains("tab is closed"));
+    }
+
+    #[tokio::test]
+    async fn facilitator_error_is_not_served() {
+        let facilitator = MockServer::start().await;
+        mock_settle(&facilitator, ResponseTemplate::new(500)).await;
+        let base = serve(&facilitator).await;
+
+        let response = get_paid(&base).await;
+        assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+        assert_ne!(response.bytes().await.unwrap().as_ref(), SEGMENT);
+    }
+
+    #[tokio::test]
+    async fn facilitator_error_bodies_reach_the_client_only_as_parsed() {
+        let facilitator = MockServer::start().await;
+        mock_settle(
+            &facilitator,
+            ResponseTemplate::new(400).set_body_json(json!({
+                "error": "tab is over its limit",
+                "invalidReason": "insufficient_funds",
+                "internal": "postgres://facilitator@db01",
+            })),
+        )
+        .await;
+        let base = serve(&facilitator).await;
+
+        let body: Value = get_paid(&base).await.json().await.unwrap();
+        assert_eq!(body["errorCode"], "insufficient_funds");
+        let error = body["error"].as_str().unwrap();
+        assert!(error.contains("tab is over its limit"), "{error}");
+        assert!(!error.contains("db01"), "{error}");
+
+        let facilitator = MockServer::start().await;
+        mock_settle(
+            &facilitator,
+            ResponseTemplate::new(400).set_body_string("panic at settle.rs:88 (db01)"),
+        )
+        .await;
+        let base = serve(&facilitator).await;
+
+        let body: Value = get_paid(&base).await.json().await.unwrap();
+        assert_eq!(body["errorCode"], "unexpected_settle_error");
+        assert!(!body["error"].as_str().unwrap().contains("db01"));
+    }
+
+    #[tokio::test]
+    async fn repeated_tab_request_is_answered_from_cache() {
+        let facilitator = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/tabs"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "tabId": "0x7",
+                "userAddress": PAYER,
+                "recipientAddress": PAY_TO,
+                "assetAddress": "0x41E94Eb019C0762f9Bfcf9Fb1E58725BfB0e7582",
+                "startTimestamp": Utc::now().timestamp(),
+                "ttlSeconds": 86400,
+            })))
+            .expect(1)
+            .mount(&facilitator)
+            .await;
+        let base = serve(&facilitator).await;
+
+        let body = json!({
+            "userAddress": PAYER,
+            "paymentRequirements": {
+                "scheme": "4mica-credit",
+                "network": "polygon-amoy",
+                "maxAmountRequired": "100",
+                "payTo": PAY_TO,
+                "asset": "0x41E94Eb019C0762f9Bfcf9Fb1E58725BfB0e7582",
+            },
+        });
+        let client = Client::new();
+        let mut caches = Vec::new();
+        for _ in 0..2 {
+            let response = client
+                .post(format!("{base}/tab"))
+                .json(&body)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            caches.push(response.headers()[X_CACHE].to_str().unwrap().to_string());
+            let tab: Value = response.json().await.unwrap();
+            assert_eq!(tab["tabId"], "0x7");
+        }
+        assert_eq!(caches, ["miss", "hit"]);
+    }
+
+    #[tokio::test]
+    async fn invalid_tab_request_never_reaches_the_facilitator() {
+        let facilitator = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/tabs"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(0)
+            .mount(&facilitator)
+            .await;
+        let base = serve(&facilitator).await;
+
+        let response = Client::new()
+            .post(format!("{base}/tab"))
+            .json(&json!({
+                "userAddress": PAYER,
+                "paymentRequirements": {
+                    "scheme": "4mica-credit",
+                    "network": "polygon-amoy",
+                    "maxAmountRequired": "100",
+                    "payTo": "0x00000000000000000000000000000000000000ac",
+                    "asset": "0x41E94Eb019C0762f9Bfcf9Fb1E58725BfB0e7582",
+                },
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], "invalid_tab_request");
+        assert_eq!(
+            body["error"]["details"]["field"],
+            "paymentRequirements.payTo"
+        );
+    }
+
+    #[tokio::test]
+    async fn client_helpers_pay_for_a_segment() {
+        use x402_client::{PaymentEnvelope, TabClient, build_payment_header, choose_requirement};
+
+        let facilitator = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/tabs"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "tabId": "0x7",
+                "userAddress": PAYER,
+                "recipientAddress": PAY_TO,
+                "assetAddress": "0x41E94Eb019C0762f9Bfcf9Fb1E58725BfB0e7582",
+                "startTimestamp": Utc::now().timestamp(),
+                "ttlSeconds": 86400,
+            })))
+            .expect(1)
+            .mount(&facilitator)
+            .await;
+        mock_settle(
+            &facilitator,
+            ResponseTemplate::new(200).set_body_json(json!({
+                "success": true,
+                "txHash": "0xabc",
+                "networkId": "polygon-amoy",
+            })),
+        )
+        .await;
+        let base = serve(&facilitator).await;
+        let http = Client::new();
+
+        let unpaid = http
+            .get(format!("{base}/stream/a.ts"))
+            .send()
+            .await
+            .unwrap();
+        let required = x402_client::payment_required(unpaid).await.unwrap();
+        let requirement = choose_requirement(&required.accepts, "4mica-credit").unwrap();
+        assert_eq!(requirement.scheme, "4mica-credit");
+
+        let tab = TabClient::new(http.clone())
+            .open_advertised_tab(PAYER, requirement)
+            .await
+            .unwrap();
+        assert_eq!(tab.tab_id, "0x7");
+
+        // Signing is the SDK's job; the mock facilitator settles any signature.
+        let envelope = PaymentEnvelope::new(
+            requirement,
+            json!({
+                "claims": {
+                    "userAddress": PAYER,
+                    "recipientAddress": requirement.pay_to,
+                    "assetAddress": requirement.asset,
+                    "amount": requirement.max_amount_required,
+                    "tab_id": tab.tab_id,
+                    "req_id": tab.next_req_id.unwrap_or_default(),
+                },
+                "signature": "0x01",
+            }),
+        );
+        let paid = http
+            .get(format!("{base}/stream/a.ts"))
+            .header("x-payment", build_payment_header(&envelope))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(paid.status(), StatusCode::OK);
+        assert_eq!(paid.bytes().await.unwrap().as_ref(), SEGMENT);
+    }
+
+    #[tokio::test]
+    async fn hostile_payment_headers_are_asked_to_pay() {
+        use base64::{Engine, prelude::BASE64_STANDARD};
+
+        let facilitator = MockServer::start().await;
+        let base = serve(&facilitator).await;
+        // Under the size limit, but far deeper than an envelope may nest.
+        let nested = format!("{{\"payload\":{}}}", "[".repeat(10_000));
+        for header in [
+            "a".repeat(16 * 1024 + 1),
+            BASE64_STANDARD.encode(nested),
+            "!!not base64!!".to_string(),
+            BASE64_STANDARD.encode(r#"{"x402Version":1,"payload":[]}"#),
+        ] {
+            let response = Client::new()
+                .get(format!("{base}/stream/a.ts"))
+                .header("x-payment", header)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+            let body: Value = response.json().await.unwrap();
+            assert_eq!(body["errorCode"], "invalid_payload");
+        }
+        assert!(facilitator.received_requests().await.unwrap().is_empty());
+    }
+
+    const CAPTIONS: &[u8] = b"WEBVTT\n";
+    const BUNDLES: &str = r#"{"extras": {"price": "50", "files": ["a.ts", "captions/a.vtt"]},
+        "broken": {"price": "50", "files": ["a.ts", "missing.ts"]}}"#;
+
+    async fn serve_bundles(facilitator: &MockServer) -> String {
+        serve_with(
+            facilitator,
+            &[("captions/a.vtt", CAPTIONS)],
+            &[("BUNDLES", BUNDLES)],
+        )
+        .await
+    }
+
+    async fn get_bundle(base: &str, name: &str, paid: bool) -> reqwest::Response {
+        let request = Client::new().get(format!("{base}/bundle/{name}"));
+        let request = if paid {
+            request.header("x-payment", payment_header())
+        } else {
+            request
+        };
+        request.send().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn bundle_is_paid_for_once_and_served_as_a_zip() {
+        let facilitator = MockServer::start().await;
+        mock_settle(
+            &facilitator,
+            ResponseTemplate::new(200).set_body_json(json!({
+                "success": true,
+                "txHash": "0xabc",
+                "networkId": "polygon-amoy",
+            })),
+        )
+        .await;
+        let base = serve_bundles(&facilitator).await;
+
+        let response = get_bundle(&base, "extras", false).await;
+        assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+        let body: Value = response.json().await.unwrap();
+        let accepts = body["accepts"].as_array().unwrap();
+        assert!(!accepts.is_empty());
+        for requirement in accepts {
+            assert_eq!(requirement["resource"], format!("{base}/bundle/extras"));
+            assert_eq!(requirement["maxAmountRequired"], "50");
+        }
+
+        let response = get_bundle(&base, "extras", true).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "application/zip");
+        assert_eq!(
+            response.headers()["content-disposition"],
+            "attachment; filename=\"extras.zip\""
+        );
+        assert!(response.headers().contains_key(PAYMENT_RESPONSE_HEADER));
+        let archive = response.bytes().await.unwrap().to_vec();
+
+        let zip = async_zip::base::read::mem::ZipFileReader::new(archive)
+            .await
+            .unwrap();
+        let mut entries = Vec::new();
+        for (index, entry) in zip.file().entries().iter().enumerate() {
+            assert_eq!(entry.compression(), async_zip::Compression::Stored);
+            let name = entry.filename().as_str().unwrap().to_string();
+            let mut contents = Vec::new();
+            let mut reader = zip.reader_with_entry(index).await.unwrap();
+            reader.read_to_end_checked(&mut contents).await.unwrap();
+            entries.push((name, contents));
+        }
+        assert_eq!(
+            entries,
+            [
+                ("a.ts".to_string(), SEGMENT.to_vec()),
+                ("captions/a.vtt".to_string(), CAPTIONS.to_vec()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn bundle_with_a_missing_file_fails_before_payment() {
+        let facilitator = MockServer::start().await;
+        let base = serve_bundles(&facilitator).await;
+
+        for paid in [false, true] {
+            let response = get_bundle(&base, "broken", paid).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            let body: Value = response.json().await.unwrap();
+            assert_eq!(body["error"]["code"], "file_not_found");
+            assert_eq!(body["error"]["details"]["file"], "missing.ts");
+        }
+
+        let response = get_bundle(&base, "unknown", true).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], "bundle_not_found");
+        assert!(facilitator.received_requests().await.unwrap().is_empty());
+    }
+
+    fn file_info() -> FileInfo {
+        FileInfo {
+            path: "a.ts".into(),
+            len: 13,
+            modified: Some(std::time::UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            mime: None,
+        }
+    }
+
+    fn conditional(headers: &[(&str, &str)]) -> bool {
+        let headers: HeaderMap = headers
+            .iter()
+            .map(|&(name, value)| (name.parse().unwrap(), HeaderValue::from_str(value).unwrap()))
+            .collect();
+        is_not_modified(&headers, &file_info())
+    }
+
+    #[test]
+    fn if_none_match_compares_weakly() {
+        let etag = file_info().etag();
+        assert_eq!(etag, "W/\"d-6553f100\"");
+        assert!(conditional(&[("if-none-match", &etag)]));
+        assert!(conditional(&[("if-none-match", "\"d-6553f100\"")]));
+        assert!(conditional(&[(
+            "if-none-match",
+            "\"other\", W/\"d-6553f100\""
+        )]));
+        assert!(conditional(&[("if-none-match", "*")]));
+        assert!(!conditional(&[("if-none-match", "W/\"d-6553f101\"")]));
+        assert!(!conditional(&[]));
+    }
+
+    #[test]
+    fn if_modified_since_applies_only_without_if_none_match() {
+        let modified = http_date(1_700_000_000).unwrap();
+        let earlier = http_date(1_699_999_999).unwrap();
+        assert!(conditional(&[("if-modified-since", &modified)]));
+        assert!(conditional(&[(
+            "if-modified-since",
+            &http_date(1_700_000_001).unwrap()
+        )]));
+        assert!(!conditional(&[("if-modified-since", &earlier)]));
+        assert!(!conditional(&[("if-modified-since", "yesterday")]));
+        assert!(!conditional(&[
+            ("if-none-match", "\"other\""),
+            ("if-modified-since", &modified),
+        ]));
+    }
+
+    #[tokio::test]
+    async fn conditional_request_for_a_paywalled_file_still_needs_payment() {
+        let facilitator = MockServer::start().await;
+        mock_settle(
+            &facilitator,
+            ResponseTemplate::new(200).set_body_json(json!({ "success": true, "txHash": "0xabc" })),
+        )
+        .await;
+        let base = serve(&facilitator).await;
+        let client = Client::new();
+
+        let etag = get_paid(&base).await.headers()[axum::http::header::ETAG].clone();
+        for validator in [etag, HeaderValue::from_static("*")] {
+            let response = client
+                .get(format!("{base}/stream/a.ts"))
+                .header(axum::http::header::IF_NONE_MATCH, validator)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+        }
+    }
+
+    #[tokio::test]
+    async fn free_playlist_is_revalidated_without_payment() {
+        let facilitator = MockServer::start().await;
+        let playlist: &[u8] = b"#EXTM3U\n#EXTINF:4.0,\na.ts\n";
+        let base = serve_with(&facilitator, &[("show/index.m3u8", playlist)], &[]).await;
+        let client = Client::new();
+        let url = format!("{base}/stream/show/index.m3u8");
+
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[axum::http::header::ETAG].clone();
+        let response = client
+            .get(&url)
+            .header(axum::http::header::IF_NONE_MATCH, etag.clone())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[axum::http::header::ETAG], etag);
+        assert!(response.bytes().await.unwrap().is_empty());
+    }
+
+    fn compressible(headers: &[(HeaderName, &str)]) -> bool {
+        let headers: HeaderMap = headers
+            .iter()
+            .map(|(name, value)| (name.clone(), HeaderValue::from_str(value).unwrap()))
+            .collect();
+        is_compressible(
+            StatusCode::OK,
+            Version::HTTP_11,
+            &headers,
+            &axum::http::Extensions::new(),
+        )
+    }
+
+    #[test]
+    fn only_text_playlists_and_json_are_compressible() {
+        assert!(compressible(&[(
+            CONTENT_TYPE,
+            "application/vnd.apple.mpegurl"
+        )]));
+        assert!(compressible(&[(CONTENT_TYPE, "application/dash+xml")]));
+        assert!(compressible(&[(
+            CONTENT_TYPE,
+            "Application/JSON; charset=utf-8"
+        )]));
+        assert!(compressible(&[(CONTENT_TYPE, "text/plain")]));
+        assert!(!compressible(&[(CONTENT_TYPE, "video/mp2t")]));
+        assert!(!compressible(&[(CONTENT_TYPE, "video/iso.segment")]));
+        assert!(!compressible(&[]));
+        assert!(!compressible(&[
+            (CONTENT_TYPE, "application/vnd.apple.mpegurl"),
+            (CONTENT_RANGE, "bytes 0-99/1000"),
+        ]));
+    }
+
+    #[tokio::test]
+    async fn compression_applies_to_playlists_and_json_only() {
+        let facilitator = MockServer::start().await;
+        mock_settle(
+            &facilitator,
+            ResponseTemplate::new(200).set_body_json(json!({ "success": true, "txHash": "0xabc" })),
+        )
+        .await;
+        let segment = vec![0x47; 4096];
+        let playlist = "#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXTINF:4.0,\na.ts\n".repeat(8);
+        let base = serve_with(
+            &facilitator,
+            &[("a.ts", &segment), ("show/index.m3u8", playlist.as_bytes())],
+            &[("COMPRESSION_ENABLED", "true")],
+        )
+        .await;
+        let client = Client::new();
+        let get = |path: &str| {
+            client
+                .get(format!("{base}{path}"))
+                .header(axum::http::header::ACCEPT_ENCODING, "gzip")
+        };
+
+        for path in ["/stream/show/index.m3u8", "/price/a.ts"] {
+            let response = get(path).send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{path}");
+            assert_eq!(
+                response.headers()[axum::http::header::CONTENT_ENCODING],
+                "gzip",
+                "{path}"
+            );
+        }
+
+        let response = get("/stream/a.ts")
+            .header("x-payment", payment_header())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "video/mp2t");
+        assert!(
+            !response
+                .headers()
+                .contains_key(axum::http::header::CONTENT_ENCODING)
+        );
+        assert_eq!(response.bytes().await.unwrap(), segment);
+    }
+
+    const MANIFEST: &[u8] = b"<?xml version=\"1.0\"?><MPD type=\"static\"></MPD>";
+
+    #[tokio::test]
+    async fn dash_manifest_is_free_and_its_segments_are_paid() {
+        let facilitator = MockServer::start().await;
+        mock_settle(
+            &facilitator,
+            ResponseTemplate::new(200).set_body_json(json!({ "success": true, "txHash": "0xabc" })),
+        )
+        .await;
+        let files: &[(&str, &[u8])] = &[
+            ("show/manifest.mpd", MANIFEST),
+            ("show/chunk_1.m4s", SEGMENT),
+        ];
+        let base = serve_with(&facilitator, files, &[]).await;
+        let client = Client::new();
+
+        let response = client
+            .get(format!("{base}/stream/show/manifest.mpd"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/dash+xml");
+        assert_eq!(
+            response.headers()[axum::http::header::CACHE_CONTROL],
+            "public, max-age=2"
+        );
+        assert_eq!(response.bytes().await.unwrap().as_ref(), MANIFEST);
+
+        let segment = format!("{base}/stream/show/chunk_1.m4s");
+        let response = client.get(&segment).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["accepts"][0]["mimeType"], "video/iso.segment");
+
+        let response = client
+            .get(&segment)
+            .header("x-payment", payment_header())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "video/iso.segment");
+        assert_eq!(response.bytes().await.unwrap().as_ref(), SEGMENT);
+    }
+
+    #[tokio::test]
+    async fn charged_dash_manifest_needs_payment() {
+        let facilitator = MockServer::start().await;
+        let base = serve_with(
+            &facilitator,
+            &[("show/manifest.mpd", MANIFEST)],
+            &[("X402_CHARGE_PLAYLISTS", "true")],
+        )
+        .await;
+
+        let response = Client::new()
+            .get(format!("{base}/stream/show/manifest.mpd"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["accepts"][0]["mimeType"], "application/dash+xml");
+    }
+
+    #[tokio::test]
+    async fn access_log_names_the_payer_of_a_paid_request() {
+        let facilitator = MockServer::start().await;
+        mock_settle(
+            &facilitator,
+            ResponseTemplate::new(200).set_body_json(json!({ "success": true, "txHash": "0xabc" })),
+        )
+        .await;
+        let log =
+            std::env::temp_dir().join(format!("router-access-log-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&log);
+        let base = serve_with(
+            &facilitator,
+            &[],
+            &[
+                ("ACCESS_LOG", "true"),
+                ("ACCESS_LOG_PATH", log.to_str().unwrap()),
+            ],
+        )
+        .await;
+
+        let response = get_paid(&base).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.bytes().await.unwrap().as_ref(), SEGMENT);
+
+        // Written by the log's own thread once the body was sent.
+        let mut contents = String::new();
+        for _ in 0..100 {
+            contents = std::fs::read_to_string(&log).unwrap_or_default();
+            if contents.ends_with('\n') {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
         }
+        let lines: Vec<Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 1, "{contents}");
+        let line = &lines[0];
+        assert_eq!(line["path"], "/stream/a.ts");
+        assert_eq!(line["status"], 200);
+        assert_eq!(line["bytesSent"], SEGMENT.len());
+        assert_eq!(line["payment"]["decision"], "settled");
+        assert_eq!(line["payment"]["payer"], PAYER);
+        assert_eq!(line["payment"]["amount"], "100");
+        std::fs::remove_file(log).unwrap();
     }
 }
@@ -0,0 +1,339 @@
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use sdk_4mica::U256;
+use serde::{Deserialize, Serialize};
+use server::x402::{SettlementSummary, parse_u256_value};
+use sha2::Sha256;
+use url::Url;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Request/response header carrying a payment session token.
+pub const SESSION_HEADER: &str = "x-payment-session";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SessionClaims {
+    /// Paying user address.
+    user: String,
+    /// Resource scope the session grants access to.
+    prefix: String,
+    /// Amount the payment settled, in the asset's base units.
+    price: U256,
+    /// Recipient the payment went to.
+    pay_to: String,
+    /// Expiry as a unix timestamp in seconds.
+    exp: i64,
+}
+
+/// Mints and checks HMAC-signed tokens that let a client stream every resource
+/// under a prefix for a while after a single payment. A token only stands in for payments
+/// of at most the price it settled, to the recipient it paid.
+///
+/// Tokens are `base64url(claims JSON).base64url(HMAC-SHA256(claims JSON))`.
+#[derive(Clone)]
+pub struct SessionSigner {
+    secret: Vec<u8>,
+    duration_seconds: i64,
+    strip_segments: usize,
+}
+
+impl SessionSigner {
+    pub fn new(secret: &str, duration_seconds: u64, strip_segments: usize) -> Self {
+        Self {
+            secret: secret.as_bytes().to_vec(),
+            duration_seconds: duration_seconds as i64,
+            strip_segments,
+        }
+    }
+
+    /// Mints a token covering every resource sharing `resource`'s prefix, for the payment
+    /// `settlement` made; `None` when it names no payer.
+    pub fn mint(&self, settlement: &SettlementSummary, resource: &str, now: i64) -> Option<String> {
+        let claims = SessionClaims {
+            user: settlement.payer.clone().filter(|payer| !payer.is_empty())?,
+            prefix: session_prefix(resource, self.strip_segments)?,
+            price: parse_u256_value(&settlement.amount).ok()?,
+            pay_to: settlement.pay_to.clone(),
+            exp: now + self.duration_seconds,
+        };
+        let claims = serde_json::to_vec(&claims).ok()?;
+        let signature = self.mac(&claims).finalize().into_bytes();
+        Some(format!(
+            "{}.{}",
+            BASE64_URL_SAFE_NO_PAD.encode(&claims),
+            BASE64_URL_SAFE_NO_PAD.encode(signature)
+        ))
+    }
+
+    /// The user `token` was minted for, when it is authentic, unexpired, its prefix covers
+    /// `resource`, and its payment covers `price` to one of `pay_tos`.
+    pub fn verify(
+        &self,
+        token: &str,
+        resource: &str,
+        price: U256,
+        pay_tos: &[&str],
+        now: i64,
+    ) -> Option<String> {
+        let (claims_b64, signature_b64) = token.split_once('.')?;
+        let claims = BASE64_URL_SAFE_NO_PAD.decode(claims_b64).ok()?;
+        let signature = BASE64_URL_SAFE_NO_PAD.decode(signature_b64).ok()?;
+        self.mac(&claims).verify_slice(&signature).ok()?;
+        let claims = serde_json::from_slice::<SessionClaims>(&claims).ok()?;
+        let covered = claims.exp > now
+            && !claims.user.is_empty()
+            && claims.price >= price
+            && pay_tos
+                .iter()
+                .any(|pay_to| pay_to.eq_ignore_ascii_case(&claims.pay_to))
+            && session_scope(resource).is_some_and(|scope| prefix_covers(&claims.prefix, &scope));
+        covered.then_some(claims.user)
+    }
+
+    fn mac(&self, data: &[u8]) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        mac.update(data);
+        mac
+    }
+}
+
+/// The comparable form of a resource URL: its path plus the decoded query, so that
+/// remote resources (`/stream/remote?url=...`) scope by the upstream URL's path.
+fn session_scope(resource: &str) -> Option<String> {
+    let url = Url::parse(resource).ok()?;
+    let mut scope = url.path().to_string();
+    let query = url
+        .query_pairs()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect::<Vec<_>>()
+        .join("&");
+    if !query.is_empty() {
+        scope.push('?');
+        scope.push_str(&query);
+    }
+    Some(scope)
+}
+
+/// Whether a session minted for `prefix` covers `scope`: everything under a prefix that
+/// ends in `/`, otherwise only the exact resource it was minted for, so `/stream/a.ts`
+/// doesn't cover `/stream/a.tsx`.
+fn prefix_covers(prefix: &str, scope: &str) -> bool {
+    if prefix.ends_with('/') {
+        scope.starts_with(prefix)
+    } else {
+        scope == prefix
+    }
+}
+
+/// Derives the session prefix by stripping `strip_segments` trailing path components
+/// from the resource scope. Stripped prefixes end in `/` so `/a/` never matches `/ab`;
+/// with zero segments stripped the session covers only the paid resource itself.
+///
+/// Stripping stops before the prefix would cover a whole route (`/stream/`) or, for a remote
+/// resource, a whole upstream origin.
+fn session_prefix(resource: &str, strip_segments: usize) -> Option<String> {
+    let mut prefix = session_scope(resource)?;
+    let floor = scope_floor(&prefix);
+    for _ in 0..strip_segments {
+        let trimmed = prefix.trim_end_matches('/');
+        let Some(cut) = trimmed.rfind('/') else {
+            break;
+        };
+        if cut < floor {
+            break;
+        }
+        prefix.truncate(cut + 1);
+    }
+    Some(prefix)
+}
+
+/// Length of the shortest prefix of `scope` a session may have: past the route's first
+/// path component, or past the origin of the upstream URL in its query.
+fn scope_floor(scope: &str) -> usize {
+    let route_end = scope[1.min(scope.len())..]
+        .find('/')
+        .map_or(scope.len(), |slash| slash + 2);
+    let Some(query_start) = scope.find('?') else {
+        return route_end;
+    };
+    let Some(origin) = scope[query_start..].find("://") else {
+        return scope.len();
+    };
+    let host_start = query_start + origin + "://".len();
+    scope[host_start..]
+        .find('/')
+        .map_or(scope.len(), |slash| host_start + slash + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    const PAY_TO: &str = "0x00000000000000000000000000000000000000AB";
+    const NOW: i64 = 1_700_000_000;
+
+    fn signer(strip_segments: usize) -> SessionSigner {
+        SessionSigner::new("secret", 300, strip_segments)
+    }
+
+    fn settlement(amount: &str) -> SettlementSummary {
+        SettlementSummary {
+            scheme: "4mica-credit".into(),
+            network: "polygon-amoy".into(),
+            pay_to: PAY_TO.into(),
+            asset: "0x00000000000000000000000000000000000000cd".into(),
+            amount: amount.into(),
+            payer: Some("0x00000000000000000000000000000000000000ef".into()),
+            tab_id: None,
+            tx_hash: None,
+            certificate: None,
+            certificate_verified: None,
+        }
+    }
+
+    fn token(resource: &str) -> String {
+        signer(1).mint(&settlement("100"), resource, NOW).unwrap()
+    }
+
+    fn verify(token: &str, resource: &str, now: i64) -> Option<String> {
+        signer(1).verify(
+            token,
+            resource,
+            U256::from(100),
+            &[&PAY_TO.to_lowercase()],
+            now,
+        )
+    }
+
+    #[test]
+    fn token_covers_siblings_until_it_expires() {
+        let token = token("http://localhost/stream/show/1.ts");
+        assert_eq!(
+            verify(&token, "http://localhost/stream/show/2.ts", NOW + 299).as_deref(),
+            Some("0x00000000000000000000000000000000000000ef")
+        );
+        assert_eq!(
+            verify(&token, "http://localhost/stream/show/2.ts", NOW + 300),
+            None
+        );
+    }
+
+    #[test]
+    fn token_does_not_cover_other_prefixes() {
+        let token = token("http://localhost/stream/show/1.ts");
+        assert_eq!(
+            verify(&token, "http://localhost/stream/other/1.ts", NOW),
+            None
+        );
+        assert_eq!(
+            verify(&token, "http://localhost/stream/showcase/1.ts", NOW),
+            None
+        );
+    }
+
+    #[test]
+    fn single_resource_token_does_not_cover_longer_names() {
+        let token = signer(0)
+            .mint(&settlement("100"), "http://localhost/stream/a.ts", NOW)
+            .unwrap();
+        let verify = |resource| signer(0).verify(&token, resource, U256::from(100), &[PAY_TO], NOW);
+        assert!(verify("http://localhost/stream/a.ts").is_some());
+        assert_eq!(verify("http://localhost/stream/a.ts2"), None);
+        assert_eq!(verify("http://localhost/stream/a.tsx"), None);
+
+        let remote = "http://localhost/stream/remote?url=https://cdn.test/a.ts";
+        let token = signer(3).mint(&settlement("100"), remote, NOW).unwrap();
+        let verify = |resource| signer(3).verify(&token, resource, U256::from(100), &[PAY_TO], NOW);
+        assert!(verify(remote).is_some());
+        assert_eq!(
+            verify("http://localhost/stream/remote?url=https://cdn.test/a.ts.evil"),
+            None
+        );
+    }
+
+    #[test]
+    fn tampered_tokens_are_rejected() {
+        let resource = "http://localhost/stream/show/1.ts";
+        let token = token(resource);
+        let (claims, signature) = token.split_once('.').unwrap();
+
+        let claims_json = BASE64_URL_SAFE_NO_PAD.decode(claims).unwrap();
+        let mut forged: Value = serde_json::from_slice(&claims_json).unwrap();
+        forged["prefix"] = "/".into();
+        let forged = BASE64_URL_SAFE_NO_PAD.encode(forged.to_string());
+        assert_eq!(
+            verify(&format!("{forged}.{signature}"), resource, NOW),
+            None
+        );
+
+        let mut signature = BASE64_URL_SAFE_NO_PAD.decode(signature).unwrap();
+        signature[0] ^= 1;
+        let signature = BASE64_URL_SAFE_NO_PAD.encode(signature);
+        assert_eq!(
+            verify(&format!("{claims}.{signature}"), resource, NOW),
+            None
+        );
+
+        let other = SessionSigner::new("other secret", 300, 1);
+        assert_eq!(
+            other.verify(&token, resource, U256::from(100), &[PAY_TO], NOW),
+            None
+        );
+    }
+
+    #[test]
+    fn token_only_covers_what_its_payment_did() {
+        let resource = "http://localhost/stream/show/1.ts";
+        let token = token(resource);
+        let pricier = signer(1).verify(&token, resource, U256::from(101), &[PAY_TO], NOW);
+        assert_eq!(pricier, None);
+        let elsewhere = "0x00000000000000000000000000000000000000ac";
+        assert_eq!(
+            signer(1).verify(&token, resource, U256::from(100), &[elsewhere], NOW),
+            None
+        );
+    }
+
+    #[test]
+    fn payments_without_a_payer_get_no_token() {
+        let settlement = SettlementSummary {
+            payer: None,
+            ..settlement("100")
+        };
+        assert_eq!(
+            signer(1).mint(&settlement, "http://localhost/stream/a/1.ts", NOW),
+            None
+        );
+    }
+
+    #[test]
+    fn prefix_never_widens_to_a_whole_route() {
+        assert_eq!(
+            session_prefix("http://localhost/stream/a.ts", 1).as_deref(),
+            Some("/stream/a.ts")
+        );
+        assert_eq!(
+            session_prefix("http://localhost/stream/show/hd/1.ts", 5).as_deref(),
+            Some("/stream/show/")
+        );
+        assert_eq!(
+            session_prefix(
+                "http://localhost/stream/remote?url=https://cdn.test/a.ts",
+                3
+            )
+            .as_deref(),
+            Some("/stream/remote?url=https://cdn.test/a.ts")
+        );
+        assert_eq!(
+            session_prefix(
+                "http://localhost/stream/remote?url=https://cdn.test/show/1.ts",
+                3
+            )
+            .as_deref(),
+            Some("/stream/remote?url=https://cdn.test/show/")
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use axum::{
+    http::{HeaderValue, StatusCode, header::RETRY_AFTER},
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+
+use crate::http::model::ApiError;
+
+/// Seconds a request turned away by a saturated limiter is told to wait.
+const RETRY_AFTER_SECONDS: u64 = 1;
+
+/// Bounds how many `settle_payment` calls run at once, so a burst of paid requests
+/// queues here instead of tripping the facilitator's or RPC's own limits.
+#[derive(Clone)]
+pub struct SettlementLimiter {
+    max: usize,
+    wait: Duration,
+    permits: Arc<Semaphore>,
+    queued: Arc<AtomicUsize>,
+    rejected: Arc<AtomicU64>,
+}
+
+/// Counts its request as queued until dropped.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl SettlementLimiter {
+    /// Returns `None` when `max` is zero, which leaves settlements unbounded.
+    pub fn new(max: usize, wait: Duration) -> Option<Self> {
+        (max > 0).then(|| Self {
+            max,
+            wait,
+            permits: Arc::new(Semaphore::new(max)),
+            queued: Arc::default(),
+            rejected: Arc::default(),
+        })
+    }
+
+    /// Waits up to the configured time for a slot, which is freed when the permit drops.
+    pub async fn acquire(&self) -> Result<OwnedSemaphorePermit, Response> {
+        let acquired = {
+            self.queued.fetch_add(1, Ordering::Relaxed);
+            let _queued = Queued(&self.queued);
+            tokio::time::timeout(self.wait, self.permits.clone().acquire_owned()).await
+        };
+        match acquired {
+            Ok(Ok(permit)) => Ok(permit),
+            // The semaphore is never closed, so only the timeout ends up here.
+            _ => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    in_flight = self.in_flight(),
+                    queued = self.queued(),
+                    "Settlement slots saturated; rejecting paid request"
+                );
+                Err(busy())
+            }
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Settlements holding a slot.
+    pub fn in_flight(&self) -> usize {
+        self.max - self.permits.available_permits()
+    }
+
+    /// Requests waiting for a slot.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Requests turned away after waiting, since startup.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+fn busy() -> Response {
+    let mut resp = ApiError::new(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "settlement_busy",
+        "Too many payments are settling; retry shortly",
+    )
+    .with_details(json!({ "retryAfterSeconds": RETRY_AFTER_SECONDS }))
+    .into_response();
+    resp.headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(RETRY_AFTER_SECONDS));
+    resp
+}
@@ -0,0 +1,278 @@
+use chrono::Utc;
+use rusqlite::{Connection, params};
+use serde::Serialize;
+use serde_json::Value;
+use server::{PaymentError, x402::SettlementSummary};
+use std::{path::Path, thread};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, warn};
+
+/// Schema migrations, applied in order; `PRAGMA user_version` records how many ran.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE settlements (
+        id          INTEGER PRIMARY KEY AUTOINCREMENT,
+        timestamp   INTEGER NOT NULL,
+        resource    TEXT NOT NULL,
+        scheme      TEXT,
+        network     TEXT,
+        payer       TEXT,
+        pay_to      TEXT,
+        asset       TEXT,
+        amount      TEXT,
+        tab_id      TEXT,
+        tx_hash     TEXT,
+        certificate TEXT,
+        outcome     TEXT NOT NULL,
+        error       TEXT
+    );
+    CREATE INDEX settlements_timestamp ON settlements (timestamp);",
+    "ALTER TABLE settlements ADD COLUMN certificate_verified INTEGER;",
+];
+
+/// Most rows one `GET /admin/settlements` call returns.
+pub const MAX_QUERY_LIMIT: u32 = 1000;
+
+/// One settlement attempt as stored and returned by `GET /admin/settlements`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SettlementRecord {
+    pub id: i64,
+    /// Unix timestamp in seconds.
+    pub timestamp: i64,
+    pub resource: String,
+    pub scheme: Option<String>,
+    pub network: Option<String>,
+    pub payer: Option<String>,
+    pub pay_to: Option<String>,
+    pub asset: Option<String>,
+    pub amount: Option<String>,
+    pub tab_id: Option<String>,
+    pub tx_hash: Option<String>,
+    pub certificate: Option<Value>,
+    /// Whether `certificate` was checked against the operator key and the payment.
+    pub certificate_verified: Option<bool>,
+    /// `settled` or `failed`.
+    pub outcome: String,
+    pub error: Option<String>,
+}
+
+enum Command {
+    Record(Box<SettlementRecord>),
+    Query {
+        since: i64,
+        limit: u32,
+        reply: oneshot::Sender<rusqlite::Result<Vec<SettlementRecord>>>,
+    },
+    Flush(oneshot::Sender<()>),
+}
+
+/// Persists every settlement attempt to SQLite (`DATABASE_PATH`) from a dedicated
+/// writer thread, so paid requests never wait on disk I/O.
+///
+/// Like the webhook queue this one is bounded; records that don't fit are dropped
+/// with a warning.
+#[derive(Clone)]
+pub struct SettlementStore {
+    tx: mpsc::Sender<Command>,
+}
+
+impl SettlementStore {
+    /// Opens (or creates) the database, runs pending migrations, and starts the writer.
+    pub fn open(path: impl AsRef<Path>, queue_size: usize) -> anyhow::Result<Self> {
+        let mut conn = Connection::open(path)?;
+        migrate(&mut conn)?;
+
+        let (tx, mut rx) = mpsc::channel::<Command>(queue_size.max(1));
+        thread::Builder::new()
+            .name("settlement-store".into())
+            .spawn(move || {
+                while let Some(command) = rx.blocking_recv() {
+                    match command {
+                        Command::Record(record) => {
+                            if let Err(e) = insert(&conn, &record) {
+                                error!("Failed to persist settlement record: {}", e);
+                            }
+                        }
+                        Command::Query {
+                            since,
+                            limit,
+                            reply,
+                        } => {
+                            let _ = reply.send(query(&conn, since, limit));
+                        }
+                        Command::Flush(reply) => {
+                            let _ = reply.send(());
+                        }
+                    }
+                }
+            })?;
+
+        Ok(Self { tx })
+    }
+
+    /// Queues a record of one `settle_payment` attempt for `resource`. `payer` is used
+    /// when the attempt failed before the payer was known.
+    pub fn record(
+        &self,
+        resource: &str,
+        payer: Option<String>,
+        result: &Result<SettlementSummary, PaymentError>,
+    ) {
+        let mut record = SettlementRecord {
+            id: 0,
+            timestamp: Utc::now().timestamp(),
+            resource: resource.to_string(),
+            scheme: None,
+            network: None,
+            payer,
+            pay_to: None,
+            asset: None,
+            amount: None,
+            tab_id: None,
+            tx_hash: None,
+            certificate: None,
+            certificate_verified: None,
+            outcome: "failed".into(),
+            error: None,
+        };
+        match result {
+            Ok(settlement) => {
+                record.scheme = Some(settlement.scheme.clone());
+                record.network = Some(settlement.network.clone());
+                record.payer = settlement.payer.clone().or(record.payer);
+                record.pay_to = Some(settlement.pay_to.clone());
+                record.asset = Some(settlement.asset.clone());
+                record.amount = Some(settlement.amount.clone());
+                record.tab_id = settlement.tab_id.clone();
+                record.tx_hash = settlement.tx_hash.clone();
+                record.certificate = settlement
+                    .certificate
+                    .as_ref()
+                    .and_then(|certificate| serde_json::to_value(certificate).ok());
+                record.certificate_verified = settlement.certificate_verified;
+                record.outcome = "settled".into();
+            }
+            Err(e) => record.error = Some(e.to_string()),
+        }
+        self.send(record);
+    }
+
+    /// Queues a record of a payment that was verified for `resource`, but whose
+    /// background settlement gave up.
+    pub fn record_unsettled(&self, resource: &str, settlement: &SettlementSummary, error: &str) {
+        self.send(SettlementRecord {
+            id: 0,
+            timestamp: Utc::now().timestamp(),
+            resource: resource.to_string(),
+            scheme: Some(settlement.scheme.clone()),
+            network: Some(settlement.network.clone()),
+            payer: settlement.payer.clone(),
+            pay_to: Some(settlement.pay_to.clone()),
+            asset: Some(settlement.asset.clone()),
+            amount: Some(settlement.amount.clone()),
+            tab_id: settlement.tab_id.clone(),
+            tx_hash: None,
+            certificate: None,
+            certificate_verified: None,
+            outcome: "failed".into(),
+            error: Some(error.to_string()),
+        });
+    }
+
+    fn send(&self, record: SettlementRecord) {
+        if let Err(e) = self.tx.try_send(Command::Record(Box::new(record))) {
+            warn!("Dropping settlement record: {}", e);
+        }
+    }
+
+    /// Up to `limit` records at or after unix time `since`, oldest first.
+    pub async fn query(
+        &self,
+        since: i64,
+        limit: u32,
+    ) -> Option<rusqlite::Result<Vec<SettlementRecord>>> {
+        let (reply, rx) = oneshot::channel();
+        self.tx
+            .send(Command::Query {
+                since,
+                limit: limit.min(MAX_QUERY_LIMIT),
+                reply,
+            })
+            .await
+            .ok()?;
+        rx.await.ok()
+    }
+
+    /// Waits until every record queued so far has been written.
+    pub async fn flush(&self) {
+        let (reply, rx) = oneshot::channel();
+        if self.tx.send(Command::Flush(reply)).await.is_ok() {
+            let _ = rx.await;
+        }
+    }
+}
+
+fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    let applied: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (version, migration) in (1..).zip(MIGRATIONS).skip(applied.max(0) as usize) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", version)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn insert(conn: &Connection, record: &SettlementRecord) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO settlements (timestamp, resource, scheme, network, payer, pay_to, asset,
+             amount, tab_id, tx_hash, certificate, certificate_verified, outcome, error)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+        params![
+            record.timestamp,
+            record.resource,
+            record.scheme,
+            record.network,
+            record.payer,
+            record.pay_to,
+            record.asset,
+            record.amount,
+            record.tab_id,
+            record.tx_hash,
+            record.certificate.as_ref().map(Value::to_string),
+            record.certificate_verified,
+            record.outcome,
+            record.error,
+        ],
+    )?;
+    Ok(())
+}
+
+fn query(conn: &Connection, since: i64, limit: u32) -> rusqlite::Result<Vec<SettlementRecord>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT id, timestamp, resource, scheme, network, payer, pay_to, asset, amount,
+             tab_id, tx_hash, certificate, outcome, error, certificate_verified
+         FROM settlements WHERE timestamp >= ?1 ORDER BY id LIMIT ?2",
+    )?;
+    let rows = stmt.query_map(params![since, limit], |row| {
+        let certificate: Option<String> = row.get(11)?;
+        Ok(SettlementRecord {
+            id: row.get(0)?,
+            timestamp: row.get(1)?,
+            resource: row.get(2)?,
+            scheme: row.get(3)?,
+            network: row.get(4)?,
+            payer: row.get(5)?,
+            pay_to: row.get(6)?,
+            asset: row.get(7)?,
+            amount: row.get(8)?,
+            tab_id: row.get(9)?,
+            tx_hash: row.get(10)?,
+            certificate: certificate.and_then(|raw| serde_json::from_str(&raw).ok()),
+            outcome: row.get(12)?,
+            error: row.get(13)?,
+            certificate_verified: row.get(14)?,
+        })
+    })?;
+    rows.collect()
+}
@@ -0,0 +1,211 @@
+use parking_lot::Mutex;
+use sdk_4mica::U256;
+use server::{PaymentError, x402::X402Config};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// A tab's charges within the window and when they were made, oldest first.
+type Charges = VecDeque<(Instant, U256)>;
+
+/// Caps what each tab may be charged on this server per sliding window, on top of the
+/// facilitator's own guarantees.
+///
+/// Payments reserve their amount before settling and give it back if settlement fails, so
+/// concurrent payments from one tab can't all slip under the cap.
+#[derive(Clone)]
+pub struct TabSpendCap {
+    cap: U256,
+    window: Duration,
+    charges: Arc<Mutex<HashMap<U256, Charges>>>,
+}
+
+/// An amount reserved against a tab's cap.
+pub struct TabCharge {
+    tab_id: U256,
+    at: Instant,
+    amount: U256,
+}
+
+impl TabSpendCap {
+    /// Returns `None` unless `X402_TAB_SPEND_CAP` is set.
+    pub fn from_config(config: &X402Config) -> Option<Self> {
+        Some(Self {
+            cap: config.tab_spend_cap?,
+            window: Duration::from_secs(config.tab_spend_window_seconds),
+            charges: Arc::default(),
+        })
+    }
+
+    /// Reserves `amount` against `tab_id`'s cap, failing without reserving anything when
+    /// the tab's charges in the window would then exceed it. Reaching the cap exactly is
+    /// allowed.
+    pub fn try_charge(&self, tab_id: U256, amount: U256) -> Result<TabCharge, PaymentError> {
+        let now = Instant::now();
+        let mut charges = self.charges.lock();
+        charges.retain(|_, tab| {
+            while tab
+                .front()
+                .is_some_and(|(at, _)| now.duration_since(*at) >= self.window)
+            {
+                tab.pop_front();
+            }
+            !tab.is_empty()
+        });
+        let tab = charges.entry(tab_id).or_default();
+        let spent = tab.iter().fold(U256::ZERO, |spent, (_, charged)| {
+            spent.saturating_add(*charged)
+        });
+        if spent.saturating_add(amount) > self.cap {
+            return Err(PaymentError::TabSpendCapExceeded {
+                tab_id: format!("{tab_id:#x}"),
+                cap: self.cap,
+                window_seconds: self.window.as_secs(),
+                retry_after: self.retry_after(tab, spent, amount, now).as_secs().max(1),
+            });
+        }
+        tab.push_back((now, amount));
+        Ok(TabCharge {
+            tab_id,
+            at: now,
+            amount,
+        })
+    }
+
+    /// Gives back a charge whose payment didn't settle.
+    pub fn refund(&self, charge: TabCharge) {
+        let mut charges = self.charges.lock();
+        if let Some(tab) = charges.get_mut(&charge.tab_id) {
+            if let Some(index) = tab
+                .iter()
+                .position(|&(at, amount)| at == charge.at && amount == charge.amount)
+            {
+                tab.remove(index);
+            }
+            if tab.is_empty() {
+                charges.remove(&charge.tab_id);
+            }
+        }
+    }
+
+    /// How long until enough of `tab`'s charges leave the window for `amount` to fit; a
+    /// whole window when it never fits.
+    fn retry_after(&self, tab: &Charges, spent: U256, amount: U256, now: Instant) -> Duration {
+        if amount > self.cap {
+            return self.window;
+        }
+        let mut remaining = spent;
+        for (at, charged) in tab {
+            remaining = remaining.saturating_sub(*charged);
+            if remaining.saturating_add(amount) <= self.cap {
+                return (*at + self.window).saturating_duration_since(now);
+            }
+        }
+        self.window
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+
+    fn cap(cap: u64, window: Duration) -> TabSpendCap {
+        TabSpendCap {
+            cap: U256::from(cap),
+            window,
+            charges: Arc::default(),
+        }
+    }
+
+    fn charge(cap: &TabSpendCap, tab_id: u64, amount: u64) -> Result<TabCharge, PaymentError> {
+        cap.try_charge(U256::from(tab_id), U256::from(amount))
+    }
+
+    #[test]
+    fn reaching_the_cap_exactly_is_allowed() {
+        let cap = cap(100, Duration::from_secs(60));
+        charge(&cap, 7, 60).unwrap();
+        charge(&cap, 7, 40).unwrap();
+        match charge(&cap, 7, 1) {
+            Err(PaymentError::TabSpendCapExceeded {
+                tab_id,
+                window_seconds,
+                retry_after,
+                ..
+            }) => {
+                assert_eq!(tab_id, "0x7");
+                assert_eq!(window_seconds, 60);
+                assert!((1..=60).contains(&retry_after), "{retry_after}");
+            }
+            other => panic!("{:?}", other.err()),
+        }
+        // Each tab has a cap of its own.
+        charge(&cap, 8, 100).unwrap();
+    }
+
+    #[test]
+    fn refunded_charges_free_their_room() {
+        let cap = cap(100, Duration::from_secs(60));
+        charge(&cap, 7, 60).unwrap();
+        let refunded = charge(&cap, 7, 40).unwrap();
+        assert!(charge(&cap, 7, 40).is_err());
+        cap.refund(refunded);
+        charge(&cap, 7, 40).unwrap();
+    }
+
+    #[test]
+    fn charges_leave_the_window() {
+        let cap = cap(100, Duration::from_millis(50));
+        charge(&cap, 7, 100).unwrap();
+        charge(&cap, 8, 100).unwrap();
+        assert!(charge(&cap, 7, 1).is_err());
+        std::thread::sleep(Duration::from_millis(60));
+        charge(&cap, 7, 100).unwrap();
+        // Tab 8's expired charges were pruned along the way.
+        assert!(!cap.charges.lock().contains_key(&U256::from(8)));
+    }
+
+    #[test]
+    fn amount_above_the_cap_waits_a_whole_window() {
+        let cap = cap(100, Duration::from_secs(60));
+        match charge(&cap, 7, 101) {
+            Err(PaymentError::TabSpendCapExceeded { retry_after, .. }) => {
+                assert_eq!(retry_after, 60)
+            }
+            other => panic!("{:?}", other.err()),
+        }
+        assert!(
+            cap.charges
+                .lock()
+                .get(&U256::from(7))
+                .is_none_or(VecDeque::is_empty)
+        );
+    }
+
+    #[test]
+    fn concurrent_charges_cannot_race_past_the_cap() {
+        let cap = cap(100, Duration::from_secs(60));
+        let barrier = Arc::new(Barrier::new(32));
+        let accepted = std::thread::scope(|scope| {
+            let racers: Vec<_> = (0..32)
+                .map(|_| {
+                    let cap = cap.clone();
+                    let barrier = barrier.clone();
+                    scope.spawn(move || {
+                        barrier.wait();
+                        charge(&cap, 7, 10).is_ok()
+                    })
+                })
+                .collect();
+            racers
+                .into_iter()
+                .map(|racer| racer.join().unwrap())
+                .filter(|accepted| *accepted)
+                .count()
+        });
+        assert_eq!(accepted, 10);
+    }
+}
@@ -0,0 +1,22 @@
+use anyhow::Context;
+use axum_server::tls_rustls::RustlsConfig;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+
+/// Loads a rustls server config from PEM files, naming the offending file on failure.
+pub async fn load_rustls_config(cert_path: &str, key_path: &str) -> anyhow::Result<RustlsConfig> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to parse TLS certificate PEM file {cert_path}"))?;
+    if certs.is_empty() {
+        anyhow::bail!("TLS certificate PEM file {cert_path} contains no certificates");
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .with_context(|| format!("Failed to parse TLS private key PEM file {key_path}"))?;
+
+    RustlsConfig::from_der(
+        certs.into_iter().map(|cert| cert.to_vec()).collect(),
+        key.secret_der().to_vec(),
+    )
+    .await
+    .with_context(|| format!("Invalid TLS certificate/key pair: {cert_path}, {key_path}"))
+}
@@ -0,0 +1,112 @@
+use std::{
+    io,
+    os::unix::{fs::FileTypeExt, fs::PermissionsExt, net::UnixStream},
+    path::Path,
+};
+use tokio::net::UnixListener;
+
+use crate::http::config::SocketMode;
+
+/// Binds a Unix socket at `path`, replacing a stale socket a previous run left behind.
+///
+/// Refuses to replace anything that is not a socket, or a socket another process still
+/// accepts connections on.
+pub fn bind(path: &str, mode: Option<SocketMode>) -> io::Result<UnixListener> {
+    let socket = Path::new(path);
+    if let Ok(metadata) = std::fs::symlink_metadata(socket) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{path} exists and is not a socket"),
+            ));
+        }
+        if UnixStream::connect(socket).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{path} is in use by another server"),
+            ));
+        }
+        std::fs::remove_file(socket)?;
+    }
+    let listener = UnixListener::bind(socket)?;
+    if let Some(SocketMode(mode)) = mode {
+        std::fs::set_permissions(socket, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
+
+/// Removes the socket file once the server stopped serving on it.
+pub fn remove(path: &str) {
+    if let Err(e) = std::fs::remove_file(path)
+        && e.kind() != io::ErrorKind::NotFound
+    {
+        tracing::warn!("Failed to remove Unix socket {}: {}", path, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, routing::get};
+    use std::future::IntoFuture;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_util::sync::CancellationToken;
+
+    fn socket_path(name: &str) -> String {
+        let directory =
+            std::env::temp_dir().join(format!("unix-socket-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        directory.join(name).display().to_string()
+    }
+
+    #[tokio::test]
+    async fn serves_over_the_socket_and_removes_it_on_shutdown() {
+        let path = socket_path("serve.sock");
+        let listener = bind(&path, Some(SocketMode(0o600))).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let shutdown = CancellationToken::new();
+        let app = Router::new().route("/healthz", get(|| async { "ok" }));
+        let server = tokio::spawn(
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown.clone().cancelled_owned())
+                .into_future(),
+        );
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("ok"), "{response}");
+
+        shutdown.cancel();
+        server.await.unwrap().unwrap();
+        remove(&path);
+        assert!(!Path::new(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn replaces_only_a_stale_socket() {
+        let path = socket_path("stale.sock");
+        let listener = bind(&path, None).unwrap();
+        let err = bind(&path, None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+
+        drop(listener);
+        assert!(Path::new(&path).exists());
+        let _listener = bind(&path, None).unwrap();
+        remove(&path);
+
+        let file = socket_path("not-a-socket");
+        std::fs::write(&file, b"keep me").unwrap();
+        let err = bind(&file, None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(&file).unwrap(), b"keep me");
+        std::fs::remove_file(file).unwrap();
+    }
+}
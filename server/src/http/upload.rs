@@ -0,0 +1,241 @@
+use axum::{
+    Json, Router,
+    body::Body,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode, header::CONTENT_LENGTH},
+    response::{IntoResponse, Response},
+    routing::put,
+};
+use http_body::Body as _;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::{
+    io,
+    path::{Component, Path as FsPath, PathBuf},
+    pin::Pin,
+};
+use tokio::io::AsyncWriteExt;
+use tracing::info;
+
+use crate::http::{
+    admin::bearer_matches,
+    config::{Config, StorageKind},
+    model::ApiError,
+    router::AppState,
+};
+
+/// Publisher uploads into `FILE_DIRECTORY`, mounted only for the `local` storage backend.
+pub fn router(config: &Config) -> Router<AppState> {
+    if config.storage_backend != StorageKind::Local {
+        return Router::new();
+    }
+    Router::new().route("/upload/{*filename}", put(handle_upload))
+}
+
+#[derive(Debug, Deserialize)]
+struct UploadQuery {
+    /// Replace an existing file instead of answering 409.
+    #[serde(default)]
+    overwrite: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Uploaded {
+    /// Path under the storage root, as `/stream/{filename}` serves it.
+    path: String,
+    size: u64,
+    /// Hex SHA-256 of the stored bytes.
+    sha256: String,
+}
+
+/// Streams the body to a temporary file next to the target, then renames it into place
+/// so readers never see a partial file.
+async fn handle_upload(
+    State(state): State<AppState>,
+    Path(filename): Path<String>,
+    Query(query): Query<UploadQuery>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, ApiError> {
+    let config = &state.config;
+    if !bearer_matches(&headers, config.upload_token.as_deref()) {
+        return Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "unauthorized",
+            "Unauthorized",
+        ));
+    }
+    let extension = FsPath::new(&filename)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default();
+    if !config.upload_extension_allowed(extension) {
+        return Err(ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "extension_not_allowed",
+            format!(
+                "Uploads must have one of the extensions: {}",
+                config.upload_extensions
+            ),
+        ));
+    }
+    let declared = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > config.upload_max_bytes) {
+        return Err(too_large(config.upload_max_bytes));
+    }
+
+    let target = upload_target(FsPath::new(&config.file_directory), &filename).await?;
+    if !query.overwrite && tokio::fs::try_exists(&target).await.unwrap_or(false) {
+        return Err(file_exists());
+    }
+
+    let temp = target.with_file_name(format!(
+        ".{}.upload-{:016x}",
+        target.file_name().unwrap_or_default().to_string_lossy(),
+        rand::random::<u64>()
+    ));
+    let written = match write_body(body, &temp, config.upload_max_bytes).await {
+        Ok(written) => written,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&temp).await;
+            return Err(e);
+        }
+    };
+    let placed = if query.overwrite {
+        tokio::fs::rename(&temp, &target).await
+    } else {
+        // Linking fails if the target appeared meanwhile, where a rename would replace it.
+        tokio::fs::hard_link(&temp, &target).await
+    };
+    let _ = tokio::fs::remove_file(&temp).await;
+    match placed {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Err(file_exists()),
+        Err(e) => {
+            return Err(ApiError::internal(
+                "upload_failed",
+                "Failed to store upload",
+                e,
+            ));
+        }
+    }
+
+    info!(path = %filename, size = written.size, sha256 = %written.sha256, "Stored upload");
+    Ok((
+        StatusCode::CREATED,
+        Json(Uploaded {
+            path: filename,
+            size: written.size,
+            sha256: written.sha256,
+        }),
+    )
+        .into_response())
+}
+
+/// Resolves `filename` under `base` with the traversal rules of
+/// [`server::io::verify_file`], creating missing parent directories.
+async fn upload_target(base: &FsPath, filename: &str) -> Result<PathBuf, ApiError> {
+    let relative = FsPath::new(filename);
+    if relative.file_name().is_none()
+        || relative
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(access_denied());
+    }
+    let base = tokio::fs::canonicalize(base)
+        .await
+        .map_err(|e| ApiError::internal("upload_failed", "Failed to open storage root", e))?;
+    let target = base.join(relative);
+    let parent = target.parent().unwrap_or(&base);
+
+    // Checked before creating anything so a symlinked directory can't lead outside.
+    let mut existing = parent;
+    while !tokio::fs::try_exists(existing).await.unwrap_or(false) {
+        existing = existing.parent().unwrap_or(&base);
+    }
+    let existing = tokio::fs::canonicalize(existing)
+        .await
+        .map_err(|e| ApiError::internal("upload_failed", "Failed to resolve upload path", e))?;
+    if !existing.starts_with(&base) {
+        return Err(access_denied());
+    }
+    tokio::fs::create_dir_all(parent)
+        .await
+        .map_err(|e| ApiError::internal("upload_failed", "Failed to create directory", e))?;
+    if tokio::fs::metadata(&target)
+        .await
+        .is_ok_and(|meta| meta.is_dir())
+    {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "not_a_file",
+            "Not a file",
+        ));
+    }
+    Ok(target)
+}
+
+struct Written {
+    size: u64,
+    sha256: String,
+}
+
+/// Writes `body` to `path`, hashing as it goes and failing as soon as it exceeds
+/// `max_bytes`.
+async fn write_body(mut body: Body, path: &FsPath, max_bytes: u64) -> Result<Written, ApiError> {
+    let write_failed =
+        |e: io::Error| ApiError::internal("upload_failed", "Failed to store upload", e);
+    let mut file = tokio::fs::File::create(path).await.map_err(write_failed)?;
+    let mut hasher = Sha256::new();
+    let mut size: u64 = 0;
+    while let Some(frame) = std::future::poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await {
+        let frame = frame.map_err(|e| {
+            ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "upload_interrupted",
+                "Failed to read upload",
+            )
+            .with_details(json!({ "reason": e.to_string() }))
+        })?;
+        let Ok(chunk) = frame.into_data() else {
+            continue;
+        };
+        size = size.saturating_add(chunk.len() as u64);
+        if size > max_bytes {
+            return Err(too_large(max_bytes));
+        }
+        hasher.update(&chunk);
+        file.write_all(&chunk).await.map_err(write_failed)?;
+    }
+    file.sync_all().await.map_err(write_failed)?;
+    Ok(Written {
+        size,
+        sha256: format!("{:x}", hasher.finalize()),
+    })
+}
+
+fn too_large(max_bytes: u64) -> ApiError {
+    ApiError::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "upload_too_large",
+        format!("Uploads may be at most {max_bytes} bytes"),
+    )
+    .with_details(json!({ "maxBytes": max_bytes }))
+}
+
+fn file_exists() -> ApiError {
+    ApiError::new(
+        StatusCode::CONFLICT,
+        "file_exists",
+        "File exists; retry with ?overwrite=true to replace it",
+    )
+}
+
+fn access_denied() -> ApiError {
+    ApiError::new(StatusCode::FORBIDDEN, "access_denied", "Access denied")
+}
@@ -0,0 +1,142 @@
+use axum::{
+    Extension, Json, Router,
+    extract::{State, rejection::JsonRejection},
+    http::StatusCode,
+    middleware,
+    routing::post,
+};
+use percent_encoding::percent_decode_str;
+use server::io::{IpfsPath, StorageBackend};
+use tower_http::request_id::RequestId;
+use url::Url;
+
+use crate::http::{
+    model::{ApiError, ApiErrorBody, PaymentVerifyRequest, PaymentVerifyResponse},
+    pricing::PricedRoute,
+    rate_limit::rate_limit,
+    router::AppState,
+    x402::{self, PricedTarget},
+};
+
+/// `/x402/verify`, rate limited like the paid routes it checks payments for.
+pub fn router(state: &AppState) -> Router<AppState> {
+    Router::new().route(
+        "/x402/verify",
+        post(handle_verify).route_layer(middleware::from_fn_with_state(state.clone(), rate_limit)),
+    )
+}
+
+/// Checks a payment header against what the paywall asks for `resource`, up to the
+/// facilitator's `/verify`, and reports every step. Nothing is settled, served, or
+/// recorded, so the header can still pay afterwards.
+#[utoipa::path(
+    post,
+    path = "/x402/verify",
+    tag = "payments",
+    request_body = PaymentVerifyRequest,
+    responses(
+        (status = 200, description = "The diagnosis; `diagnosis.valid` tells whether the paywall would settle the payment", body = PaymentVerifyResponse),
+        (status = 400, description = "`invalid_verify_request`: the body is not valid JSON; `invalid_resource`: the resource is not a paid route of this server; `invalid_cid` or `invalid_ipfs_path`", body = ApiErrorBody),
+        (status = 403, description = "`access_denied`: the path leaves the storage root", body = ApiErrorBody),
+        (status = 404, description = "`file_not_found`: no such file", body = ApiErrorBody),
+        (status = 422, description = "`invalid_verify_request`: the body is missing fields", body = ApiErrorBody),
+        (status = 429, description = "`rate_limited`: retry after `Retry-After` seconds", body = ApiErrorBody),
+    )
+)]
+pub(super) async fn handle_verify(
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+    body: Result<Json<PaymentVerifyRequest>, JsonRejection>,
+) -> Result<Json<PaymentVerifyResponse>, ApiError> {
+    let Json(body) = body.map_err(|rejection| {
+        ApiError::new(
+            rejection.status(),
+            "invalid_verify_request",
+            rejection.body_text(),
+        )
+    })?;
+    let target = stream_target(&state, body.resource.trim())?;
+    let path = target
+        .split_once('?')
+        .map_or(target.as_str(), |(path, _)| path);
+    let header = body.payment_header.trim();
+
+    if path == "/stream/remote" {
+        let target = PricedTarget {
+            route: PricedRoute::Remote,
+            target: &target,
+            file: None,
+            mount: None,
+            ipfs: None,
+        };
+        return x402::diagnose(&state, target, header, &request_id)
+            .await
+            .map(Json);
+    }
+    if state.config.ipfs_gateways.is_some()
+        && let Some(rest) = path.strip_prefix("/stream/ipfs/")
+    {
+        let rest = percent_decode_str(rest).decode_utf8_lossy();
+        let ipfs = match rest.split_once('/') {
+            Some((cid, sub_path)) => IpfsPath::new(cid, Some(sub_path)),
+            None => IpfsPath::new(&rest, None),
+        }?;
+        let target = PricedTarget {
+            route: PricedRoute::Ipfs,
+            target: &target,
+            file: None,
+            mount: None,
+            ipfs: Some(&ipfs),
+        };
+        return x402::diagnose(&state, target, header, &request_id)
+            .await
+            .map(Json);
+    }
+    let Some(filename) = path.strip_prefix("/stream/") else {
+        return Err(invalid_resource(&body.resource));
+    };
+    let filename = percent_decode_str(filename).decode_utf8_lossy();
+    let (file, mount) = match state.mounts.resolve(&filename) {
+        Some((mount, path)) => (mount.storage.verify(path).await?, Some(mount)),
+        None => (state.storage.verify(&filename).await?, None),
+    };
+    let target = PricedTarget {
+        route: PricedRoute::Stream,
+        target: &target,
+        file: Some(&file),
+        mount: mount.map(AsRef::as_ref),
+        ipfs: None,
+    };
+    x402::diagnose(&state, target, header, &request_id)
+        .await
+        .map(Json)
+}
+
+/// The path and query a payment for `resource` is checked on: an `ipfs://{cid}/{path}`
+/// resource, a URL under `SERVER_ADVERTISED_URL`, or a path on this server.
+fn stream_target(state: &AppState, resource: &str) -> Result<String, ApiError> {
+    if let Some(rest) = resource.strip_prefix("ipfs://") {
+        return Ok(format!("/stream/ipfs/{rest}"));
+    }
+    if resource.starts_with('/') {
+        return Ok(resource.to_string());
+    }
+    let url = Url::parse(resource).map_err(|_| invalid_resource(resource))?;
+    if url.origin() != state.config.server_advertised_url.origin() {
+        return Err(invalid_resource(resource));
+    }
+    let mut target = url.path().to_string();
+    if let Some(query) = url.query() {
+        target.push('?');
+        target.push_str(query);
+    }
+    Ok(target)
+}
+
+fn invalid_resource(resource: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "invalid_resource",
+        format!("{resource} is not a paid resource of this server"),
+    )
+}
@@ -0,0 +1,125 @@
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::Client;
+use serde::Serialize;
+use server::x402::SettlementSummary;
+use sha2::Sha256;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+use url::Url;
+
+/// Header carrying `sha256=<hex HMAC of the body>` when `WEBHOOK_SECRET` is set.
+pub const SIGNATURE_HEADER: &str = "x-webhook-signature";
+
+const MAX_ATTEMPTS: u32 = 4;
+const BASE_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SettlementEvent {
+    pub resource: String,
+    #[serde(flatten)]
+    pub settlement: SettlementSummary,
+    pub timestamp: String,
+}
+
+/// Delivers settlement events to `WEBHOOK_URL` from a background task.
+///
+/// The queue is bounded; events that don't fit are dropped with a warning so a
+/// dead endpoint can't exhaust memory or slow down paid requests.
+#[derive(Clone)]
+pub struct WebhookNotifier {
+    tx: mpsc::Sender<SettlementEvent>,
+}
+
+impl WebhookNotifier {
+    pub fn spawn(
+        url: Url,
+        secret: Option<String>,
+        queue_size: usize,
+        shutdown: CancellationToken,
+    ) -> Self {
+        let (tx, mut rx) = mpsc::channel::<SettlementEvent>(queue_size.max(1));
+        let client = Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .unwrap_or_default();
+
+        tokio::spawn(async move {
+            loop {
+                let event = tokio::select! {
+                    event = rx.recv() => match event {
+                        Some(event) => event,
+                        None => break,
+                    },
+                    _ = shutdown.cancelled() => break,
+                };
+                deliver(&client, &url, secret.as_deref(), &event).await;
+            }
+        });
+
+        Self { tx }
+    }
+
+    pub fn notify(&self, resource: String, settlement: &SettlementSummary) {
+        let event = SettlementEvent {
+            resource,
+            settlement: settlement.clone(),
+            timestamp: Utc::now().to_rfc3339(),
+        };
+        if let Err(e) = self.tx.try_send(event) {
+            warn!("Dropping settlement webhook event: {}", e);
+        }
+    }
+}
+
+async fn deliver(client: &Client, url: &Url, secret: Option<&str>, event: &SettlementEvent) {
+    let body = match serde_json::to_vec(event) {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Failed to serialize settlement webhook event: {}", e);
+            return;
+        }
+    };
+    let signature = secret.map(|secret| {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(&body);
+        format!("sha256={:x}", mac.finalize().into_bytes())
+    });
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        let mut req = client
+            .post(url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.clone());
+        if let Some(signature) = &signature {
+            req = req.header(SIGNATURE_HEADER, signature);
+        }
+        match req.send().await {
+            Ok(resp) if resp.status().is_success() => {
+                debug!("Delivered settlement webhook to {}", url);
+                return;
+            }
+            Ok(resp) => warn!(
+                "Settlement webhook returned {} (attempt {}/{})",
+                resp.status(),
+                attempt,
+                MAX_ATTEMPTS
+            ),
+            Err(e) => warn!(
+                "Settlement webhook delivery failed (attempt {}/{}): {}",
+                attempt, MAX_ATTEMPTS, e
+            ),
+        }
+        if attempt < MAX_ATTEMPTS {
+            tokio::time::sleep(BASE_DELAY * 2u32.pow(attempt - 1)).await;
+        }
+    }
+    warn!(
+        "Dropping settlement webhook event for {} after {} attempts",
+        event.resource, MAX_ATTEMPTS
+    );
+}
@@ -1,16 +1,72 @@
 use axum::{
     Json,
-    http::{HeaderMap, HeaderValue},
+    extract::{Request, State},
+    http::{HeaderMap, HeaderValue, Method, Uri},
+    middleware::Next,
     response::{IntoResponse, Response},
 };
 use base64::{Engine, prelude::BASE64_STANDARD};
+use chrono::Utc;
 use http::StatusCode;
-use log::{error, info, warn};
+use percent_encoding::{AsciiSet, CONTROLS, percent_decode_str, utf8_percent_encode};
 use sdk_4mica::U256;
+use server::{
+    PaymentError,
+    io::{FileInfo, IpfsPath},
+    x402::{
+        BASE64_URL_SAFE_LENIENT, BackgroundSettlers, Facilitator, FacilitatorClientError,
+        MeteredPayment, ResourceMeta, SettlementSummary,
+    },
+};
+use std::{
+    path::{Component, Path},
+    sync::{Arc, OnceLock},
+};
+use tower_http::request_id::RequestId;
+use tracing::{error, info, warn};
+use url::form_urlencoded;
+
+use crate::http::{
+    access_log::PaymentLog,
+    audit::{AuditEntry, Decision},
+    bundle::{Bundle, VerifiedBundle},
+    client_ip::{ClientIp, client_key},
+    free_paths::resource_path,
+    metered::meter,
+    model::{ApiError, PaymentRequiredResponse, PaymentVerifyResponse, PriceQuote},
+    mounts::Mount,
+    pricing::{PricedRoute, is_playlist},
+    router::AppState,
+    session::SESSION_HEADER,
+    tab_spend::TabCharge,
+};
+
+/// Request headers that may carry a payment, in order of preference.
+const PAYMENT_HEADERS: [&str; 2] = ["payment-signature", "x-payment"];
+
+/// Query parameter that may carry the payment when `X402_ALLOW_QUERY_PAYMENT` is set.
+const PAYMENT_QUERY_PARAM: &str = "payment";
 
-use crate::http::{model::PaymentRequiredResponse, router::AppState};
+/// Characters encoded again in a decoded path segment of a resource URL; `%` among them,
+/// so an escape left after the one decode is never read as one.
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
 
-fn encode_payment_required_header(required: &server::x402::PaymentRequiredV2) -> Option<HeaderValue> {
+/// Response header carrying the base64 JSON settlement summary after a fresh payment.
+pub const PAYMENT_RESPONSE_HEADER: &str = "payment-response";
+
+fn encode_payment_required_header(
+    required: &server::x402::PaymentRequiredV2,
+) -> Option<HeaderValue> {
     let json = serde_json::to_vec(required).ok()?;
     let encoded = BASE64_STANDARD.encode(json);
     HeaderValue::from_str(&encoded).ok()
@@ -20,6 +76,7 @@ fn build_payment_required_response(
     payment_requirements: Vec<sdk_4mica::x402::PaymentRequirements>,
     payment_required_v2: Option<&server::x402::PaymentRequiredV2>,
     error: Option<String>,
+    error_code: Option<&str>,
 ) -> Response {
     let error_clone = error.clone();
     let mut resp = (
@@ -28,6 +85,7 @@ fn build_payment_required_response(
             x402_version: server::x402::X402_VERSION,
             accepts: payment_requirements,
             error,
+            error_code: error_code.map(str::to_string),
         }),
     )
         .into_response();
@@ -35,102 +93,1043 @@ fn build_payment_required_response(
         let mut payment_required_v2 = payment_required_v2.clone();
         payment_required_v2.error = error_clone;
         if let Some(header) = encode_payment_required_header(&payment_required_v2) {
-            resp.headers_mut()
-                .insert("payment-required", header);
+            resp.headers_mut().insert("payment-required", header);
         }
     }
     resp
 }
 
-pub async fn handle_x402_paywall(
-    state: &AppState,
-    price: U256,
-    resource: String,
-    headers: HeaderMap,
-) -> Result<(), Response> {
-    info!(
-        "x402 paywall check: resource={}, price_wei={:#x}",
-        resource, price
-    );
-    let tab_endpoint = match state.config.server_advertised_url.join("/tab") {
-        Ok(tab_endpoint) => tab_endpoint,
+/// Request extension inserted by [`require_payment`] once the paywall lets a request through.
+#[derive(Clone, Debug)]
+pub struct PaidRequest {
+    /// The fresh settlement, or `None` when a payment session covered the request.
+    pub settlement: Option<SettlementSummary>,
+}
+
+impl PaidRequest {
+    /// The address that paid, when the payment settled on this request.
+    pub fn payer(&self) -> Option<&str> {
+        self.settlement.as_ref()?.payer.as_deref()
+    }
+
+    /// Adds the base64 JSON settlement summary as the `payment-response` header.
+    pub fn attach(&self, resp: &mut Response) {
+        let Some(settlement) = &self.settlement else {
+            return;
+        };
+        let Ok(json) = serde_json::to_vec(settlement) else {
+            return;
+        };
+        if let Ok(header) = HeaderValue::from_str(&BASE64_STANDARD.encode(json)) {
+            resp.headers_mut().insert(PAYMENT_RESPONSE_HEADER, header);
+        }
+    }
+}
+
+/// State for the [`require_payment`] middleware: the app plus which route price applies.
+#[derive(Clone)]
+pub struct Paywall {
+    state: AppState,
+    route: PricedRoute,
+}
+
+impl Paywall {
+    pub fn new(state: AppState, route: PricedRoute) -> Self {
+        Self { state, route }
+    }
+}
+
+/// Charges for the request before running the handler; add it to paid routes with
+/// `.route_layer(middleware::from_fn_with_state(Paywall::new(..), require_payment))`.
+///
+/// Resources matching `X402_FREE_PATHS` (by default `.m3u8` playlists and `.mpd` manifests,
+/// unless `X402_CHARGE_PLAYLISTS` is set) pass through free without touching the preview
+/// quota.
+/// Handlers can read [`PaidRequest`] from the request extensions.
+pub async fn require_payment(
+    State(paywall): State<Paywall>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let state = &paywall.state;
+    if !state.config.x402.enabled
+        || state
+            .free_paths
+            .is_free_request(paywall.route, request.uri())
+    {
+        return next.run(request).await;
+    }
+
+    // The payment is not part of what is paid for, so it never reaches the resource URL.
+    let (target, mut query_payment) = if state.config.x402.allow_query_payment {
+        take_query_payment(request.uri())
+    } else {
+        (request.uri().to_string(), None)
+    };
+    let ipfs = request.extensions().get::<IpfsPath>();
+    let resource = match ipfs {
+        // The content, not the gateway or route serving it, is what the payment names.
+        Some(path) => path.resource(),
+        None => match resource_url(state, &target) {
+            Ok(resource) => resource,
+            Err(e) => return e.into_response(),
+        },
+    };
+
+    let mut headers = request.headers().clone();
+    if request.method() == Method::HEAD && state.config.x402.free_head {
+        // No bytes are delivered, so never settle; clients still learn the price.
+        for name in PAYMENT_HEADERS {
+            headers.remove(name);
+        }
+        query_payment = None;
+    }
+    let bundle = request.extensions().get::<VerifiedBundle>();
+    // HEAD delivers no bytes, so it neither uses nor needs preview quota, and a bundle is
+    // never a preview.
+    let preview = (request.method() != Method::HEAD && bundle.is_none()).then(|| PreviewClaim {
+        client: client_key(&request),
+        bytes: request.extensions().get::<FileInfo>().map(|file| file.len),
+    });
+
+    let file = request.extensions().get::<FileInfo>();
+    let mount = request.extensions().get::<Arc<Mount>>();
+    let price = bundle
+        .map(|bundle| bundle.bundle.price)
+        .or_else(|| mount.and_then(|mount| mount.price))
+        .unwrap_or_else(|| {
+            state.pricing.current().price(
+                paywall.route,
+                file,
+                is_playlist_request(paywall.route, request.uri()),
+            )
+        });
+    let meta = match (request.extensions().get::<IpfsPath>(), bundle) {
+        (Some(path), _) => ipfs_meta(path),
+        (None, Some(bundle)) => bundle_meta(bundle),
+        (None, None) => resource_meta(state, &target, file),
+    };
+    let request_id = request.extensions().get::<RequestId>().cloned();
+    let pass = match handle_x402_paywall(
+        state,
+        price,
+        resource.clone(),
+        &headers,
+        query_payment.as_deref(),
+        PaywallRequest {
+            meta,
+            preview,
+            request_id,
+            metered: metered_rate(state, paywall.route),
+            client_ip: request.extensions().get::<ClientIp>().copied(),
+            pay_to: mount.map(|mount| mount.pay_to.clone()),
+            payment_log: request.extensions().get::<PaymentLog>().cloned(),
+        },
+    )
+    .await
+    {
+        Ok(pass) => pass,
+        Err(resp) => return resp,
+    };
+
+    request.extensions_mut().insert(PaidRequest {
+        settlement: pass.settlement,
+    });
+    let mut resp = next.run(request).await;
+    if resp.status().is_success()
+        && let Some(token) = pass.session_token
+    {
+        resp.headers_mut().insert(SESSION_HEADER, token);
+    }
+    if let Some(metered) = pass.metered {
+        // Failed responses deliver nothing worth paying for.
+        let charged = resp.status().is_success();
+        let max_bytes = metered.payment.max_bytes();
+        let state = state.clone();
+        resp = resp.map(|body| {
+            meter(body, max_bytes, move |bytes| {
+                let bytes = if charged { bytes } else { 0 };
+                tokio::spawn(settle_metered(state, resource, metered, bytes));
+            })
+        });
+    }
+    resp
+}
+
+/// The per-byte rate `route` is offered at with the metered scheme, if any.
+fn metered_rate(state: &AppState, route: PricedRoute) -> Option<U256> {
+    state
+        .config
+        .x402
+        .price_per_byte
+        .filter(|_| state.config.x402.enable_4mica)
+        .filter(|_| matches!(route, PricedRoute::Remote | PricedRoute::Ipfs))
+}
+
+/// Settles a metered payment once its response delivered `bytes`, recording the outcome
+/// like an upfront settlement.
+async fn settle_metered(state: AppState, resource: String, metered: MeteredPass, bytes: u64) {
+    let MeteredPass {
+        payment,
+        facilitator,
+        client_ip,
+    } = metered;
+    let payer = payment.summary().payer.clone();
+    if payment.amount_for(bytes).is_zero() {
+        info!(bytes, "x402 metered payment delivered nothing; not settled");
+        return;
+    }
+    let result = payment
+        .settle(
+            &resource,
+            bytes,
+            facilitator.as_ref(),
+            state.deferred.as_deref(),
+        )
+        .await;
+    if let Some(store) = &state.settlement_store {
+        store.record(&resource, payer.clone(), &result);
+    }
+    match result {
+        Ok(settlement) => {
+            info!(bytes, amount = %settlement.amount, "x402 metered payment settled");
+            state
+                .ledger
+                .record(&settlement, &resource, Utc::now().timestamp());
+            if let Some(webhook) = &state.webhook {
+                webhook.notify(resource.clone(), &settlement);
+            }
+            audit(
+                &state,
+                client_ip,
+                AuditEntry {
+                    scheme: Some(settlement.scheme.clone()),
+                    payer: settlement.payer.clone(),
+                    amount: Some(settlement.amount.clone()),
+                    ..AuditEntry::new(Decision::Settled, &resource)
+                },
+            );
+        }
         Err(e) => {
-            error!("Failed to construct tab endpoint: {}", e);
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to construct tab endpoint",
+            error!(bytes, "x402 metered payment settlement failed: {}", e);
+            audit(
+                &state,
+                client_ip,
+                AuditEntry {
+                    payer,
+                    error: Some(e.to_string()),
+                    ..AuditEntry::new(Decision::SettlementFailed, &resource)
+                },
+            );
+        }
+    }
+}
+
+/// The absolute URL a payment for `target` (path and query) must name.
+fn resource_url(state: &AppState, target: &str) -> Result<String, ApiError> {
+    let target = resource_target(target)?;
+    state
+        .config
+        .server_advertised_url
+        .join(&target)
+        .map(|resource| resource.to_string())
+        .map_err(|e| {
+            ApiError::internal(
+                "invalid_resource_url",
+                "Failed to construct resource URL",
+                e,
             )
-                .into_response());
+        })
+}
+
+/// `target` with its path decoded once and encoded again segment by segment, so every
+/// spelling of a nested `/stream/` path, `%2F` included, names the same resource. A path
+/// that decodes to anything but plain names (`..`, `.`, a second root, backslashes) is
+/// rejected rather than resolved, and what is left encoded after the one decode stays
+/// part of the name.
+fn resource_target(target: &str) -> Result<String, ApiError> {
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    };
+    let decoded = percent_decode_str(path).decode_utf8_lossy();
+    let relative = decoded.strip_prefix('/').unwrap_or(&decoded);
+    // `Path::components` would quietly drop `.` and doubled slashes, so segments are
+    // checked one by one; only a trailing slash may leave one empty.
+    let names = relative.strip_suffix('/').unwrap_or(relative);
+    let plain = relative.is_empty()
+        || names.split('/').all(|segment| {
+            !segment.contains('\\')
+                && matches!(
+                    Path::new(segment).components().collect::<Vec<_>>()[..],
+                    [Component::Normal(name)] if name == segment
+                )
+        });
+    if !plain {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_path",
+            "The path must only name files below the route",
+        ));
+    }
+    let segments: Vec<String> = relative
+        .split('/')
+        .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT).to_string())
+        .collect();
+    let mut target = format!("/{}", segments.join("/"));
+    if let Some(query) = query {
+        target.push('?');
+        target.push_str(query);
+    }
+    Ok(target)
+}
+
+/// What [`require_payment`] would ask for `target` on `route`, without settling or using
+/// any preview quota; resources that pass through free are quoted at zero. `mount` is the
+/// `MOUNTS` entry `file` was found in, if any.
+pub(super) fn quote(
+    state: &AppState,
+    route: PricedRoute,
+    target: &str,
+    file: Option<&FileInfo>,
+    mount: Option<&Mount>,
+) -> Result<PriceQuote, ApiError> {
+    let priced = PricedTarget {
+        route,
+        target,
+        file,
+        mount,
+        ipfs: None,
+    }
+    .price(state)?;
+    Ok(PriceQuote {
+        x402_version: server::x402::X402_VERSION,
+        resource: priced.resource,
+        price: format!("{:#x}", priced.price),
+        free: priced.offer.is_none(),
+        accepts: priced
+            .offer
+            .map(|offer| offer.requirements)
+            .unwrap_or_default(),
+    })
+}
+
+/// Runs `payment_header` through every check [`require_payment`] would make for the
+/// resource, up to the facilitator's `/verify`, without settling it or serving anything.
+pub(super) async fn diagnose(
+    state: &AppState,
+    target: PricedTarget<'_>,
+    payment_header: &str,
+    request_id: &RequestId,
+) -> Result<PaymentVerifyResponse, ApiError> {
+    let priced = target.price(state)?;
+    let facilitator = state
+        .facilitator
+        .as_ref()
+        .map(|facilitator| facilitator.with_request_id(request_id.clone().into_header_value()));
+    let diagnosis = match &priced.offer {
+        Some(offer) => Some(
+            server::x402::diagnose_payment(
+                payment_header,
+                &priced.resource,
+                &offer.requirements,
+                &offer.requirements_v2,
+                facilitator.as_deref(),
+                &state.rpc,
+                &state.config.x402,
+            )
+            .await,
+        ),
+        None => None,
+    };
+    Ok(PaymentVerifyResponse {
+        resource: priced.resource,
+        price: format!("{:#x}", priced.price),
+        free: diagnosis.is_none(),
+        diagnosis,
+    })
+}
+
+/// A paid resource as [`require_payment`] sees it once its route's middleware ran.
+pub(super) struct PricedTarget<'a> {
+    pub route: PricedRoute,
+    /// Path and query of the request.
+    pub target: &'a str,
+    /// The verified local file, if any.
+    pub file: Option<&'a FileInfo>,
+    /// The `MOUNTS` entry `file` was found in, if any.
+    pub mount: Option<&'a Mount>,
+    /// The content an IPFS route names.
+    pub ipfs: Option<&'a IpfsPath>,
+}
+
+/// A [`PricedTarget`]'s resource URL and price, and the offer a 402 would list unless it is
+/// free.
+struct Priced {
+    resource: String,
+    price: U256,
+    offer: Option<Offer>,
+}
+
+impl PricedTarget<'_> {
+    fn price(&self, state: &AppState) -> Result<Priced, ApiError> {
+        let resource = match self.ipfs {
+            Some(path) => path.resource(),
+            None => resource_url(state, self.target)?,
+        };
+        let uri = self.target.parse::<Uri>().ok();
+        let free = !state.config.x402.enabled
+            || uri
+                .as_ref()
+                .is_some_and(|uri| state.free_paths.is_free_request(self.route, uri));
+        if free {
+            return Ok(Priced {
+                resource,
+                price: U256::ZERO,
+                offer: None,
+            });
+        }
+        let playlist = uri
+            .as_ref()
+            .is_some_and(|uri| is_playlist_request(self.route, uri));
+        let price = self.mount.and_then(|mount| mount.price).unwrap_or_else(|| {
+            state
+                .pricing
+                .current()
+                .price(self.route, self.file, playlist)
+        });
+        let meta = match self.ipfs {
+            Some(path) => ipfs_meta(path),
+            None => resource_meta(state, self.target, self.file),
+        };
+        let offer = advertise(
+            state,
+            price,
+            &resource,
+            meta,
+            metered_rate(state, self.route),
+            self.mount.map(|mount| mount.pay_to.as_str()),
+        )?;
+        Ok(Priced {
+            resource,
+            price,
+            offer: Some(offer),
+        })
+    }
+}
+
+/// Wallet-facing description of what `target` (path and query) sells; `file` is the
+/// verified local file, if any.
+fn resource_meta(state: &AppState, target: &str, file: Option<&FileInfo>) -> ResourceMeta {
+    if let Some(file) = file {
+        let name = file
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let description = match state.pricing.current().segment_millis(file) {
+            Some(millis) => format!("HLS segment {}, {:.1}s", name, millis as f64 / 1000.0),
+            None if name.ends_with(".mpd") => format!("DASH manifest {}", name),
+            None if is_playlist(&name) => format!("HLS playlist {}", name),
+            None if file.mime.is_some() => format!("Media segment {}", name),
+            None => format!("File {}", name),
+        };
+        return ResourceMeta {
+            description: Some(description),
+            mime_type: file.mime.map(str::to_string),
+        };
+    }
+    let url = target.split_once('?').and_then(|(_, query)| {
+        form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == "url")
+            .map(|(_, value)| value.into_owned())
+    });
+    match url {
+        Some(url) => ResourceMeta {
+            mime_type: mime_type_for(url.split(['?', '#']).next().unwrap_or_default()),
+            description: Some(format!("Remote file {}", url)),
+        },
+        None => ResourceMeta::default(),
+    }
+}
+
+/// Wallet-facing description of IPFS content.
+fn bundle_meta(bundle: &VerifiedBundle) -> ResourceMeta {
+    let Bundle { name, files, .. } = bundle.bundle.as_ref();
+    ResourceMeta {
+        description: Some(format!("Bundle {} of {} files", name, files.len())),
+        mime_type: Some("application/zip".to_string()),
+    }
+}
+
+fn ipfs_meta(path: &IpfsPath) -> ResourceMeta {
+    ResourceMeta {
+        mime_type: mime_type_for(path.file_name()),
+        description: Some(format!("IPFS content {}", path.resource())),
+    }
+}
+
+/// Whether the resource `uri` asks for on `route` is an HLS playlist or DASH manifest.
+fn is_playlist_request(route: PricedRoute, uri: &Uri) -> bool {
+    resource_path(route, uri).is_some_and(|path| is_playlist(&path))
+}
+
+fn mime_type_for(name: &str) -> Option<String> {
+    server::io::mime_type_for(name).map(str::to_string)
+}
+
+/// Splits the `payment` query parameter off `uri`, returning the remaining path and query
+/// and the parameter's value. Other parameters keep their original encoding.
+fn take_query_payment(uri: &Uri) -> (String, Option<String>) {
+    let Some(query) = uri.query() else {
+        return (uri.to_string(), None);
+    };
+    let mut payment = None;
+    let mut kept = Vec::new();
+    for pair in query.split('&') {
+        match form_urlencoded::parse(pair.as_bytes()).next() {
+            Some((key, value)) if key == PAYMENT_QUERY_PARAM => {
+                payment.get_or_insert_with(|| value.into_owned());
+            }
+            _ => kept.push(pair),
         }
+    }
+    let target = if kept.is_empty() {
+        uri.path().to_string()
+    } else {
+        format!("{}?{}", uri.path(), kept.join("&"))
     };
+    (target, payment)
+}
 
-    let payment_requirements = server::x402::build_accepted_payment_requirements(
+/// The payment a request carries, preferring the payment headers over the `payment`
+/// query parameter; a value that cannot be read, or is longer than `max_len`, yields the
+/// error to report.
+fn payment_value(
+    headers: &HeaderMap,
+    query_payment: Option<&str>,
+    max_len: usize,
+) -> Option<Result<String, &'static str>> {
+    if let Some(value) = PAYMENT_HEADERS.iter().find_map(|name| headers.get(*name)) {
+        if value.len() > max_len {
+            warn!(len = value.len(), max_len, "Payment header too large");
+            return Some(Err("Payment header too large"));
+        }
+        return Some(value.to_str().map(str::to_string).map_err(|e| {
+            error!("Invalid payment header: {}", e);
+            "Invalid payment header"
+        }));
+    }
+    // Re-encoded as standard base64 so it settles exactly like a header would.
+    query_payment.map(|value| {
+        if value.len() > max_len {
+            warn!(
+                len = value.len(),
+                max_len, "Payment query parameter too large"
+            );
+            return Err("Payment query parameter too large");
+        }
+        BASE64_URL_SAFE_LENIENT
+            .decode(value)
+            .map(|payload| BASE64_STANDARD.encode(payload))
+            .map_err(|e| {
+                error!("Invalid payment query parameter: {}", e);
+                "Invalid payment query parameter"
+            })
+    })
+}
+
+/// Who is asking for a free preview, and how large the response will be if known.
+struct PreviewClaim {
+    client: String,
+    bytes: Option<u64>,
+}
+
+fn audit(state: &AppState, client_ip: Option<ClientIp>, entry: AuditEntry) {
+    if let Some(audit_log) = &state.audit_log {
+        audit_log.log(AuditEntry {
+            client_ip: client_ip.map(|ClientIp(ip)| ip.to_string()),
+            ..entry
+        });
+    }
+}
+
+/// What the paywall needs to know about the request besides its payment.
+struct PaywallRequest {
+    meta: ResourceMeta,
+    preview: Option<PreviewClaim>,
+    request_id: Option<RequestId>,
+    /// Per-byte rate of the metered offer, when the route makes one.
+    metered: Option<U256>,
+    client_ip: Option<ClientIp>,
+    /// Recipient in place of each network's, for files under a `MOUNTS` entry.
+    pay_to: Option<String>,
+    /// Where the access log picks up the paywall's decision, when it is on.
+    payment_log: Option<PaymentLog>,
+}
+
+/// The payment options advertised for a resource.
+struct Offer {
+    requirements: Vec<sdk_4mica::x402::PaymentRequirements>,
+    requirements_v2: Vec<server::x402::PaymentRequirementsV2>,
+    /// The v2 `payment-required` body listing `requirements_v2`.
+    required_v2: server::x402::PaymentRequiredV2,
+}
+
+/// Builds the [`Offer`] for `resource` at `price`, adding a metered offer capped at `price`
+/// when `metered` has a rate; without a description in `meta`, one naming the resource is
+/// used. `pay_to` replaces the recipient on every network.
+fn advertise(
+    state: &AppState,
+    price: U256,
+    resource: &str,
+    meta: ResourceMeta,
+    metered: Option<U256>,
+    pay_to: Option<&str>,
+) -> Result<Offer, ApiError> {
+    let tab_endpoint = state
+        .config
+        .server_advertised_url
+        .join("/tab")
+        .map_err(|e| {
+            ApiError::internal(
+                "invalid_tab_endpoint",
+                "Failed to construct tab endpoint",
+                e,
+            )
+        })?;
+
+    let meta = ResourceMeta {
+        description: meta
+            .description
+            .or_else(|| Some(format!("Access to resource: {}", resource))),
+        ..meta
+    };
+    let mut requirements = server::x402::build_accepted_payment_requirements(
         &state.config.x402,
         price,
         tab_endpoint.to_string(),
-        Some(resource.clone()),
+        Some(resource.to_string()),
+        &meta,
+        metered,
     );
-    let payment_requirements_v2 = server::x402::build_accepted_payment_requirements_v2(
+    let mut requirements_v2 = server::x402::build_accepted_payment_requirements_v2(
         &state.config.x402,
         price,
         tab_endpoint.to_string(),
     );
-    let description = Some(format!("Access to resource: {}", resource));
-    let payment_required_v2 = server::x402::build_payment_required_v2(
-        payment_requirements_v2.clone(),
+    if let Some(pay_to) = pay_to {
+        for requirement in &mut requirements {
+            requirement.pay_to = pay_to.to_string();
+        }
+        for requirement in &mut requirements_v2 {
+            requirement.pay_to = pay_to.to_string();
+        }
+    }
+    let required_v2 = server::x402::build_payment_required_v2(
+        requirements_v2.clone(),
         server::x402::X402ResourceInfo {
-            url: resource.clone(),
-            description,
-            mime_type: Some("video/mp2t".to_string()),
+            url: resource.to_string(),
+            description: meta.description,
+            mime_type: meta.mime_type,
         },
     );
+    Ok(Offer {
+        requirements,
+        requirements_v2,
+        required_v2,
+    })
+}
+
+/// Outcome of a passed paywall check.
+struct PaywallPass {
+    settlement: Option<SettlementSummary>,
+    /// Session token minted after a fresh settlement.
+    session_token: Option<HeaderValue>,
+    /// A verified metered payment, settled once the response is delivered.
+    metered: Option<MeteredPass>,
+}
+
+/// A metered payment and the facilitator to settle it through.
+struct MeteredPass {
+    payment: MeteredPayment,
+    facilitator: Arc<dyn Facilitator>,
+    client_ip: Option<ClientIp>,
+}
+
+async fn handle_x402_paywall(
+    state: &AppState,
+    price: U256,
+    resource: String,
+    headers: &HeaderMap,
+    query_payment: Option<&str>,
+    request: PaywallRequest,
+) -> Result<PaywallPass, Response> {
+    let PaywallRequest {
+        meta,
+        preview,
+        request_id,
+        metered,
+        client_ip,
+        pay_to,
+        payment_log,
+    } = request;
+    // Every decision goes to the audit log, and to the access log line of the request.
+    let decide = |entry: AuditEntry| {
+        if let Some(payment_log) = &payment_log {
+            payment_log.record(&entry);
+        }
+        audit(state, client_ip, entry);
+    };
+    tracing::Span::current().record("resource", resource.as_str());
+    info!(
+        price_wei = %format!("{:#x}", price),
+        client_ip = client_ip.map(|ClientIp(ip)| ip.to_string()),
+        "x402 paywall check"
+    );
 
-    let payment_header = headers
-        .get("payment-signature")
-        .or_else(|| headers.get("PAYMENT-SIGNATURE"))
-        .or_else(|| headers.get("x-payment"));
+    if let Some(sessions) = &state.sessions
+        && let Some(token) = headers.get(SESSION_HEADER).and_then(|v| v.to_str().ok())
+    {
+        let pay_tos = match &pay_to {
+            Some(pay_to) => vec![pay_to.clone()],
+            None => state
+                .config
+                .x402
+                .networks()
+                .into_iter()
+                .map(|network| network.pay_to)
+                .collect(),
+        };
+        let pay_tos: Vec<&str> = pay_tos.iter().map(String::as_str).collect();
+        if let Some(user) =
+            sessions.verify(token, &resource, price, &pay_tos, Utc::now().timestamp())
+        {
+            info!(%user, "x402 payment session accepted");
+            decide(AuditEntry {
+                payer: Some(user),
+                ..AuditEntry::new(Decision::ServedFree, &resource)
+            });
+            return Ok(PaywallPass {
+                settlement: None,
+                session_token: None,
+                metered: None,
+            });
+        }
+        warn!("x402 payment session rejected; falling back to payment");
+    }
+    if let Some(quota) = &state.previews
+        && let Some(preview) = preview
+        && quota.try_consume(&preview.client, preview.bytes)
+    {
+        info!(client = %preview.client, "x402 free preview granted");
+        decide(AuditEntry::new(Decision::ServedFree, &resource));
+        return Ok(PaywallPass {
+            settlement: None,
+            session_token: None,
+            metered: None,
+        });
+    }
+    let Offer {
+        requirements: payment_requirements,
+        requirements_v2: payment_requirements_v2,
+        required_v2: payment_required_v2,
+    } = advertise(state, price, &resource, meta, metered, pay_to.as_deref())
+        .map_err(IntoResponse::into_response)?;
 
-    let Some(payment_header) = payment_header else {
+    let Some(payment_header) = payment_value(
+        headers,
+        query_payment,
+        state.config.x402.max_payment_header_bytes,
+    ) else {
         warn!("x402 payment header missing; returning 402 with requirements");
+        decide(AuditEntry {
+            amount: Some(price.to_string()),
+            ..AuditEntry::new(Decision::PaymentRequired, &resource)
+        });
         return Err(build_payment_required_response(
             payment_requirements,
             Some(&payment_required_v2),
             None,
+            None,
         ));
     };
-    let payment_header = match payment_header.to_str() {
-        Ok(s) => s.to_string(),
+    let payment_header = match payment_header {
+        Ok(payment_header) => payment_header,
+        Err(message) => {
+            decide(AuditEntry {
+                amount: Some(price.to_string()),
+                error: Some(message.to_string()),
+                ..AuditEntry::new(Decision::PaymentRequired, &resource)
+            });
+            return Err(build_payment_required_response(
+                payment_requirements,
+                Some(&payment_required_v2),
+                Some(message.to_string()),
+                Some("invalid_payload"),
+            ));
+        }
+    };
+
+    // Tagged so the facilitator's logs for this payment carry the same request id.
+    let facilitator = state
+        .facilitator
+        .as_ref()
+        .map(|facilitator| match request_id {
+            Some(id) => facilitator.with_request_id(id.into_header_value()),
+            None => facilitator.clone(),
+        });
+    let permit = match &state.settlement_limiter {
+        Some(limiter) => Some(limiter.acquire().await?),
+        None => None,
+    };
+    // A metered payment is only verified here and settles once the response is delivered.
+    let metered = metered
+        .filter(|_| {
+            server::x402::payment_scheme(&payment_header)
+                .is_some_and(|scheme| scheme.eq_ignore_ascii_case(&state.config.x402.scheme_upto))
+        })
+        .zip(facilitator.clone());
+    let verified = OnceLock::new();
+    let valid_until = server::x402::payment_valid_until(&payment_header, &state.config.x402);
+    let settlement = state
+        .settlements
+        .settle_once(&payment_header, &resource, valid_until, || async {
+            // Reserved before settling, so concurrent payments from one tab can't all fit.
+            // A metered payment counts at its cap.
+            let charge = match &state.tab_spend {
+                Some(cap) => server::x402::payment_tab_id(&payment_header)
+                    .map(|tab_id| cap.try_charge(tab_id, price))
+                    .transpose()?,
+                None => None,
+            };
+            let refund = |charge: Option<TabCharge>| {
+                if let (Some(cap), Some(charge)) = (&state.tab_spend, charge) {
+                    cap.refund(charge);
+                }
+            };
+            if let Some((rate, facilitator)) = &metered {
+                let payment = server::x402::verify_metered_payment(
+                    &payment_header,
+                    &resource,
+                    &payment_requirements,
+                    *rate,
+                    facilitator.as_ref(),
+                    &state.config.x402,
+                )
+                .await
+                .inspect_err(|_| refund(charge))?;
+                let summary = payment.summary().clone();
+                let _ = verified.set(payment);
+                return Ok(summary);
+            }
+            let result = server::x402::settle_payment(
+                &payment_header,
+                &resource,
+                &payment_requirements,
+                &payment_requirements_v2,
+                facilitator.as_deref(),
+                &state.rpc,
+                &state.config.x402,
+                BackgroundSettlers {
+                    deferred: state.deferred.as_deref(),
+                    queue: state.settle_queue.as_deref(),
+                },
+            )
+            .await;
+            if let Some(store) = &state.settlement_store {
+                store.record(
+                    &resource,
+                    server::x402::claimed_payer(&payment_header),
+                    &result,
+                );
+            }
+            let settlement = result.inspect_err(|_| refund(charge))?;
+            state
+                .ledger
+                .record(&settlement, &resource, Utc::now().timestamp());
+            if let Some(webhook) = &state.webhook {
+                webhook.notify(resource.clone(), &settlement);
+            }
+            Ok(settlement)
+        })
+        .await;
+    drop(permit);
+    // A metered payment reused from the cache would be served again without paying.
+    let settlement = settlement.and_then(|settlement| match metered {
+        Some(_) if verified.get().is_none() => Err(PaymentError::PaymentReplayed),
+        _ => Ok(settlement),
+    });
+    let settlement = match settlement {
+        Ok(settlement) => settlement,
         Err(e) => {
-            error!("Invalid payment header: {}", e);
+            error!("Payment settlement failed: {}", e);
+            decide(AuditEntry {
+                payer: server::x402::claimed_payer(&payment_header),
+                error: Some(e.to_string()),
+                ..AuditEntry::new(Decision::SettlementFailed, &resource)
+            });
+            let code = e.reason_code().to_string();
+            let message = match e {
+                PaymentError::Facilitator(FacilitatorClientError::Http { .. }) => {
+                    "Payment settlement failed: facilitator unavailable".to_string()
+                }
+                e => format!("Payment settlement failed: {}", e),
+            };
             return Err(build_payment_required_response(
                 payment_requirements,
                 Some(&payment_required_v2),
-                Some("Invalid payment header".to_string()),
+                Some(message),
+                Some(&code),
             ));
         }
     };
 
-    if let Err(e) = server::x402::settle_payment(
-        &payment_header,
-        &payment_requirements,
-        &payment_requirements_v2,
-        &state.facilitator,
-        &state.config.x402,
-    )
-    .await
-    {
-        error!("Payment settlement failed: {}", e);
-        return Err(build_payment_required_response(
-            payment_requirements,
-            Some(&payment_required_v2),
-            Some(format!("Payment settlement failed: {}", e)),
-        ));
+    if let (Some(payment), Some((_, facilitator))) = (verified.into_inner(), metered) {
+        info!(
+            max_bytes = payment.max_bytes(),
+            "x402 metered payment verified"
+        );
+        // Only audited once it settles, for what was delivered.
+        if let Some(payment_log) = &payment_log {
+            let summary = payment.summary();
+            payment_log.record(&AuditEntry {
+                scheme: Some(summary.scheme.clone()),
+                payer: summary.payer.clone(),
+                amount: Some(summary.amount.clone()),
+                ..AuditEntry::new(Decision::Verified, &resource)
+            });
+        }
+        return Ok(PaywallPass {
+            settlement: None,
+            session_token: None,
+            metered: Some(MeteredPass {
+                payment,
+                facilitator,
+                client_ip,
+            }),
+        });
     }
 
-    info!(
-        "x402 payment settled successfully for resource={}",
-        resource
-    );
+    info!("x402 payment settled successfully");
+    decide(AuditEntry {
+        scheme: Some(settlement.scheme.clone()),
+        payer: settlement.payer.clone(),
+        amount: Some(settlement.amount.clone()),
+        ..AuditEntry::new(Decision::Settled, &resource)
+    });
+
+    let session_token = state
+        .sessions
+        .as_ref()
+        .and_then(|sessions| sessions.mint(&settlement, &resource, Utc::now().timestamp()))
+        .and_then(|token| HeaderValue::from_str(&token).ok());
 
-    Ok(())
+    Ok(PaywallPass {
+        settlement: Some(settlement),
+        session_token,
+        metered: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(raw: &str) -> Option<String> {
+        resource_target(raw).ok()
+    }
+
+    #[test]
+    fn nested_paths_name_one_resource() {
+        for raw in [
+            "/stream/show/1080p/seg_001.ts",
+            "/stream/show%2F1080p%2Fseg_001.ts",
+            "/stream/show%2f1080p/seg_001.ts",
+        ] {
+            assert_eq!(
+                target(raw).as_deref(),
+                Some("/stream/show/1080p/seg_001.ts")
+            );
+        }
+        assert_eq!(
+            target("/stream/remote?url=https%3A%2F%2Fcdn.example%2Fa.ts").as_deref(),
+            Some("/stream/remote?url=https%3A%2F%2Fcdn.example%2Fa.ts")
+        );
+        assert_eq!(
+            target("/stream/my%20show/a.ts").as_deref(),
+            Some("/stream/my%20show/a.ts")
+        );
+    }
+
+    #[test]
+    fn traversal_is_rejected() {
+        for raw in [
+            "/stream/../secret.ts",
+            "/stream/a/../../secret.ts",
+            "/stream/%2e%2e/secret.ts",
+            "/stream/%2E%2E%2Fsecret.ts",
+            "/stream/a%2F..%2F..%2Fsecret.ts",
+            "/stream/a%2f..%2f..%2fsecret.ts",
+            "/stream/./a.ts",
+            "/stream/..%5Csecret.ts",
+            "/stream/..%5csecret.ts",
+            "/stream/a%5C..%5C..%5Csecret.ts",
+            "//etc/passwd",
+            "/stream%2F%2Fetc/passwd",
+        ] {
+            let response = resource_target(raw).unwrap_err().into_response();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{raw}");
+        }
+    }
+
+    #[test]
+    fn double_encoding_is_decoded_only_once() {
+        // `%252e%252e` decodes to the name `%2e%2e`, which stays a name rather than `..`.
+        let resolved = target("/stream/%252e%252e/secret.ts").unwrap();
+        assert_eq!(resolved, "/stream/%252e%252e/secret.ts");
+        let url = url::Url::parse("http://localhost:3000/")
+            .unwrap()
+            .join(&resolved)
+            .unwrap();
+        assert_eq!(url.path(), "/stream/%252e%252e/secret.ts");
+
+        assert_eq!(
+            target("/stream/a%252F..%252Fsecret.ts").as_deref(),
+            Some("/stream/a%252F..%252Fsecret.ts")
+        );
+    }
+
+    #[test]
+    fn payment_value_is_bounded() {
+        let mut headers = HeaderMap::new();
+        assert!(payment_value(&headers, None, 16).is_none());
+
+        headers.insert("x-payment", HeaderValue::from_static("abcd"));
+        assert_eq!(
+            payment_value(&headers, None, 16),
+            Some(Ok("abcd".to_string()))
+        );
+        let long = HeaderValue::from_str(&"a".repeat(17)).unwrap();
+        headers.insert("x-payment", long);
+        assert_eq!(
+            payment_value(&headers, None, 16),
+            Some(Err("Payment header too large"))
+        );
+
+        // A query payment arrives url-safe and is settled in the standard alphabet.
+        let headers = HeaderMap::new();
+        assert_eq!(
+            payment_value(&headers, Some("-_8"), 16),
+            Some(Ok("+/8=".to_string()))
+        );
+        assert_eq!(
+            payment_value(&headers, Some(&"a".repeat(17)), 16),
+            Some(Err("Payment query parameter too large"))
+        );
+        assert_eq!(
+            payment_value(&headers, Some("***"), 16),
+            Some(Err("Invalid payment query parameter"))
+        );
+    }
 }
@@ -0,0 +1,222 @@
+use axum::http::{HeaderMap, StatusCode};
+use std::{fmt, str::FromStr};
+use tracing::warn;
+use url::Url;
+
+use crate::{
+    error::{IpfsPathError, RemoteFetchError},
+    io::{RemoteFetcher, RemoteHead, RemoteStream},
+};
+
+const BASE58_BTC: &str = "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+const BASE32: &str = "abcdefghijklmnopqrstuvwxyz234567";
+const BASE36: &str = "0123456789abcdefghijklmnopqrstuvwxyz";
+const BASE16: &str = "0123456789abcdef";
+
+/// Longest CIDv1 string accepted, ruling out absurd identity-hash CIDs.
+const MAX_CID_LEN: usize = 256;
+
+/// A syntactically valid IPFS content identifier: a CIDv0 (`Qm…`), or a CIDv1 in base32,
+/// base36, base58btc, or base16 multibase.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cid(String);
+
+impl FromStr for Cid {
+    type Err = IpfsPathError;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let valid = if raw.len() == 46 && raw.starts_with("Qm") {
+            in_alphabet(raw, BASE58_BTC)
+        } else {
+            match raw.split_at_checked(1) {
+                Some((prefix, digits)) if (2..MAX_CID_LEN).contains(&digits.len()) => {
+                    match prefix {
+                        "z" => in_alphabet(digits, BASE58_BTC),
+                        // Version 1 leads the bytes, so base32 CIDs start `ba` then e-h.
+                        "b" | "B" => {
+                            let digits = digits.to_ascii_lowercase();
+                            in_alphabet(&digits, BASE32)
+                                && digits.starts_with('a')
+                                && matches!(digits.as_bytes()[1], b'e'..=b'h')
+                        }
+                        "k" | "K" => in_alphabet(&digits.to_ascii_lowercase(), BASE36),
+                        "f" | "F" => {
+                            let digits = digits.to_ascii_lowercase();
+                            in_alphabet(&digits, BASE16)
+                                && digits.len().is_multiple_of(2)
+                                && digits.starts_with("01")
+                        }
+                        _ => false,
+                    }
+                }
+                _ => false,
+            }
+        };
+        if valid {
+            Ok(Self(raw.to_string()))
+        } else {
+            Err(IpfsPathError::InvalidCid(raw.to_string()))
+        }
+    }
+}
+
+impl fmt::Display for Cid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+fn in_alphabet(digits: &str, alphabet: &str) -> bool {
+    digits.chars().all(|c| alphabet.contains(c))
+}
+
+/// A CID and the path below it inside a UnixFS directory, as `/stream/ipfs/{cid}/{path}`
+/// names them.
+#[derive(Clone, Debug)]
+pub struct IpfsPath {
+    pub cid: Cid,
+    /// Decoded path segments; none is empty, `.`, or `..`.
+    segments: Vec<String>,
+}
+
+impl IpfsPath {
+    /// Validates `cid` and the decoded `sub_path`, before anything is fetched.
+    pub fn new(cid: &str, sub_path: Option<&str>) -> Result<Self, IpfsPathError> {
+        let cid = cid.parse()?;
+        let segments = sub_path
+            .map(|path| path.trim_end_matches('/'))
+            .filter(|path| !path.is_empty())
+            .map(|path| {
+                path.split('/')
+                    .map(|segment| {
+                        let invalid = matches!(segment, "" | "." | "..")
+                            || segment.contains(|c: char| c == '\\' || c.is_control());
+                        if invalid {
+                            Err(IpfsPathError::InvalidSegment(segment.to_string()))
+                        } else {
+                            Ok(segment.to_string())
+                        }
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .transpose()?
+            .unwrap_or_default();
+        Ok(Self { cid, segments })
+    }
+
+    /// `ipfs://{cid}/{path}`: the resource payments for this content name.
+    pub fn resource(&self) -> String {
+        let mut resource = format!("ipfs://{}", self.cid);
+        for segment in &self.segments {
+            resource.push('/');
+            resource.push_str(segment);
+        }
+        resource
+    }
+
+    /// The last path segment, or the CID for a file addressed directly.
+    pub fn file_name(&self) -> &str {
+        self.segments.last().map_or(&self.cid.0, String::as_str)
+    }
+
+    /// `{gateway}/ipfs/{cid}/{path}`, each segment percent-encoded on its own so none can
+    /// reach another path, the query, or the fragment.
+    pub fn gateway_url(&self, gateway: &Url) -> Url {
+        let mut url = gateway.clone();
+        url.set_query(None);
+        url.set_fragment(None);
+        if let Ok(mut path) = url.path_segments_mut() {
+            path.pop_if_empty().push("ipfs").push(&self.cid.0);
+            for segment in &self.segments {
+                path.push(segment);
+            }
+        }
+        url
+    }
+}
+
+/// `IPFS_GATEWAYS`: comma-separated base URLs of path gateways (`https://ipfs.io/`), in the
+/// order they are tried.
+#[derive(Clone, Debug)]
+pub struct IpfsGateways(pub Vec<Url>);
+
+impl FromStr for IpfsGateways {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let gateways = raw
+            .split(',')
+            .map(str::trim)
+            .filter(|gateway| !gateway.is_empty())
+            .map(|gateway| {
+                let url = Url::parse(gateway)
+                    .map_err(|e| format!("invalid IPFS gateway {gateway}: {e}"))?;
+                if !matches!(url.scheme(), "http" | "https") || url.cannot_be_a_base() {
+                    return Err(format!("IPFS gateway {gateway} must be an http(s) URL"));
+                }
+                Ok(url)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if gateways.is_empty() {
+            return Err("IPFS gateway list is empty".into());
+        }
+        Ok(Self(gateways))
+    }
+}
+
+impl IpfsGateways {
+    /// Streams `path` from the first gateway that has it, moving on when a gateway fails,
+    /// answers with an error other than `416`, or sends an empty body.
+    pub async fn stream(
+        &self,
+        fetcher: &RemoteFetcher,
+        path: &IpfsPath,
+        request_headers: Option<&HeaderMap>,
+    ) -> Result<RemoteStream, RemoteFetchError> {
+        let mut last_error = None;
+        for gateway in &self.0 {
+            let url = path.gateway_url(gateway);
+            match fetcher
+                .stream_non_empty(url.as_str(), request_headers)
+                .await
+            {
+                Ok(stream) => return Ok(stream),
+                Err(e) if !tries_next_gateway(&e) => return Err(e),
+                Err(e) => {
+                    warn!(gateway = %gateway, cid = %path.cid, "IPFS gateway failed: {}", e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.expect("the gateway list is never empty"))
+    }
+
+    /// Size and type of `path` from the first gateway that answers a `HEAD` for it.
+    pub async fn head(
+        &self,
+        fetcher: &RemoteFetcher,
+        path: &IpfsPath,
+    ) -> Result<RemoteHead, RemoteFetchError> {
+        let mut last_error = None;
+        for gateway in &self.0 {
+            match fetcher.head(path.gateway_url(gateway).as_str()).await {
+                Ok(head) => return Ok(head),
+                Err(e) => {
+                    warn!(gateway = %gateway, cid = %path.cid, "IPFS gateway HEAD failed: {}", e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.expect("the gateway list is never empty"))
+    }
+}
+
+/// Whether another gateway could answer where one failed with `e`: the content is the same
+/// everywhere, so a rejected range or oversized file fails on every gateway.
+fn tries_next_gateway(e: &RemoteFetchError) -> bool {
+    match e {
+        RemoteFetchError::Status(status) => *status != StatusCode::RANGE_NOT_SATISFIABLE,
+        RemoteFetchError::TooLarge { .. } => false,
+        RemoteFetchError::Request(_) | RemoteFetchError::Empty => true,
+    }
+}
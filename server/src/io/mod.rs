@@ -0,0 +1,296 @@
+use crate::error::FileStreamError;
+use axum::body::Body;
+use std::{
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio_util::io::ReaderStream;
+
+mod ipfs;
+mod remote;
+mod s3;
+mod storage;
+
+pub use ipfs::{Cid, IpfsGateways, IpfsPath};
+pub use remote::{
+    REMOTE_FORWARDED_HEADERS, RemoteConfig, RemoteFetcher, RemoteFile, RemoteHead, RemoteStream,
+};
+pub use s3::{S3Config, S3Storage};
+pub use storage::{ByteRange, LocalStorage, StorageBackend, StorageFuture};
+
+/// When an append-only file is synced to disk: `AUDIT_LOG_FSYNC` and
+/// `X402_PENDING_SETTLEMENTS_FSYNC`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Leave flushing to the OS.
+    Never,
+    /// Sync after every write.
+    Always,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "never" => Ok(Self::Never),
+            "always" => Ok(Self::Always),
+            other => Err(format!(
+                "invalid fsync policy {other:?}: expected never or always"
+            )),
+        }
+    }
+}
+
+/// A verified file and the metadata handlers, pricing, and HTTP validators share, read
+/// once when the file is verified.
+#[derive(Clone, Debug)]
+pub struct FileInfo {
+    pub path: PathBuf,
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+    /// Content type by extension, for the HLS and DASH types [`mime_type_for`] knows.
+    pub mime: Option<&'static str>,
+}
+
+impl FileInfo {
+    /// Weak ETag derived from size and modification time.
+    pub fn etag(&self) -> String {
+        format!(
+            "W/\"{:x}-{:x}\"",
+            self.len,
+            self.modified_secs().unwrap_or(0)
+        )
+    }
+
+    /// Modification time in whole seconds since the epoch, the precision of HTTP dates.
+    pub fn modified_secs(&self) -> Option<u64> {
+        self.modified
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|elapsed| elapsed.as_secs())
+    }
+}
+
+/// When a local file is still being written and must not be served yet:
+/// `MIN_FILE_AGE_MS` and `IGNORE_FILE_SUFFIXES`. The default serves every file.
+#[derive(Clone, Debug, Default)]
+pub struct FileStability {
+    /// Files modified more recently than this are answered with
+    /// [`FileStreamError::NotReady`]; zero turns this age check off. A file changed between
+    /// being verified and opened is never served, whatever the age.
+    pub min_age: Duration,
+    /// Name endings, such as `.part`, of files a packager is still writing; they are
+    /// never served and answer as missing. Compared ignoring case.
+    pub ignore_suffixes: Vec<String>,
+}
+
+impl FileStability {
+    /// Whether `name` ends in one of the ignored suffixes.
+    pub fn is_ignored(&self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        self.ignore_suffixes
+            .iter()
+            .any(|suffix| name.ends_with(&suffix.to_ascii_lowercase()))
+    }
+
+    /// How long until a file last modified at `modified` is old enough to serve; `None`
+    /// once it is. A modification time in the future counts as just now.
+    pub fn wait(&self, modified: Option<SystemTime>) -> Option<Duration> {
+        if self.min_age.is_zero() {
+            return None;
+        }
+        let age = modified
+            .map(|modified| modified.elapsed().unwrap_or_default())
+            .unwrap_or(self.min_age);
+        self.min_age.checked_sub(age).filter(|wait| !wait.is_zero())
+    }
+
+    /// Fails when `file` is too fresh to serve.
+    pub fn check(&self, file: &FileInfo) -> Result<(), FileStreamError> {
+        match self.wait(file.modified) {
+            Some(retry_after) => Err(FileStreamError::NotReady {
+                path: file.path.clone(),
+                retry_after,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Resolves `filename` under `base_directory`, which must already be canonical
+/// (see [`LocalStorage::new`]).
+///
+/// Absolute paths and `..` components are rejected before touching the filesystem,
+/// and the resolved path must stay under the base even after following symlinks.
+pub fn verify_file(base_directory: &Path, filename: &str) -> Result<FileInfo, FileStreamError> {
+    let relative = Path::new(filename);
+    if relative
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(FileStreamError::AccessDenied);
+    }
+
+    let file_path = base_directory.join(relative);
+    let file_path = match file_path.canonicalize() {
+        Ok(canonical) => canonical,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(FileStreamError::NotFound(file_path));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if !file_path.starts_with(base_directory) {
+        return Err(FileStreamError::AccessDenied);
+    }
+
+    let metadata = std::fs::metadata(&file_path).map_err(|e| open_error(&file_path, e))?;
+    if !metadata.is_file() {
+        return Err(FileStreamError::NotAFile(file_path));
+    }
+    Ok(FileInfo {
+        modified: metadata.modified().ok(),
+        len: metadata.len(),
+        mime: mime_type_for(&file_path.to_string_lossy()),
+        path: file_path,
+    })
+}
+
+/// Content type of an HLS or DASH file, by the extension ending `name`.
+pub fn mime_type_for(name: &str) -> Option<&'static str> {
+    if name.ends_with(".m3u8") {
+        Some("application/vnd.apple.mpegurl")
+    } else if name.ends_with(".mpd") {
+        Some("application/dash+xml")
+    } else if name.ends_with(".ts") {
+        Some("video/mp2t")
+    } else if name.ends_with(".m4s") {
+        Some("video/iso.segment")
+    } else if name.ends_with(".mp4") {
+        Some("video/mp4")
+    } else {
+        None
+    }
+}
+
+/// A failed read of `path`; a file removed since it was verified is still `NotFound`.
+fn open_error(path: &Path, e: std::io::Error) -> FileStreamError {
+    if e.kind() == ErrorKind::NotFound {
+        FileStreamError::NotFound(path.to_path_buf())
+    } else {
+        e.into()
+    }
+}
+
+/// Read size of file streams unless `STREAM_BUFFER_BYTES` says otherwise.
+pub const DEFAULT_STREAM_BUFFER_BYTES: usize = 64 * 1024;
+
+/// Streams `file_path` in reads of up to `buffer_bytes`.
+pub async fn stream_file(
+    file_path: impl AsRef<Path>,
+    buffer_bytes: usize,
+) -> Result<Body, FileStreamError> {
+    let file_path = file_path.as_ref();
+    let file = tokio::fs::File::open(file_path)
+        .await
+        .map_err(|e| open_error(file_path, e))?;
+    let stream = ReaderStream::with_capacity(file, buffer_bytes);
+    let body = Body::from_stream(stream);
+
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A canonical base directory holding `a.ts` and `show/1080p/seg.ts`, next to a
+    /// `secret.env` outside it.
+    fn base(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("verify-test-{}-{name}", std::process::id()));
+        let base = root.join("hls");
+        std::fs::create_dir_all(base.join("show/1080p")).unwrap();
+        std::fs::write(base.join("a.ts"), b"segment").unwrap();
+        std::fs::write(base.join("show/1080p/seg.ts"), b"segment").unwrap();
+        std::fs::write(root.join("secret.env"), b"secret").unwrap();
+        base.canonicalize().unwrap()
+    }
+
+    #[test]
+    fn files_below_the_base_are_found() {
+        let base = base("found");
+        let file = verify_file(&base, "a.ts").unwrap();
+        assert_eq!(file.len, 7);
+        assert_eq!(file.mime, Some("video/mp2t"));
+        assert!(verify_file(&base, "show/1080p/seg.ts").is_ok());
+        assert!(verify_file(&base, "./a.ts").is_ok());
+        assert!(matches!(
+            verify_file(&base, "missing.ts"),
+            Err(FileStreamError::NotFound(_))
+        ));
+        assert!(matches!(
+            verify_file(&base, "show"),
+            Err(FileStreamError::NotAFile(_))
+        ));
+    }
+
+    #[test]
+    fn traversal_is_denied() {
+        let base = base("traversal");
+        let secret = base.parent().unwrap().join("secret.env");
+        for filename in [
+            "../secret.env",
+            "show/../../secret.env",
+            "show/1080p/../../../secret.env",
+            secret.to_str().unwrap(),
+            "/etc/passwd",
+        ] {
+            assert!(
+                matches!(
+                    verify_file(&base, filename),
+                    Err(FileStreamError::AccessDenied)
+                ),
+                "{filename}"
+            );
+        }
+    }
+
+    #[test]
+    fn encoded_traversal_is_denied_once_decoded() {
+        // Axum hands the route the decoded path, so these reach `verify_file` as `..`.
+        let base = base("encoded");
+        for encoded in [
+            "..%2Fsecret.env",
+            "%2e%2e/secret.env",
+            "show%2F..%2F..%2Fsecret.env",
+        ] {
+            let filename = percent_encoding::percent_decode_str(encoded).decode_utf8_lossy();
+            assert!(
+                matches!(
+                    verify_file(&base, &filename),
+                    Err(FileStreamError::AccessDenied)
+                ),
+                "{encoded}"
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_out_of_the_base_are_denied() {
+        let base = base("symlink");
+        let link = base.join("escape.env");
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink(base.parent().unwrap().join("secret.env"), &link).unwrap();
+        assert!(matches!(
+            verify_file(&base, "escape.env"),
+            Err(FileStreamError::AccessDenied)
+        ));
+
+        let inside = base.join("alias.ts");
+        let _ = std::fs::remove_file(&inside);
+        std::os::unix::fs::symlink(base.join("a.ts"), &inside).unwrap();
+        assert!(verify_file(&base, "alias.ts").is_ok());
+    }
+}
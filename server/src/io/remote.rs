@@ -0,0 +1,577 @@
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode, header},
+};
+use envconfig::Envconfig;
+use http_body::{Frame, SizeHint};
+use reqwest::Client;
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tracing::{debug, warn};
+
+use crate::{error::RemoteFetchError, io::DEFAULT_STREAM_BUFFER_BYTES};
+
+/// Upstream response headers passed through on `/stream/remote`; hop-by-hop headers
+/// never are.
+const REMOTE_PASSTHROUGH_HEADERS: [HeaderName; 7] = [
+    header::CONTENT_TYPE,
+    header::CONTENT_LENGTH,
+    header::CACHE_CONTROL,
+    header::LAST_MODIFIED,
+    header::ETAG,
+    header::CONTENT_RANGE,
+    header::ACCEPT_RANGES,
+];
+
+/// Client request headers forwarded to the upstream by [`RemoteFetcher::stream`], so seeks
+/// and revalidations don't refetch the whole file.
+pub const REMOTE_FORWARDED_HEADERS: [HeaderName; 4] = [
+    header::RANGE,
+    header::IF_NONE_MATCH,
+    header::IF_MODIFIED_SINCE,
+    header::ACCEPT,
+];
+
+#[derive(Envconfig, Debug, Clone)]
+pub struct RemoteConfig {
+    /// How long connecting to an upstream may take.
+    #[envconfig(from = "REMOTE_CONNECT_TIMEOUT_SECONDS", default = "10")]
+    pub connect_timeout_seconds: u64,
+
+    /// How long a whole remote fetch may take, from sending the request to the last byte
+    /// of the body.
+    #[envconfig(from = "REMOTE_READ_TIMEOUT_SECONDS", default = "300")]
+    pub read_timeout_seconds: u64,
+
+    /// Largest body a remote fetch may deliver; unlimited when unset.
+    #[envconfig(from = "REMOTE_MAX_BYTES")]
+    pub max_bytes: Option<u64>,
+}
+
+pub struct RemoteStream {
+    /// `200`, or the upstream's `206`/`304` answer to forwarded headers.
+    pub status: StatusCode,
+    pub body: Body,
+    /// The upstream's [`REMOTE_PASSTHROUGH_HEADERS`] that it sent.
+    pub headers: HeaderMap,
+}
+
+/// A remote file read fully into memory, with the same headers as [`RemoteStream`].
+#[derive(Clone, Debug)]
+pub struct RemoteFile {
+    pub bytes: Bytes,
+    pub headers: HeaderMap,
+}
+
+/// Size and type of a remote file, learned from an upstream `HEAD`.
+pub struct RemoteHead {
+    pub content_length: Option<u64>,
+    pub content_type: Option<HeaderValue>,
+}
+
+/// Fetches remote files within the [`RemoteConfig`] timeouts and size cap.
+pub struct RemoteFetcher {
+    client: Client,
+    max_bytes: Option<u64>,
+    /// Body chunks already received are merged up to this size before being sent on.
+    buffer_bytes: usize,
+}
+
+impl RemoteFetcher {
+    pub fn new(config: &RemoteConfig) -> Result<Self, reqwest::Error> {
+        let client = Client::builder()
+            .connect_timeout(Duration::from_secs(config.connect_timeout_seconds))
+            .timeout(Duration::from_secs(config.read_timeout_seconds))
+            .build()?;
+        Ok(Self {
+            client,
+            max_bytes: config.max_bytes,
+            buffer_bytes: DEFAULT_STREAM_BUFFER_BYTES,
+        })
+    }
+
+    /// Merges streamed chunks up to `buffer_bytes` instead of
+    /// [`DEFAULT_STREAM_BUFFER_BYTES`].
+    pub fn with_buffer_bytes(mut self, buffer_bytes: usize) -> Self {
+        self.buffer_bytes = buffer_bytes.max(1);
+        self
+    }
+
+    /// Streams `url`, forwarding the [`REMOTE_FORWARDED_HEADERS`] of `request_headers`.
+    ///
+    /// An upstream `Content-Length` above the cap fails before anything is streamed; a body
+    /// that runs past it anyway is cut off there and ends with an error.
+    pub async fn stream(
+        &self,
+        url: &str,
+        request_headers: Option<&HeaderMap>,
+    ) -> Result<RemoteStream, RemoteFetchError> {
+        let (response, headers) = self.get(url, forwarded(request_headers)).await?;
+        let status = response.status();
+        Ok(RemoteStream {
+            status,
+            body: self.capped(url, None, response),
+            headers,
+        })
+    }
+
+    /// Like [`RemoteFetcher::stream`], but fails with [`RemoteFetchError::Empty`] when the
+    /// upstream's successful answer has no body; a `304` never does.
+    pub async fn stream_non_empty(
+        &self,
+        url: &str,
+        request_headers: Option<&HeaderMap>,
+    ) -> Result<RemoteStream, RemoteFetchError> {
+        let (mut response, headers) = self.get(url, forwarded(request_headers)).await?;
+        let status = response.status();
+        let mut first = None;
+        if status != StatusCode::NOT_MODIFIED {
+            while let Some(chunk) = response.chunk().await? {
+                if !chunk.is_empty() {
+                    first = Some(chunk);
+                    break;
+                }
+            }
+            if first.is_none() {
+                return Err(RemoteFetchError::Empty);
+            }
+        }
+        Ok(RemoteStream {
+            status,
+            body: self.capped(url, first, response),
+            headers,
+        })
+    }
+
+    /// The rest of `response`'s body after the `first` chunk already read, within the cap.
+    fn capped(&self, url: &str, first: Option<Bytes>, response: reqwest::Response) -> Body {
+        let body = Body::new(CoalescedBody {
+            inner: Body::from_stream(response.bytes_stream()),
+            buffer: Vec::new(),
+            buffer_bytes: self.buffer_bytes,
+            held: None,
+        });
+        let body = match first {
+            Some(first) => Body::new(PrefixedBody {
+                first: Some(first),
+                inner: body,
+            }),
+            None => body,
+        };
+        match self.max_bytes {
+            Some(max_bytes) => Body::new(CappedBody {
+                inner: body,
+                url: url.to_string(),
+                max_bytes,
+                sent: 0,
+                capped: false,
+            }),
+            None => body,
+        }
+    }
+
+    /// Like [`RemoteFetcher::stream`] without forwarded headers, but reads the whole body
+    /// before returning.
+    pub async fn fetch(&self, url: &str) -> Result<RemoteFile, RemoteFetchError> {
+        let (mut response, headers) = self.get(url, HeaderMap::new()).await?;
+        let bytes = match self.max_bytes {
+            Some(max_bytes) => {
+                let mut bytes = Vec::new();
+                while let Some(chunk) = response.chunk().await? {
+                    bytes.extend_from_slice(&chunk);
+                    if bytes.len() as u64 > max_bytes {
+                        return Err(RemoteFetchError::TooLarge { max_bytes });
+                    }
+                }
+                Bytes::from(bytes)
+            }
+            None => response.bytes().await?,
+        };
+
+        Ok(RemoteFile { bytes, headers })
+    }
+
+    /// Sends `GET url` with `forwarded` request headers and returns the successful (or,
+    /// for a conditional request, `304`) response with its passthrough headers.
+    async fn get(
+        &self,
+        url: &str,
+        forwarded: HeaderMap,
+    ) -> Result<(reqwest::Response, HeaderMap), RemoteFetchError> {
+        let response = self.client.get(url).headers(forwarded).send().await?;
+        debug!(
+            url,
+            status = %response.status(),
+            headers = ?response.headers(),
+            "Remote file response"
+        );
+
+        if !response.status().is_success() && response.status() != StatusCode::NOT_MODIFIED {
+            return Err(RemoteFetchError::Status(response.status()));
+        }
+        if let Some(max_bytes) = self.max_bytes
+            && let Some(length) = response.content_length()
+            && length > max_bytes
+        {
+            warn!(
+                url,
+                length, max_bytes, "Remote file is larger than REMOTE_MAX_BYTES"
+            );
+            return Err(RemoteFetchError::TooLarge { max_bytes });
+        }
+
+        let mut headers = HeaderMap::new();
+        for name in REMOTE_PASSTHROUGH_HEADERS {
+            if let Some(value) = response.headers().get(&name) {
+                headers.insert(name, value.clone());
+            }
+        }
+        Ok((response, headers))
+    }
+
+    pub async fn head(&self, url: &str) -> Result<RemoteHead, RemoteFetchError> {
+        let response = self.client.head(url).send().await?;
+        debug!(
+            url,
+            status = %response.status(),
+            headers = ?response.headers(),
+            "Remote file HEAD response"
+        );
+
+        if !response.status().is_success() {
+            return Err(RemoteFetchError::Status(response.status()));
+        }
+
+        let content_length = response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
+        let content_type = response.headers().get(header::CONTENT_TYPE).cloned();
+
+        Ok(RemoteHead {
+            content_length,
+            content_type,
+        })
+    }
+}
+
+/// The [`REMOTE_FORWARDED_HEADERS`] of `request_headers`.
+fn forwarded(request_headers: Option<&HeaderMap>) -> HeaderMap {
+    let mut forwarded = HeaderMap::new();
+    for name in REMOTE_FORWARDED_HEADERS {
+        if let Some(value) = request_headers.and_then(|headers| headers.get(&name)) {
+            forwarded.insert(name, value.clone());
+        }
+    }
+    forwarded
+}
+
+/// A body whose `first` chunk was read ahead of the rest.
+struct PrefixedBody {
+    first: Option<Bytes>,
+    inner: Body,
+}
+
+impl HttpBody for PrefixedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        match self.first.take() {
+            Some(first) => Poll::Ready(Some(Ok(Frame::data(first)))),
+            None => Pin::new(&mut self.inner).poll_frame(cx),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.first.is_none() && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let first = self.first.as_ref().map_or(0, |first| first.len() as u64);
+        let inner = self.inner.size_hint();
+        let mut hint = SizeHint::new();
+        hint.set_lower(inner.lower() + first);
+        if let Some(upper) = inner.upper() {
+            hint.set_upper(upper + first);
+        }
+        hint
+    }
+}
+
+/// Remote body that sends the small chunks the upstream connection delivers in larger
+/// ones, up to `buffer_bytes`. Only chunks already received are merged: once the upstream
+/// has nothing more ready, whatever is buffered goes out, so no data waits on the network.
+struct CoalescedBody {
+    inner: Body,
+    buffer: Vec<u8>,
+    buffer_bytes: usize,
+    /// What the inner body yielded after the buffer's data, sent once the buffer is.
+    held: Option<Option<Result<Frame<Bytes>, axum::Error>>>,
+}
+
+impl CoalescedBody {
+    fn flush(&mut self) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let data = Bytes::from(std::mem::take(&mut self.buffer));
+        Poll::Ready(Some(Ok(Frame::data(data))))
+    }
+}
+
+impl HttpBody for CoalescedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if let Some(held) = self.held.take() {
+            return Poll::Ready(held);
+        }
+        loop {
+            let polled = Pin::new(&mut self.inner).poll_frame(cx);
+            let frame = match polled {
+                Poll::Ready(Some(Ok(frame))) => frame,
+                Poll::Pending if self.buffer.is_empty() => return Poll::Pending,
+                Poll::Pending => return self.flush(),
+                Poll::Ready(done) if self.buffer.is_empty() => return Poll::Ready(done),
+                Poll::Ready(done) => {
+                    self.held = Some(done);
+                    return self.flush();
+                }
+            };
+            let data = match frame.into_data() {
+                // A chunk as large as the buffer goes out as it is, without a copy.
+                Ok(data) if self.buffer.is_empty() && data.len() >= self.buffer_bytes => {
+                    return Poll::Ready(Some(Ok(Frame::data(data))));
+                }
+                Ok(data) => data,
+                Err(frame) if self.buffer.is_empty() => return Poll::Ready(Some(Ok(frame))),
+                Err(frame) => {
+                    self.held = Some(Some(Ok(frame)));
+                    return self.flush();
+                }
+            };
+            self.buffer.extend_from_slice(&data);
+            if self.buffer.len() >= self.buffer_bytes {
+                return self.flush();
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.held.is_none() && self.buffer.is_empty() && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let buffered = self.buffer.len() as u64;
+        let inner = self.inner.size_hint();
+        let mut hint = SizeHint::new();
+        hint.set_lower(inner.lower() + buffered);
+        if let Some(upper) = inner.upper() {
+            hint.set_upper(upper + buffered);
+        }
+        hint
+    }
+}
+
+/// Remote body that yields at most `max_bytes`, then fails so the client response is
+/// closed instead of completing short.
+struct CappedBody {
+    inner: Body,
+    url: String,
+    max_bytes: u64,
+    sent: u64,
+    /// Set once data was cut at the cap; the next poll fails the body.
+    capped: bool,
+}
+
+impl HttpBody for CappedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if self.capped {
+            return Poll::Ready(Some(Err(axum::Error::new(RemoteFetchError::TooLarge {
+                max_bytes: self.max_bytes,
+            }))));
+        }
+        let frame = match Pin::new(&mut self.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => frame,
+            polled => return polled,
+        };
+        let mut data = match frame.into_data() {
+            Ok(data) => data,
+            Err(frame) => return Poll::Ready(Some(Ok(frame))),
+        };
+        let remaining = self.max_bytes - self.sent;
+        if data.len() as u64 > remaining {
+            data.truncate(remaining as usize);
+            self.capped = true;
+            warn!(
+                url = %self.url,
+                sent = self.max_bytes,
+                max_bytes = self.max_bytes,
+                "Remote file ran past REMOTE_MAX_BYTES; aborting the transfer"
+            );
+        }
+        self.sent += data.len() as u64;
+        Poll::Ready(Some(Ok(Frame::data(data))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        !self.capped && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use wiremock::{Mock, MockServer, ResponseTemplate, matchers::method};
+
+    fn fetcher(read_timeout_seconds: u64, max_bytes: Option<u64>) -> RemoteFetcher {
+        RemoteFetcher::new(&RemoteConfig {
+            connect_timeout_seconds: 1,
+            read_timeout_seconds,
+            max_bytes,
+        })
+        .unwrap()
+    }
+
+    async fn upstream(response: ResponseTemplate) -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(response)
+            .mount(&server)
+            .await;
+        server
+    }
+
+    /// Serves one chunked response without a `Content-Length`, then stalls unless `finish`.
+    async fn chunked_upstream(chunks: Vec<Vec<u8>>, finish: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/file", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0; 4096];
+            let _ = socket.read(&mut request).await;
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n")
+                .await
+                .unwrap();
+            for chunk in chunks {
+                socket
+                    .write_all(format!("{:x}\r\n", chunk.len()).as_bytes())
+                    .await
+                    .unwrap();
+                socket.write_all(&chunk).await.unwrap();
+                socket.write_all(b"\r\n").await.unwrap();
+            }
+            if finish {
+                socket.write_all(b"0\r\n\r\n").await.unwrap();
+            } else {
+                tokio::time::sleep(Duration::from_secs(30)).await;
+            }
+        });
+        url
+    }
+
+    /// The bytes `body` yields before it ends or fails, and whether it failed.
+    async fn drain(body: Body) -> (usize, bool) {
+        let mut stream = body.into_data_stream();
+        let mut received = 0;
+        while let Some(chunk) = stream.next().await {
+            match chunk {
+                Ok(chunk) => received += chunk.len(),
+                Err(_) => return (received, true),
+            }
+        }
+        (received, false)
+    }
+
+    #[tokio::test]
+    async fn body_within_the_cap_streams_whole() {
+        let server = upstream(ResponseTemplate::new(200).set_body_bytes(vec![7; 1024])).await;
+        let remote = fetcher(5, Some(1024))
+            .stream(&format!("{}/file", server.uri()), None)
+            .await
+            .unwrap();
+        assert_eq!(remote.status, StatusCode::OK);
+        assert_eq!(remote.headers[header::CONTENT_LENGTH], "1024");
+        assert_eq!(drain(remote.body).await, (1024, false));
+    }
+
+    #[tokio::test]
+    async fn content_length_above_the_cap_fails_before_streaming() {
+        let server = upstream(ResponseTemplate::new(200).set_body_bytes(vec![7; 4096])).await;
+        let url = format!("{}/file", server.uri());
+        let fetcher = fetcher(5, Some(1024));
+        assert!(matches!(
+            fetcher.stream(&url, None).await,
+            Err(RemoteFetchError::TooLarge { max_bytes: 1024 })
+        ));
+        assert!(matches!(
+            fetcher.fetch(&url).await,
+            Err(RemoteFetchError::TooLarge { max_bytes: 1024 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn body_running_past_the_cap_is_cut_off() {
+        let url = chunked_upstream(vec![vec![7; 600], vec![7; 600], vec![7; 600]], true).await;
+        let remote = fetcher(5, Some(1000)).stream(&url, None).await.unwrap();
+        assert_eq!(drain(remote.body).await, (1000, true));
+
+        let url = chunked_upstream(vec![vec![7; 600], vec![7; 600]], true).await;
+        assert!(matches!(
+            fetcher(5, Some(1000)).fetch(&url).await,
+            Err(RemoteFetchError::TooLarge { max_bytes: 1000 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn slow_upstream_times_out() {
+        let server = upstream(
+            ResponseTemplate::new(200)
+                .set_body_bytes(vec![7; 16])
+                .set_delay(Duration::from_secs(5)),
+        )
+        .await;
+        match fetcher(1, None)
+            .stream(&format!("{}/file", server.uri()), None)
+            .await
+        {
+            Err(RemoteFetchError::Request(e)) => assert!(e.is_timeout(), "{e}"),
+            Err(e) => panic!("unexpected error: {e}"),
+            Ok(_) => panic!("a stalled upstream was streamed"),
+        }
+    }
+
+    #[tokio::test]
+    async fn stalled_body_is_cut_off_by_the_read_timeout() {
+        let url = chunked_upstream(vec![vec![7; 100]], false).await;
+        let started = std::time::Instant::now();
+        let remote = fetcher(1, None).stream(&url, None).await.unwrap();
+        assert_eq!(drain(remote.body).await, (100, true));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
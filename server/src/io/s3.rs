@@ -0,0 +1,253 @@
+use axum::body::Body;
+use chrono::{DateTime, Utc};
+use envconfig::Envconfig;
+use hmac::{Hmac, Mac};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode, header};
+use sha2::{Digest, Sha256};
+use std::{path::PathBuf, time::SystemTime};
+use url::Url;
+
+use crate::{
+    error::FileStreamError,
+    io::{
+        FileInfo, mime_type_for,
+        storage::{ByteRange, StorageBackend, StorageFuture},
+    },
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Characters SigV4 leaves unencoded in a path segment.
+const UNRESERVED: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// SHA-256 of an empty body; every request this backend signs is bodiless.
+const EMPTY_PAYLOAD_SHA256: &str =
+    "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+#[derive(Envconfig, Debug, Clone)]
+pub struct S3Config {
+    #[envconfig(from = "S3_BUCKET")]
+    pub bucket: Option<String>,
+
+    /// Key prefix prepended to every requested path.
+    #[envconfig(from = "S3_PREFIX", default = "")]
+    pub prefix: String,
+
+    #[envconfig(from = "S3_REGION", default = "us-east-1")]
+    pub region: String,
+
+    /// Endpoint of an S3-compatible service; defaults to AWS for `S3_REGION`.
+    #[envconfig(from = "S3_ENDPOINT")]
+    pub endpoint: Option<Url>,
+
+    #[envconfig(from = "AWS_ACCESS_KEY_ID")]
+    pub access_key_id: Option<String>,
+
+    #[envconfig(from = "AWS_SECRET_ACCESS_KEY")]
+    pub secret_access_key: Option<String>,
+
+    #[envconfig(from = "AWS_SESSION_TOKEN")]
+    pub session_token: Option<String>,
+}
+
+/// Objects in an S3-compatible bucket, addressed path-style and signed with SigV4.
+pub struct S3Storage {
+    client: Client,
+    endpoint: Url,
+    bucket: String,
+    prefix: String,
+    region: String,
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl S3Storage {
+    pub fn new(config: &S3Config) -> Result<Self, String> {
+        let bucket = config.bucket.clone().ok_or("S3_BUCKET is required")?;
+        let access_key_id = config
+            .access_key_id
+            .clone()
+            .ok_or("AWS_ACCESS_KEY_ID is required")?;
+        let secret_access_key = config
+            .secret_access_key
+            .clone()
+            .ok_or("AWS_SECRET_ACCESS_KEY is required")?;
+        let endpoint = match &config.endpoint {
+            Some(endpoint) => endpoint.clone(),
+            None => Url::parse(&format!("https://s3.{}.amazonaws.com", config.region))
+                .map_err(|e| format!("invalid S3_REGION {}: {e}", config.region))?,
+        };
+        Ok(Self {
+            client: Client::new(),
+            endpoint,
+            bucket,
+            prefix: config.prefix.trim_matches('/').to_string(),
+            region: config.region.clone(),
+            access_key_id,
+            secret_access_key,
+            session_token: config.session_token.clone(),
+        })
+    }
+
+    fn object_key(&self, path: &str) -> Result<String, FileStreamError> {
+        let path = path.trim_start_matches('/');
+        if path.split('/').any(|segment| segment == "..") {
+            return Err(FileStreamError::AccessDenied);
+        }
+        if path.is_empty() || path.ends_with('/') {
+            return Err(FileStreamError::NotAFile(PathBuf::from(path)));
+        }
+        Ok(if self.prefix.is_empty() {
+            path.to_string()
+        } else {
+            format!("{}/{}", self.prefix, path)
+        })
+    }
+
+    fn signed_request(&self, method: Method, key: &str) -> Result<RequestBuilder, FileStreamError> {
+        let canonical_uri = std::iter::once(self.bucket.as_str())
+            .chain(key.split('/'))
+            .map(|segment| utf8_percent_encode(segment, UNRESERVED).to_string())
+            .fold(String::new(), |uri, segment| uri + "/" + &segment);
+        let base = self.endpoint.as_str().trim_end_matches('/');
+        let url = Url::parse(&format!("{base}{canonical_uri}"))
+            .map_err(|e| FileStreamError::IoError(std::io::Error::other(e)))?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            (Some(host), None) => host.to_string(),
+            (None, _) => {
+                return Err(FileStreamError::IoError(std::io::Error::other(
+                    "S3 endpoint has no host",
+                )));
+            }
+        };
+
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", EMPTY_PAYLOAD_SHA256.to_string()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+            .collect();
+        let canonical_request = format!(
+            "{method}\n{canonical_uri}\n\n{canonical_headers}\n{signed_headers}\n{EMPTY_PAYLOAD_SHA256}"
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{:x}",
+            Sha256::digest(canonical_request.as_bytes())
+        );
+
+        let signing_key = [self.region.as_str(), "s3", "aws4_request"].iter().fold(
+            hmac(
+                format!("AWS4{}", self.secret_access_key).as_bytes(),
+                date.as_bytes(),
+            ),
+            |key, part| hmac(&key, part.as_bytes()),
+        );
+        let signature = hmac(&signing_key, string_to_sign.as_bytes())
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+            self.access_key_id
+        );
+
+        let mut request = self
+            .client
+            .request(method, url)
+            .header(header::AUTHORIZATION, authorization);
+        // `host` is set by reqwest from the URL.
+        for (name, value) in headers.into_iter().skip(1) {
+            request = request.header(name, value);
+        }
+        Ok(request)
+    }
+
+    async fn send(&self, request: RequestBuilder, key: &str) -> Result<Response, FileStreamError> {
+        let response = request
+            .send()
+            .await
+            .map_err(|e| FileStreamError::IoError(std::io::Error::other(e)))?;
+        match response.status() {
+            status if status.is_success() => Ok(response),
+            StatusCode::NOT_FOUND => Err(FileStreamError::NotFound(PathBuf::from(key))),
+            StatusCode::FORBIDDEN => Err(FileStreamError::AccessDenied),
+            status => Err(FileStreamError::IoError(std::io::Error::other(format!(
+                "S3 returned HTTP {status} for {key}"
+            )))),
+        }
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+impl StorageBackend for S3Storage {
+    fn verify<'a>(&'a self, path: &'a str) -> StorageFuture<'a, FileInfo> {
+        Box::pin(async move {
+            let key = self.object_key(path)?;
+            let response = self
+                .send(self.signed_request(Method::HEAD, &key)?, &key)
+                .await?;
+            let headers = response.headers();
+            let len = headers
+                .get(header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(0);
+            let modified = headers
+                .get(header::LAST_MODIFIED)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| DateTime::parse_from_rfc2822(value).ok())
+                .map(SystemTime::from);
+            Ok(FileInfo {
+                mime: mime_type_for(&key),
+                path: PathBuf::from(key),
+                len,
+                modified,
+            })
+        })
+    }
+
+    fn open_stream<'a>(
+        &'a self,
+        file: &'a FileInfo,
+        range: Option<ByteRange>,
+    ) -> StorageFuture<'a, Body> {
+        Box::pin(async move {
+            let key = file.path.to_string_lossy();
+            let mut request = self.signed_request(Method::GET, &key)?;
+            if let Some(range) = range {
+                let end = range.end.map(|end| end.to_string()).unwrap_or_default();
+                request = request.header(header::RANGE, format!("bytes={}-{end}", range.start));
+            }
+            let response = self.send(request, &key).await?;
+            Ok(Body::from_stream(response.bytes_stream()))
+        })
+    }
+}
@@ -0,0 +1,183 @@
+use axum::body::Body;
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    error::FileStreamError,
+    io::{DEFAULT_STREAM_BUFFER_BYTES, FileInfo, FileStability, open_error, verify_file},
+};
+
+pub type StorageFuture<'a, T> =
+    Pin<Box<dyn Future<Output = Result<T, FileStreamError>> + Send + 'a>>;
+
+/// Inclusive byte range, as in an HTTP `Range: bytes=start-end` header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    /// Last byte to send; `None` reads to the end.
+    pub end: Option<u64>,
+}
+
+/// Where `/stream/{filename}` content lives.
+///
+/// Errors use [`FileStreamError`] so every backend maps onto the same HTTP statuses.
+pub trait StorageBackend: Send + Sync {
+    /// Checks that `path` names a readable file and returns its metadata.
+    fn verify<'a>(&'a self, path: &'a str) -> StorageFuture<'a, FileInfo>;
+
+    /// Streams a file previously returned by [`StorageBackend::verify`].
+    fn open_stream<'a>(
+        &'a self,
+        file: &'a FileInfo,
+        range: Option<ByteRange>,
+    ) -> StorageFuture<'a, Body>;
+}
+
+/// Files under a local directory.
+pub struct LocalStorage {
+    /// Canonical form of the configured directory.
+    base_directory: PathBuf,
+    /// Largest read of a file stream.
+    buffer_bytes: usize,
+    stability: FileStability,
+}
+
+impl LocalStorage {
+    /// Canonicalizes `base_directory`, which must exist.
+    pub fn new(base_directory: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Self {
+            base_directory: base_directory.as_ref().canonicalize()?,
+            buffer_bytes: DEFAULT_STREAM_BUFFER_BYTES,
+            stability: FileStability::default(),
+        })
+    }
+
+    /// Reads files in chunks of up to `buffer_bytes` instead of
+    /// [`DEFAULT_STREAM_BUFFER_BYTES`].
+    pub fn with_buffer_bytes(mut self, buffer_bytes: usize) -> Self {
+        self.buffer_bytes = buffer_bytes.max(1);
+        self
+    }
+
+    /// Refuses files `stability` says are still being written.
+    pub fn with_stability(mut self, stability: FileStability) -> Self {
+        self.stability = stability;
+        self
+    }
+}
+
+impl StorageBackend for LocalStorage {
+    fn verify<'a>(&'a self, path: &'a str) -> StorageFuture<'a, FileInfo> {
+        Box::pin(async move {
+            if self.stability.is_ignored(path) {
+                return Err(FileStreamError::NotFound(self.base_directory.join(path)));
+            }
+            let file = verify_file(&self.base_directory, path)?;
+            self.stability.check(&file)?;
+            Ok(file)
+        })
+    }
+
+    fn open_stream<'a>(
+        &'a self,
+        file: &'a FileInfo,
+        range: Option<ByteRange>,
+    ) -> StorageFuture<'a, Body> {
+        Box::pin(async move { open_local(file, range, self.buffer_bytes, &self.stability).await })
+    }
+}
+
+async fn open_local(
+    info: &FileInfo,
+    range: Option<ByteRange>,
+    buffer_bytes: usize,
+    stability: &FileStability,
+) -> Result<Body, FileStreamError> {
+    let path = &info.path;
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| open_error(path, e))?;
+    // A file written to since it was verified, while its payment settled, isn't done yet;
+    // serving it would send other bytes than were priced, or cut off `len` early.
+    let metadata = file.metadata().await?;
+    if metadata.len() != info.len || metadata.modified().ok() != info.modified {
+        return Err(FileStreamError::NotReady {
+            path: path.clone(),
+            retry_after: stability.min_age,
+        });
+    }
+    let capacity = |len| read_capacity(buffer_bytes, len);
+    let Some(range) = range else {
+        let stream = ReaderStream::with_capacity(file, capacity(info.len));
+        return Ok(Body::from_stream(stream));
+    };
+    file.seek(std::io::SeekFrom::Start(range.start)).await?;
+    let body = match range.end {
+        Some(end) => {
+            let len = end.saturating_sub(range.start).saturating_add(1);
+            Body::from_stream(ReaderStream::with_capacity(file.take(len), capacity(len)))
+        }
+        None => {
+            let len = info.len.saturating_sub(range.start);
+            Body::from_stream(ReaderStream::with_capacity(file, capacity(len)))
+        }
+    };
+    Ok(body)
+}
+
+/// The buffer for reading `len` bytes, no larger than the read needs.
+fn read_capacity(buffer_bytes: usize, len: u64) -> usize {
+    usize::try_from(len)
+        .map_or(buffer_bytes, |len| len.min(buffer_bytes))
+        .max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("storage-test-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[tokio::test]
+    async fn unchanged_file_is_streamed() {
+        let directory = directory("unchanged");
+        std::fs::write(directory.join("a.ts"), b"segment").unwrap();
+        let storage = LocalStorage::new(&directory).unwrap();
+
+        let file = storage.verify("a.ts").await.unwrap();
+        let body = storage.open_stream(&file, None).await.unwrap();
+        let bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        assert_eq!(&bytes[..], b"segment");
+    }
+
+    #[tokio::test]
+    async fn file_grown_between_verify_and_open_is_not_ready() {
+        let directory = directory("grown");
+        std::fs::write(directory.join("a.ts"), b"segment").unwrap();
+        // No minimum age, so only the comparison after opening can notice the write.
+        let storage = LocalStorage::new(&directory).unwrap();
+
+        let file = storage.verify("a.ts").await.unwrap();
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(directory.join("a.ts"))
+            .unwrap()
+            .write_all(b" and more")
+            .unwrap();
+        assert!(matches!(
+            storage.open_stream(&file, None).await,
+            Err(FileStreamError::NotReady { .. })
+        ));
+    }
+}
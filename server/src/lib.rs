@@ -2,4 +2,4 @@ pub mod error;
 pub mod io;
 pub mod x402;
 
-pub use error::{FileStreamError, PaymentError};
+pub use error::{FileStreamError, IpfsPathError, PaymentError, RemoteFetchError};
@@ -1,49 +1,623 @@
 mod http;
 
-use env_logger::Env;
-use http::Config;
-use log::{error, info};
-use server::x402::FacilitatorClient;
-use std::sync::Arc;
+use axum::http::{HeaderMap, HeaderValue, header::AUTHORIZATION};
+use clap::Parser;
+use http::{
+    Config,
+    access_log::AccessLog,
+    audit::{AuditEntry, AuditLog, Decision},
+    config::{LogFormat, StorageKind},
+    free_paths::FreePaths,
+    mounts::Mounts,
+    playlist::GeneratedPlaylists,
+    playlist_cache::RemotePlaylistCache,
+    preflight,
+    preview::PreviewQuota,
+    pricing::{PriceResolver, PriceTable},
+    rate_limit::RateLimiter,
+    session::SessionSigner,
+    settlement_limit::SettlementLimiter,
+    settlement_store::SettlementStore,
+    shutdown::{InFlight, shutdown_signal},
+    tab_spend::TabSpendCap,
+    webhook::WebhookNotifier,
+};
+use server::{
+    io::{LocalStorage, RemoteFetcher, S3Storage, StorageBackend},
+    x402::{
+        AsyncSettler, DeferredSettler, Facilitator, FacilitatorClient, FailedSettlement,
+        FailureHook, PendingSettlements, SANDBOX_SCHEME, SettlementCache, SettlementMode,
+        SpendLedger, TabAudit, X402Mode,
+    },
+};
+use std::{future::IntoFuture, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::EnvFilter;
+
+/// Settings come from the environment; `--config` (or `CONFIG_FILE`) adds a TOML file
+/// underneath it.
+#[derive(Debug, Parser)]
+struct Args {
+    /// TOML configuration file; environment variables override its values.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Check the configuration, storage, facilitator, and RPC endpoints, print a report,
+    /// and exit non-zero if any check failed, without serving.
+    #[arg(long)]
+    check: bool,
+}
+
+/// Deadline for the startup request that checks the facilitator is reachable.
+pub(crate) const FACILITATOR_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Installs the global tracing subscriber. `log` records emitted by dependencies are
+/// forwarded through the tracing-log bridge.
+fn init_tracing(config: &Config) {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(config.log_level.as_str()));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match config.log_format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
+/// Records a payment whose background settlement gave up in the settlement store and the
+/// audit log.
+fn settlement_failure_hook(
+    settlement_store: &Option<SettlementStore>,
+    audit_log: &Option<AuditLog>,
+) -> FailureHook {
+    let settlement_store = settlement_store.clone();
+    let audit_log = audit_log.clone();
+    Box::new(move |failed: FailedSettlement| {
+        let error = failed.error.to_string();
+        if let Some(store) = &settlement_store {
+            store.record_unsettled(&failed.resource, &failed.summary, &error);
+        }
+        if let Some(audit_log) = &audit_log {
+            audit_log.log(AuditEntry {
+                scheme: Some(failed.summary.scheme),
+                payer: failed.summary.payer,
+                amount: Some(failed.summary.amount),
+                error: Some(error),
+                ..AuditEntry::new(Decision::SettlementFailed, &failed.resource)
+            });
+        }
+    })
+}
+
+/// The facilitator client payments go through, with its fallbacks, credentials, and
+/// retry settings.
+fn facilitator_client(config: &Config) -> anyhow::Result<FacilitatorClient> {
+    let mut facilitator_headers = HeaderMap::new();
+    if let Some(api_key) = &config.x402.facilitator_api_key {
+        let mut value = HeaderValue::from_str(&format!("Bearer {api_key}"))?;
+        value.set_sensitive(true);
+        facilitator_headers.insert(AUTHORIZATION, value);
+    }
+    let mut facilitator_urls = config.x402.facilitators().into_iter();
+    let primary = facilitator_urls
+        .next()
+        .expect("at least one facilitator is configured");
+    let facilitator = FacilitatorClient::try_new_with_fallbacks(primary, facilitator_urls)?
+        .with_headers(facilitator_headers)
+        .with_timeout(Duration::from_secs(config.x402.facilitator_timeout_seconds))
+        .with_retries(
+            config.x402.facilitator_max_attempts,
+            Duration::from_millis(config.x402.facilitator_retry_base_delay_ms),
+        )
+        .with_tab_failure_ttl(Duration::from_secs(config.x402.tab_failure_cache_seconds))
+        .with_tab_cache_ttl(Duration::from_secs(config.x402.tab_cache_seconds))
+        .with_tab_stale_window(Duration::from_secs(config.x402.tab_stale_seconds))
+        .with_failover_cooldown(Duration::from_secs(
+            config.x402.facilitator_failover_cooldown_seconds,
+        ));
+    Ok(match &config.x402.facilitator_hmac_key {
+        Some(key) => facilitator.with_signing_key(key),
+        None => facilitator,
+    })
+}
+
+/// The storage backend `/stream/{filename}` serves from.
+fn open_storage(config: &Config) -> Result<Arc<dyn StorageBackend>, String> {
+    match config.storage_backend {
+        StorageKind::Local => match LocalStorage::new(&config.file_directory) {
+            Ok(storage) => Ok(Arc::new(
+                storage
+                    .with_buffer_bytes(config.stream_buffer_bytes)
+                    .with_stability(config.file_stability()),
+            )),
+            Err(e) => Err(format!(
+                "Failed to open FILE_DIRECTORY {}: {}",
+                config.file_directory, e
+            )),
+        },
+        StorageKind::S3 => match S3Storage::new(&config.s3) {
+            Ok(storage) => Ok(Arc::new(storage)),
+            Err(e) => Err(format!("Failed to configure S3 storage: {}", e)),
+        },
+    }
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenv::dotenv().ok();
 
-    let config = match Config::from_env() {
+    let args = Args::parse();
+    let config_file = args
+        .config
+        .or_else(|| std::env::var_os("CONFIG_FILE").map(PathBuf::from));
+    let config = match Config::load(config_file.as_deref()) {
         Ok(config) => Arc::new(config),
         Err(e) => {
-            error!("Failed to load configuration: {}", e);
+            // Logging isn't initialized yet: it depends on the config.
+            eprintln!("Failed to load configuration: {}", e);
             std::process::exit(1);
         }
     };
 
-    env_logger::Builder::from_env(Env::default().default_filter_or(config.log_level.as_str()))
-        .init();
+    init_tracing(&config);
+    debug!(config = ?config.redacted(), "Effective configuration");
+    let validated = config.validate();
+    if let Err(errors) = &validated
+        && !args.check
+    {
+        for error in errors {
+            error!("Invalid configuration: {}", error);
+        }
+        std::process::exit(1);
+    }
 
-    let facilitator = FacilitatorClient::try_new(config.x402.facilitator_url.clone())?;
+    let facilitator = match config.x402.uses_facilitator() {
+        true => Some(facilitator_client(&config)?),
+        false => None,
+    };
+    if args.check {
+        let storage = open_storage(&config);
+        let passed = preflight::run(&config, validated, facilitator.as_ref(), storage).await;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+    let facilitator_reachable = match &facilitator {
+        None => true,
+        Some(facilitator) => match facilitator.probe(FACILITATOR_PROBE_TIMEOUT).await {
+            Ok(latency) => {
+                info!(
+                    "Facilitator at {} is reachable ({} ms)",
+                    facilitator.base_url(),
+                    latency.as_millis()
+                );
+                true
+            }
+            Err(e) if config.x402.require_facilitator => {
+                error!(
+                    "Facilitator at {} is unreachable: {}",
+                    facilitator.base_url(),
+                    e
+                );
+                std::process::exit(1);
+            }
+            Err(e) => {
+                warn!(
+                    "Facilitator at {} is unreachable, starting degraded: {}",
+                    facilitator.base_url(),
+                    e
+                );
+                false
+            }
+        },
+    };
+    let storage = match open_storage(&config) {
+        Ok(storage) => storage,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let remote = match RemoteFetcher::new(&config.remote) {
+        Ok(remote) => Arc::new(remote.with_buffer_bytes(config.stream_buffer_bytes)),
+        Err(e) => {
+            error!("Failed to build the remote fetch client: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let ledger = match &config.spend_ledger_path {
+        Some(path) => match SpendLedger::open(path, config.spend_ledger_recent) {
+            Ok(ledger) => ledger,
+            Err(e) => {
+                error!("Failed to open SPEND_LEDGER_PATH {}: {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        None => SpendLedger::new(config.spend_ledger_recent),
+    }
+    .with_split(config.x402.pay_to_split.clone());
+    let settlement_store = match &config.database_path {
+        Some(path) => match SettlementStore::open(path, config.database_queue_size) {
+            Ok(store) => Some(store),
+            Err(e) => {
+                error!("Failed to open DATABASE_PATH {}: {:#}", path, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let audit_log = config.audit_log_path.as_ref().and_then(|path| {
+        AuditLog::spawn(
+            path.into(),
+            config.audit_log_max_bytes,
+            config.audit_log_keep,
+            config.audit_log_fsync,
+        )
+        .inspect_err(|e| warn!("Audit log disabled: {}", e))
+        .ok()
+    });
+    let access_log = match (&config.access_log_path, config.access_log) {
+        (Some(path), true) => match AccessLog::spawn(path.into()) {
+            Ok(access_log) => Some(access_log),
+            Err(e) => {
+                error!("Failed to open ACCESS_LOG_PATH {}: {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        (None, true) => Some(AccessLog::logger()),
+        (_, false) => None,
+    };
+    let free_paths = match FreePaths::from_config(&config.x402) {
+        Ok(free_paths) => free_paths,
+        Err(e) => {
+            error!("Invalid X402_FREE_PATHS: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let mounts = match Mounts::from_config(&config) {
+        Ok(mounts) => mounts,
+        Err(e) => {
+            error!("Invalid MOUNTS: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let shutdown = CancellationToken::new();
+    let in_flight = InFlight::default();
+    let facilitator = facilitator.map(|facilitator| Arc::new(facilitator) as Arc<dyn Facilitator>);
+    if config.x402.mode == X402Mode::Sandbox {
+        warn!(
+            "SANDBOX mode: `{}` payments are accepted without settling anything",
+            SANDBOX_SCHEME
+        );
+    }
+    let pending_settlements = match &config.x402.pending_settlements_path {
+        Some(path) => match PendingSettlements::open(path, config.x402.pending_settlements_fsync) {
+            Ok((journal, leftover)) => {
+                match &facilitator {
+                    Some(facilitator) => journal.recover(
+                        facilitator.clone(),
+                        leftover,
+                        settlement_failure_hook(&settlement_store, &audit_log),
+                        shutdown.clone(),
+                    ),
+                    // Left open in the journal for a run that has a facilitator.
+                    None if !leftover.is_empty() => warn!(
+                        "{} pending settlements need a facilitator; not recovering them",
+                        leftover.len()
+                    ),
+                    None => {}
+                }
+                Some(journal)
+            }
+            Err(e) => {
+                error!(
+                    "Failed to open X402_PENDING_SETTLEMENTS_PATH {}: {}",
+                    path, e
+                );
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    // Both only take payments the facilitator settles.
+    let deferred = facilitator
+        .clone()
+        .filter(|_| config.x402.settlement_mode == SettlementMode::Deferred)
+        .map(|facilitator| {
+            DeferredSettler::spawn(
+                facilitator,
+                config.x402.deferred_threshold,
+                Duration::from_secs(config.x402.deferred_max_age_seconds),
+                shutdown.clone(),
+                pending_settlements.clone(),
+            )
+        });
+    let settle_queue = facilitator
+        .clone()
+        .filter(|_| config.x402.async_settle)
+        .map(|facilitator| {
+            AsyncSettler::spawn(
+                facilitator,
+                config.x402.async_settle_queue_size,
+                settlement_failure_hook(&settlement_store, &audit_log),
+                pending_settlements.clone(),
+            )
+        });
+    let tab_audit = match config.tab_audit_interval_seconds {
+        Some(_) if !config.x402.enable_4mica => {
+            warn!("X402_ENABLE_4MICA is off; ignoring TAB_AUDIT_INTERVAL_SECONDS");
+            None
+        }
+        Some(_) if std::env::var_os("4MICA_WALLET_PRIVATE_KEY").is_none() => {
+            warn!("4MICA_WALLET_PRIVATE_KEY is unset; ignoring TAB_AUDIT_INTERVAL_SECONDS");
+            None
+        }
+        Some(seconds) => Some(TabAudit::spawn(
+            config.x402.clone(),
+            Duration::from_secs(seconds),
+            shutdown.clone(),
+        )),
+        None => None,
+    };
+    let rpc = server::x402::rpc_client(&config.x402);
+    let decimals = match (&config.x402.price_human, config.x402.asset_decimals) {
+        (Some(_), None) => {
+            let x402 = &config.x402;
+            match server::x402::asset_decimals(&rpc, x402, &x402.network, &x402.asset).await {
+                Ok(decimals) => {
+                    info!("Asset {} has {} decimals", x402.asset, decimals);
+                    Some(decimals)
+                }
+                Err(e) => {
+                    error!("Failed to read the decimals of {}: {}", x402.asset, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        (_, decimals) => decimals,
+    };
+    let resolver = match PriceResolver::from_config(&config.x402, decimals) {
+        Ok(resolver) => resolver,
+        Err(e) => {
+            error!("Invalid price: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let pricing = Arc::new(PriceTable::new(resolver, config_file.clone(), decimals));
+    tokio::spawn(pricing.clone().watch(shutdown.clone()));
     let state = http::router::AppState {
         config: config.clone(),
-        facilitator: Arc::new(facilitator),
+        facilitator,
+        shutdown: shutdown.clone(),
+        in_flight: in_flight.clone(),
+        facilitator_reachable,
+        pricing: pricing.clone(),
+        free_paths,
+        previews: PreviewQuota::from_config(&config.x402),
+        tab_audit,
+        tab_spend: TabSpendCap::from_config(&config.x402),
+        rate_limiter: RateLimiter::new(config.rate_limit_rps, config.rate_limit_burst),
+        settlement_limiter: SettlementLimiter::new(
+            config.x402.max_concurrent_settlements,
+            Duration::from_millis(config.x402.settlement_wait_ms),
+        ),
+        tab_rate_limiter: config.tab_rate_limit_per_minute.and_then(|per_minute| {
+            RateLimiter::new(Some(f64::from(per_minute) / 60.0), per_minute)
+        }),
+        storage,
+        mounts,
+        remote_playlists: RemotePlaylistCache::from_config(&config, remote.clone()),
+        remote,
+        rpc,
+        playlists: Arc::new(GeneratedPlaylists::new(config.file_stability())),
+        settlements: Arc::new(SettlementCache::new(
+            Duration::from_secs(config.x402.settlement_cache_seconds),
+            config.x402.replay_retention(),
+        )),
+        ledger: Arc::new(ledger),
+        deliveries: Arc::default(),
+        deferred: deferred.clone(),
+        settle_queue: settle_queue.clone(),
+        pending_settlements: pending_settlements.clone(),
+        settlement_store: settlement_store.clone(),
+        webhook: config.webhook_url.clone().map(|url| {
+            WebhookNotifier::spawn(
+                url,
+                config.webhook_secret.clone(),
+                config.webhook_queue_size,
+                shutdown.clone(),
+            )
+        }),
+        audit_log: audit_log.clone(),
+        access_log,
+        sessions: config.session_secret.as_deref().map(|secret| {
+            SessionSigner::new(
+                secret,
+                config.session_duration_seconds,
+                config.session_prefix_strip_segments,
+            )
+        }),
     };
     let app = http::router::build_router(state);
 
-    let addr = format!("{}:{}", config.server_host, config.server_port);
-    let listener = match tokio::net::TcpListener::bind(&addr).await {
-        Ok(listener) => listener,
-        Err(e) => {
-            error!("Failed to bind to {}: {}", addr, e);
+    let tls = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert), Some(key)) => match http::tls::load_rustls_config(cert, key).await {
+            Ok(tls) => Some(tls),
+            Err(e) => {
+                error!("Failed to load TLS configuration: {:#}", e);
+                std::process::exit(1);
+            }
+        },
+        (None, None) => None,
+        _ => {
+            error!("TLS_CERT_PATH and TLS_KEY_PATH must be set together");
             std::process::exit(1);
         }
     };
+    if tls.is_some() && config.server_advertised_url.scheme() == "http" {
+        warn!(
+            "TLS is enabled but SERVER_ADVERTISED_URL is {}; clients will be sent http:// tab endpoints",
+            config.server_advertised_url
+        );
+    }
+
+    let listener = match &config.unix_socket {
+        #[cfg(unix)]
+        Some(path) => match http::unix_socket::bind(path, config.unix_socket_mode) {
+            Ok(listener) => {
+                info!("Server listening on unix:{}", path);
+                Listener::Unix(listener)
+            }
+            Err(e) => {
+                error!("Failed to bind to Unix socket {}: {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        // `SERVER_UNIX_SOCKET` is rejected by validation where Unix sockets don't exist.
+        _ => {
+            let addr = format!("{}:{}", config.server_host, config.server_port);
+            match tokio::net::TcpListener::bind(&addr).await {
+                Ok(listener) => {
+                    info!(
+                        "Server listening on {}{}",
+                        addr,
+                        if tls.is_some() { " (TLS)" } else { "" }
+                    );
+                    Listener::Tcp(listener)
+                }
+                Err(e) => {
+                    error!("Failed to bind to {}: {}", addr, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+    };
+    match config.storage_backend {
+        StorageKind::Local => info!("Serving files from: {}", config.file_directory),
+        StorageKind::S3 => info!(
+            "Serving files from: s3://{}/{}",
+            config.s3.bucket.as_deref().unwrap_or_default(),
+            config.s3.prefix
+        ),
+    }
+    info!(
+        "Streaming in chunks of up to {} bytes",
+        config.stream_buffer_bytes
+    );
 
-    info!("Server listening on {}", addr);
-    info!("Serving files from: {}", config.file_directory);
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown_signal().await;
+            shutdown.cancel();
+        }
+    });
 
-    if let Err(e) = axum::serve(listener, app).await {
+    let grace = Duration::from_secs(config.shutdown_grace_seconds);
+    let result = match (listener, tls) {
+        // Validation rules out TLS here; nginx or another local proxy terminates it.
+        #[cfg(unix)]
+        (Listener::Unix(listener), _) => {
+            let serve = axum::serve(listener, app.into_make_service())
+                .with_graceful_shutdown(shutdown.clone().cancelled_owned())
+                .into_future();
+            serve_until_drained(serve, &shutdown, &in_flight, grace).await
+        }
+        (Listener::Tcp(listener), Some(tls)) => {
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                let shutdown = shutdown.clone();
+                async move {
+                    shutdown.cancelled().await;
+                    handle.graceful_shutdown(None);
+                }
+            });
+            let serve = async {
+                axum_server::from_tcp_rustls(listener.into_std()?, tls)
+                    .handle(handle)
+                    .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                    .await
+            };
+            serve_until_drained(serve, &shutdown, &in_flight, grace).await
+        }
+        (Listener::Tcp(listener), None) => {
+            let serve = axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(shutdown.clone().cancelled_owned())
+            .into_future();
+            serve_until_drained(serve, &shutdown, &in_flight, grace).await
+        }
+    };
+    #[cfg(unix)]
+    if let Some(path) = &config.unix_socket {
+        http::unix_socket::remove(path);
+    }
+
+    if let Some(deferred) = &deferred {
+        deferred.flush_all().await;
+    }
+    if let Some(settle_queue) = &settle_queue {
+        settle_queue.drain().await;
+    }
+    if let Some(journal) = &pending_settlements {
+        journal.flush().await;
+    }
+    if let Some(store) = &settlement_store {
+        store.flush().await;
+    }
+    if let Some(audit_log) = &audit_log {
+        audit_log.flush().await;
+    }
+
+    if let Err(e) = result {
         error!("Server error: {}", e);
         std::process::exit(1);
     }
 
     Ok(())
 }
+
+/// Where the server accepts connections.
+enum Listener {
+    Tcp(tokio::net::TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+/// Runs the server future until it exits, or until shutdown is requested and either
+/// every in-flight request finished or the grace period elapsed.
+async fn serve_until_drained<F>(
+    serve: F,
+    shutdown: &CancellationToken,
+    in_flight: &InFlight,
+    grace: Duration,
+) -> std::io::Result<()>
+where
+    F: Future<Output = std::io::Result<()>>,
+{
+    tokio::pin!(serve);
+
+    tokio::select! {
+        result = &mut serve => result,
+        _ = shutdown.cancelled() => {
+            let pending = in_flight.count();
+            info!("Shutting down; draining {} in-flight requests (grace {:?})", pending, grace);
+            match tokio::time::timeout(grace, &mut serve).await {
+                Ok(result) => {
+                    info!("Drained {} in-flight requests, aborted 0", pending);
+                    result
+                }
+                Err(_) => {
+                    let aborted = in_flight.count();
+                    warn!(
+                        "Grace period elapsed; drained {} in-flight requests, aborted {}",
+                        pending.saturating_sub(aborted),
+                        aborted
+                    );
+                    Ok(())
+                }
+            }
+        }
+    }
+}
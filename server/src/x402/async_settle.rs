@@ -0,0 +1,302 @@
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info, warn};
+
+use crate::{
+    error::PaymentError,
+    x402::{
+        Facilitator, PendingSettlements, SettlementSummary,
+        deferred::{DeferredPayment, settle_verified},
+        settlement_cache::payment_key,
+    },
+};
+
+/// Settle attempts per payment before it is parked as failed.
+const MAX_ATTEMPTS: u32 = 5;
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+type HeaderHash = [u8; 32];
+
+/// A verified payment whose settlement gave up after [`MAX_ATTEMPTS`].
+pub struct FailedSettlement {
+    pub resource: String,
+    pub summary: SettlementSummary,
+    pub attempts: u32,
+    pub error: PaymentError,
+}
+
+/// Called once for every [`FailedSettlement`], from the worker task.
+pub type FailureHook = Box<dyn Fn(FailedSettlement) + Send + Sync>;
+
+struct Job {
+    key: HeaderHash,
+    resource: String,
+    payment: DeferredPayment,
+    summary: SettlementSummary,
+}
+
+enum Command {
+    Settle(Box<Job>),
+    Drain(oneshot::Sender<()>),
+}
+
+struct Unsettled {
+    resource: String,
+    summary: SettlementSummary,
+    queued: Instant,
+    attempts: u32,
+    last_error: Option<String>,
+}
+
+/// One verified but unsettled payment, as listed by `GET /admin/unsettled`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnsettledPaymentView {
+    pub resource: String,
+    pub scheme: String,
+    pub network: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payer: Option<String>,
+    pub amount: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tab_id: Option<String>,
+    pub age_seconds: u64,
+    pub attempts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+impl From<&Unsettled> for UnsettledPaymentView {
+    fn from(payment: &Unsettled) -> Self {
+        Self {
+            resource: payment.resource.clone(),
+            scheme: payment.summary.scheme.clone(),
+            network: payment.summary.network.clone(),
+            payer: payment.summary.payer.clone(),
+            amount: payment.summary.amount.clone(),
+            tab_id: payment.summary.tab_id.clone(),
+            age_seconds: payment.queued.elapsed().as_secs(),
+            attempts: payment.attempts,
+            last_error: payment.last_error.clone(),
+        }
+    }
+}
+
+/// Payments waiting for their settlement, plus those that gave up after
+/// [`MAX_ATTEMPTS`].
+#[derive(Debug, Serialize)]
+pub struct UnsettledSnapshot {
+    pub pending: Vec<UnsettledPaymentView>,
+    pub failed: Vec<UnsettledPaymentView>,
+}
+
+/// Settles verified payments with the facilitator from a background task, so paid
+/// requests only wait for `/verify`.
+///
+/// Payments are settled one at a time in the order they were queued, each retried with
+/// exponential backoff; after [`MAX_ATTEMPTS`] a payment is parked as failed and handed
+/// to the [`FailureHook`]. The queue is bounded: once full, [`AsyncSettler::enqueue`]
+/// waits for room. With a `journal`, queued payments survive a crash as pending
+/// settlements.
+pub struct AsyncSettler {
+    tx: mpsc::Sender<Command>,
+    journal: Option<Arc<PendingSettlements>>,
+    pending: Mutex<HashMap<HeaderHash, Unsettled>>,
+    /// Headers being verified, not yet queued.
+    reserved: Mutex<HashSet<HeaderHash>>,
+    failed: Mutex<Vec<Unsettled>>,
+}
+
+impl AsyncSettler {
+    /// Starts the worker; call [`AsyncSettler::drain`] on shutdown to settle what is
+    /// still queued.
+    pub fn spawn(
+        facilitator: Arc<dyn Facilitator>,
+        queue_size: usize,
+        on_failure: FailureHook,
+        journal: Option<Arc<PendingSettlements>>,
+    ) -> Arc<Self> {
+        let (tx, mut rx) = mpsc::channel(queue_size.max(1));
+        let settler = Arc::new(Self {
+            tx,
+            journal,
+            pending: Mutex::new(HashMap::new()),
+            reserved: Mutex::new(HashSet::new()),
+            failed: Mutex::new(Vec::new()),
+        });
+        tokio::spawn({
+            let settler = Arc::downgrade(&settler);
+            async move {
+                while let Some(command) = rx.recv().await {
+                    let Some(settler) = settler.upgrade() else {
+                        break;
+                    };
+                    match command {
+                        Command::Settle(job) => {
+                            if let Some(failed) = settler.settle(facilitator.as_ref(), *job).await {
+                                on_failure(failed);
+                            }
+                        }
+                        Command::Drain(reply) => {
+                            let _ = reply.send(());
+                        }
+                    }
+                }
+            }
+        });
+        settler
+    }
+
+    /// Claims `payment_header` while it is verified, so a concurrent request carrying it is
+    /// turned away; `None` if it is already claimed or still waits for its settlement. The
+    /// claim is given up when the guard drops, so take it until [`AsyncSettler::enqueue`]
+    /// has queued the payment.
+    pub(crate) fn reserve(&self, payment_header: &str) -> Option<Reservation<'_>> {
+        let key = payment_key(payment_header);
+        let pending = self.pending.lock();
+        if pending.contains_key(&key) || !self.reserved.lock().insert(key) {
+            return None;
+        }
+        Some(Reservation { settler: self, key })
+    }
+
+    /// Queues the settlement of a payment verified for `resource`.
+    pub async fn enqueue(
+        &self,
+        resource: &str,
+        payment: DeferredPayment,
+        summary: SettlementSummary,
+    ) {
+        if let Some(journal) = &self.journal {
+            journal.record(resource, &summary, &payment).await;
+        }
+        let key = payment_key(&payment.payment_header);
+        self.pending.lock().insert(
+            key,
+            Unsettled {
+                resource: resource.to_string(),
+                summary: summary.clone(),
+                queued: Instant::now(),
+                attempts: 0,
+                last_error: None,
+            },
+        );
+        let job = Job {
+            key,
+            resource: resource.to_string(),
+            payment,
+            summary,
+        };
+        if self.tx.send(Command::Settle(Box::new(job))).await.is_err() {
+            error!(
+                resource,
+                "Settlement worker stopped; payment left unsettled"
+            );
+        }
+    }
+
+    /// Waits until every payment queued so far has settled or given up.
+    pub async fn drain(&self) {
+        let queued = self.pending.lock().len();
+        if queued > 0 {
+            info!("Settling {} queued payments before shutdown", queued);
+        }
+        let (reply, rx) = oneshot::channel();
+        if self.tx.send(Command::Drain(reply)).await.is_ok() {
+            let _ = rx.await;
+        }
+    }
+
+    pub fn snapshot(&self) -> UnsettledSnapshot {
+        let mut pending: Vec<UnsettledPaymentView> =
+            self.pending.lock().values().map(Into::into).collect();
+        pending.sort_by_key(|view| std::cmp::Reverse(view.age_seconds));
+        UnsettledSnapshot {
+            pending,
+            failed: self.failed.lock().iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Settles one payment, retrying until it succeeds or runs out of attempts.
+    async fn settle(&self, facilitator: &dyn Facilitator, job: Job) -> Option<FailedSettlement> {
+        let mut attempts = 0;
+        let error = loop {
+            attempts += 1;
+            let error = match settle_verified(facilitator, &job.payment).await {
+                Ok(tx_hash) => {
+                    info!(resource = %job.resource, ?tx_hash, attempts, "Settled queued payment");
+                    self.pending.lock().remove(&job.key);
+                    if let Some(journal) = &self.journal {
+                        journal.settled(&job.payment.payment_header);
+                    }
+                    return None;
+                }
+                Err(e) => e,
+            };
+            if attempts >= MAX_ATTEMPTS {
+                break error;
+            }
+            let delay = RETRY_BASE_DELAY * 2u32.pow(attempts - 1);
+            warn!(
+                resource = %job.resource,
+                attempt = attempts,
+                error = %error,
+                "Queued payment settlement failed; retrying in {:?}",
+                delay
+            );
+            if let Some(payment) = self.pending.lock().get_mut(&job.key) {
+                payment.attempts = attempts;
+                payment.last_error = Some(error.to_string());
+            }
+            tokio::time::sleep(delay).await;
+        };
+
+        error!(
+            resource = %job.resource,
+            attempts,
+            error = %error,
+            "Giving up on queued payment settlement"
+        );
+        if let Some(journal) = &self.journal {
+            journal.failed(&job.payment.payment_header, &error.to_string());
+        }
+        let mut parked = self
+            .pending
+            .lock()
+            .remove(&job.key)
+            .unwrap_or_else(|| Unsettled {
+                resource: job.resource.clone(),
+                summary: job.summary.clone(),
+                queued: Instant::now(),
+                attempts,
+                last_error: None,
+            });
+        parked.attempts = attempts;
+        parked.last_error = Some(error.to_string());
+        self.failed.lock().push(parked);
+        Some(FailedSettlement {
+            resource: job.resource,
+            summary: job.summary,
+            attempts,
+            error,
+        })
+    }
+}
+
+/// A payment header claimed by [`AsyncSettler::reserve`], released on drop.
+pub(crate) struct Reservation<'a> {
+    settler: &'a AsyncSettler,
+    key: HeaderHash,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.settler.reserved.lock().remove(&self.key);
+    }
+}
@@ -0,0 +1,417 @@
+use sdk_4mica::{BLSCert, PaymentGuaranteeClaims, error::VerifyGuaranteeError};
+use std::str::FromStr;
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::{
+    PaymentError,
+    x402::{
+        FourMicaCertificate, SettlementSummary, X402Config, fourmica,
+        onchain::{normalize_address, parse_u256_value},
+    },
+};
+
+/// `X402_OPERATOR_PUBKEY`: the 4mica operator's compressed BLS12-381 public key, 48
+/// bytes in hex.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OperatorKey(pub [u8; 48]);
+
+impl FromStr for OperatorKey {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let digits = raw.trim().trim_start_matches("0x");
+        let bytes =
+            decode_hex(digits).ok_or_else(|| format!("operator public key {raw:?} is not hex"))?;
+        let key = <[u8; 48]>::try_from(bytes.as_slice())
+            .map_err(|_| format!("operator public key is {} bytes, expected 48", bytes.len()))?;
+        Ok(Self(key))
+    }
+}
+
+fn decode_hex(digits: &str) -> Option<Vec<u8>> {
+    if !digits.len().is_multiple_of(2) || !digits.is_ascii() {
+        return None;
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Why a settlement certificate does not prove the payment was guaranteed.
+#[derive(Debug, Error)]
+pub enum CertificateError {
+    #[error("the facilitator returned no certificate")]
+    Missing,
+
+    #[error("certificate is malformed: {0}")]
+    Malformed(String),
+
+    #[error("certificate signature does not match the operator key")]
+    BadSignature,
+
+    #[error("certificate {field} is {certified}, expected {expected}")]
+    Mismatch {
+        field: &'static str,
+        certified: String,
+        expected: String,
+    },
+
+    #[error("operator public key unavailable: {0}")]
+    NoOperatorKey(String),
+}
+
+/// Checks the certificate of a settled 4mica payment when `X402_OPERATOR_PUBKEY` or
+/// `X402_REQUIRE_CERTIFICATE` asks for it, recording the outcome on `summary`.
+///
+/// A bad certificate fails the settlement with `X402_REQUIRE_CERTIFICATE`, and is only
+/// logged otherwise. `claimed_amount` is the amount the payment's claims signed.
+pub(crate) async fn check_settlement_certificate(
+    summary: &mut SettlementSummary,
+    claimed_amount: Option<&str>,
+    config: &X402Config,
+) -> Result<(), PaymentError> {
+    if !config.require_certificate && config.operator_pubkey.is_none() {
+        return Ok(());
+    }
+    let result = match &summary.certificate {
+        Some(certificate) => {
+            verify_settlement_certificate(certificate, summary, claimed_amount, config).await
+        }
+        None => Err(CertificateError::Missing),
+    };
+    summary.certificate_verified = Some(result.is_ok());
+    match result {
+        Ok(claims) => {
+            info!(
+                tab_id = %claims.tab_id,
+                req_id = %claims.req_id,
+                amount = %claims.amount,
+                "Settlement certificate verified"
+            );
+            Ok(())
+        }
+        Err(e) if config.require_certificate => Err(PaymentError::Certificate(e)),
+        Err(e) => {
+            warn!("Settlement certificate not verified: {}", e);
+            Ok(())
+        }
+    }
+}
+
+async fn verify_settlement_certificate(
+    certificate: &FourMicaCertificate,
+    summary: &SettlementSummary,
+    claimed_amount: Option<&str>,
+    config: &X402Config,
+) -> Result<PaymentGuaranteeClaims, CertificateError> {
+    let cert = BLSCert {
+        claims: certificate.claims.clone(),
+        signature: certificate.signature.clone(),
+    };
+    let claims = match &config.operator_pubkey {
+        Some(key) => verify_certificate(&cert, key)?,
+        None => {
+            let client = fourmica::build_fourmica_client(config)
+                .await
+                .ok_or_else(|| {
+                    CertificateError::NoOperatorKey(
+                        "set X402_OPERATOR_PUBKEY or 4MICA_WALLET_PRIVATE_KEY".into(),
+                    )
+                })?;
+            client
+                .recipient
+                .verify_payment_guarantee(&cert)
+                .map_err(|e| match e {
+                    VerifyGuaranteeError::CertificateMismatch => CertificateError::BadSignature,
+                    e => CertificateError::Malformed(e.to_string()),
+                })?
+        }
+    };
+    check_claims(&claims, summary, claimed_amount)?;
+    Ok(claims)
+}
+
+/// The claims `cert` signs, if its signature verifies against `key`.
+pub fn verify_certificate(
+    cert: &BLSCert,
+    key: &OperatorKey,
+) -> Result<PaymentGuaranteeClaims, CertificateError> {
+    let valid = cert
+        .verify(&key.0)
+        .map_err(|e| CertificateError::Malformed(e.to_string()))?;
+    if !valid {
+        return Err(CertificateError::BadSignature);
+    }
+    let bytes = cert
+        .claims_bytes()
+        .map_err(|e| CertificateError::Malformed(e.to_string()))?;
+    PaymentGuaranteeClaims::try_from(bytes.as_slice())
+        .map_err(|e| CertificateError::Malformed(e.to_string()))
+}
+
+/// Whether certified `claims` pay `summary`: its recipient, asset and tab, and at least
+/// its amount; with `claimed_amount`, exactly what the payment claimed.
+fn check_claims(
+    claims: &PaymentGuaranteeClaims,
+    summary: &SettlementSummary,
+    claimed_amount: Option<&str>,
+) -> Result<(), CertificateError> {
+    let mismatch = |field, certified: &dyn ToString, expected: &str| CertificateError::Mismatch {
+        field,
+        certified: certified.to_string(),
+        expected: expected.to_string(),
+    };
+    if normalize_address(&claims.recipient_address) != normalize_address(&summary.pay_to) {
+        return Err(mismatch(
+            "recipient",
+            &claims.recipient_address,
+            &summary.pay_to,
+        ));
+    }
+    if normalize_address(&claims.asset_address) != normalize_address(&summary.asset) {
+        return Err(mismatch("asset", &claims.asset_address, &summary.asset));
+    }
+    if let Some(tab_id) = &summary.tab_id {
+        let expected = parse_u256_value(tab_id).map_err(CertificateError::Malformed)?;
+        if claims.tab_id != expected {
+            return Err(mismatch("tab id", &claims.tab_id, tab_id));
+        }
+    }
+    let price = parse_u256_value(&summary.amount).map_err(CertificateError::Malformed)?;
+    if claims.amount < price {
+        return Err(mismatch("amount", &claims.amount, &summary.amount));
+    }
+    if let Some(claimed) = claimed_amount
+        && let Ok(expected) = parse_u256_value(claimed)
+        && claims.amount != expected
+    {
+        return Err(mismatch("amount", &claims.amount, claimed));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use envconfig::Envconfig;
+    use sdk_4mica::U256;
+    use std::collections::HashMap;
+
+    const PAY_TO: &str = "0x1111111111111111111111111111111111111111";
+    const ASSET: &str = "0x2222222222222222222222222222222222222222";
+    const PAYER: &str = "0x3333333333333333333333333333333333333333";
+    /// The public key of the operator secret scalar `1`.
+    const OPERATOR_PUBKEY: &str = "97f1d3a73197d7942695638c4fa9ac0fc3688c4f9774b905a14e3a3f171bac58\
+                                   6c55e83ff97a1aeffb3af00adb22c6bb";
+    /// The public key of secret scalar `2`, which signs nothing here.
+    const OTHER_PUBKEY: &str = "a572cbea904d67468808c8eb50a9450c9721db309128012543902d0ac358a62a\
+                                e28f75bb8f1c7c42c39a8c5529bf0f4e";
+
+    fn operator_secret() -> [u8; 32] {
+        let mut secret = [0; 32];
+        secret[31] = 1;
+        secret
+    }
+
+    fn claims() -> PaymentGuaranteeClaims {
+        PaymentGuaranteeClaims {
+            domain: [0; 32],
+            user_address: PAYER.to_string(),
+            recipient_address: PAY_TO.to_string(),
+            tab_id: U256::from(7),
+            req_id: U256::from(1),
+            amount: U256::from(1000),
+            total_amount: U256::from(1000),
+            asset_address: ASSET.to_string(),
+            timestamp: 1_700_000_000,
+            version: 1,
+        }
+    }
+
+    fn certificate(claims: PaymentGuaranteeClaims) -> BLSCert {
+        BLSCert::new(&operator_secret(), claims).unwrap()
+    }
+
+    fn summary(certificate: Option<&BLSCert>) -> SettlementSummary {
+        SettlementSummary {
+            scheme: "4mica-credit".to_string(),
+            network: "polygon-amoy".to_string(),
+            pay_to: PAY_TO.to_string(),
+            asset: ASSET.to_string(),
+            amount: "1000".to_string(),
+            payer: Some(PAYER.to_string()),
+            tab_id: Some("0x7".to_string()),
+            tx_hash: None,
+            certificate: certificate.map(|cert| FourMicaCertificate {
+                claims: cert.claims.clone(),
+                signature: cert.signature.clone(),
+            }),
+            certificate_verified: None,
+        }
+    }
+
+    fn config(settings: &[(&str, &str)]) -> X402Config {
+        let mut env = HashMap::from([
+            ("X402_PAY_TO".to_string(), PAY_TO.to_string()),
+            ("X402_TAB_SNAPSHOT".to_string(), "off".to_string()),
+        ]);
+        for (name, value) in settings {
+            env.insert(name.to_string(), value.to_string());
+        }
+        X402Config::init_from_hashmap(&env).unwrap()
+    }
+
+    fn key(raw: &str) -> OperatorKey {
+        raw.parse().unwrap()
+    }
+
+    #[test]
+    fn operator_keys_are_48_byte_hex() {
+        assert_eq!(key(&format!("0x{OPERATOR_PUBKEY}")), key(OPERATOR_PUBKEY));
+        for raw in [
+            "",
+            "0x",
+            "zz",
+            "97f1d3",
+            &OPERATOR_PUBKEY[1..],
+            &format!("{OPERATOR_PUBKEY}00"),
+        ] {
+            assert!(raw.parse::<OperatorKey>().is_err(), "{raw:?}");
+        }
+    }
+
+    #[test]
+    fn valid_certificate_yields_its_claims() {
+        let certified = verify_certificate(&certificate(claims()), &key(OPERATOR_PUBKEY)).unwrap();
+        assert_eq!(certified.tab_id, U256::from(7));
+        assert_eq!(certified.amount, U256::from(1000));
+        assert_eq!(
+            normalize_address(&certified.recipient_address),
+            normalize_address(PAY_TO)
+        );
+        check_claims(&certified, &summary(None), Some("1000")).unwrap();
+    }
+
+    #[test]
+    fn tampered_claims_fail_the_signature() {
+        let mut cert = certificate(claims());
+        let mut raised = claims();
+        raised.amount = U256::from(1_000_000);
+        cert.claims = certificate(raised).claims;
+        assert!(matches!(
+            verify_certificate(&cert, &key(OPERATOR_PUBKEY)),
+            Err(CertificateError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn another_key_fails_the_signature() {
+        assert!(matches!(
+            verify_certificate(&certificate(claims()), &key(OTHER_PUBKEY)),
+            Err(CertificateError::BadSignature)
+        ));
+        let garbled = BLSCert {
+            claims: certificate(claims()).claims,
+            signature: "00".repeat(96),
+        };
+        assert!(matches!(
+            verify_certificate(&garbled, &key(OPERATOR_PUBKEY)),
+            Err(CertificateError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn claims_must_bind_the_payment() {
+        let cases: [(&str, PaymentGuaranteeClaims); 4] = [
+            (
+                "recipient",
+                PaymentGuaranteeClaims {
+                    recipient_address: PAYER.to_string(),
+                    ..claims()
+                },
+            ),
+            (
+                "asset",
+                PaymentGuaranteeClaims {
+                    asset_address: PAYER.to_string(),
+                    ..claims()
+                },
+            ),
+            (
+                "tab id",
+                PaymentGuaranteeClaims {
+                    tab_id: U256::from(8),
+                    ..claims()
+                },
+            ),
+            (
+                "amount",
+                PaymentGuaranteeClaims {
+                    amount: U256::from(999),
+                    ..claims()
+                },
+            ),
+        ];
+        for (field, claims) in cases {
+            match check_claims(&claims, &summary(None), None) {
+                Err(CertificateError::Mismatch { field: named, .. }) => assert_eq!(named, field),
+                other => panic!("{field}: {other:?}"),
+            }
+        }
+
+        // More than the price is fine, unless the payment claimed exactly another amount.
+        let over = PaymentGuaranteeClaims {
+            amount: U256::from(1500),
+            ..claims()
+        };
+        check_claims(&over, &summary(None), None).unwrap();
+        assert!(check_claims(&over, &summary(None), Some("1000")).is_err());
+    }
+
+    #[tokio::test]
+    async fn bad_certificates_fail_settlement_only_when_required() {
+        let tampered = BLSCert {
+            claims: certificate(claims()).claims,
+            signature: certificate(PaymentGuaranteeClaims {
+                amount: U256::from(1),
+                ..claims()
+            })
+            .signature,
+        };
+
+        let lenient = config(&[("X402_OPERATOR_PUBKEY", OPERATOR_PUBKEY)]);
+        let mut settled = summary(Some(&tampered));
+        check_settlement_certificate(&mut settled, None, &lenient)
+            .await
+            .unwrap();
+        assert_eq!(settled.certificate_verified, Some(false));
+
+        let strict = config(&[
+            ("X402_OPERATOR_PUBKEY", OPERATOR_PUBKEY),
+            ("X402_REQUIRE_CERTIFICATE", "true"),
+        ]);
+        for certificate in [Some(&tampered), None] {
+            let mut settled = summary(certificate);
+            assert!(matches!(
+                check_settlement_certificate(&mut settled, None, &strict).await,
+                Err(PaymentError::Certificate(_))
+            ));
+            assert_eq!(settled.certificate_verified, Some(false));
+        }
+
+        let mut settled = summary(Some(&certificate(claims())));
+        check_settlement_certificate(&mut settled, Some("1000"), &strict)
+            .await
+            .unwrap();
+        assert_eq!(settled.certificate_verified, Some(true));
+
+        // Nothing asks for a check, so none runs.
+        let mut settled = summary(Some(&tampered));
+        check_settlement_certificate(&mut settled, None, &config(&[]))
+            .await
+            .unwrap();
+        assert_eq!(settled.certificate_verified, None);
+    }
+}
@@ -1,26 +1,295 @@
 use envconfig::Envconfig;
+use sdk_4mica::U256;
+use serde::Deserialize;
+use std::{collections::HashMap, str::FromStr, time::Duration};
 use url::Url;
 
+use crate::{
+    io::FsyncPolicy,
+    x402::{OperatorKey, RevenueSplit, parse_units},
+};
+
+/// One network this server advertises and accepts payments on.
+#[derive(Debug, Clone)]
+pub struct Network {
+    /// v1 network name, e.g. `polygon-amoy`.
+    pub name: String,
+    /// CAIP-2 identifier used for v2 requirements; the network is v1-only without one.
+    pub network_v2: Option<String>,
+    /// Comma-separated endpoints, like `X402_RPC_URL`.
+    pub rpc_url: String,
+    pub asset: String,
+    pub pay_to: String,
+    pub asset_name: String,
+    pub asset_version: String,
+    /// Decimals of `asset`, when configured rather than read from the token.
+    pub asset_decimals: Option<u8>,
+}
+
+/// `X402_NETWORK_ALIASES`: comma-separated `alias=chain` pairs naming more identifiers of
+/// a chain, e.g. `amoy=eip155:80002`.
+#[derive(Debug, Clone)]
+pub struct NetworkAliasList(pub Vec<(String, String)>);
+
+impl FromStr for NetworkAliasList {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        raw.split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| match pair.split_once('=') {
+                Some((alias, chain)) if !alias.trim().is_empty() && !chain.trim().is_empty() => {
+                    Ok((alias.trim().to_string(), chain.trim().to_string()))
+                }
+                _ => Err(format!(
+                    "invalid network alias {pair:?}: expected alias=chain"
+                )),
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// The identifiers each chain is known by, so a network named in the v1 style
+/// (`polygon-amoy`) matches one named in the CAIP-2 style (`eip155:80002`). Identifiers
+/// compare without case; one not listed only matches itself.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkAliases {
+    /// Lowercase identifier to the lowercase chain it names.
+    chains: HashMap<String, String>,
+}
+
+impl NetworkAliases {
+    /// Makes `alias` another name of `chain`.
+    fn insert(&mut self, alias: &str, chain: &str) {
+        let chain = self.chain(chain);
+        self.chains.insert(alias.to_ascii_lowercase(), chain);
+    }
+
+    /// The chain `network` names, or `network` itself when it is not a known alias.
+    pub fn chain(&self, network: &str) -> String {
+        let network = network.to_ascii_lowercase();
+        self.chains.get(&network).cloned().unwrap_or(network)
+    }
+
+    /// Whether `a` and `b` name the same chain.
+    pub fn same_chain(&self, a: &str, b: &str) -> bool {
+        self.chain(a) == self.chain(b)
+    }
+
+    /// `network` followed by every other identifier of its chain.
+    pub fn identifiers(&self, network: &str) -> Vec<String> {
+        let chain = self.chain(network);
+        let mut identifiers = vec![network.to_string()];
+        let mut others: Vec<&String> = self
+            .chains
+            .iter()
+            .filter(|(_, aliased)| **aliased == chain)
+            .map(|(alias, _)| alias)
+            .chain(std::iter::once(&chain))
+            .filter(|alias| !alias.eq_ignore_ascii_case(network))
+            .collect();
+        others.sort();
+        others.dedup();
+        identifiers.extend(others.into_iter().cloned());
+        identifiers
+    }
+}
+
+/// An `X402_NETWORKS` entry; omitted fields fall back to the top-level settings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NetworkEntry {
+    pub name: String,
+    pub network_v2: Option<String>,
+    pub rpc_url: Option<String>,
+    pub asset: Option<String>,
+    pub pay_to: Option<String>,
+    pub asset_name: Option<String>,
+    pub asset_version: Option<String>,
+    pub asset_decimals: Option<u8>,
+}
+
+/// `X402_NETWORKS`: a JSON array of [`NetworkEntry`] values, or a path to a file holding one.
+#[derive(Debug, Clone)]
+pub struct NetworkList(pub Vec<NetworkEntry>);
+
+impl FromStr for NetworkList {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let raw = raw.trim();
+        let json = if raw.starts_with('[') {
+            raw.to_string()
+        } else {
+            std::fs::read_to_string(raw).map_err(|e| format!("failed to read {raw}: {e}"))?
+        };
+        let networks: Vec<NetworkEntry> =
+            serde_json::from_str(&json).map_err(|e| format!("invalid network list: {e}"))?;
+        if networks.is_empty() {
+            return Err("network list is empty".into());
+        }
+        Ok(Self(networks))
+    }
+}
+
+/// `X402_FACILITATOR_URLS`: comma-separated facilitator base URLs, primary first.
+#[derive(Debug, Clone)]
+pub struct FacilitatorUrls(pub Vec<Url>);
+
+impl FromStr for FacilitatorUrls {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let urls = raw
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(|url| Url::parse(url).map_err(|e| format!("invalid facilitator URL {url}: {e}")))
+            .collect::<Result<Vec<_>, _>>()?;
+        if urls.is_empty() {
+            return Err("facilitator URL list is empty".into());
+        }
+        Ok(Self(urls))
+    }
+}
+
+/// `X402_TAB_SNAPSHOT`: whether and how the 4mica tab is logged after a settlement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TabSnapshotMode {
+    Off,
+    /// Logged from a background task; the response doesn't wait for it.
+    Async,
+    /// Logged before the settlement returns, for debugging.
+    Sync,
+}
+
+impl FromStr for TabSnapshotMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" => Ok(Self::Off),
+            "async" => Ok(Self::Async),
+            "sync" => Ok(Self::Sync),
+            other => Err(format!(
+                "invalid tab snapshot mode {other:?}: expected off, async, or sync"
+            )),
+        }
+    }
+}
+
+/// `X402_SETTLEMENT_MODE`: when 4mica payments are settled with the facilitator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettlementMode {
+    /// Settle every payment before serving it.
+    Immediate,
+    /// Only verify each payment, and settle a tab's payments in the background once
+    /// they add up to the threshold or the oldest reaches the max age.
+    Deferred,
+}
+
+impl FromStr for SettlementMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "immediate" => Ok(Self::Immediate),
+            "deferred" => Ok(Self::Deferred),
+            other => Err(format!(
+                "invalid settlement mode {other:?}: expected immediate or deferred"
+            )),
+        }
+    }
+}
+
+/// `X402_MODE`: whether payments are real.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum X402Mode {
+    Live,
+    /// Also accept `test` payments, which settle nothing and need no facilitator or RPC.
+    Sandbox,
+}
+
+impl FromStr for X402Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "live" => Ok(Self::Live),
+            "sandbox" => Ok(Self::Sandbox),
+            other => Err(format!(
+                "invalid x402 mode {other:?}: expected live or sandbox"
+            )),
+        }
+    }
+}
+
 #[derive(Envconfig, Debug, Clone)]
 pub struct X402Config {
     #[envconfig(from = "X402_ENABLED", default = "true")]
     pub enabled: bool,
 
+    /// Offer and accept 4mica payments, credit and metered; without them no facilitator is
+    /// needed unless `X402_EXACT_FACILITATOR` is set.
+    #[envconfig(from = "X402_ENABLE_4MICA", default = "true")]
+    pub enable_4mica: bool,
+
+    /// Offer and accept `exact` payments, settled as `X402_DIRECT_SETTLEMENT` and
+    /// `X402_EXACT_FACILITATOR` allow; without them no RPC endpoint is needed.
+    #[envconfig(from = "X402_ENABLE_EXACT", default = "true")]
+    pub enable_exact: bool,
+
     #[envconfig(from = "X402_SCHEME_4MICA", default = "4mica-credit")]
     pub scheme_4mica: String,
 
+    /// Scheme of the metered offer made with `X402_PRICE_PER_BYTE`.
+    #[envconfig(from = "X402_SCHEME_UPTO", default = "4mica-upto")]
+    pub scheme_upto: String,
+
     #[envconfig(from = "X402_NETWORK", default = "polygon-amoy")]
     pub network: String,
 
     #[envconfig(from = "X402_NETWORK_V2", default = "eip155:80002")]
     pub network_v2: String,
 
+    /// More names of the advertised chains, matched like their v1 name and CAIP-2 id.
+    #[envconfig(from = "X402_NETWORK_ALIASES")]
+    pub network_aliases: Option<NetworkAliasList>,
+
+    /// Overrides the single network above with several, see [`NetworkList`].
+    #[envconfig(from = "X402_NETWORKS")]
+    pub networks: Option<NetworkList>,
+
     #[envconfig(from = "X402_PAY_TO")]
     pub pay_to: String,
 
+    /// How settled revenue divides between recipients, see [`RevenueSplit`].
+    #[envconfig(from = "X402_PAY_TO_SPLIT")]
+    pub pay_to_split: Option<RevenueSplit>,
+
+    /// Comma-separated JSON-RPC endpoints, tried in order when verifying exact payments.
     #[envconfig(from = "X402_RPC_URL", default = "https://rpc.ankr.com/polygon_amoy")]
     pub rpc_url: String,
 
+    /// Overall deadline for each JSON-RPC call made while verifying exact payments.
+    #[envconfig(from = "X402_RPC_TIMEOUT_SECONDS", default = "10")]
+    pub rpc_timeout_seconds: u64,
+
+    /// Confirmations an exact-scheme transaction needs; 0 or 1 accepts any mined one.
+    #[envconfig(from = "X402_MIN_CONFIRMATIONS", default = "1")]
+    pub min_confirmations: u64,
+
+    /// How long to keep polling for the receipt of an exact-scheme transaction that is
+    /// unknown or not yet mined; 0 fails such payments at once.
+    #[envconfig(from = "X402_TX_WAIT_SECONDS", default = "0")]
+    pub tx_wait_seconds: u64,
+
+    /// Delay between receipt polls while waiting for a transaction to be mined.
+    #[envconfig(from = "X402_TX_POLL_INTERVAL_MS", default = "1000")]
+    pub tx_poll_interval_ms: u64,
+
     #[envconfig(
         from = "X402_ASSET",
         // USDC on Polygon Amoy
@@ -28,9 +297,386 @@ pub struct X402Config {
     )]
     pub asset: String,
 
+    /// Default price per paid resource, in the asset's smallest unit.
+    #[envconfig(from = "X402_PRICE", default = "100")]
+    pub price: U256,
+
+    /// Default price as a decimal amount of the asset, e.g. `0.05`; replaces `X402_PRICE`.
+    #[envconfig(from = "X402_PRICE_HUMAN")]
+    pub price_human: Option<String>,
+
+    /// Decimals of `X402_ASSET` used to scale `X402_PRICE_HUMAN`; read from the token at
+    /// startup when unset.
+    #[envconfig(from = "X402_ASSET_DECIMALS")]
+    pub asset_decimals: Option<u8>,
+
+    /// Price override for `/stream/{filename}`.
+    #[envconfig(from = "X402_PRICE_STREAM")]
+    pub price_stream: Option<U256>,
+
+    /// Price override for `/stream/remote`, which also costs upstream egress.
+    #[envconfig(from = "X402_PRICE_REMOTE")]
+    pub price_remote: Option<U256>,
+
+    /// Price override for `/stream/ipfs/{cid}`, fetched through the IPFS gateways.
+    #[envconfig(from = "X402_PRICE_IPFS")]
+    pub price_ipfs: Option<U256>,
+
+    /// Per-byte rate of a metered `/stream/remote` or `/stream/ipfs` offer; the route's
+    /// price becomes the cap and the payment settles for the bytes delivered.
+    #[envconfig(from = "X402_PRICE_PER_BYTE")]
+    pub price_per_byte: Option<U256>,
+
+    /// Price per second of media for local `.ts`/`.m4s` segments, by their `#EXTINF`
+    /// duration in a sibling playlist.
+    #[envconfig(from = "X402_PRICE_PER_SECOND")]
+    pub price_per_second: Option<U256>,
+
+    /// Charge for `.m3u8` playlists and `.mpd` manifests instead of serving them free;
+    /// patterns listed in `X402_FREE_PATHS` still win.
+    #[envconfig(from = "X402_CHARGE_PLAYLISTS", default = "false")]
+    pub charge_playlists: bool,
+
+    /// Price of a charged playlist; defaults to the price of its route.
+    #[envconfig(from = "X402_PLAYLIST_PRICE")]
+    pub playlist_price: Option<U256>,
+
+    /// HEAD requests never settle a payment: paid resources answer 402 with the
+    /// requirements unless a payment session covers them. Disable to charge HEAD like GET.
+    #[envconfig(from = "X402_FREE_HEAD", default = "true")]
+    pub free_head: bool,
+
+    /// Accept the payment as a URL-safe base64 `payment` query parameter when the request
+    /// carries no payment header, for players that cannot set request headers.
+    #[envconfig(from = "X402_ALLOW_QUERY_PAYMENT", default = "false")]
+    pub allow_query_payment: bool,
+
+    /// Longest payment header or query parameter accepted; longer ones are answered 402
+    /// without being decoded.
+    #[envconfig(from = "X402_MAX_PAYMENT_HEADER_BYTES", default = "16384")]
+    pub max_payment_header_bytes: usize,
+
+    /// Comma-separated glob patterns naming resources served without payment, matched
+    /// against the file path under `/stream/` or the path of a remote `url`. Only `.m3u8`
+    /// playlists and `.mpd` manifests are free when unset, and nothing is with
+    /// `X402_CHARGE_PLAYLISTS`.
+    #[envconfig(from = "X402_FREE_PATHS")]
+    pub free_paths: Option<String>,
+
+    /// Paid requests each client may make for free per preview window.
+    #[envconfig(from = "X402_FREE_SEGMENT_COUNT")]
+    pub free_segment_count: Option<u32>,
+
+    /// Bytes each client may stream for free per preview window; a response that would
+    /// exceed the remaining budget is charged in full.
+    #[envconfig(from = "X402_FREE_BYTE_BUDGET")]
+    pub free_byte_budget: Option<u64>,
+
+    /// How long a client's free preview quota lasts before it refreshes.
+    #[envconfig(from = "X402_FREE_PREVIEW_WINDOW_SECONDS", default = "86400")]
+    pub free_preview_window_seconds: u64,
+
+    /// Most a single tab may be charged per spend window on this server; payments that
+    /// would exceed it are refused before settlement. Unlimited when unset.
+    #[envconfig(from = "X402_TAB_SPEND_CAP")]
+    pub tab_spend_cap: Option<U256>,
+
+    /// Length of the sliding window `X402_TAB_SPEND_CAP` applies to.
+    #[envconfig(from = "X402_TAB_SPEND_WINDOW_SECONDS", default = "3600")]
+    pub tab_spend_window_seconds: u64,
+
     #[envconfig(from = "X402_FACILITATOR_URL", default = "https://x402.4mica.xyz/")]
     pub facilitator_url: Url,
 
+    /// Facilitators tried in order when the one before fails; replaces
+    /// `X402_FACILITATOR_URL`.
+    #[envconfig(from = "X402_FACILITATOR_URLS")]
+    pub facilitator_urls: Option<FacilitatorUrls>,
+
+    /// How long a facilitator that answered after a failover stays first before the
+    /// primary is tried again.
+    #[envconfig(from = "X402_FACILITATOR_FAILOVER_COOLDOWN_SECONDS", default = "60")]
+    pub facilitator_failover_cooldown_seconds: u64,
+
+    /// Refuse to start when the facilitator doesn't answer the startup probe.
+    #[envconfig(from = "X402_REQUIRE_FACILITATOR", default = "false")]
+    pub require_facilitator: bool,
+
+    #[envconfig(from = "X402_FACILITATOR_TIMEOUT_SECONDS", default = "10")]
+    pub facilitator_timeout_seconds: u64,
+
+    /// Sent as `Authorization: Bearer <key>` on every facilitator request.
+    #[envconfig(from = "X402_FACILITATOR_API_KEY")]
+    pub facilitator_api_key: Option<String>,
+
+    /// Signs every facilitator `POST` (`/verify`, `/settle`, `/tabs`) with an HMAC-SHA256
+    /// under this key, so a facilitator can check the request came from this server.
+    #[envconfig(from = "X402_FACILITATOR_HMAC_KEY")]
+    pub facilitator_hmac_key: Option<String>,
+
+    #[envconfig(from = "X402_FACILITATOR_MAX_ATTEMPTS", default = "3")]
+    pub facilitator_max_attempts: u32,
+
+    #[envconfig(from = "X402_FACILITATOR_RETRY_BASE_DELAY_MS", default = "200")]
+    pub facilitator_retry_base_delay_ms: u64,
+
+    /// How long a failed tab request is answered from cache instead of asking the
+    /// facilitator again.
+    #[envconfig(from = "X402_TAB_FAILURE_CACHE_SECONDS", default = "3")]
+    pub tab_failure_cache_seconds: u64,
+
+    /// How long an opened tab is answered from cache when the same user asks for a tab
+    /// with the same recipient and asset again.
+    #[envconfig(from = "X402_TAB_CACHE_SECONDS", default = "60")]
+    pub tab_cache_seconds: u64,
+
+    /// How long past `X402_TAB_CACHE_SECONDS` a cached tab is still answered while it is
+    /// refreshed from the facilitator in the background.
+    #[envconfig(from = "X402_TAB_STALE_SECONDS", default = "30")]
+    pub tab_stale_seconds: u64,
+
     #[envconfig(from = "X402_DIRECT_SETTLEMENT", default = "false")]
     pub direct_settlement: bool,
+
+    /// Settle standard `exact` payments (signed EIP-3009 authorizations) through the facilitator.
+    #[envconfig(from = "X402_EXACT_FACILITATOR", default = "false")]
+    pub exact_facilitator: bool,
+
+    /// Also offer an `exact` requirement in the chain's native coin, with the zero address
+    /// as its asset, paid by a plain value transfer to `pay_to` and checked on-chain.
+    /// Needs `X402_DIRECT_SETTLEMENT` and `X402_NATIVE_PRICE`.
+    #[envconfig(from = "X402_ACCEPT_NATIVE", default = "false")]
+    pub accept_native: bool,
+
+    /// Price of a native payment in wei, the same for every paid resource.
+    #[envconfig(from = "X402_NATIVE_PRICE")]
+    pub native_price: Option<U256>,
+
+    /// Accept 4mica claims that omit `recipientAddress`, `assetAddress`, or `amount`, as
+    /// older clients send them; claims that carry them must still match the requirements.
+    #[envconfig(from = "X402_LENIENT_CLAIMS", default = "false")]
+    pub lenient_claims: bool,
+
+    /// Before a 4mica payment is verified or settled, check through the 4mica SDK that its
+    /// tab's guarantees still cover the price beyond what the user already paid.
+    #[envconfig(from = "X402_REQUIRE_GUARANTEE", default = "false")]
+    pub require_guarantee: bool,
+
+    /// Fail 4mica settlements whose certificate is missing, is not signed by the operator,
+    /// or does not bind the recipient, asset, amount, and tab of the payment.
+    #[envconfig(from = "X402_REQUIRE_CERTIFICATE", default = "false")]
+    pub require_certificate: bool,
+
+    /// Key settlement certificates are checked against; without it the 4mica SDK
+    /// supplies the operator key, which needs `4MICA_WALLET_PRIVATE_KEY`. Setting it also
+    /// checks certificates without `X402_REQUIRE_CERTIFICATE`, logging bad ones.
+    #[envconfig(from = "X402_OPERATOR_PUBKEY")]
+    pub operator_pubkey: Option<OperatorKey>,
+
+    /// Accept the payment when the guarantee check can't reach the 4mica SDK.
+    #[envconfig(from = "X402_GUARANTEE_FAIL_OPEN", default = "false")]
+    pub guarantee_fail_open: bool,
+
+    /// EIP-712 domain `name` of the asset, advertised for `exact` payments.
+    #[envconfig(from = "X402_ASSET_NAME", default = "USDC")]
+    pub asset_name: String,
+
+    /// EIP-712 domain `version` of the asset, advertised for `exact` payments.
+    #[envconfig(from = "X402_ASSET_VERSION", default = "2")]
+    pub asset_version: String,
+
+    #[envconfig(from = "X402_SETTLEMENT_MODE", default = "immediate")]
+    pub settlement_mode: SettlementMode,
+
+    /// `sandbox` also accepts `test` payments; refused at startup without
+    /// `X402_SANDBOX_ACK=I_UNDERSTAND`.
+    #[envconfig(from = "X402_MODE", default = "live")]
+    pub mode: X402Mode,
+
+    #[envconfig(from = "X402_SANDBOX_ACK")]
+    pub sandbox_ack: Option<String>,
+
+    /// Logs the tab, payment status, guarantees, and collateral events of a settled
+    /// 4mica payment's tab.
+    #[envconfig(from = "X402_TAB_SNAPSHOT", default = "async")]
+    pub tab_snapshot: TabSnapshotMode,
+
+    /// Deferred mode: settle a tab once its verified payments add up to this amount.
+    #[envconfig(from = "X402_DEFERRED_THRESHOLD", default = "10000")]
+    pub deferred_threshold: U256,
+
+    /// Deferred mode: settle a tab once its oldest unsettled payment is this old.
+    #[envconfig(from = "X402_DEFERRED_MAX_AGE_SECONDS", default = "300")]
+    pub deferred_max_age_seconds: u64,
+
+    /// Only verify facilitator payments before serving them, and settle them from a
+    /// background queue. 4mica payments deferred by `X402_SETTLEMENT_MODE` stay deferred.
+    #[envconfig(from = "X402_ASYNC_SETTLE", default = "false")]
+    pub async_settle: bool,
+
+    /// Async settlement: verified payments that may wait for the worker before paid
+    /// requests wait for room.
+    #[envconfig(from = "X402_ASYNC_SETTLE_QUEUE_SIZE", default = "1024")]
+    pub async_settle_queue_size: usize,
+
+    /// Journal of payments served before their settlement is final (async and deferred
+    /// settlement), so settlements a crash interrupted are retried on the next start.
+    #[envconfig(from = "X402_PENDING_SETTLEMENTS_PATH")]
+    pub pending_settlements_path: Option<String>,
+
+    #[envconfig(from = "X402_PENDING_SETTLEMENTS_FSYNC", default = "always")]
+    pub pending_settlements_fsync: FsyncPolicy,
+
+    /// Window in which a repeated payment header reuses the earlier settlement.
+    #[envconfig(from = "X402_SETTLEMENT_CACHE_SECONDS", default = "30")]
+    pub settlement_cache_seconds: u64,
+
+    /// Settlements that may run at once; further paid requests wait for a slot. 0 leaves
+    /// them unbounded.
+    #[envconfig(from = "X402_MAX_CONCURRENT_SETTLEMENTS", default = "32")]
+    pub max_concurrent_settlements: usize,
+
+    /// How long a paid request waits for a settlement slot before it is answered 503.
+    #[envconfig(from = "X402_SETTLEMENT_WAIT_MS", default = "5000")]
+    pub settlement_wait_ms: u64,
+
+    /// How long an advertised payment stays valid, sent as `maxTimeoutSeconds`.
+    #[envconfig(from = "X402_MAX_TIMEOUT_SECONDS", default = "300")]
+    pub max_timeout_seconds: u64,
+
+    /// Oldest on-chain payment transaction accepted, by its block's timestamp; defaults to
+    /// the requirements' `maxTimeoutSeconds`.
+    #[envconfig(from = "X402_MAX_TX_AGE_SECONDS")]
+    pub max_tx_age_seconds: Option<u64>,
+}
+
+impl X402Config {
+    /// How long a settled payment header is remembered as spent: while an advertised
+    /// payment, or an on-chain payment transaction, would still be accepted.
+    pub fn replay_retention(&self) -> Duration {
+        let seconds = self
+            .max_timeout_seconds
+            .max(self.max_tx_age_seconds.unwrap_or(0));
+        Duration::from_secs(seconds)
+    }
+
+    /// Every advertised network, with defaults filled in from the top-level settings.
+    pub fn networks(&self) -> Vec<Network> {
+        let Some(NetworkList(entries)) = &self.networks else {
+            return vec![Network {
+                name: self.network.clone(),
+                network_v2: Some(self.network_v2.clone()),
+                rpc_url: self.rpc_url.clone(),
+                asset: self.asset.clone(),
+                pay_to: self.pay_to.clone(),
+                asset_name: self.asset_name.clone(),
+                asset_version: self.asset_version.clone(),
+                asset_decimals: self.asset_decimals,
+            }];
+        };
+        entries
+            .iter()
+            .map(|entry| {
+                let asset = entry.asset.clone().unwrap_or_else(|| self.asset.clone());
+                // X402_ASSET_DECIMALS only describes X402_ASSET.
+                let default_decimals = self
+                    .asset_decimals
+                    .filter(|_| asset.eq_ignore_ascii_case(&self.asset));
+                Network {
+                    name: entry.name.clone(),
+                    network_v2: entry.network_v2.clone(),
+                    rpc_url: entry
+                        .rpc_url
+                        .clone()
+                        .unwrap_or_else(|| self.rpc_url.clone()),
+                    pay_to: entry.pay_to.clone().unwrap_or_else(|| self.pay_to.clone()),
+                    asset_name: entry
+                        .asset_name
+                        .clone()
+                        .unwrap_or_else(|| self.asset_name.clone()),
+                    asset_version: entry
+                        .asset_version
+                        .clone()
+                        .unwrap_or_else(|| self.asset_version.clone()),
+                    asset_decimals: entry.asset_decimals.or(default_decimals),
+                    asset,
+                }
+            })
+            .collect()
+    }
+
+    /// Each advertised network's v1 name as an alias of its CAIP-2 id, plus
+    /// `X402_NETWORK_ALIASES`.
+    pub fn network_aliases(&self) -> NetworkAliases {
+        let mut aliases = NetworkAliases::default();
+        for network in self.networks() {
+            if let Some(chain) = &network.network_v2 {
+                aliases.insert(&network.name, chain);
+            }
+        }
+        for (alias, chain) in self.network_aliases.iter().flat_map(|list| &list.0) {
+            aliases.insert(alias, chain);
+        }
+        aliases
+    }
+
+    /// The facilitators to call, primary first: `X402_FACILITATOR_URLS`, else
+    /// `X402_FACILITATOR_URL`.
+    pub fn facilitators(&self) -> Vec<Url> {
+        match &self.facilitator_urls {
+            Some(FacilitatorUrls(urls)) => urls.clone(),
+            None => vec![self.facilitator_url.clone()],
+        }
+    }
+
+    /// Whether a facilitator is needed: for 4mica payments, or `exact` ones it settles.
+    pub fn uses_facilitator(&self) -> bool {
+        self.enable_4mica || (self.enable_exact && self.exact_facilitator)
+    }
+
+    /// Whether `exact` payments are offered, settled on-chain or through the facilitator.
+    pub fn offers_exact(&self) -> bool {
+        self.enable_exact && (self.direct_settlement || self.exact_facilitator)
+    }
+
+    /// RPC endpoints for the v1 network `name`, or another identifier of its chain, falling
+    /// back to `X402_RPC_URL`.
+    pub fn rpc_urls_for(&self, name: &str) -> Vec<String> {
+        let aliases = self.network_aliases();
+        let rpc_url = self
+            .networks()
+            .into_iter()
+            .find(|network| aliases.same_chain(&network.name, name))
+            .map(|network| network.rpc_url)
+            .unwrap_or_else(|| self.rpc_url.clone());
+        split_rpc_urls(&rpc_url)
+    }
+
+    /// The default price in the asset's smallest unit: `X402_PRICE_HUMAN` scaled by
+    /// `decimals` when it is set, else `X402_PRICE`.
+    pub fn default_price(&self, decimals: Option<u8>) -> Result<U256, String> {
+        let Some(human) = &self.price_human else {
+            return Ok(self.price);
+        };
+        let decimals = decimals
+            .or(self.asset_decimals)
+            .ok_or("X402_PRICE_HUMAN needs the asset decimals")?;
+        parse_units(human, decimals).map_err(|e| format!("X402_PRICE_HUMAN: {e}"))
+    }
+
+    /// The first `X402_RPC_URL` endpoint, for clients that take a single URL.
+    pub fn primary_rpc_url(&self) -> String {
+        split_rpc_urls(&self.rpc_url)
+            .into_iter()
+            .next()
+            .unwrap_or_default()
+    }
+}
+
+fn split_rpc_urls(raw: &str) -> Vec<String> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(String::from)
+        .collect()
 }
@@ -0,0 +1,407 @@
+use parking_lot::Mutex;
+use sdk_4mica::{U256, x402::PaymentRequirements};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use crate::{
+    error::PaymentError,
+    x402::{
+        Facilitator, FourMicaCertificate, PaymentRequirementsV2, PendingSettlements,
+        SettlementSummary,
+        model::{
+            FacilitatorSettleParams, FacilitatorSettleParamsV2, FacilitatorSettleResponse,
+            FacilitatorVerifyParams, FacilitatorVerifyParamsV2,
+        },
+    },
+};
+
+/// How often the flusher checks tabs for their max age and retry deadlines.
+const TICK: Duration = Duration::from_secs(1);
+/// Settle attempts per tab before it is parked as failed.
+const MAX_ATTEMPTS: u32 = 5;
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(2);
+
+/// Requirements a deferred payment was verified against, kept for its settlement.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeferredRequirements {
+    V1(PaymentRequirements),
+    V2(PaymentRequirementsV2),
+}
+
+impl DeferredRequirements {
+    pub fn pay_to(&self) -> &str {
+        match self {
+            Self::V1(requirements) => &requirements.pay_to,
+            Self::V2(requirements) => &requirements.pay_to,
+        }
+    }
+
+    pub fn asset(&self) -> &str {
+        match self {
+            Self::V1(requirements) => &requirements.asset,
+            Self::V2(requirements) => &requirements.asset,
+        }
+    }
+
+    /// The amount the payment must cover, in the asset's base units.
+    pub fn amount(&self) -> &str {
+        match self {
+            Self::V1(requirements) => &requirements.max_amount_required,
+            Self::V2(requirements) => &requirements.amount,
+        }
+    }
+}
+
+/// A verified 4mica payment waiting to be settled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeferredPayment {
+    pub payment_header: String,
+    pub payment_payload: Value,
+    pub requirements: DeferredRequirements,
+    pub amount: U256,
+}
+
+struct PendingTab {
+    opened: Instant,
+    payments: Vec<DeferredPayment>,
+    attempts: u32,
+    retry_at: Option<Instant>,
+    last_error: Option<String>,
+    settling: bool,
+}
+
+impl PendingTab {
+    fn total(&self) -> U256 {
+        self.payments.iter().fold(U256::from(0), |sum, payment| {
+            sum.saturating_add(payment.amount)
+        })
+    }
+}
+
+/// Unsettled payments of one tab, as listed by `GET /admin/deferred`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeferredTabView {
+    pub tab_id: String,
+    pub payments: usize,
+    pub amount: String,
+    pub age_seconds: u64,
+    pub attempts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+/// Pending tabs, plus tabs that gave up after [`MAX_ATTEMPTS`] settle attempts.
+#[derive(Debug, Serialize)]
+pub struct DeferredSnapshot {
+    pub pending: Vec<DeferredTabView>,
+    pub failed: Vec<DeferredTabView>,
+}
+
+/// Accumulates verified 4mica payments per tab and settles them with the facilitator
+/// from a background task, once a tab's total reaches `threshold` or its oldest
+/// payment is `max_age` old.
+///
+/// A tab whose settlement fails is retried with exponential backoff, and after
+/// [`MAX_ATTEMPTS`] it is parked as failed with its payments kept for inspection. With a
+/// `journal`, unsettled payments survive a crash as pending settlements.
+pub struct DeferredSettler {
+    facilitator: Arc<dyn Facilitator>,
+    journal: Option<Arc<PendingSettlements>>,
+    threshold: U256,
+    max_age: Duration,
+    tabs: Mutex<HashMap<String, PendingTab>>,
+    failed: Mutex<HashMap<String, PendingTab>>,
+    wake: Notify,
+}
+
+impl DeferredSettler {
+    /// Starts the flusher, which stops when `shutdown` is cancelled; call
+    /// [`DeferredSettler::flush_all`] afterwards to settle what is still pending.
+    pub fn spawn(
+        facilitator: Arc<dyn Facilitator>,
+        threshold: U256,
+        max_age: Duration,
+        shutdown: CancellationToken,
+        journal: Option<Arc<PendingSettlements>>,
+    ) -> Arc<Self> {
+        let settler = Arc::new(Self {
+            facilitator,
+            journal,
+            threshold,
+            max_age,
+            tabs: Mutex::new(HashMap::new()),
+            failed: Mutex::new(HashMap::new()),
+            wake: Notify::new(),
+        });
+        tokio::spawn({
+            let settler = settler.clone();
+            async move {
+                loop {
+                    tokio::select! {
+                        _ = settler.wake.notified() => {}
+                        _ = tokio::time::sleep(TICK) => {}
+                        _ = shutdown.cancelled() => break,
+                    }
+                    for tab_id in settler.take_due(false) {
+                        settler.settle_tab(&tab_id).await;
+                    }
+                }
+            }
+        });
+        settler
+    }
+
+    /// Adds a payment verified for `resource` to `tab_id`, waking the flusher if the tab is
+    /// now due.
+    pub async fn enqueue(
+        &self,
+        tab_id: &str,
+        resource: &str,
+        payment: DeferredPayment,
+        summary: &SettlementSummary,
+    ) {
+        if let Some(journal) = &self.journal {
+            journal.record(resource, summary, &payment).await;
+        }
+        let due = {
+            let mut tabs = self.tabs.lock();
+            let tab = tabs
+                .entry(tab_id.to_string())
+                .or_insert_with(|| PendingTab {
+                    opened: Instant::now(),
+                    payments: Vec::new(),
+                    attempts: 0,
+                    retry_at: None,
+                    last_error: None,
+                    settling: false,
+                });
+            tab.payments.push(payment);
+            tab.total() >= self.threshold
+        };
+        if due {
+            self.wake.notify_one();
+        }
+    }
+
+    /// Settles every pending tab now, regardless of threshold, age, or backoff.
+    pub async fn flush_all(&self) {
+        let tab_ids = self.take_due(true);
+        if !tab_ids.is_empty() {
+            info!("Settling {} deferred tabs before shutdown", tab_ids.len());
+        }
+        for tab_id in tab_ids {
+            self.settle_tab(&tab_id).await;
+        }
+    }
+
+    pub fn snapshot(&self) -> DeferredSnapshot {
+        let view = |tabs: &HashMap<String, PendingTab>| {
+            let mut views: Vec<DeferredTabView> = tabs
+                .iter()
+                .map(|(tab_id, tab)| DeferredTabView {
+                    tab_id: tab_id.clone(),
+                    payments: tab.payments.len(),
+                    amount: tab.total().to_string(),
+                    age_seconds: tab.opened.elapsed().as_secs(),
+                    attempts: tab.attempts,
+                    last_error: tab.last_error.clone(),
+                })
+                .collect();
+            views.sort_by(|a, b| a.tab_id.cmp(&b.tab_id));
+            views
+        };
+        DeferredSnapshot {
+            pending: view(&self.tabs.lock()),
+            failed: view(&self.failed.lock()),
+        }
+    }
+
+    /// Marks due tabs as settling and returns their ids; `all` ignores the triggers.
+    fn take_due(&self, all: bool) -> Vec<String> {
+        let now = Instant::now();
+        let mut tabs = self.tabs.lock();
+        tabs.iter_mut()
+            .filter(|(_, tab)| !tab.settling && !tab.payments.is_empty())
+            .filter(|(_, tab)| {
+                all || match tab.retry_at {
+                    Some(retry_at) => now >= retry_at,
+                    None => {
+                        tab.total() >= self.threshold
+                            || now.duration_since(tab.opened) >= self.max_age
+                    }
+                }
+            })
+            .map(|(tab_id, tab)| {
+                tab.settling = true;
+                tab_id.clone()
+            })
+            .collect()
+    }
+
+    /// Settles a tab's payments in order; payments arriving meanwhile wait for the next round.
+    async fn settle_tab(&self, tab_id: &str) {
+        let payments = match self.tabs.lock().get_mut(tab_id) {
+            Some(tab) => std::mem::take(&mut tab.payments),
+            None => return,
+        };
+
+        let mut settled = 0;
+        let mut failure = None;
+        for payment in &payments {
+            match settle_verified(self.facilitator.as_ref(), payment).await {
+                Ok(_) => {
+                    settled += 1;
+                    if let Some(journal) = &self.journal {
+                        journal.settled(&payment.payment_header);
+                    }
+                }
+                Err(e) => {
+                    failure = Some(e.to_string());
+                    break;
+                }
+            }
+        }
+
+        let mut tabs = self.tabs.lock();
+        let Some(tab) = tabs.get_mut(tab_id) else {
+            return;
+        };
+        tab.settling = false;
+        let Some(error) = failure else {
+            info!(tab_id, payments = settled, "Settled deferred tab");
+            if tab.payments.is_empty() {
+                tabs.remove(tab_id);
+            } else {
+                // Newer payments start a fresh round.
+                tab.opened = Instant::now();
+                tab.attempts = 0;
+                tab.retry_at = None;
+                tab.last_error = None;
+            }
+            return;
+        };
+
+        let mut remaining: Vec<DeferredPayment> = payments.into_iter().skip(settled).collect();
+        remaining.append(&mut tab.payments);
+        tab.payments = remaining;
+        tab.attempts += 1;
+        tab.last_error = Some(error.clone());
+        if tab.attempts >= MAX_ATTEMPTS {
+            error!(
+                tab_id,
+                attempts = tab.attempts,
+                error = %error,
+                "Giving up on deferred tab settlement"
+            );
+            if let Some(journal) = &self.journal {
+                for payment in &tab.payments {
+                    journal.failed(&payment.payment_header, &error);
+                }
+            }
+            if let Some(tab) = tabs.remove(tab_id) {
+                self.failed.lock().insert(tab_id.to_string(), tab);
+            }
+        } else {
+            let delay = RETRY_BASE_DELAY * 2u32.pow(tab.attempts - 1);
+            warn!(
+                tab_id,
+                attempt = tab.attempts,
+                error = %error,
+                "Deferred tab settlement failed; retrying in {:?}",
+                delay
+            );
+            tab.retry_at = Some(Instant::now() + delay);
+        }
+    }
+}
+
+/// Asks the facilitator's `/verify` about `payment`; returns the certificate it issued.
+pub(crate) async fn verify_deferred(
+    facilitator: &dyn Facilitator,
+    payment: &DeferredPayment,
+) -> Result<Option<FourMicaCertificate>, PaymentError> {
+    let response = match &payment.requirements {
+        DeferredRequirements::V1(requirements) => {
+            facilitator
+                .verify(&FacilitatorVerifyParams {
+                    x402_version: 1,
+                    payment_header: &payment.payment_header,
+                    payment_payload: Some(payment.payment_payload.clone()),
+                    payment_requirements: requirements,
+                })
+                .await?
+        }
+        DeferredRequirements::V2(requirements) => {
+            facilitator
+                .verify_v2(&FacilitatorVerifyParamsV2 {
+                    x402_version: 2,
+                    payment_header: &payment.payment_header,
+                    payment_payload: Some(payment.payment_payload.clone()),
+                    payment_requirements: requirements,
+                })
+                .await?
+        }
+    };
+    if !response.is_valid {
+        return Err(PaymentError::VerificationFailed(
+            response.invalid_reason.unwrap_or_default(),
+        ));
+    }
+    Ok(response.certificate)
+}
+
+/// Settles `payment` with the facilitator's `/settle`, failing unless it succeeded.
+pub(crate) async fn settle_deferred(
+    facilitator: &dyn Facilitator,
+    payment: &DeferredPayment,
+) -> Result<FacilitatorSettleResponse, PaymentError> {
+    let response = match &payment.requirements {
+        DeferredRequirements::V1(requirements) => {
+            facilitator
+                .settle(&FacilitatorSettleParams {
+                    x402_version: 1,
+                    payment_header: &payment.payment_header,
+                    payment_payload: Some(payment.payment_payload.clone()),
+                    payment_requirements: requirements,
+                })
+                .await?
+        }
+        DeferredRequirements::V2(requirements) => {
+            facilitator
+                .settle_v2(&FacilitatorSettleParamsV2 {
+                    x402_version: 2,
+                    payment_header: &payment.payment_header,
+                    payment_payload: Some(payment.payment_payload.clone()),
+                    payment_requirements: requirements,
+                })
+                .await?
+        }
+    };
+    if !response.success {
+        return Err(PaymentError::SettlementFailed(
+            response.error.unwrap_or_default(),
+        ));
+    }
+    Ok(response)
+}
+
+/// Settles a payment that was verified earlier; returns the settlement's `tx_hash`.
+pub(crate) async fn settle_verified(
+    facilitator: &dyn Facilitator,
+    payment: &DeferredPayment,
+) -> Result<Option<String>, PaymentError> {
+    settle_deferred(facilitator, payment)
+        .await
+        .map(|response| response.tx_hash)
+}
@@ -0,0 +1,547 @@
+use sdk_4mica::x402::PaymentRequirements;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    error::PaymentError,
+    x402::{
+        Facilitator, PaymentRequirementsV2, PaymentRoute, X402_VERSION, X402Config,
+        model::{FacilitatorVerifyParams, FacilitatorVerifyParamsV2},
+    },
+};
+
+/// What [`diagnose_payment`] found out about a payment header, without settling it.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymentDiagnosis {
+    /// Whether every check passed, so the paywall would go on to settle the payment.
+    pub valid: bool,
+    /// What the header decodes to, once it does.
+    pub envelope: Option<EnvelopeSummary>,
+    /// The advertised requirement the payment is checked against, once one matches.
+    pub matched_requirement: Option<MatchedRequirement>,
+    /// The checks run, in the paywall's order; they stop at the first failure.
+    pub checks: Vec<DiagnosisCheck>,
+    /// The facilitator's `/verify` answer, when the checks got that far.
+    pub facilitator: Option<FacilitatorVerdict>,
+}
+
+/// The fields of a decoded envelope the paywall reads.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EnvelopeSummary {
+    pub x402_version: u64,
+    pub scheme: Option<String>,
+    pub network: Option<String>,
+    /// The resource the client echoed back, if any.
+    pub resource: Option<String>,
+    pub payer: Option<String>,
+    /// Claimed recipient, asset, and amount of a 4mica payment.
+    pub recipient: Option<String>,
+    pub asset: Option<String>,
+    pub amount: Option<String>,
+    pub tab_id: Option<String>,
+    pub tx_hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MatchedRequirement {
+    pub x402_version: u64,
+    pub scheme: String,
+    pub network: String,
+    pub pay_to: String,
+    pub asset: String,
+    pub amount: String,
+}
+
+/// One step of the pipeline and how the payment fared.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiagnosisCheck {
+    /// `decode`, `version`, `resource`, `scheme`, `route`, `requirement`, `claims`, then
+    /// `sandbox`, `onchain`, or `facilitator`.
+    pub name: &'static str,
+    pub passed: bool,
+    /// Why the check failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// The x402 `invalidReason` code of the failure, as a 402 would report it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FacilitatorVerdict {
+    pub is_valid: bool,
+    pub invalid_reason: Option<String>,
+}
+
+impl PaymentDiagnosis {
+    /// Records the outcome of check `name`, returning what passed it.
+    fn check<T>(&mut self, name: &'static str, result: Result<T, PaymentError>) -> Option<T> {
+        match result {
+            Ok(value) => {
+                self.checks.push(DiagnosisCheck {
+                    name,
+                    passed: true,
+                    message: None,
+                    error_code: None,
+                });
+                Some(value)
+            }
+            Err(e) => {
+                self.checks.push(DiagnosisCheck {
+                    name,
+                    passed: false,
+                    message: Some(e.to_string()),
+                    error_code: Some(e.reason_code().to_string()),
+                });
+                None
+            }
+        }
+    }
+}
+
+/// Runs `payment_header` through the checks [`super::settle_payment`] makes for
+/// `resource` against the `accepted` requirements, up to and including the facilitator's
+/// `/verify`, and reports each one. Nothing is settled or recorded, so the same header
+/// can still pay afterwards.
+pub async fn diagnose_payment(
+    payment_header: &str,
+    resource: &str,
+    accepted: &[PaymentRequirements],
+    accepted_v2: &[PaymentRequirementsV2],
+    facilitator: Option<&dyn Facilitator>,
+    rpc: &reqwest::Client,
+    config: &X402Config,
+) -> PaymentDiagnosis {
+    let mut diagnosis = PaymentDiagnosis {
+        valid: false,
+        envelope: None,
+        matched_requirement: None,
+        checks: Vec::new(),
+        facilitator: None,
+    };
+    diagnosis.valid = run_checks(
+        &mut diagnosis,
+        payment_header,
+        resource,
+        accepted,
+        accepted_v2,
+        facilitator,
+        rpc,
+        config,
+    )
+    .await
+    .is_some();
+    diagnosis
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_checks(
+    diagnosis: &mut PaymentDiagnosis,
+    payment_header: &str,
+    resource: &str,
+    accepted: &[PaymentRequirements],
+    accepted_v2: &[PaymentRequirementsV2],
+    facilitator: Option<&dyn Facilitator>,
+    rpc: &reqwest::Client,
+    config: &X402Config,
+) -> Option<()> {
+    let decoded = super::decode_payment_header(payment_header).and_then(|mut envelope| {
+        let header = super::normalize_payment_header(payment_header, &mut envelope)?;
+        Ok((envelope, header))
+    });
+    let (envelope, header) = diagnosis.check("decode", decoded)?;
+    let version = super::extract_x402_version(&envelope);
+    diagnosis.envelope = Some(summarize(&envelope, version));
+
+    diagnosis.check("version", super::check_x402_version(version))?;
+    diagnosis.check("resource", super::check_paid_resource(&envelope, resource))?;
+    let (scheme, network) =
+        diagnosis.check("scheme", super::extract_scheme_network(&envelope, version))?;
+    let route = diagnosis.check(
+        "route",
+        super::payment_route(&envelope, &scheme, version, config),
+    )?;
+    let aliases = config.network_aliases();
+    if route == PaymentRoute::Sandbox {
+        let approved =
+            super::sandbox::settle_sandbox_payment(&envelope, network, accepted, &aliases);
+        return diagnosis.check("sandbox", approved.map(drop));
+    }
+    let is_exact = route != PaymentRoute::Facilitator;
+    let payment_payload = envelope.clone();
+
+    let verdict = if version == 2 {
+        let requirement = diagnosis.check(
+            "requirement",
+            super::find_matching_payment_requirements_v2(&scheme, &network, accepted_v2, &aliases),
+        )?;
+        diagnosis.matched_requirement = Some(MatchedRequirement {
+            x402_version: 2,
+            scheme: requirement.scheme.clone(),
+            network: requirement.network.clone(),
+            pay_to: requirement.pay_to.clone(),
+            asset: requirement.asset.clone(),
+            amount: requirement.amount.clone(),
+        });
+        if !is_exact {
+            diagnosis.check(
+                "claims",
+                super::validate_claims(
+                    &envelope,
+                    &requirement.pay_to,
+                    &requirement.asset,
+                    &requirement.amount,
+                    config.lenient_claims,
+                ),
+            )?;
+        }
+        let Some(facilitator) = facilitator else {
+            return diagnosis.check("facilitator", Err(no_facilitator()));
+        };
+        facilitator
+            .verify_v2(&FacilitatorVerifyParamsV2 {
+                x402_version: 2,
+                payment_header: &header,
+                payment_payload: Some(payment_payload),
+                payment_requirements: requirement,
+            })
+            .await
+    } else {
+        let asset = match route {
+            PaymentRoute::ExactDirect => {
+                super::onchain::paid_asset(rpc, &envelope, &network, config).await
+            }
+            _ => Ok(None),
+        };
+        let requirement = diagnosis.check(
+            "requirement",
+            asset.and_then(|asset| {
+                super::find_matching_payment_requirements(
+                    &scheme,
+                    &network,
+                    asset.as_deref(),
+                    accepted,
+                    &aliases,
+                )
+            }),
+        )?;
+        diagnosis.matched_requirement = Some(MatchedRequirement {
+            x402_version: version,
+            scheme: requirement.scheme.clone(),
+            network: requirement.network.clone(),
+            pay_to: requirement.pay_to.clone(),
+            asset: requirement.asset.clone(),
+            amount: requirement.max_amount_required.clone(),
+        });
+        if route == PaymentRoute::ExactDirect {
+            let onchain =
+                super::onchain::verify_onchain_payment(rpc, &envelope, requirement, config);
+            return diagnosis.check("onchain", onchain.await);
+        }
+        if !is_exact {
+            diagnosis.check(
+                "claims",
+                super::validate_claims(
+                    &envelope,
+                    &requirement.pay_to,
+                    &requirement.asset,
+                    &requirement.max_amount_required,
+                    config.lenient_claims,
+                ),
+            )?;
+        }
+        let Some(facilitator) = facilitator else {
+            return diagnosis.check("facilitator", Err(no_facilitator()));
+        };
+        facilitator
+            .verify(&FacilitatorVerifyParams {
+                x402_version: X402_VERSION,
+                payment_header: &header,
+                payment_payload: Some(payment_payload),
+                payment_requirements: requirement,
+            })
+            .await
+    };
+    let verdict = verdict.map_err(PaymentError::from).and_then(|response| {
+        diagnosis.facilitator = Some(FacilitatorVerdict {
+            is_valid: response.is_valid,
+            invalid_reason: response.invalid_reason.clone(),
+        });
+        if response.is_valid {
+            Ok(())
+        } else {
+            Err(PaymentError::VerificationFailed(
+                response.invalid_reason.unwrap_or_default(),
+            ))
+        }
+    });
+    diagnosis.check("facilitator", verdict)
+}
+
+fn no_facilitator() -> PaymentError {
+    PaymentError::Other("No facilitator is configured".into())
+}
+
+fn summarize(envelope: &Value, x402_version: u64) -> EnvelopeSummary {
+    let (scheme, network) = match super::extract_scheme_network(envelope, x402_version) {
+        Ok((scheme, network)) => (Some(scheme), Some(network)),
+        Err(_) => (None, None),
+    };
+    let claim = |camel: &str, snake: &str| {
+        super::extract_claim_value(envelope, camel)
+            .or_else(|| super::extract_claim_value(envelope, snake))
+    };
+    EnvelopeSummary {
+        x402_version,
+        scheme,
+        network,
+        resource: super::extract_resource(envelope),
+        payer: claim("userAddress", "user_address")
+            .or_else(|| super::extract_authorization_from(envelope))
+            .or_else(|| super::extract_payload_value(envelope, "payer"))
+            .or_else(|| super::extract_payload_value(envelope, "from")),
+        recipient: claim("recipientAddress", "recipient_address"),
+        asset: claim("assetAddress", "asset_address"),
+        amount: claim("amount", "amount"),
+        tab_id: claim("tabId", "tab_id"),
+        tx_hash: super::extract_payload_value(envelope, "txHash")
+            .or_else(|| super::extract_payload_value(envelope, "tx_hash")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x402::{
+        ResourceMeta, build_accepted_payment_requirements, build_accepted_payment_requirements_v2,
+        encode_payment_header, mock::MockFacilitator,
+    };
+    use envconfig::Envconfig;
+    use sdk_4mica::U256;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    const PAY_TO: &str = "0x00000000000000000000000000000000000000ab";
+    const PAYER: &str = "0x00000000000000000000000000000000000000ef";
+    const RESOURCE: &str = "http://localhost:3000/stream/a.ts";
+
+    fn config() -> X402Config {
+        X402Config::init_from_hashmap(&HashMap::from([
+            ("X402_PAY_TO".to_string(), PAY_TO.to_string()),
+            ("X402_TAB_SNAPSHOT".to_string(), "off".to_string()),
+        ]))
+        .unwrap()
+    }
+
+    /// A 4mica payment as an x402 `version` client sends it, after `change` edits it.
+    fn payment_header(
+        config: &X402Config,
+        version: u64,
+        change: impl FnOnce(&mut Value),
+    ) -> String {
+        let payload = json!({
+            "claims": {
+                "userAddress": PAYER,
+                "recipientAddress": PAY_TO,
+                "assetAddress": config.asset,
+                "amount": "100",
+                "tab_id": "7",
+                "req_id": "0",
+            },
+            "signature": "0x01",
+        });
+        let mut envelope = if version == 2 {
+            json!({
+                "x402Version": 2,
+                "accepted": { "scheme": config.scheme_4mica, "network": config.network_v2 },
+                "payload": payload,
+            })
+        } else {
+            json!({
+                "x402Version": 1,
+                "scheme": config.scheme_4mica,
+                "network": config.network,
+                "payload": payload,
+            })
+        };
+        change(&mut envelope);
+        encode_payment_header(&envelope).unwrap()
+    }
+
+    async fn diagnose(
+        config: &X402Config,
+        header: &str,
+        facilitator: Option<&MockFacilitator>,
+    ) -> PaymentDiagnosis {
+        let price = U256::from(100);
+        let tab_endpoint = "http://localhost:3000/tab".to_string();
+        diagnose_payment(
+            header,
+            RESOURCE,
+            &build_accepted_payment_requirements(
+                config,
+                price,
+                tab_endpoint.clone(),
+                Some(RESOURCE.to_string()),
+                &ResourceMeta::default(),
+                None,
+            ),
+            &build_accepted_payment_requirements_v2(config, price, tab_endpoint),
+            facilitator.map(|facilitator| facilitator as &dyn Facilitator),
+            &reqwest::Client::new(),
+            config,
+        )
+        .await
+    }
+
+    /// The names of the checks run, and the one that failed, if any.
+    fn outcome(diagnosis: &PaymentDiagnosis) -> (Vec<&'static str>, Option<&DiagnosisCheck>) {
+        let names = diagnosis.checks.iter().map(|check| check.name).collect();
+        (names, diagnosis.checks.iter().find(|check| !check.passed))
+    }
+
+    #[tokio::test]
+    async fn valid_payment_passes_every_check_without_settling() {
+        let config = config();
+        let facilitator = MockFacilitator::default();
+        for version in [1, 2] {
+            let header = payment_header(&config, version, |_| {});
+            let diagnosis = diagnose(&config, &header, Some(&facilitator)).await;
+            assert!(diagnosis.valid, "{diagnosis:?}");
+            let (names, failed) = outcome(&diagnosis);
+            assert_eq!(
+                names,
+                [
+                    "decode",
+                    "version",
+                    "resource",
+                    "scheme",
+                    "route",
+                    "requirement",
+                    "claims",
+                    "facilitator"
+                ]
+            );
+            assert!(failed.is_none());
+
+            let envelope = diagnosis.envelope.unwrap();
+            assert_eq!(envelope.x402_version, version);
+            assert_eq!(envelope.payer.as_deref(), Some(PAYER));
+            assert_eq!(envelope.amount.as_deref(), Some("100"));
+            assert_eq!(envelope.tab_id.as_deref(), Some("7"));
+            let matched = diagnosis.matched_requirement.unwrap();
+            assert_eq!(matched.x402_version, version);
+            assert_eq!(matched.pay_to, PAY_TO);
+            assert_eq!(matched.amount, "100");
+            assert!(diagnosis.facilitator.unwrap().is_valid);
+        }
+        assert_eq!(facilitator.verifies(), 2);
+        assert_eq!(facilitator.settles(), 0);
+    }
+
+    #[tokio::test]
+    async fn undecodable_header_stops_at_decode() {
+        let diagnosis = diagnose(&config(), "not a header!", None).await;
+        assert!(!diagnosis.valid);
+        let (names, failed) = outcome(&diagnosis);
+        assert_eq!(names, ["decode"]);
+        assert_eq!(
+            failed.unwrap().error_code.as_deref(),
+            Some("invalid_payload")
+        );
+        assert!(diagnosis.envelope.is_none());
+    }
+
+    #[tokio::test]
+    async fn unadvertised_network_matches_no_requirement() {
+        let config = config();
+        let facilitator = MockFacilitator::default();
+        let header = payment_header(&config, 1, |envelope| {
+            envelope["network"] = "ethereum-mainnet".into();
+        });
+        let diagnosis = diagnose(&config, &header, Some(&facilitator)).await;
+        let (names, failed) = outcome(&diagnosis);
+        assert_eq!(names.last(), Some(&"requirement"));
+        let failed = failed.unwrap();
+        assert_eq!(failed.name, "requirement");
+        assert_eq!(
+            failed.error_code.as_deref(),
+            Some("invalid_payment_requirements")
+        );
+        // The envelope is still summarized, so the client can see what it sent.
+        assert_eq!(
+            diagnosis.envelope.unwrap().network.as_deref(),
+            Some("ethereum-mainnet")
+        );
+        assert!(diagnosis.matched_requirement.is_none());
+        assert!(diagnosis.facilitator.is_none());
+        assert_eq!(facilitator.verifies(), 0);
+    }
+
+    #[tokio::test]
+    async fn bad_claims_never_reach_the_facilitator() {
+        let config = config();
+        let facilitator = MockFacilitator::default();
+        let header = payment_header(&config, 2, |envelope| {
+            envelope["payload"]["claims"]["recipientAddress"] = PAYER.into();
+        });
+        let diagnosis = diagnose(&config, &header, Some(&facilitator)).await;
+        let (_, failed) = outcome(&diagnosis);
+        let failed = failed.unwrap();
+        assert_eq!(failed.name, "claims");
+        assert_eq!(failed.error_code.as_deref(), Some("invalid_payload"));
+        assert!(diagnosis.matched_requirement.is_some());
+        assert_eq!(facilitator.verifies(), 0);
+    }
+
+    #[tokio::test]
+    async fn resource_mismatch_is_named() {
+        let config = config();
+        let header = payment_header(&config, 1, |envelope| {
+            envelope["resource"] = "http://localhost:3000/stream/b.ts".into();
+        });
+        let diagnosis = diagnose(&config, &header, None).await;
+        let (names, failed) = outcome(&diagnosis);
+        assert_eq!(names, ["decode", "version", "resource"]);
+        assert!(failed.unwrap().message.is_some());
+    }
+
+    #[tokio::test]
+    async fn facilitator_rejection_reports_its_reason() {
+        let config = config();
+        let facilitator = MockFacilitator {
+            invalid_reason: Some("insufficient_funds".into()),
+            ..Default::default()
+        };
+        let header = payment_header(&config, 1, |_| {});
+        let diagnosis = diagnose(&config, &header, Some(&facilitator)).await;
+        assert!(!diagnosis.valid);
+        let (_, failed) = outcome(&diagnosis);
+        let failed = failed.unwrap();
+        assert_eq!(failed.name, "facilitator");
+        assert_eq!(failed.error_code.as_deref(), Some("insufficient_funds"));
+        let verdict = diagnosis.facilitator.unwrap();
+        assert!(!verdict.is_valid);
+        assert_eq!(
+            verdict.invalid_reason.as_deref(),
+            Some("insufficient_funds")
+        );
+        assert_eq!(facilitator.settles(), 0);
+    }
+
+    #[tokio::test]
+    async fn missing_facilitator_fails_the_last_check() {
+        let config = config();
+        let header = payment_header(&config, 1, |_| {});
+        let diagnosis = diagnose(&config, &header, None).await;
+        let (names, failed) = outcome(&diagnosis);
+        assert_eq!(names.last(), Some(&"facilitator"));
+        assert_eq!(failed.unwrap().name, "facilitator");
+        assert!(diagnosis.facilitator.is_none());
+    }
+}
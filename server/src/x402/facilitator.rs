@@ -1,39 +1,322 @@
 /// Copied and modified from x402-axum crate: https://github.com/x402-rs/x402-rs/blob/main/crates/x402-axum/src/facilitator_client.rs
-use http::{HeaderMap, StatusCode};
-use reqwest::Client;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use http::{HeaderMap, HeaderName, HeaderValue, StatusCode, header::CONTENT_TYPE};
+use parking_lot::Mutex;
+use reqwest::{Client, RequestBuilder};
+use serde::Serialize;
 use serde_json;
-use std::time::Duration;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::OnceCell;
+use tracing::{debug, info, warn};
 use url::Url;
 
-use crate::x402::model::{
-    FacilitatorSettleParams, FacilitatorSettleParamsV2, FacilitatorSettleResponse,
-    FacilitatorTabRequestParams, FacilitatorTabResponse, FacilitatorVerifyParams,
-    FacilitatorVerifyResponse,
+use crate::x402::{
+    FacilitatorTabResponse,
+    model::{
+        FacilitatorSettleParams, FacilitatorSettleParamsV2, FacilitatorSettleResponse,
+        FacilitatorSupportedResponse, FacilitatorTabRequestParams, FacilitatorVerifyParams,
+        FacilitatorVerifyParamsV2, FacilitatorVerifyResponse,
+    },
 };
 
+/// Headers carrying the HMAC signature of a facilitator request and the time it was signed.
+pub const SIGNATURE_HEADER: &str = "x-4mica-signature";
+pub const SIGNATURE_TIMESTAMP_HEADER: &str = "x-4mica-timestamp";
+
+pub type FacilitatorFuture<'a, T> =
+    Pin<Box<dyn Future<Output = Result<T, FacilitatorClientError>> + Send + 'a>>;
+
+/// The x402 facilitator payments are verified and settled with, and 4mica tabs opened by.
+///
+/// [`FacilitatorClient`] talks to a remote one over HTTP; the trait lets the paywall run
+/// against anything else that answers the same calls.
+pub trait Facilitator: Send + Sync {
+    fn verify<'a>(
+        &'a self,
+        request: &'a FacilitatorVerifyParams<'a>,
+    ) -> FacilitatorFuture<'a, FacilitatorVerifyResponse>;
+
+    fn verify_v2<'a>(
+        &'a self,
+        request: &'a FacilitatorVerifyParamsV2<'a>,
+    ) -> FacilitatorFuture<'a, FacilitatorVerifyResponse>;
+
+    fn settle<'a>(
+        &'a self,
+        request: &'a FacilitatorSettleParams<'a>,
+    ) -> FacilitatorFuture<'a, FacilitatorSettleResponse>;
+
+    fn settle_v2<'a>(
+        &'a self,
+        request: &'a FacilitatorSettleParamsV2<'a>,
+    ) -> FacilitatorFuture<'a, FacilitatorSettleResponse>;
+
+    /// Opens the tab `request` describes, or returns the one already open.
+    fn request_tab<'a>(
+        &'a self,
+        request: &'a FacilitatorTabRequestParams,
+    ) -> FacilitatorFuture<'a, FacilitatorTabResponse>;
+
+    /// The payment kinds the facilitator accepts.
+    fn supported(&self) -> FacilitatorFuture<'_, FacilitatorSupportedResponse>;
+
+    /// A tab [`Facilitator::request_tab`] would answer without calling out, if any.
+    fn cached_tab(&self, _request: &FacilitatorTabRequestParams) -> Option<FacilitatorTabResponse> {
+        None
+    }
+
+    /// The opened tabs [`Facilitator::cached_tab`] currently answers with.
+    fn tab_cache_entries(&self) -> Vec<TabCacheEntry> {
+        Vec::new()
+    }
+
+    /// Forgets the cached tabs and failures of `user_address`, returning how many tabs
+    /// were dropped.
+    fn evict_cached_tabs(&self, _user_address: &str) -> usize {
+        0
+    }
+
+    /// Forgets every cached tab and failure, returning how many tabs were dropped.
+    fn clear_tab_cache(&self) -> usize {
+        0
+    }
+
+    /// Which of the configured facilitators calls currently go to first, if this is a
+    /// remote one.
+    fn failover_status(&self) -> Option<FailoverStatus> {
+        None
+    }
+
+    /// A handle whose calls carry `request_id`, so facilitator logs can be correlated
+    /// with this server's.
+    fn with_request_id(&self, request_id: HeaderValue) -> Arc<dyn Facilitator>;
+}
+
 /// A client for communicating with a remote x402 facilitator.
 ///
-/// Handles `/verify` and `/settle` endpoints via JSON HTTP POST.
+/// Handles `/verify` and `/settle` endpoints via JSON HTTP POST. With fallbacks, a call the
+/// active facilitator fails is repeated against the next one; see
+/// [`FacilitatorClient::try_new_with_fallbacks`].
 #[derive(Clone, Debug)]
 pub struct FacilitatorClient {
+    /// Endpoints of each facilitator, primary first
+    facilitators: Arc<[Endpoints]>,
+    /// Which facilitator is tried first, shared between clones
+    failover: Arc<Mutex<Failover>>,
+    /// How long a facilitator that answered after a failover stays first
+    failover_cooldown: Duration,
+    /// Shared Reqwest HTTP client
+    client: Client,
+    /// Optional custom headers sent with each request
+    headers: HeaderMap,
+    /// Key requests are signed with, if any
+    signing_key: Option<SigningKey>,
+    /// Optional request timeout
+    timeout: Option<Duration>,
+    /// Retry behavior for failed requests
+    retry: RetryPolicy,
+    /// How long a failed `POST /tabs` is answered from cache
+    tab_failure_ttl: Duration,
+    /// How long an opened tab is answered from cache
+    tab_cache_ttl: Duration,
+    /// How long past the tab cache TTL a tab is still answered while it is refreshed
+    tab_stale_window: Duration,
+    /// In-flight, recently opened, and recently failed `POST /tabs` requests, shared
+    /// between clones
+    tabs: Arc<Mutex<TabRequests>>,
+}
+
+/// HMAC key for [`FacilitatorClient::with_signing_key`], kept out of `Debug` output.
+#[derive(Clone)]
+struct SigningKey(Arc<[u8]>);
+
+impl std::fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SigningKey(<redacted>)")
+    }
+}
+
+/// Hex HMAC-SHA256 under `key` of the canonical form of a request:
+/// `{method}\n{path}\n{timestamp}\n{hex sha256 of body}`.
+pub fn request_signature(
+    key: &[u8],
+    method: &str,
+    path: &str,
+    timestamp: i64,
+    body: &[u8],
+) -> String {
+    let canonical = format!("{method}\n{path}\n{timestamp}\n{:x}", Sha256::digest(body));
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(canonical.as_bytes());
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+/// Endpoint URLs of one facilitator.
+#[derive(Clone, Debug)]
+struct Endpoints {
     /// Base URL of the facilitator (e.g. `https://facilitator.example/`)
-    #[allow(dead_code)] // Public for consumption by downstream crates.
     base_url: Url,
     /// Full URL to `POST /verify` requests
     verify_url: Url,
     /// Full URL to `POST /settle` requests
     settle_url: Url,
     /// Full URL to `GET /supported` requests
-    #[allow(dead_code)] // Public for consumption by downstream crates.
     supported_url: Url,
     /// Full URL to `POST /tab` requests
     tab_url: Url,
-    /// Shared Reqwest HTTP client
-    client: Client,
-    /// Optional custom headers sent with each request
-    headers: HeaderMap,
-    /// Optional request timeout
-    timeout: Option<Duration>,
+}
+
+#[derive(Debug, Default)]
+struct Failover {
+    /// Index of the facilitator tried first.
+    active: usize,
+    /// When `active` last answered a call another facilitator failed.
+    since: Option<Instant>,
+    /// Times calls moved to another facilitator.
+    failovers: u64,
+}
+
+/// The facilitator a [`FacilitatorClient`] currently sends calls to first.
+#[derive(Clone, Debug)]
+pub struct FailoverStatus {
+    pub base_url: Url,
+    /// Position in the configured list; 0 is the primary.
+    pub priority: usize,
+    /// Times calls moved to another facilitator since startup.
+    pub failovers: u64,
+}
+
+impl FailoverStatus {
+    /// Host (and port) of the active facilitator, safe to show to clients.
+    pub fn label(&self) -> String {
+        match (self.base_url.host_str(), self.base_url.port()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            (Some(host), None) => host.to_string(),
+            _ => "<invalid url>".into(),
+        }
+    }
+}
+
+/// Identifies `POST /tabs` requests that would open the same tab.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct TabKey {
+    user_address: String,
+    recipient_address: String,
+    erc20_token: String,
+    ttl_seconds: Option<u64>,
+}
+
+impl TabKey {
+    fn new(request: &FacilitatorTabRequestParams) -> Self {
+        Self {
+            user_address: request.user_address.to_lowercase(),
+            recipient_address: request.recipient_address.to_lowercase(),
+            erc20_token: request.erc20_token.to_lowercase(),
+            ttl_seconds: request.ttl_seconds,
+        }
+    }
+}
+
+/// A tab held in the tab cache, as listed by [`Facilitator::tab_cache_entries`].
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TabCacheEntry {
+    pub user_address: String,
+    pub recipient_address: String,
+    pub asset_address: String,
+    pub tab_id: String,
+    /// Unix timestamp in seconds at which the entry stops being answered.
+    pub expires_at: i64,
+}
+
+/// How long a stale tab whose refresh failed is answered before it is refreshed again.
+const TAB_REFRESH_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Outcome of a `POST /tabs` call as seen by the callers that waited on it.
+type TabOutcome = Result<FacilitatorTabResponse, String>;
+
+#[derive(Debug, Default)]
+struct TabRequests {
+    in_flight: HashMap<TabKey, Arc<OnceCell<TabOutcome>>>,
+    /// Error message and time of the last failure per key.
+    failed: HashMap<TabKey, (String, Instant)>,
+    /// Opened tab per key.
+    opened: HashMap<TabKey, CachedTab>,
+    /// Keys whose stale tab is being refreshed in the background.
+    refreshing: HashSet<TabKey>,
+}
+
+impl TabRequests {
+    fn drop_expired_tabs(&mut self) {
+        let now = Instant::now();
+        self.opened.retain(|_, cached| now < cached.stale_until);
+    }
+}
+
+#[derive(Debug)]
+struct CachedTab {
+    tab: FacilitatorTabResponse,
+    /// Until when the tab is answered without refreshing it.
+    fresh_until: Instant,
+    /// Until when the tab is answered at all, refreshing it in the background once fresh
+    /// has passed.
+    stale_until: Instant,
+}
+
+/// Retry behavior applied to facilitator requests.
+///
+/// Delays grow exponentially from `base_delay` with up to 50% random jitter added.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry
+    pub base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            base_delay: Duration::from_millis(200),
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn delay_for(&self, attempt: u32) -> Duration {
+        let exp = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+        let jitter_ms = rand::random_range(0..=exp.as_millis() as u64 / 2);
+        exp.saturating_add(Duration::from_millis(jitter_ms))
+    }
+}
+
+/// Whether a request may be repeated after the facilitator already responded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Idempotency {
+    /// Retried on transport failures and 5xx responses (`/verify`, `/tabs`, `/supported`).
+    Idempotent,
+    /// Retried only when connecting failed, so the request was never sent (`/settle`).
+    NonIdempotent,
+}
+
+impl Idempotency {
+    /// Whether a request that failed with `error` before any response may be sent again.
+    /// A non-idempotent one only if it never reached the facilitator: after a timeout or a
+    /// dropped connection, the facilitator may have acted on it.
+    fn retries_transport_error(self, error: &reqwest::Error) -> bool {
+        self == Self::Idempotent || error.is_connect()
+    }
 }
 
 /// Errors that can occur while interacting with a remote facilitator.
@@ -45,23 +328,38 @@ pub enum FacilitatorClientError {
         #[source]
         source: url::ParseError,
     },
-    #[error("HTTP error: {context}: {source}")]
+    #[error("HTTP error: {context} (failed after {attempts} attempts): {source}")]
     Http {
         context: &'static str,
+        attempts: u32,
         #[source]
         source: reqwest::Error,
     },
+    #[error("Failed to serialize JSON: {context}: {source}")]
+    JsonSerialization {
+        context: &'static str,
+        #[source]
+        source: serde_json::Error,
+    },
     #[error("Failed to deserialize JSON: {context}: {source}")]
     JsonDeserialization {
         context: &'static str,
         #[source]
         source: serde_json::Error,
     },
-    #[error("Unexpected HTTP status {status}: {context}: {body}")]
+    /// Shows only `error`, never `body`: the message may reach clients in a 402.
+    #[error(
+        "Unexpected HTTP status {status}: {context} (failed after {attempts} attempts){}",
+        error.as_ref().map(|error| format!(": {error}")).unwrap_or_default()
+    )]
     HttpStatus {
         context: &'static str,
+        attempts: u32,
         status: StatusCode,
+        /// The response body as received, logged when the error is made.
         body: String,
+        /// `body`, when it has a shape facilitators report errors in.
+        error: Option<FacilitatorErrorBody>,
     },
     #[error("Failed to read response body as text: {context}: {source}")]
     ResponseBodyRead {
@@ -69,17 +367,63 @@ pub enum FacilitatorClientError {
         #[source]
         source: reqwest::Error,
     },
+    /// Another caller's request for the same tab failed, just now or while this one waited.
+    #[error("{context} failed recently: {message}")]
+    RecentFailure {
+        context: &'static str,
+        message: String,
+    },
 }
 
-impl FacilitatorClient {
-    /// Constructs a new [`FacilitatorClient`] from a base URL.
-    ///
-    /// This sets up `./verify` and `./settle` endpoint URLs relative to the base.
-    pub fn try_new(mut base_url: Url) -> Result<Self, FacilitatorClientError> {
+/// A facilitator's JSON error body: `{"error": ..., "invalidReason": ...}`, with
+/// `message` for `error`, `errorReason` for `invalidReason`, or `error` an object holding
+/// `message` and `code`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FacilitatorErrorBody {
+    pub error: Option<String>,
+    /// Why the payment was rejected, ideally an x402 `invalidReason` code.
+    pub invalid_reason: Option<String>,
+}
+
+impl FacilitatorErrorBody {
+    /// Parses `body`; `None` unless it is a JSON object naming an error or a reason.
+    pub fn parse(body: &str) -> Option<Self> {
+        let value: serde_json::Value = serde_json::from_str(body).ok()?;
+        let text = |value: Option<&serde_json::Value>| {
+            value
+                .and_then(serde_json::Value::as_str)
+                .map(str::trim)
+                .filter(|text| !text.is_empty())
+                .map(str::to_string)
+        };
+        let nested = value.get("error").filter(|error| error.is_object());
+        let parsed = Self {
+            error: text(value.get("error"))
+                .or_else(|| text(nested.and_then(|error| error.get("message"))))
+                .or_else(|| text(value.get("message"))),
+            invalid_reason: text(value.get("invalidReason"))
+                .or_else(|| text(value.get("errorReason")))
+                .or_else(|| text(nested.and_then(|error| error.get("code")))),
+        };
+        (parsed.error.is_some() || parsed.invalid_reason.is_some()).then_some(parsed)
+    }
+}
+
+impl std::fmt::Display for FacilitatorErrorBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.error, &self.invalid_reason) {
+            (Some(error), Some(reason)) => write!(f, "{error} ({reason})"),
+            (Some(text), None) | (None, Some(text)) => f.write_str(text),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+impl Endpoints {
+    /// Sets up `./verify`, `./settle`, `./supported`, and `./tabs` URLs relative to `base_url`.
+    fn try_new(mut base_url: Url) -> Result<Self, FacilitatorClientError> {
         if base_url.host_str() == Some("0.0.0.0") {
-            log::warn!(
-                "Facilitator URL host is 0.0.0.0; rewriting to 127.0.0.1 for client requests"
-            );
+            warn!("Facilitator URL host is 0.0.0.0; rewriting to 127.0.0.1 for client requests");
             base_url
                 .set_host(Some("127.0.0.1"))
                 .map_err(|e| FacilitatorClientError::UrlParse {
@@ -87,8 +431,7 @@ impl FacilitatorClient {
                     source: e,
                 })?;
         }
-        let client = Client::new();
-        log::info!("Initializing facilitator client at {}", base_url);
+        info!(url = %base_url, "Initializing facilitator client");
         let verify_url =
             base_url
                 .join("./verify")
@@ -118,82 +461,265 @@ impl FacilitatorClient {
                 source: e,
             })?;
 
-        log::info!(
-            "Facilitator endpoints: verify={} settle={} supported={} tabs={}",
-            verify_url,
-            settle_url,
-            supported_url,
-            tab_url
+        info!(
+            verify = %verify_url,
+            settle = %settle_url,
+            supported = %supported_url,
+            tabs = %tab_url,
+            "Facilitator endpoints"
         );
 
         Ok(Self {
-            client,
             base_url,
             verify_url,
             settle_url,
             supported_url,
             tab_url,
+        })
+    }
+}
+
+impl FacilitatorClientError {
+    /// An [`HttpStatus`](Self::HttpStatus) error for `body`, which is logged in full
+    /// here since the error only shows what parses out of it.
+    fn http_status(context: &'static str, attempts: u32, status: StatusCode, body: String) -> Self {
+        warn!(context, status = %status, body = %body, "Facilitator rejected request");
+        Self::HttpStatus {
+            context,
+            attempts,
+            status,
+            error: FacilitatorErrorBody::parse(&body),
+            body,
+        }
+    }
+
+    /// Whether another facilitator might answer the call that failed with this error.
+    ///
+    /// Only failures to connect qualify for non-idempotent calls: once the request was
+    /// sent, the facilitator may have acted on it even if no response arrived.
+    fn fails_over(&self, idempotency: Idempotency) -> bool {
+        match self {
+            Self::Http { source, .. } => idempotency.retries_transport_error(source),
+            Self::HttpStatus { status, .. } => {
+                status.is_server_error() && idempotency == Idempotency::Idempotent
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FacilitatorClient {
+    /// Constructs a new [`FacilitatorClient`] from a base URL.
+    ///
+    /// This sets up `./verify` and `./settle` endpoint URLs relative to the base.
+    pub fn try_new(base_url: Url) -> Result<Self, FacilitatorClientError> {
+        Self::try_new_with_fallbacks(base_url, [])
+    }
+
+    /// Constructs a [`FacilitatorClient`] that calls `fallbacks`, in order, when `primary`
+    /// fails.
+    ///
+    /// A call moves on to the next facilitator after transport failures and 5xx responses,
+    /// once the [retries](Self::with_retries) against the current one are used up. `/settle`
+    /// only moves on when connecting failed, so it never reaches two facilitators. The facilitator that answers then stays first for the
+    /// [failover cooldown](Self::with_failover_cooldown) before the primary is tried again.
+    pub fn try_new_with_fallbacks(
+        primary: Url,
+        fallbacks: impl IntoIterator<Item = Url>,
+    ) -> Result<Self, FacilitatorClientError> {
+        let facilitators = std::iter::once(primary)
+            .chain(fallbacks)
+            .map(Endpoints::try_new)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            facilitators: facilitators.into(),
+            failover: Arc::default(),
+            failover_cooldown: Duration::ZERO,
+            client: Client::new(),
             headers: HeaderMap::new(),
+            signing_key: None,
             timeout: None,
+            retry: RetryPolicy::default(),
+            tab_failure_ttl: Duration::ZERO,
+            tab_cache_ttl: Duration::ZERO,
+            tab_stale_window: Duration::ZERO,
+            tabs: Arc::default(),
         })
     }
 
     /// Attaches custom headers to all future requests.
-    #[allow(dead_code)] // Public for consumption by downstream crates.
     pub fn with_headers(&self, headers: HeaderMap) -> Self {
         let mut this = self.clone();
         this.headers = headers;
         this
     }
 
+    /// Signs every future `POST` with an HMAC-SHA256 under `key`, sent in
+    /// [`SIGNATURE_HEADER`] next to the [`SIGNATURE_TIMESTAMP_HEADER`] it covers; see
+    /// [`request_signature`].
+    pub fn with_signing_key(&self, key: impl AsRef<[u8]>) -> Self {
+        let mut this = self.clone();
+        this.signing_key = Some(SigningKey(key.as_ref().into()));
+        this
+    }
+
     /// Sets a timeout for all future requests.
-    #[allow(dead_code)] // Public for consumption by downstream crates.
     pub fn with_timeout(&self, timeout: Duration) -> Self {
         let mut this = self.clone();
         this.timeout = Some(timeout);
         this
     }
 
-    /// Sends a `POST /verify` request to the facilitator.
-    pub async fn verify(
-        &self,
-        request: &FacilitatorVerifyParams<'_>,
-    ) -> Result<FacilitatorVerifyResponse, FacilitatorClientError> {
-        self.post_json(&self.verify_url, "POST /verify", request)
-            .await
+    /// Retries failed requests up to `max_attempts` total attempts with exponential backoff.
+    ///
+    /// `/settle` is not idempotent, so it is only retried when connecting failed: after a
+    /// timeout or a dropped connection the facilitator may already have settled.
+    pub fn with_retries(&self, max_attempts: u32, base_delay: Duration) -> Self {
+        let mut this = self.clone();
+        this.retry = RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            base_delay,
+        };
+        this
     }
 
-    /// Sends a `POST /settle` request to the facilitator.
-    pub async fn settle(
-        &self,
-        request: &FacilitatorSettleParams<'_>,
-    ) -> Result<FacilitatorSettleResponse, FacilitatorClientError> {
-        self.post_json(&self.settle_url, "POST /settle", request)
-            .await
+    /// Answers `POST /tabs` from cache with the last error for `ttl` after a request fails,
+    /// instead of calling the facilitator again. Disabled with a zero `ttl`.
+    pub fn with_tab_failure_ttl(&self, ttl: Duration) -> Self {
+        let mut this = self.clone();
+        this.tab_failure_ttl = ttl;
+        this
     }
 
-    /// Sends a `POST /settle` request to the facilitator with v2 requirements.
-    pub async fn settle_v2(
+    /// Answers `POST /tabs` from cache with the opened tab for `ttl` after a request
+    /// succeeds, instead of opening it again. Disabled with a zero `ttl`.
+    pub fn with_tab_cache_ttl(&self, ttl: Duration) -> Self {
+        let mut this = self.clone();
+        this.tab_cache_ttl = ttl;
+        this
+    }
+
+    /// Keeps answering a cached tab for `window` past the [tab cache
+    /// TTL](Self::with_tab_cache_ttl), refreshing it in the background, so callers don't
+    /// wait on the facilitator each time a tab expires. Disabled with a zero `window`.
+    pub fn with_tab_stale_window(&self, window: Duration) -> Self {
+        let mut this = self.clone();
+        this.tab_stale_window = window;
+        this
+    }
+
+    /// Keeps a facilitator that answered after a failover first for `cooldown` before
+    /// the primary is tried again, so calls don't flap between them.
+    pub fn with_failover_cooldown(&self, cooldown: Duration) -> Self {
+        let mut this = self.clone();
+        this.failover_cooldown = cooldown;
+        this
+    }
+
+    /// Base URL of the facilitator calls currently go to first, after any `0.0.0.0`
+    /// rewrite.
+    pub fn base_url(&self) -> &Url {
+        &self.facilitators[self.failover.lock().active].base_url
+    }
+
+    /// Sends a single `GET /supported` within `timeout` to each facilitator in turn, without
+    /// retries, until one answers. That one is made active, and how long it took to answer
+    /// is returned.
+    pub async fn probe(&self, timeout: Duration) -> Result<Duration, FacilitatorClientError> {
+        let mut last_error = None;
+        for (index, facilitator) in self.facilitators.iter().enumerate() {
+            match self.probe_one(facilitator, timeout).await {
+                Ok(latency) => {
+                    self.record_answer(index, index > 0);
+                    return Ok(latency);
+                }
+                Err(e) => {
+                    if self.facilitators.len() > 1 {
+                        warn!(
+                            facilitator = %facilitator.base_url,
+                            error = %e,
+                            "Facilitator failed the probe"
+                        );
+                    }
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.expect("a facilitator client has at least one facilitator"))
+    }
+
+    async fn probe_one(
         &self,
-        request: &FacilitatorSettleParamsV2<'_>,
-    ) -> Result<FacilitatorSettleResponse, FacilitatorClientError> {
-        self.post_json(&self.settle_url, "POST /settle", request)
+        facilitator: &Endpoints,
+        timeout: Duration,
+    ) -> Result<Duration, FacilitatorClientError> {
+        const CONTEXT: &str = "GET /supported";
+        let started = Instant::now();
+        let response = self
+            .client
+            .get(facilitator.supported_url.clone())
+            .headers(self.headers.clone())
+            .timeout(timeout)
+            .send()
             .await
+            .map_err(|e| FacilitatorClientError::Http {
+                context: CONTEXT,
+                attempts: 1,
+                source: e,
+            })?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(FacilitatorClientError::http_status(
+                CONTEXT, 1, status, body,
+            ));
+        }
+        Ok(started.elapsed())
     }
 
-    /// Sends a `POST /tabs` request to the facilitator with caching.
-    ///
-    /// Tabs are cached to prevent duplicate requests.
-    pub async fn request_tab(
-        &self,
-        request: &FacilitatorTabRequestParams,
-    ) -> Result<FacilitatorTabResponse, FacilitatorClientError> {
-        log::info!("POST /tabs to facilitator {}", self.tab_url);
-        let response: FacilitatorTabResponse = self
-            .post_json(&self.tab_url, "POST /tabs", &request)
-            .await?;
+    /// `tab` as cached when it was just opened.
+    fn cached(&self, tab: FacilitatorTabResponse) -> CachedTab {
+        let fresh_until = Instant::now() + self.tab_cache_ttl;
+        CachedTab {
+            tab,
+            fresh_until,
+            stale_until: fresh_until + self.tab_stale_window,
+        }
+    }
 
-        Ok(response)
+    /// Reopens the stale tab cached under `key` in the background. A failure keeps the
+    /// stale tab for another [`TAB_REFRESH_BACKOFF`] instead of dropping it.
+    fn refresh_tab(&self, key: TabKey, request: FacilitatorTabRequestParams) {
+        let this = self.clone();
+        tokio::spawn(async move {
+            info!(facilitator = %this.base_url(), "Refreshing stale tab");
+            let result: Result<FacilitatorTabResponse, _> = this
+                .post_json(
+                    |facilitator| &facilitator.tab_url,
+                    "POST /tabs",
+                    Idempotency::Idempotent,
+                    &request,
+                )
+                .await;
+            let mut tabs = this.tabs.lock();
+            tabs.refreshing.remove(&key);
+            match result {
+                Ok(tab) => {
+                    tabs.failed.remove(&key);
+                    // An entry evicted while it was refreshing stays evicted.
+                    if let Some(cached) = tabs.opened.get_mut(&key) {
+                        *cached = this.cached(tab);
+                    }
+                }
+                Err(e) => {
+                    warn!(error = %e, "Stale tab refresh failed, answering the cached tab");
+                    if let Some(cached) = tabs.opened.get_mut(&key) {
+                        cached.fresh_until = Instant::now() + TAB_REFRESH_BACKOFF;
+                        cached.stale_until = cached.stale_until.max(cached.fresh_until);
+                    }
+                }
+            }
+        });
     }
 
     /// Generic POST helper that handles JSON serialization, error mapping,
@@ -202,108 +728,1160 @@ impl FacilitatorClient {
     /// `context` is a human-readable identifier used in tracing and error messages (e.g. `"POST /verify"`).
     async fn post_json<T, R>(
         &self,
-        url: &Url,
+        endpoint: fn(&Endpoints) -> &Url,
         context: &'static str,
+        idempotency: Idempotency,
         payload: &T,
     ) -> Result<R, FacilitatorClientError>
     where
         T: serde::Serialize + ?Sized,
         R: serde::de::DeserializeOwned,
     {
-        let payload_json =
-            serde_json::to_string(payload).unwrap_or_else(|_| "<serialize_failed>".into());
-        log::debug!(
-            "Facilitator request: context={} url={} payload={}",
+        // Serialized once, so the signature covers exactly the bytes sent.
+        let body = serde_json::to_vec(payload)
+            .map_err(|e| FacilitatorClientError::JsonSerialization { context, source: e })?;
+        debug!(
             context,
-            url,
-            payload_json
+            payload = %String::from_utf8_lossy(&body),
+            "Facilitator request"
         );
 
-        let mut req = self.client.post(url.clone()).json(payload);
-        for (key, value) in self.headers.iter() {
-            req = req.header(key, value);
-        }
-        if let Some(timeout) = self.timeout {
-            req = req.timeout(timeout);
-        }
-        let http_response = req
-            .send()
-            .await
-            .map_err(|e| FacilitatorClientError::Http { context, source: e })?;
-
-        let status = http_response.status();
-        let body = http_response
-            .text()
-            .await
-            .map_err(|e| FacilitatorClientError::ResponseBodyRead { context, source: e })?;
-
-        log::debug!(
-            "Facilitator response: context={} status={} body={}",
-            context,
-            status,
-            body
-        );
-
-        if status == StatusCode::OK {
-            serde_json::from_str::<R>(&body)
-                .map_err(|e| FacilitatorClientError::JsonDeserialization { context, source: e })
-        } else {
-            Err(FacilitatorClientError::HttpStatus {
-                context,
-                status,
-                body,
-            })
-        }
+        self.send_with_failover(context, idempotency, endpoint, |url| {
+            let request = self
+                .client
+                .post(url.clone())
+                .header(CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            match &self.signing_key {
+                // Signed for each attempt, so a retry carries a current timestamp.
+                Some(SigningKey(key)) => {
+                    let timestamp = Utc::now().timestamp();
+                    let signature = request_signature(key, "POST", url.path(), timestamp, &body);
+                    request
+                        .header(SIGNATURE_HEADER, signature)
+                        .header(SIGNATURE_TIMESTAMP_HEADER, timestamp)
+                }
+                None => request,
+            }
+        })
+        .await
     }
 
     /// Generic GET helper that handles JSON serialization, error mapping,
     /// timeout application, and telemetry integration.
     ///
     /// `context` is a human-readable identifier used in tracing and error messages (e.g. `"POST /verify"`).
-    #[allow(dead_code)]
     async fn get_json<R>(
         &self,
-        url: &Url,
+        endpoint: fn(&Endpoints) -> &Url,
+        context: &'static str,
+    ) -> Result<R, FacilitatorClientError>
+    where
+        R: serde::de::DeserializeOwned,
+    {
+        debug!(context, "Facilitator request");
+        self.send_with_failover(context, Idempotency::Idempotent, endpoint, |url| {
+            self.client.get(url.clone())
+        })
+        .await
+    }
+
+    /// Sends the request `build` makes for each facilitator's `endpoint`, starting with the
+    /// active one, until a facilitator answers or fails in a way another one wouldn't fix.
+    async fn send_with_failover<R, F>(
+        &self,
         context: &'static str,
+        idempotency: Idempotency,
+        endpoint: fn(&Endpoints) -> &Url,
+        build: F,
     ) -> Result<R, FacilitatorClientError>
     where
         R: serde::de::DeserializeOwned,
+        F: Fn(&Url) -> RequestBuilder,
     {
-        log::debug!("Facilitator request: context={} url={}", context, url);
-        let mut req = self.client.get(url.clone());
-        for (key, value) in self.headers.iter() {
-            req = req.header(key, value);
+        let order = self.failover_order();
+        let mut remaining = order.len();
+        for index in order {
+            remaining -= 1;
+            let url = endpoint(&self.facilitators[index]);
+            debug!(context, url = %url, "Facilitator request");
+            match self
+                .send_with_retries(context, idempotency, || build(url))
+                .await
+            {
+                Ok(response) => {
+                    self.record_answer(index, remaining + 1 < self.facilitators.len());
+                    return Ok(response);
+                }
+                Err(e) if remaining > 0 && e.fails_over(idempotency) => {
+                    warn!(
+                        facilitator = %self.facilitators[index].base_url,
+                        context,
+                        error = %e,
+                        "Facilitator failed, trying the next one"
+                    );
+                }
+                Err(e) => return Err(e),
+            }
         }
-        if let Some(timeout) = self.timeout {
-            req = req.timeout(timeout);
+        unreachable!("a facilitator client has at least one facilitator")
+    }
+
+    /// Facilitator indices in the order to try them: the active one, then the rest by
+    /// priority. A fallback stays first only for the failover cooldown.
+    fn failover_order(&self) -> Vec<usize> {
+        let failover = self.failover.lock();
+        let cooled_down = failover
+            .since
+            .is_none_or(|at| at.elapsed() >= self.failover_cooldown);
+        let first = if failover.active != 0 && cooled_down {
+            0
+        } else {
+            failover.active
+        };
+        std::iter::once(first)
+            .chain((0..self.facilitators.len()).filter(|&index| index != first))
+            .collect()
+    }
+
+    /// Makes `index` the active facilitator after it answered, restarting the cooldown
+    /// when another facilitator failed the call first.
+    fn record_answer(&self, index: usize, failed_over: bool) {
+        let mut failover = self.failover.lock();
+        if failover.active != index {
+            warn!(
+                facilitator = %self.facilitators[index].base_url,
+                "Facilitator calls now go to this facilitator first"
+            );
+            failover.active = index;
+            failover.failovers += 1;
         }
-        let http_response = req
-            .send()
+        if failed_over {
+            failover.since = Some(Instant::now());
+        }
+    }
+
+    /// Sends the request built by `build`, retrying according to the configured
+    /// [`RetryPolicy`] and the request's [`Idempotency`].
+    async fn send_with_retries<R, F>(
+        &self,
+        context: &'static str,
+        idempotency: Idempotency,
+        build: F,
+    ) -> Result<R, FacilitatorClientError>
+    where
+        R: serde::de::DeserializeOwned,
+        F: Fn() -> RequestBuilder,
+    {
+        let max_attempts = self.retry.max_attempts.max(1);
+        let mut attempt = 1;
+        loop {
+            let mut req = build();
+            for (key, value) in self.headers.iter() {
+                req = req.header(key, value);
+            }
+            if let Some(timeout) = self.timeout {
+                req = req.timeout(timeout);
+            }
+
+            let http_response = match req.send().await {
+                Ok(response) => response,
+                Err(e) if attempt < max_attempts && idempotency.retries_transport_error(&e) => {
+                    let delay = self.retry.delay_for(attempt);
+                    warn!(
+                        context,
+                        attempt,
+                        max_attempts,
+                        retry_in = ?delay,
+                        error = %e,
+                        "Facilitator transport error"
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                    continue;
+                }
+                Err(e) => {
+                    return Err(FacilitatorClientError::Http {
+                        context,
+                        attempts: attempt,
+                        source: e,
+                    });
+                }
+            };
+
+            let status = http_response.status();
+            let body = http_response
+                .text()
+                .await
+                .map_err(|e| FacilitatorClientError::ResponseBodyRead { context, source: e })?;
+
+            debug!(context, status = %status, body = %body, "Facilitator response");
+
+            if status == StatusCode::OK {
+                return serde_json::from_str::<R>(&body).map_err(|e| {
+                    FacilitatorClientError::JsonDeserialization { context, source: e }
+                });
+            }
+
+            if status.is_server_error()
+                && idempotency == Idempotency::Idempotent
+                && attempt < max_attempts
+            {
+                let delay = self.retry.delay_for(attempt);
+                warn!(
+                    context,
+                    status = %status,
+                    attempt,
+                    max_attempts,
+                    retry_in = ?delay,
+                    "Facilitator returned a server error"
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+                continue;
+            }
+
+            return Err(FacilitatorClientError::http_status(
+                context, attempt, status, body,
+            ));
+        }
+    }
+}
+
+impl Facilitator for FacilitatorClient {
+    /// Sends a `POST /verify` request to the facilitator.
+    fn verify<'a>(
+        &'a self,
+        request: &'a FacilitatorVerifyParams<'a>,
+    ) -> FacilitatorFuture<'a, FacilitatorVerifyResponse> {
+        Box::pin(async move {
+            self.post_json(
+                |facilitator| &facilitator.verify_url,
+                "POST /verify",
+                Idempotency::Idempotent,
+                request,
+            )
             .await
-            .map_err(|e| FacilitatorClientError::Http { context, source: e })?;
+        })
+    }
 
-        let status = http_response.status();
-        let body = http_response
-            .text()
+    /// Sends a `POST /verify` request to the facilitator with v2 requirements.
+    fn verify_v2<'a>(
+        &'a self,
+        request: &'a FacilitatorVerifyParamsV2<'a>,
+    ) -> FacilitatorFuture<'a, FacilitatorVerifyResponse> {
+        Box::pin(async move {
+            self.post_json(
+                |facilitator| &facilitator.verify_url,
+                "POST /verify",
+                Idempotency::Idempotent,
+                request,
+            )
             .await
-            .map_err(|e| FacilitatorClientError::ResponseBodyRead { context, source: e })?;
+        })
+    }
 
-        log::debug!(
-            "Facilitator response: context={} status={} body={}",
-            context,
-            status,
-            body
+    /// Sends a `POST /settle` request to the facilitator.
+    fn settle<'a>(
+        &'a self,
+        request: &'a FacilitatorSettleParams<'a>,
+    ) -> FacilitatorFuture<'a, FacilitatorSettleResponse> {
+        Box::pin(async move {
+            self.post_json(
+                |facilitator| &facilitator.settle_url,
+                "POST /settle",
+                Idempotency::NonIdempotent,
+                request,
+            )
+            .await
+        })
+    }
+
+    /// Sends a `POST /settle` request to the facilitator with v2 requirements.
+    fn settle_v2<'a>(
+        &'a self,
+        request: &'a FacilitatorSettleParamsV2<'a>,
+    ) -> FacilitatorFuture<'a, FacilitatorSettleResponse> {
+        Box::pin(async move {
+            self.post_json(
+                |facilitator| &facilitator.settle_url,
+                "POST /settle",
+                Idempotency::NonIdempotent,
+                request,
+            )
+            .await
+        })
+    }
+
+    /// Sends a `POST /tabs` request to the facilitator.
+    ///
+    /// Concurrent requests for the same tab share one call, and failures are remembered
+    /// for the [tab failure TTL](Self::with_tab_failure_ttl) so that an unavailable
+    /// facilitator isn't asked again by every caller. Any success clears the failure, and
+    /// is itself reused for the [tab cache TTL](Self::with_tab_cache_ttl), and past it
+    /// for the [stale window](Self::with_tab_stale_window) while it is refreshed.
+    fn request_tab<'a>(
+        &'a self,
+        request: &'a FacilitatorTabRequestParams,
+    ) -> FacilitatorFuture<'a, FacilitatorTabResponse> {
+        Box::pin(async move {
+            const CONTEXT: &str = "POST /tabs";
+            if let Some(tab) = self.cached_tab(request) {
+                return Ok(tab);
+            }
+            let key = TabKey::new(request);
+            let cell = {
+                let mut tabs = self.tabs.lock();
+                let ttl = self.tab_failure_ttl;
+                tabs.failed.retain(|_, (_, at)| at.elapsed() < ttl);
+                if let Some((message, _)) = tabs.failed.get(&key) {
+                    return Err(FacilitatorClientError::RecentFailure {
+                        context: CONTEXT,
+                        message: message.clone(),
+                    });
+                }
+                tabs.in_flight.entry(key.clone()).or_default().clone()
+            };
+
+            // Whoever runs the call keeps its typed error; the rest only see its message.
+            let mut own_error = None;
+            let outcome = cell
+                .get_or_init(|| async {
+                    info!(facilitator = %self.base_url(), "POST /tabs to facilitator");
+                    let result: Result<FacilitatorTabResponse, _> = self
+                        .post_json(
+                            |facilitator| &facilitator.tab_url,
+                            CONTEXT,
+                            Idempotency::Idempotent,
+                            &request,
+                        )
+                        .await;
+                    let outcome = result.as_ref().cloned().map_err(ToString::to_string);
+                    {
+                        let mut tabs = self.tabs.lock();
+                        tabs.in_flight.remove(&key);
+                        match &outcome {
+                            Ok(tab) => {
+                                tabs.failed.remove(&key);
+                                if !self.tab_cache_ttl.is_zero() {
+                                    tabs.opened.insert(key.clone(), self.cached(tab.clone()));
+                                }
+                            }
+                            Err(message) if !self.tab_failure_ttl.is_zero() => {
+                                tabs.failed
+                                    .insert(key.clone(), (message.clone(), Instant::now()));
+                            }
+                            Err(_) => {}
+                        }
+                    }
+                    own_error = result.err();
+                    outcome
+                })
+                .await;
+
+            if let Some(e) = own_error {
+                return Err(e);
+            }
+            outcome
+                .clone()
+                .map_err(|message| FacilitatorClientError::RecentFailure {
+                    context: CONTEXT,
+                    message,
+                })
+        })
+    }
+
+    /// Sends a `GET /supported` request to the facilitator.
+    fn supported(&self) -> FacilitatorFuture<'_, FacilitatorSupportedResponse> {
+        Box::pin(async move {
+            self.get_json(|facilitator| &facilitator.supported_url, "GET /supported")
+                .await
+        })
+    }
+
+    /// The tab a recent `POST /tabs` for the same request opened, if it is still within
+    /// the [tab cache TTL](Self::with_tab_cache_ttl) or the [stale
+    /// window](Self::with_tab_stale_window). A stale tab starts one background refresh.
+    fn cached_tab(&self, request: &FacilitatorTabRequestParams) -> Option<FacilitatorTabResponse> {
+        let key = TabKey::new(request);
+        let (tab, refresh) = {
+            let mut tabs = self.tabs.lock();
+            tabs.drop_expired_tabs();
+            let cached = tabs.opened.get(&key)?;
+            let tab = cached.tab.clone();
+            let stale = Instant::now() >= cached.fresh_until;
+            (tab, stale && tabs.refreshing.insert(key.clone()))
+        };
+        if refresh {
+            self.refresh_tab(key, request.clone());
+        }
+        Some(tab)
+    }
+
+    fn tab_cache_entries(&self) -> Vec<TabCacheEntry> {
+        let mut tabs = self.tabs.lock();
+        tabs.drop_expired_tabs();
+        let now = Utc::now().timestamp();
+        let mut entries: Vec<_> = tabs
+            .opened
+            .iter()
+            .map(|(key, cached)| TabCacheEntry {
+                user_address: key.user_address.clone(),
+                recipient_address: key.recipient_address.clone(),
+                asset_address: key.erc20_token.clone(),
+                tab_id: cached.tab.tab_id.clone(),
+                expires_at: now
+                    + cached
+                        .stale_until
+                        .saturating_duration_since(Instant::now())
+                        .as_secs() as i64,
+            })
+            .collect();
+        entries
+            .sort_by(|a, b| (&a.user_address, a.expires_at).cmp(&(&b.user_address, b.expires_at)));
+        entries
+    }
+
+    fn evict_cached_tabs(&self, user_address: &str) -> usize {
+        let user_address = user_address.to_lowercase();
+        let mut tabs = self.tabs.lock();
+        tabs.drop_expired_tabs();
+        tabs.failed
+            .retain(|key, _| key.user_address != user_address);
+        let before = tabs.opened.len();
+        tabs.opened
+            .retain(|key, _| key.user_address != user_address);
+        before - tabs.opened.len()
+    }
+
+    fn clear_tab_cache(&self) -> usize {
+        let mut tabs = self.tabs.lock();
+        tabs.drop_expired_tabs();
+        tabs.failed.clear();
+        let evicted = tabs.opened.len();
+        tabs.opened.clear();
+        evicted
+    }
+
+    fn failover_status(&self) -> Option<FailoverStatus> {
+        let failover = self.failover.lock();
+        Some(FailoverStatus {
+            base_url: self.facilitators[failover.active].base_url.clone(),
+            priority: failover.active,
+            failovers: failover.failovers,
+        })
+    }
+
+    /// Sends `request_id` as `x-request-id` alongside any custom headers.
+    fn with_request_id(&self, request_id: HeaderValue) -> Arc<dyn Facilitator> {
+        let mut this = self.clone();
+        this.headers
+            .insert(HeaderName::from_static("x-request-id"), request_id);
+        Arc::new(this)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::PaymentError;
+    use sdk_4mica::x402::PaymentRequirements;
+    use wiremock::{
+        Mock, MockServer, ResponseTemplate,
+        matchers::{method, path},
+    };
+
+    fn requirements() -> PaymentRequirements {
+        PaymentRequirements {
+            scheme: "4mica-credit".into(),
+            network: "polygon-amoy".into(),
+            max_amount_required: "100".into(),
+            resource: Some("/stream/a.ts".into()),
+            description: None,
+            mime_type: None,
+            output_schema: None,
+            pay_to: "0x00000000000000000000000000000000000000ab".into(),
+            max_timeout_seconds: Some(300),
+            asset: "0x00000000000000000000000000000000000000cd".into(),
+            extra: None,
+        }
+    }
+
+    fn client(server: &MockServer) -> FacilitatorClient {
+        let url = Url::parse(&format!("{}/", server.uri())).unwrap();
+        FacilitatorClient::try_new(url)
+            .unwrap()
+            .with_retries(3, Duration::from_millis(1))
+    }
+
+    fn settled() -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "success": true,
+            "txHash": "0xabc",
+            "networkId": "polygon-amoy",
+        }))
+    }
+
+    #[tokio::test]
+    async fn verify_fails_twice_then_succeeds() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/verify"))
+            .respond_with(ResponseTemplate::new(502))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/verify"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "isValid": true,
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let requirements = requirements();
+        let response = client(&server)
+            .verify(&FacilitatorVerifyParams {
+                x402_version: 1,
+                payment_header: "header",
+                payment_payload: None,
+                payment_requirements: &requirements,
+            })
+            .await
+            .unwrap();
+        assert!(response.is_valid);
+    }
+
+    #[tokio::test]
+    async fn settle_is_not_retried_after_a_response() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/settle"))
+            .respond_with(ResponseTemplate::new(502))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let requirements = requirements();
+        let err = client(&server)
+            .settle(&FacilitatorSettleParams {
+                x402_version: 1,
+                payment_header: "header",
+                payment_payload: None,
+                payment_requirements: &requirements,
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            FacilitatorClientError::HttpStatus { attempts: 1, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn settle_is_not_retried_after_a_timeout() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/settle"))
+            .respond_with(settled().set_delay(Duration::from_millis(500)))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let requirements = requirements();
+        let err = client(&server)
+            .with_timeout(Duration::from_millis(50))
+            .settle(&FacilitatorSettleParams {
+                x402_version: 1,
+                payment_header: "header",
+                payment_payload: None,
+                payment_requirements: &requirements,
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            FacilitatorClientError::Http { attempts: 1, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn settle_is_retried_when_connecting_fails() {
+        let requirements = requirements();
+        let err = FacilitatorClient::try_new(closed_url())
+            .unwrap()
+            .with_retries(3, Duration::from_millis(1))
+            .settle(&FacilitatorSettleParams {
+                x402_version: 1,
+                payment_header: "header",
+                payment_payload: None,
+                payment_requirements: &requirements,
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            FacilitatorClientError::Http { attempts: 3, .. }
+        ));
+    }
+
+    /// A port nothing listens on, as it was just released.
+    fn closed_url() -> Url {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        Url::parse(&format!("http://127.0.0.1:{port}/")).unwrap()
+    }
+
+    fn with_fallback(primary: Url, fallback: &MockServer) -> FacilitatorClient {
+        let fallback = Url::parse(&format!("{}/", fallback.uri())).unwrap();
+        FacilitatorClient::try_new_with_fallbacks(primary, [fallback]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn settle_fails_over_when_connecting_fails() {
+        let fallback = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/settle"))
+            .respond_with(settled())
+            .expect(1)
+            .mount(&fallback)
+            .await;
+
+        let requirements = requirements();
+        let client = with_fallback(closed_url(), &fallback);
+        let response = client
+            .settle(&FacilitatorSettleParams {
+                x402_version: 1,
+                payment_header: "header",
+                payment_payload: None,
+                payment_requirements: &requirements,
+            })
+            .await
+            .unwrap();
+        assert!(response.success);
+        assert_eq!(client.failover_status().unwrap().priority, 1);
+    }
+
+    #[tokio::test]
+    async fn settle_does_not_fail_over_after_a_timeout() {
+        let primary = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/settle"))
+            .respond_with(settled().set_delay(Duration::from_millis(500)))
+            .expect(1)
+            .mount(&primary)
+            .await;
+        let fallback = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/settle"))
+            .respond_with(settled())
+            .expect(0)
+            .mount(&fallback)
+            .await;
+
+        let requirements = requirements();
+        let primary_url = Url::parse(&format!("{}/", primary.uri())).unwrap();
+        let err = with_fallback(primary_url, &fallback)
+            .with_timeout(Duration::from_millis(50))
+            .settle(&FacilitatorSettleParams {
+                x402_version: 1,
+                payment_header: "header",
+                payment_payload: None,
+                payment_requirements: &requirements,
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, FacilitatorClientError::Http { .. }));
+    }
+
+    #[tokio::test]
+    async fn verify_fails_over_after_a_timeout() {
+        let primary = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/verify"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(500)))
+            .mount(&primary)
+            .await;
+        let fallback = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/verify"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "isValid": true,
+            })))
+            .expect(1)
+            .mount(&fallback)
+            .await;
+
+        let requirements = requirements();
+        let primary_url = Url::parse(&format!("{}/", primary.uri())).unwrap();
+        let response = with_fallback(primary_url, &fallback)
+            .with_timeout(Duration::from_millis(50))
+            .verify(&FacilitatorVerifyParams {
+                x402_version: 1,
+                payment_header: "header",
+                payment_payload: None,
+                payment_requirements: &requirements,
+            })
+            .await
+            .unwrap();
+        assert!(response.is_valid);
+    }
+
+    fn tab_request() -> FacilitatorTabRequestParams {
+        FacilitatorTabRequestParams {
+            user_address: "0x00000000000000000000000000000000000000ef".into(),
+            recipient_address: "0x00000000000000000000000000000000000000ab".into(),
+            erc20_token: "0x00000000000000000000000000000000000000cd".into(),
+            ttl_seconds: Some(86400),
+        }
+    }
+
+    fn opened_tab() -> ResponseTemplate {
+        opened_tab_with_id("0x7")
+    }
+
+    fn opened_tab_with_id(tab_id: &str) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "tabId": tab_id,
+            "userAddress": "0x00000000000000000000000000000000000000ef",
+            "recipientAddress": "0x00000000000000000000000000000000000000ab",
+            "assetAddress": "0x00000000000000000000000000000000000000cd",
+            "startTimestamp": 1_700_000_000,
+            "ttlSeconds": 86400,
+        }))
+    }
+
+    /// A client that tries each tab request once and caches no opened tab, so every
+    /// call not answered from the failure cache or an in-flight call reaches `server`.
+    fn tab_client(server: &MockServer, failure_ttl: Duration) -> FacilitatorClient {
+        client(server)
+            .with_retries(1, Duration::from_millis(1))
+            .with_tab_cache_ttl(Duration::ZERO)
+            .with_tab_failure_ttl(failure_ttl)
+    }
+
+    #[tokio::test]
+    async fn concurrent_tab_requests_share_one_call() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/tabs"))
+            .respond_with(opened_tab().set_delay(Duration::from_millis(200)))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = tab_client(&server, Duration::from_secs(5));
+        let request = tab_request();
+        let tabs =
+            futures_util::future::join_all((0..8).map(|_| client.request_tab(&request))).await;
+        for tab in tabs {
+            assert_eq!(tab.unwrap().tab_id, "0x7");
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_tab_requests_share_one_failure() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/tabs"))
+            .respond_with(ResponseTemplate::new(502).set_delay(Duration::from_millis(200)))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = tab_client(&server, Duration::from_secs(5));
+        let request = tab_request();
+        let tabs =
+            futures_util::future::join_all((0..8).map(|_| client.request_tab(&request))).await;
+        assert!(tabs.iter().all(Result::is_err));
+    }
+
+    #[tokio::test]
+    async fn failed_tab_request_is_answered_from_cache() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/tabs"))
+            .respond_with(ResponseTemplate::new(502))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = tab_client(&server, Duration::from_secs(5));
+        let request = tab_request();
+        assert!(client.request_tab(&request).await.is_err());
+        for _ in 0..3 {
+            assert!(matches!(
+                client.request_tab(&request).await,
+                Err(FacilitatorClientError::RecentFailure { .. })
+            ));
+        }
+
+        // Other tabs are not held back by this one's failure.
+        let other = FacilitatorTabRequestParams {
+            user_address: "0x00000000000000000000000000000000000000ee".into(),
+            ..tab_request()
+        };
+        server.reset().await;
+        Mock::given(method("POST"))
+            .and(path("/tabs"))
+            .respond_with(opened_tab())
+            .expect(1)
+            .mount(&server)
+            .await;
+        client.request_tab(&other).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn tab_request_is_retried_once_the_failure_expires() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/tabs"))
+            .respond_with(ResponseTemplate::new(502))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/tabs"))
+            .respond_with(opened_tab())
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let client = tab_client(&server, Duration::from_millis(100));
+        let request = tab_request();
+        assert!(client.request_tab(&request).await.is_err());
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        client.request_tab(&request).await.unwrap();
+        // The success cleared the failure, so nothing is answered from it.
+        client.request_tab(&request).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn zero_failure_ttl_asks_again_at_once() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/tabs"))
+            .respond_with(ResponseTemplate::new(502))
+            .expect(3)
+            .mount(&server)
+            .await;
+
+        let client = tab_client(&server, Duration::ZERO);
+        let request = tab_request();
+        for _ in 0..3 {
+            let err = client.request_tab(&request).await.unwrap_err();
+            assert!(!matches!(err, FacilitatorClientError::RecentFailure { .. }));
+        }
+    }
+
+    /// A client that caches opened tabs for `ttl`, then answers them for `stale_window`
+    /// more while refreshing them.
+    fn stale_tab_client(
+        server: &MockServer,
+        ttl: Duration,
+        stale_window: Duration,
+    ) -> FacilitatorClient {
+        client(server)
+            .with_retries(1, Duration::from_millis(1))
+            .with_tab_cache_ttl(ttl)
+            .with_tab_stale_window(stale_window)
+    }
+
+    #[tokio::test]
+    async fn stale_tab_is_answered_while_one_refresh_runs() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/tabs"))
+            .respond_with(opened_tab())
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/tabs"))
+            .respond_with(opened_tab_with_id("0x8").set_delay(Duration::from_millis(200)))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = stale_tab_client(&server, Duration::from_millis(100), Duration::from_secs(5));
+        let request = tab_request();
+        assert_eq!(client.request_tab(&request).await.unwrap().tab_id, "0x7");
+        tokio::time::sleep(Duration::from_millis(150)).await;
+
+        // Every caller gets the stale tab at once; the refresh runs behind them.
+        let started = Instant::now();
+        let tabs =
+            futures_util::future::join_all((0..16).map(|_| client.request_tab(&request))).await;
+        assert!(started.elapsed() < Duration::from_millis(150));
+        for tab in tabs {
+            assert_eq!(tab.unwrap().tab_id, "0x7");
+        }
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(client.request_tab(&request).await.unwrap().tab_id, "0x8");
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_refresh_keeps_answering_the_stale_tab() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/tabs"))
+            .respond_with(opened_tab())
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/tabs"))
+            .respond_with(ResponseTemplate::new(502))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = stale_tab_client(
+            &server,
+            Duration::from_millis(50),
+            Duration::from_millis(100),
         );
+        let request = tab_request();
+        client.request_tab(&request).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(75)).await;
+        assert_eq!(client.request_tab(&request).await.unwrap().tab_id, "0x7");
 
-        if status == StatusCode::OK {
-            serde_json::from_str::<R>(&body)
-                .map_err(|e| FacilitatorClientError::JsonDeserialization { context, source: e })
-        } else {
-            Err(FacilitatorClientError::HttpStatus {
-                context,
-                status,
-                body,
+        // Past the stale window, the failed refresh's backoff still keeps the tab, and
+        // the facilitator isn't asked again within it.
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        for _ in 0..4 {
+            assert_eq!(client.request_tab(&request).await.unwrap().tab_id, "0x7");
+        }
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn tab_past_the_stale_window_is_reopened_inline() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/tabs"))
+            .respond_with(opened_tab())
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/tabs"))
+            .respond_with(opened_tab_with_id("0x8"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = stale_tab_client(
+            &server,
+            Duration::from_millis(50),
+            Duration::from_millis(50),
+        );
+        let request = tab_request();
+        client.request_tab(&request).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(client.request_tab(&request).await.unwrap().tab_id, "0x8");
+        assert_eq!(client.request_tab(&request).await.unwrap().tab_id, "0x8");
+    }
+
+    #[test]
+    fn signatures_match_known_answers() {
+        let key = b"facilitator-secret";
+        assert_eq!(
+            request_signature(
+                key,
+                "POST",
+                "/settle",
+                1_700_000_000,
+                br#"{"x402Version":1}"#
+            ),
+            "4bf29aa41ce07f773764f036142f8f651b5f62d0d5648397e83542f14e58e520"
+        );
+        assert_eq!(
+            request_signature(key, "POST", "/settle", 1_700_000_000, b""),
+            "791ef3e3efe6234700a402fc93e29b7672cf7671c95a2ea597ff229be3cdb83a"
+        );
+        assert_eq!(
+            request_signature(b"", "POST", "/tabs", 0, b"{}"),
+            "3dfffe7fb1d38a0d86d9f7768a6655edd1ebb02f365097a59c6c29cf572c8247"
+        );
+    }
+
+    fn header<'a>(request: &'a wiremock::Request, name: &str) -> Option<&'a str> {
+        request
+            .headers
+            .get(name)
+            .map(|value| value.to_str().unwrap())
+    }
+
+    #[tokio::test]
+    async fn posts_are_signed_over_the_bytes_sent() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/settle"))
+            .respond_with(settled())
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/tabs"))
+            .respond_with(opened_tab())
+            .mount(&server)
+            .await;
+
+        let key = "facilitator-secret";
+        let client = client(&server).with_signing_key(key);
+        let requirements = requirements();
+        client
+            .settle(&FacilitatorSettleParams {
+                x402_version: 1,
+                payment_header: "header",
+                payment_payload: None,
+                payment_requirements: &requirements,
+            })
+            .await
+            .unwrap();
+        client.request_tab(&tab_request()).await.unwrap();
+
+        let posts = server.received_requests().await.unwrap();
+        assert_eq!(posts.len(), 2);
+        let now = Utc::now().timestamp();
+        for request in posts {
+            let timestamp: i64 = header(&request, SIGNATURE_TIMESTAMP_HEADER)
+                .expect("a signed request carries its timestamp")
+                .parse()
+                .unwrap();
+            assert!((now - 60..=now).contains(&timestamp));
+            let path = request.url.path();
+            let expected =
+                request_signature(key.as_bytes(), "POST", path, timestamp, &request.body);
+            assert_eq!(header(&request, SIGNATURE_HEADER), Some(expected.as_str()));
+            assert_eq!(request.headers[CONTENT_TYPE], "application/json");
+            serde_json::from_slice::<serde_json::Value>(&request.body).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn posts_are_unsigned_without_a_key() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/tabs"))
+            .respond_with(opened_tab())
+            .mount(&server)
+            .await;
+
+        client(&server).request_tab(&tab_request()).await.unwrap();
+        let posts = server.received_requests().await.unwrap();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(header(&posts[0], SIGNATURE_HEADER), None);
+        assert_eq!(header(&posts[0], SIGNATURE_TIMESTAMP_HEADER), None);
+    }
+
+    #[test]
+    fn known_error_bodies_parse() {
+        let parsed = |body: &str| FacilitatorErrorBody::parse(body);
+        assert_eq!(
+            parsed(r#"{"error": "tab closed", "invalidReason": "invalid_payload"}"#),
+            Some(FacilitatorErrorBody {
+                error: Some("tab closed".into()),
+                invalid_reason: Some("invalid_payload".into()),
+            })
+        );
+        assert_eq!(
+            parsed(r#"{"message": "no funds", "errorReason": "insufficient_funds"}"#),
+            Some(FacilitatorErrorBody {
+                error: Some("no funds".into()),
+                invalid_reason: Some("insufficient_funds".into()),
+            })
+        );
+        assert_eq!(
+            parsed(r#"{"error": {"message": "expired", "code": "invalid_transaction_state"}}"#),
+            Some(FacilitatorErrorBody {
+                error: Some("expired".into()),
+                invalid_reason: Some("invalid_transaction_state".into()),
+            })
+        );
+        let unrecognized = [
+            "",
+            "   ",
+            "Bad Gateway",
+            "[]",
+            r#"{"error": ""}"#,
+            r#"{"ok": 1}"#,
+        ];
+        for body in unrecognized {
+            assert_eq!(parsed(body), None, "{body:?}");
+        }
+    }
+
+    /// The error a settle answered with `status` and `body` fails with.
+    async fn settle_rejected(status: u16, body: &str) -> FacilitatorClientError {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/settle"))
+            .respond_with(ResponseTemplate::new(status).set_body_string(body))
+            .mount(&server)
+            .await;
+        let requirements = requirements();
+        client(&server)
+            .settle(&FacilitatorSettleParams {
+                x402_version: 1,
+                payment_header: "header",
+                payment_payload: None,
+                payment_requirements: &requirements,
             })
+            .await
+            .unwrap_err()
+    }
+
+    #[tokio::test]
+    async fn json_error_body_names_its_reason() {
+        let err = settle_rejected(
+            400,
+            r#"{"error": "tab closed", "invalidReason": "insufficient_funds", "trace": "db01"}"#,
+        )
+        .await;
+        let message = err.to_string();
+        assert!(
+            message.ends_with(": tab closed (insufficient_funds)"),
+            "{message}"
+        );
+        assert!(!message.contains("db01"));
+        assert_eq!(PaymentError::from(err).reason_code(), "insufficient_funds");
+    }
+
+    #[tokio::test]
+    async fn unrecognized_error_bodies_are_redacted() {
+        for body in ["panic at facilitator/src/settle.rs:88 (db01)", ""] {
+            let err = settle_rejected(400, body).await;
+            let FacilitatorClientError::HttpStatus {
+                status,
+                body: received,
+                error,
+                ..
+            } = &err
+            else {
+                panic!("{err}");
+            };
+            assert_eq!(*status, StatusCode::BAD_REQUEST);
+            // Kept for the logs, but never shown.
+            assert_eq!(received, body);
+            assert_eq!(error, &None);
+            let message = err.to_string();
+            assert!(message.ends_with("(failed after 1 attempts)"), "{message}");
+            assert_eq!(
+                PaymentError::from(err).reason_code(),
+                "unexpected_settle_error"
+            );
         }
     }
 }
@@ -1,23 +1,28 @@
-use log::{info, warn};
 use sdk_4mica::{Client as FourMicaClient, ConfigBuilder, U256};
+use serde::Serialize;
 use serde_json::Value;
-use std::str::FromStr;
+use std::{str::FromStr, time::Duration};
+use thiserror::Error;
+use tokio::sync::OnceCell;
+use tracing::Instrument;
+use tracing::{debug, info, warn};
+use utoipa::ToSchema;
 
-use crate::x402::config::X402Config;
+use crate::{
+    PaymentError,
+    x402::{
+        config::{TabSnapshotMode, X402Config},
+        onchain::parse_u256_value,
+    },
+};
 
-fn parse_u256_value(raw: &str) -> Result<U256, String> {
-    let trimmed = raw.trim();
-    if trimmed.is_empty() {
-        return Err("empty numeric value".into());
-    }
-    if let Some(stripped) = trimmed.strip_prefix("0x") {
-        U256::from_str_radix(stripped, 16).map_err(|e| format!("invalid hex value {trimmed}: {e}"))
-    } else {
-        U256::from_str(trimmed).map_err(|e| format!("invalid decimal value {trimmed}: {e}"))
-    }
-}
+/// Longest a background tab snapshot may take before it is dropped.
+const TAB_SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Built on first use and shared by every lookup; a failed build is retried next time.
+static FOURMICA_CLIENT: OnceCell<FourMicaClient> = OnceCell::const_new();
 
-fn extract_tab_id(envelope: &Value) -> Option<String> {
+pub(super) fn extract_tab_id(envelope: &Value) -> Option<String> {
     envelope
         .get("payload")
         .and_then(|payload| payload.get("claims"))
@@ -49,137 +54,469 @@ fn fmt_u256_hex(value: &U256) -> String {
     format!("0x{:x}", value)
 }
 
-async fn build_fourmica_client(config: &X402Config) -> Option<FourMicaClient> {
+pub(crate) async fn build_fourmica_client(config: &X402Config) -> Option<&'static FourMicaClient> {
+    FOURMICA_CLIENT
+        .get_or_try_init(|| new_fourmica_client(config))
+        .await
+        .map_err(|err| warn!("Skipping 4mica tab lookup: {}", err))
+        .ok()
+}
+
+/// Builds a 4mica SDK client the way tab lookups do, without keeping it.
+pub async fn check_fourmica_client(config: &X402Config) -> Result<(), String> {
+    new_fourmica_client(config).await.map(drop)
+}
+
+async fn new_fourmica_client(config: &X402Config) -> Result<FourMicaClient, String> {
     let mut builder = ConfigBuilder::default().from_env();
 
-    if !config.rpc_url.is_empty() {
-        builder = builder.ethereum_http_rpc_url(config.rpc_url.clone());
+    let rpc_url = config.primary_rpc_url();
+    if !rpc_url.is_empty() {
+        builder = builder.ethereum_http_rpc_url(rpc_url);
     }
 
-    let cfg = match builder.build() {
-        Ok(cfg) => cfg,
-        Err(err) => {
-            warn!(
-                "Skipping 4mica tab logging: failed to build config (set 4MICA_WALLET_PRIVATE_KEY?): {}",
-                err
-            );
-            return None;
+    let cfg = builder
+        .build()
+        .map_err(|err| format!("failed to build config (set 4MICA_WALLET_PRIVATE_KEY?): {err}"))?;
+    FourMicaClient::new(cfg)
+        .await
+        .map_err(|err| format!("failed to init client: {err}"))
+}
+
+/// One section of a [`TabSnapshot`]: either the fetched data or the SDK error.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotSection<T> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl<T> SnapshotSection<T> {
+    fn from_result<E: std::fmt::Display>(result: Result<T, E>) -> Self {
+        match result {
+            Ok(data) => Self {
+                data: Some(data),
+                error: None,
+            },
+            Err(err) => Self {
+                data: None,
+                error: Some(err.to_string()),
+            },
         }
-    };
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TabView {
+    pub tab_id: String,
+    pub user_address: String,
+    pub recipient_address: String,
+    pub asset_address: String,
+    pub status: String,
+    pub settlement_status: String,
+    pub ttl_seconds: i64,
+    pub start_timestamp: i64,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymentStatusView {
+    pub paid: String,
+    pub remunerated: bool,
+    pub asset: String,
+}
 
-    match FourMicaClient::new(cfg).await {
-        Ok(client) => Some(client),
-        Err(err) => {
-            warn!("Skipping 4mica tab logging: failed to init client: {}", err);
-            None
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GuaranteeView {
+    pub req_id: String,
+    pub from_address: String,
+    pub to_address: String,
+    pub asset_address: String,
+    pub amount: String,
+    pub timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub certificate: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CollateralEventView {
+    pub id: String,
+    pub event_type: String,
+    pub user_address: String,
+    pub asset_address: String,
+    pub amount: String,
+    pub tab_id: Option<String>,
+    pub req_id: Option<String>,
+    pub tx_id: Option<String>,
+    pub created_at: i64,
+}
+
+/// Everything the 4mica SDK reports about a tab. Amounts are decimal strings,
+/// ids are hex.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TabSnapshot {
+    pub tab_id: String,
+    /// `data` is `null` when the SDK reports no such tab.
+    pub tab: SnapshotSection<Option<TabView>>,
+    pub payment_status: SnapshotSection<PaymentStatusView>,
+    pub guarantees: SnapshotSection<Vec<GuaranteeView>>,
+    pub collateral_events: SnapshotSection<Vec<CollateralEventView>>,
+}
+
+impl TabSnapshot {
+    /// `true` when the tab lookup succeeded but found nothing.
+    pub fn is_missing(&self) -> bool {
+        self.tab.error.is_none() && matches!(self.tab.data, Some(None))
+    }
+}
+
+/// Fetches a [`TabSnapshot`], or `None` if the 4mica SDK client can't be built.
+pub async fn fetch_tab_snapshot(tab_id: U256, config: &X402Config) -> Option<TabSnapshot> {
+    let client = build_fourmica_client(config).await?;
+
+    let (tab, payment_status, guarantees, collateral_events) = tokio::join!(
+        client.recipient.get_tab(tab_id),
+        client.recipient.get_tab_payment_status(tab_id),
+        client.recipient.get_tab_guarantees(tab_id),
+        client.recipient.get_collateral_events_for_tab(tab_id),
+    );
+    let tab = tab.map(|tab| {
+        tab.map(|tab| TabView {
+            tab_id: fmt_u256_hex(&tab.tab_id),
+            user_address: tab.user_address,
+            recipient_address: tab.recipient_address,
+            asset_address: tab.asset_address,
+            status: tab.status,
+            settlement_status: tab.settlement_status,
+            ttl_seconds: tab.ttl_seconds,
+            start_timestamp: tab.start_timestamp,
+            created_at: tab.created_at,
+            updated_at: tab.updated_at,
+        })
+    });
+    let payment_status = payment_status.map(|status| PaymentStatusView {
+        paid: fmt_u256(&status.paid),
+        remunerated: status.remunerated,
+        asset: status.asset,
+    });
+    let guarantees = guarantees.map(|list| {
+        list.into_iter()
+            .map(|g| GuaranteeView {
+                req_id: fmt_u256_hex(&g.req_id),
+                from_address: g.from_address,
+                to_address: g.to_address,
+                asset_address: g.asset_address,
+                amount: fmt_u256(&g.amount),
+                timestamp: g.timestamp,
+                certificate: g.certificate,
+            })
+            .collect()
+    });
+    let collateral_events = collateral_events.map(|events| {
+        events
+            .into_iter()
+            .map(|ev| CollateralEventView {
+                id: ev.id,
+                event_type: ev.event_type,
+                user_address: ev.user_address,
+                asset_address: ev.asset_address,
+                amount: fmt_u256(&ev.amount),
+                tab_id: ev.tab_id.as_ref().map(fmt_u256_hex),
+                req_id: ev.req_id.as_ref().map(fmt_u256_hex),
+                tx_id: ev.tx_id,
+                created_at: ev.created_at,
+            })
+            .collect()
+    });
+
+    Some(TabSnapshot {
+        tab_id: fmt_u256_hex(&tab_id),
+        tab: SnapshotSection::from_result(tab),
+        payment_status: SnapshotSection::from_result(payment_status),
+        guarantees: SnapshotSection::from_result(guarantees),
+        collateral_events: SnapshotSection::from_result(collateral_events),
+    })
+}
+
+/// The part of a tab its user may see: its state and how much of it is used. Amounts are
+/// decimal strings in the asset's base units, ids are hex.
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TabStatus {
+    pub tab_id: String,
+    pub user_address: String,
+    pub asset_address: String,
+    pub status: String,
+    pub settlement_status: String,
+    pub ttl_seconds: i64,
+    /// Unix time the tab expires at, its start plus its TTL.
+    pub expires_at: i64,
+    /// Sum of every guarantee issued against the tab.
+    pub guaranteed: String,
+    pub paid: String,
+    pub remunerated: bool,
+}
+
+#[derive(Debug, Error)]
+pub enum TabStatusError {
+    #[error("4mica SDK client unavailable; is 4MICA_WALLET_PRIVATE_KEY set?")]
+    Unavailable,
+    #[error("Tab not found")]
+    NotFound,
+    #[error("4mica SDK request failed: {0}")]
+    Sdk(String),
+}
+
+/// Fetches the [`TabStatus`] of `tab_id` from the 4mica SDK.
+pub async fn fetch_tab_status(
+    tab_id: U256,
+    config: &X402Config,
+) -> Result<TabStatus, TabStatusError> {
+    let client = build_fourmica_client(config)
+        .await
+        .ok_or(TabStatusError::Unavailable)?;
+    let sdk_error = |e: &dyn std::fmt::Display| TabStatusError::Sdk(e.to_string());
+
+    let tab = client
+        .recipient
+        .get_tab(tab_id)
+        .await
+        .map_err(|e| sdk_error(&e))?
+        .ok_or(TabStatusError::NotFound)?;
+    let payment_status = client
+        .recipient
+        .get_tab_payment_status(tab_id)
+        .await
+        .map_err(|e| sdk_error(&e))?;
+    let guaranteed = client
+        .recipient
+        .get_tab_guarantees(tab_id)
+        .await
+        .map_err(|e| sdk_error(&e))?
+        .iter()
+        .fold(U256::ZERO, |total, g| total.saturating_add(g.amount));
+
+    Ok(TabStatus {
+        tab_id: fmt_u256_hex(&tab.tab_id),
+        user_address: tab.user_address,
+        asset_address: tab.asset_address,
+        status: tab.status,
+        settlement_status: tab.settlement_status,
+        ttl_seconds: tab.ttl_seconds,
+        expires_at: tab.start_timestamp.saturating_add(tab.ttl_seconds),
+        guaranteed: fmt_u256(&guaranteed),
+        paid: fmt_u256(&payment_status.paid),
+        remunerated: payment_status.remunerated,
+    })
+}
+
+/// The tab totals the guarantee check needs, so it can run against other sources than
+/// the 4mica SDK.
+pub(crate) trait TabAccounts {
+    /// Sum of every guarantee issued against the tab.
+    async fn total_guaranteed(&self, tab_id: U256) -> Result<U256, String>;
+    /// What the tab's user has paid back so far.
+    async fn total_paid(&self, tab_id: U256) -> Result<U256, String>;
+}
+
+impl TabAccounts for FourMicaClient {
+    async fn total_guaranteed(&self, tab_id: U256) -> Result<U256, String> {
+        let guarantees = self
+            .recipient
+            .get_tab_guarantees(tab_id)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(guarantees
+            .iter()
+            .fold(U256::ZERO, |total, g| total.saturating_add(g.amount)))
+    }
+
+    async fn total_paid(&self, tab_id: U256) -> Result<U256, String> {
+        self.recipient
+            .get_tab_payment_status(tab_id)
+            .await
+            .map(|status| status.paid)
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Rejects the payment unless the tab's guaranteed amount, less what is already paid,
+/// covers `price`. Lookup failures are accepted with `fail_open` and rejected without.
+pub(crate) async fn check_guarantee_headroom(
+    accounts: &impl TabAccounts,
+    tab_id: U256,
+    price: U256,
+    fail_open: bool,
+) -> Result<(), PaymentError> {
+    let totals = match accounts.total_guaranteed(tab_id).await {
+        Ok(guaranteed) => accounts
+            .total_paid(tab_id)
+            .await
+            .map(|paid| (guaranteed, paid)),
+        Err(e) => Err(e),
+    };
+    let (guaranteed, paid) = match totals {
+        Ok(totals) => totals,
+        Err(e) if fail_open => {
+            warn!(tab_id = %fmt_u256_hex(&tab_id), error = %e, "[4mica] Guarantee check skipped");
+            return Ok(());
         }
+        Err(e) => return Err(PaymentError::GuaranteeCheckFailed(e)),
+    };
+
+    let headroom = guaranteed.saturating_sub(paid);
+    debug!(
+        tab_id = %fmt_u256_hex(&tab_id),
+        guaranteed = %guaranteed,
+        paid = %paid,
+        headroom = %headroom,
+        price = %price,
+        "[4mica] Checked tab guarantee headroom"
+    );
+    if headroom < price {
+        return Err(PaymentError::InsufficientGuarantee { headroom, price });
     }
+    Ok(())
 }
 
-async fn log_tab_snapshot(tab_id: U256, config: &X402Config) {
+/// [`check_guarantee_headroom`] against the shared 4mica SDK client; `tab_id` and
+/// `price` are as they appear in the payment.
+pub(crate) async fn enforce_guarantee(
+    tab_id: Option<&str>,
+    price: &str,
+    config: &X402Config,
+) -> Result<(), PaymentError> {
+    let tab_id = tab_id
+        .ok_or_else(|| PaymentError::GuaranteeCheckFailed("payment has no tab id".into()))
+        .and_then(|raw| parse_u256_value(raw).map_err(PaymentError::GuaranteeCheckFailed))?;
+    let price = parse_u256_value(price).map_err(PaymentError::Other)?;
     let Some(client) = build_fourmica_client(config).await else {
-        return;
+        if config.guarantee_fail_open {
+            warn!("[4mica] Guarantee check skipped: SDK client unavailable");
+            return Ok(());
+        }
+        return Err(PaymentError::GuaranteeCheckFailed(
+            TabStatusError::Unavailable.to_string(),
+        ));
     };
+    check_guarantee_headroom(client, tab_id, price, config.guarantee_fail_open).await
+}
 
-    let tab_info = client.recipient.get_tab(tab_id).await;
-    let payment_status = client.recipient.get_tab_payment_status(tab_id).await;
-    let guarantees = client.recipient.get_tab_guarantees(tab_id).await;
-    let collateral_events = client.recipient.get_collateral_events_for_tab(tab_id).await;
+async fn log_tab_snapshot(tab_id: U256, config: &X402Config) {
+    let Some(snapshot) = fetch_tab_snapshot(tab_id, config).await else {
+        return;
+    };
+    let tab_id_hex = &snapshot.tab_id;
 
-    match tab_info {
-        Ok(Some(tab)) => {
+    match (&snapshot.tab.data, &snapshot.tab.error) {
+        (Some(Some(tab)), _) => {
             info!(
-                "[4mica] Tab info id={} user={} recipient={} asset={} status={} settlement_status={} ttl={} started_at={} created_at={} updated_at={}",
-                fmt_u256_hex(&tab.tab_id),
-                tab.user_address,
-                tab.recipient_address,
-                tab.asset_address,
-                tab.status,
-                tab.settlement_status,
-                tab.ttl_seconds,
-                tab.start_timestamp,
-                tab.created_at,
-                tab.updated_at
+                tab_id = %tab.tab_id,
+                user = %tab.user_address,
+                recipient = %tab.recipient_address,
+                asset = %tab.asset_address,
+                status = %tab.status,
+                settlement_status = %tab.settlement_status,
+                ttl = tab.ttl_seconds,
+                started_at = tab.start_timestamp,
+                created_at = tab.created_at,
+                updated_at = tab.updated_at,
+                "[4mica] Tab info"
             );
         }
-        Ok(None) => info!("[4mica] Tab {} not found via SDK", fmt_u256_hex(&tab_id)),
-        Err(err) => warn!(
-            "[4mica] Failed to fetch tab {}: {}",
-            fmt_u256_hex(&tab_id),
-            err
-        ),
+        (_, Some(err)) => warn!(tab_id = %tab_id_hex, error = %err, "[4mica] Failed to fetch tab"),
+        _ => info!(tab_id = %tab_id_hex, "[4mica] Tab not found via SDK"),
     }
 
-    match payment_status {
-        Ok(status) => {
+    match (
+        &snapshot.payment_status.data,
+        &snapshot.payment_status.error,
+    ) {
+        (Some(status), _) => {
             info!(
-                "[4mica] Tab payment status id={} paid={} remunerated={} asset={}",
-                fmt_u256_hex(&tab_id),
-                fmt_u256(&status.paid),
-                status.remunerated,
-                status.asset
+                tab_id = %tab_id_hex,
+                paid = %status.paid,
+                remunerated = status.remunerated,
+                asset = %status.asset,
+                "[4mica] Tab payment status"
             );
         }
-        Err(err) => warn!(
-            "[4mica] Failed to fetch payment status for {}: {}",
-            fmt_u256_hex(&tab_id),
-            err
+        (None, err) => warn!(
+            tab_id = %tab_id_hex,
+            error = err.as_deref().unwrap_or_default(),
+            "[4mica] Failed to fetch payment status"
         ),
     }
 
-    match guarantees {
-        Ok(list) => {
-            let total = list.iter().fold(U256::from(0), |acc, g| acc + g.amount);
+    match (&snapshot.guarantees.data, &snapshot.guarantees.error) {
+        (Some(list), _) => {
+            let total = list
+                .iter()
+                .filter_map(|g| U256::from_str(&g.amount).ok())
+                .fold(U256::from(0), |acc, amount| acc + amount);
             info!(
-                "[4mica] Guarantees for tab {}: count={} total_amount={}",
-                fmt_u256_hex(&tab_id),
-                list.len(),
-                fmt_u256(&total)
+                tab_id = %tab_id_hex,
+                count = list.len(),
+                total_amount = %fmt_u256(&total),
+                "[4mica] Tab guarantees"
             );
             for g in list {
-                info!(
-                    "[4mica]  - req_id={} amount={} from={} to={} asset={} timestamp={}",
-                    g.req_id,
-                    fmt_u256(&g.amount),
-                    g.from_address,
-                    g.to_address,
-                    g.asset_address,
-                    g.timestamp
+                debug!(
+                    tab_id = %tab_id_hex,
+                    req_id = %g.req_id,
+                    amount = %g.amount,
+                    from = %g.from_address,
+                    to = %g.to_address,
+                    asset = %g.asset_address,
+                    timestamp = g.timestamp,
+                    "[4mica] Guarantee"
                 );
             }
         }
-        Err(err) => warn!(
-            "[4mica] Failed to fetch guarantees for {}: {}",
-            fmt_u256_hex(&tab_id),
-            err
+        (None, err) => warn!(
+            tab_id = %tab_id_hex,
+            error = err.as_deref().unwrap_or_default(),
+            "[4mica] Failed to fetch guarantees"
         ),
     }
 
-    match collateral_events {
-        Ok(events) => {
+    match (
+        &snapshot.collateral_events.data,
+        &snapshot.collateral_events.error,
+    ) {
+        (Some(events), _) => {
             info!(
-                "[4mica] Collateral events for tab {}: count={}",
-                fmt_u256_hex(&tab_id),
-                events.len()
+                tab_id = %tab_id_hex,
+                count = events.len(),
+                "[4mica] Tab collateral events"
             );
             for ev in events {
-                info!(
-                    "[4mica]  - event_id={} type={} amount={} asset={} req_id={:?} tab_id={:?} tx_id={:?} created_at={}",
-                    ev.id,
-                    ev.event_type,
-                    fmt_u256(&ev.amount),
-                    ev.asset_address,
-                    ev.req_id,
-                    ev.tab_id,
-                    ev.tx_id,
-                    ev.created_at
+                debug!(
+                    tab_id = %tab_id_hex,
+                    event_id = %ev.id,
+                    event_type = %ev.event_type,
+                    amount = %ev.amount,
+                    asset = %ev.asset_address,
+                    req_id = ?ev.req_id,
+                    event_tab_id = ?ev.tab_id,
+                    tx_id = ?ev.tx_id,
+                    created_at = %ev.created_at,
+                    "[4mica] Collateral event"
                 );
             }
         }
-        Err(err) => warn!(
-            "[4mica] Failed to fetch collateral events for {}: {}",
-            fmt_u256_hex(&tab_id),
-            err
+        (None, err) => warn!(
+            tab_id = %tab_id_hex,
+            error = err.as_deref().unwrap_or_default(),
+            "[4mica] Failed to fetch collateral events"
         ),
     }
 }
@@ -199,23 +536,128 @@ pub async fn log_fourmica_payment_info(envelope: &Value, config: &X402Config) {
         .and_then(|raw| parse_u256_value(raw).ok().map(|v| fmt_u256_hex(&v)));
 
     info!(
-        "[4mica] Payment claims from header: tab_id={:?} user={:?} recipient={:?} amount={:?} asset={:?}",
-        tab_id_hex.as_ref().or(tab_id_raw.as_ref()),
-        user_addr,
-        recipient_addr,
-        amount_raw,
-        asset_addr
+        tab_id = ?tab_id_hex.as_ref().or(tab_id_raw.as_ref()),
+        user = ?user_addr,
+        recipient = ?recipient_addr,
+        amount = ?amount_raw,
+        asset = ?asset_addr,
+        "[4mica] Payment claims from header"
     );
 
+    if config.tab_snapshot == TabSnapshotMode::Off {
+        return;
+    }
     if let Some(tab_id_raw) = tab_id_raw {
         match parse_u256_value(&tab_id_raw) {
-            Ok(tab_id) => log_tab_snapshot(tab_id, config).await,
+            Ok(tab_id) if config.tab_snapshot == TabSnapshotMode::Sync => {
+                log_tab_snapshot(tab_id, config).await
+            }
+            Ok(tab_id) => {
+                // Detached: a panic stays in the task, and shutdown doesn't wait for it.
+                let config = config.clone();
+                tokio::spawn(
+                    async move {
+                        let snapshot = log_tab_snapshot(tab_id, &config);
+                        if tokio::time::timeout(TAB_SNAPSHOT_TIMEOUT, snapshot)
+                            .await
+                            .is_err()
+                        {
+                            warn!("[4mica] Tab snapshot timed out");
+                        }
+                    }
+                    .in_current_span(),
+                );
+            }
             Err(err) => warn!(
-                "[4mica] Unable to parse tab id from payment header {}: {}",
-                tab_id_raw, err
+                tab_id = %tab_id_raw,
+                error = %err,
+                "[4mica] Unable to parse tab id from payment header"
             ),
         }
     } else {
         warn!("[4mica] Payment header missing tab id; skipping SDK tab logging");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Accounts {
+        guaranteed: Result<U256, String>,
+        paid: Result<U256, String>,
+    }
+
+    impl Accounts {
+        fn new(guaranteed: u64, paid: u64) -> Self {
+            Self {
+                guaranteed: Ok(U256::from(guaranteed)),
+                paid: Ok(U256::from(paid)),
+            }
+        }
+    }
+
+    impl TabAccounts for Accounts {
+        async fn total_guaranteed(&self, _tab_id: U256) -> Result<U256, String> {
+            self.guaranteed.clone()
+        }
+
+        async fn total_paid(&self, _tab_id: U256) -> Result<U256, String> {
+            self.paid.clone()
+        }
+    }
+
+    async fn check(accounts: &Accounts, price: u64, fail_open: bool) -> Result<(), PaymentError> {
+        check_guarantee_headroom(accounts, U256::from(7), U256::from(price), fail_open).await
+    }
+
+    #[tokio::test]
+    async fn headroom_covering_the_price_passes() {
+        let accounts = Accounts::new(1_000, 400);
+        assert!(check(&accounts, 600, false).await.is_ok());
+        assert!(check(&accounts, 100, false).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn too_little_headroom_is_rejected() {
+        let err = check(&Accounts::new(1_000, 400), 601, false)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            PaymentError::InsufficientGuarantee { headroom, price }
+                if headroom == U256::from(600) && price == U256::from(601)
+        ));
+    }
+
+    #[tokio::test]
+    async fn overpaid_tab_has_no_headroom() {
+        let err = check(&Accounts::new(100, 500), 1, false).await.unwrap_err();
+        assert!(matches!(
+            err,
+            PaymentError::InsufficientGuarantee { headroom, .. } if headroom == U256::ZERO
+        ));
+    }
+
+    #[tokio::test]
+    async fn lookup_errors_follow_fail_open() {
+        let failing = [
+            Accounts {
+                guaranteed: Err("core unreachable".into()),
+                paid: Ok(U256::ZERO),
+            },
+            Accounts {
+                guaranteed: Ok(U256::from(1_000)),
+                paid: Err("core unreachable".into()),
+            },
+        ];
+        for accounts in &failing {
+            assert!(check(accounts, 100, true).await.is_ok());
+            let err = check(accounts, 100, false).await.unwrap_err();
+            assert!(
+                matches!(&err, PaymentError::GuaranteeCheckFailed(e) if e == "core unreachable"),
+                "{err:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use parking_lot::Mutex;
+use sdk_4mica::U256;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+};
+use tracing::warn;
+
+use crate::x402::{RevenueSplit, SettlementSummary, SplitAmount, parse_u256_value};
+
+/// One settled purchase, as appended to the ledger file (one JSON object per line).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpendItem {
+    pub payer: String,
+    pub asset: String,
+    /// Decimal amount in the asset's smallest unit.
+    pub amount: String,
+    pub resource: String,
+    /// Unix timestamp in seconds.
+    pub at: i64,
+    /// The amount divided by `X402_PAY_TO_SPLIT` when the purchase was recorded.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub split: Vec<SplitAmount>,
+}
+
+/// What one address has spent, per asset, plus its most recent purchases (newest first).
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpendSummary {
+    pub address: String,
+    /// Decimal totals keyed by asset address.
+    pub totals: BTreeMap<String, String>,
+    pub purchases: u64,
+    pub first_purchase_at: i64,
+    pub last_purchase_at: i64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub recent: Vec<SpendItem>,
+}
+
+/// What one split recipient is owed, per asset, across every recorded purchase.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecipientSummary {
+    pub recipient: String,
+    /// Decimal totals keyed by asset address.
+    pub totals: BTreeMap<String, String>,
+    pub purchases: u64,
+}
+
+#[derive(Default)]
+struct RecipientAccount {
+    totals: BTreeMap<String, U256>,
+    purchases: u64,
+}
+
+struct Account {
+    totals: BTreeMap<String, U256>,
+    purchases: u64,
+    first_purchase_at: i64,
+    last_purchase_at: i64,
+    recent: VecDeque<SpendItem>,
+}
+
+impl Account {
+    fn summary(&self, address: &str, with_recent: bool) -> SpendSummary {
+        SpendSummary {
+            address: address.to_string(),
+            totals: self
+                .totals
+                .iter()
+                .map(|(asset, total)| (asset.clone(), total.to_string()))
+                .collect(),
+            purchases: self.purchases,
+            first_purchase_at: self.first_purchase_at,
+            last_purchase_at: self.last_purchase_at,
+            recent: if with_recent {
+                self.recent.iter().rev().cloned().collect()
+            } else {
+                Vec::new()
+            },
+        }
+    }
+}
+
+struct Inner {
+    accounts: HashMap<String, Account>,
+    recipients: BTreeMap<String, RecipientAccount>,
+    file: Option<File>,
+}
+
+/// Totals per payer address of every settlement this server made, optionally
+/// persisted as an append-only JSON lines file that is replayed on startup.
+pub struct SpendLedger {
+    recent_limit: usize,
+    split: Option<RevenueSplit>,
+    inner: Mutex<Inner>,
+}
+
+impl SpendLedger {
+    /// An in-memory ledger keeping the last `recent_limit` purchases per address.
+    pub fn new(recent_limit: usize) -> Self {
+        Self {
+            recent_limit,
+            split: None,
+            inner: Mutex::new(Inner {
+                accounts: HashMap::new(),
+                recipients: BTreeMap::new(),
+                file: None,
+            }),
+        }
+    }
+
+    /// Records how `split` divides each purchase recorded from now on.
+    pub fn with_split(mut self, split: Option<RevenueSplit>) -> Self {
+        self.split = split;
+        self
+    }
+
+    /// Like [`SpendLedger::new`], but replays `path` and appends new purchases to it.
+    /// Unparseable lines are skipped with a warning.
+    pub fn open(path: impl AsRef<Path>, recent_limit: usize) -> io::Result<Self> {
+        let path = path.as_ref();
+        let ledger = Self::new(recent_limit);
+        {
+            let mut inner = ledger.inner.lock();
+            if path.exists() {
+                for (index, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match serde_json::from_str::<SpendItem>(&line) {
+                        Ok(item) => ledger.apply(&mut inner, item),
+                        Err(e) => warn!("Skipping spend ledger line {}: {}", index + 1, e),
+                    }
+                }
+            }
+            inner.file = Some(OpenOptions::new().create(true).append(true).open(path)?);
+        }
+        Ok(ledger)
+    }
+
+    /// Records a settlement of `resource` at unix time `at`. Settlements without a
+    /// payer address or with an unparseable amount are not recorded.
+    pub fn record(&self, settlement: &SettlementSummary, resource: &str, at: i64) {
+        let Some(payer) = settlement.payer.as_deref() else {
+            warn!("Settlement has no payer address; not recorded in the spend ledger");
+            return;
+        };
+        let amount = match parse_u256_value(&settlement.amount) {
+            Ok(amount) => amount,
+            Err(e) => {
+                warn!("Settlement amount not recorded in the spend ledger: {}", e);
+                return;
+            }
+        };
+        let item = SpendItem {
+            payer: payer.to_lowercase(),
+            asset: settlement.asset.to_lowercase(),
+            amount: amount.to_string(),
+            resource: resource.to_string(),
+            at,
+            split: self
+                .split
+                .iter()
+                .flat_map(|split| split.divide(amount))
+                .map(|(recipient, amount)| SplitAmount {
+                    recipient,
+                    amount: amount.to_string(),
+                })
+                .collect(),
+        };
+
+        let mut inner = self.inner.lock();
+        if let Some(file) = &mut inner.file {
+            let appended = serde_json::to_string(&item)
+                .map_err(io::Error::from)
+                .and_then(|line| writeln!(file, "{line}"));
+            if let Err(e) = appended {
+                warn!("Failed to persist spend ledger entry: {}", e);
+            }
+        }
+        self.apply(&mut inner, item);
+    }
+
+    /// The aggregate and recent purchases for `address`, if it ever paid.
+    pub fn summary(&self, address: &str) -> Option<SpendSummary> {
+        let address = address.to_lowercase();
+        let inner = self.inner.lock();
+        inner
+            .accounts
+            .get(&address)
+            .map(|account| account.summary(&address, true))
+    }
+
+    /// Up to `limit` addresses with the largest totals of `asset`, or of all assets
+    /// combined when `asset` is `None`.
+    pub fn top_spenders(&self, asset: Option<&str>, limit: usize) -> Vec<SpendSummary> {
+        let asset = asset.map(str::to_lowercase);
+        let inner = self.inner.lock();
+        let mut ranked: Vec<(U256, &String, &Account)> = inner
+            .accounts
+            .iter()
+            .filter_map(|(address, account)| {
+                let total = match &asset {
+                    Some(asset) => *account.totals.get(asset)?,
+                    None => account
+                        .totals
+                        .values()
+                        .fold(U256::from(0), |sum, total| sum.saturating_add(*total)),
+                };
+                Some((total, address, account))
+            })
+            .collect();
+        ranked.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(b.1)));
+        ranked
+            .into_iter()
+            .take(limit)
+            .map(|(_, address, account)| account.summary(address, false))
+            .collect()
+    }
+
+    /// What every split recipient is owed, ordered by address.
+    pub fn recipients(&self) -> Vec<RecipientSummary> {
+        let inner = self.inner.lock();
+        inner
+            .recipients
+            .iter()
+            .map(|(recipient, account)| RecipientSummary {
+                recipient: recipient.clone(),
+                totals: account
+                    .totals
+                    .iter()
+                    .map(|(asset, total)| (asset.clone(), total.to_string()))
+                    .collect(),
+                purchases: account.purchases,
+            })
+            .collect()
+    }
+
+    fn apply(&self, inner: &mut Inner, item: SpendItem) {
+        let Ok(amount) = parse_u256_value(&item.amount) else {
+            return;
+        };
+        for share in &item.split {
+            let Ok(amount) = parse_u256_value(&share.amount) else {
+                continue;
+            };
+            let recipient = inner.recipients.entry(share.recipient.clone()).or_default();
+            let total = recipient
+                .totals
+                .entry(item.asset.clone())
+                .or_insert(U256::from(0));
+            *total = total.saturating_add(amount);
+            recipient.purchases += 1;
+        }
+        let account = inner
+            .accounts
+            .entry(item.payer.clone())
+            .or_insert_with(|| Account {
+                totals: BTreeMap::new(),
+                purchases: 0,
+                first_purchase_at: item.at,
+                last_purchase_at: item.at,
+                recent: VecDeque::new(),
+            });
+        let total = account
+            .totals
+            .entry(item.asset.clone())
+            .or_insert(U256::from(0));
+        *total = total.saturating_add(amount);
+        account.purchases += 1;
+        account.first_purchase_at = account.first_purchase_at.min(item.at);
+        account.last_purchase_at = account.last_purchase_at.max(item.at);
+        if self.recent_limit > 0 {
+            if account.recent.len() == self.recent_limit {
+                account.recent.pop_front();
+            }
+            account.recent.push_back(item);
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use sdk_4mica::{U256, x402::PaymentRequirements};
+use serde_json::Value;
+use tracing::info;
+
+use crate::{
+    error::PaymentError,
+    x402::{
+        DeferredPayment, DeferredRequirements, DeferredSettler, Facilitator, SettlementSummary,
+        X402_VERSION, X402Config,
+        model::{FacilitatorSettleParams, FacilitatorVerifyParams},
+        parse_u256_value,
+    },
+};
+
+/// A verified payment for a metered (`X402_SCHEME_UPTO`) offer, settled once delivery ends
+/// for what was actually sent, never more than the advertised cap.
+#[derive(Clone, Debug)]
+pub struct MeteredPayment {
+    payment_header: String,
+    payment_payload: Value,
+    /// The matched requirement; its `max_amount_required` is the cap.
+    requirements: PaymentRequirements,
+    cap: U256,
+    price_per_byte: U256,
+    /// The verified payment, whose amount is the cap until it settles.
+    summary: SettlementSummary,
+}
+
+impl MeteredPayment {
+    /// The payment as verified, before the final amount is known.
+    pub fn summary(&self) -> &SettlementSummary {
+        &self.summary
+    }
+
+    /// The most bytes the cap pays for.
+    pub fn max_bytes(&self) -> u64 {
+        if self.price_per_byte.is_zero() {
+            return u64::MAX;
+        }
+        u64::try_from(self.cap / self.price_per_byte).unwrap_or(u64::MAX)
+    }
+
+    /// What delivering `bytes` costs, at most the cap.
+    pub fn amount_for(&self, bytes: u64) -> U256 {
+        U256::from(bytes)
+            .saturating_mul(self.price_per_byte)
+            .min(self.cap)
+    }
+
+    /// Settles the payment for `bytes` of `resource` delivered: through the facilitator
+    /// with the final amount as the requirement, or by handing it to `deferred` for its
+    /// tab. Nothing is settled for zero bytes.
+    pub async fn settle(
+        self,
+        resource: &str,
+        bytes: u64,
+        facilitator: &dyn Facilitator,
+        deferred: Option<&DeferredSettler>,
+    ) -> Result<SettlementSummary, PaymentError> {
+        let amount = self.amount_for(bytes);
+        let mut summary = self.summary;
+        summary.amount = amount.to_string();
+        if amount.is_zero() {
+            info!(bytes, "Metered payment delivered nothing; not settling");
+            return Ok(summary);
+        }
+        let requirements = PaymentRequirements {
+            max_amount_required: amount.to_string(),
+            ..self.requirements
+        };
+
+        if let Some((settler, tab_id)) = deferred.zip(summary.tab_id.clone()) {
+            info!(bytes, %amount, tab_id, "Deferring metered payment settlement");
+            let payment = DeferredPayment {
+                payment_header: self.payment_header,
+                payment_payload: self.payment_payload,
+                requirements: DeferredRequirements::V1(requirements),
+                amount,
+            };
+            settler.enqueue(&tab_id, resource, payment, &summary).await;
+            return Ok(summary);
+        }
+
+        info!(bytes, %amount, cap = %self.cap, "Calling facilitator /settle for metered payment");
+        let settle_response = facilitator
+            .settle(&FacilitatorSettleParams {
+                x402_version: X402_VERSION,
+                payment_header: &self.payment_header,
+                payment_payload: Some(self.payment_payload),
+                payment_requirements: &requirements,
+            })
+            .await?;
+        if !settle_response.success {
+            return Err(PaymentError::SettlementFailed(
+                settle_response.error.unwrap_or_default(),
+            ));
+        }
+        summary.tx_hash = settle_response.tx_hash;
+        summary.certificate = settle_response.certificate;
+        Ok(summary)
+    }
+}
+
+/// Verifies a v1 payment for the metered offer among `accepted`, whose 4mica claims must
+/// authorize the whole cap, without settling it.
+pub async fn verify_metered_payment(
+    payment_header: &str,
+    resource: &str,
+    accepted: &[PaymentRequirements],
+    price_per_byte: U256,
+    facilitator: &dyn Facilitator,
+    config: &X402Config,
+) -> Result<MeteredPayment, PaymentError> {
+    let mut envelope = super::decode_payment_header(payment_header)?;
+    let payment_header = super::normalize_payment_header(payment_header, &mut envelope)?;
+    let version = super::extract_x402_version(&envelope);
+    if version != X402_VERSION {
+        return Err(PaymentError::UnsupportedVersion(version));
+    }
+    super::check_paid_resource(&envelope, resource)?;
+    let (scheme, network) = super::extract_scheme_network(&envelope, version)?;
+    if !config.enable_4mica || !scheme.eq_ignore_ascii_case(&config.scheme_upto) {
+        return Err(PaymentError::UnsupportedScheme(scheme));
+    }
+    let aliases = config.network_aliases();
+    let requirements =
+        super::find_matching_payment_requirements(&scheme, &network, None, accepted, &aliases)?;
+    super::validate_claims(
+        &envelope,
+        &requirements.pay_to,
+        &requirements.asset,
+        &requirements.max_amount_required,
+        config.lenient_claims,
+    )?;
+    let tab_id = super::extract_claim_value(&envelope, "tab_id")
+        .or_else(|| super::extract_claim_value(&envelope, "tabId"));
+    if config.require_guarantee {
+        super::fourmica::enforce_guarantee(
+            tab_id.as_deref(),
+            &requirements.max_amount_required,
+            config,
+        )
+        .await?;
+    }
+
+    info!(
+        %scheme,
+        %network,
+        cap = %requirements.max_amount_required,
+        "Calling facilitator /verify (metered)"
+    );
+    let payment_payload = serde_json::to_value(&envelope)?;
+    let verify_response = facilitator
+        .verify(&FacilitatorVerifyParams {
+            x402_version: X402_VERSION,
+            payment_header: &payment_header,
+            payment_payload: Some(payment_payload.clone()),
+            payment_requirements: requirements,
+        })
+        .await?;
+    if !verify_response.is_valid {
+        return Err(PaymentError::VerificationFailed(
+            verify_response.invalid_reason.unwrap_or_default(),
+        ));
+    }
+
+    let cap = parse_u256_value(&requirements.max_amount_required).map_err(PaymentError::Other)?;
+    let summary = SettlementSummary {
+        scheme,
+        network,
+        pay_to: requirements.pay_to.clone(),
+        asset: requirements.asset.clone(),
+        amount: cap.to_string(),
+        payer: super::extract_claim_value(&envelope, "userAddress")
+            .or_else(|| super::extract_claim_value(&envelope, "user_address")),
+        tab_id,
+        tx_hash: None,
+        certificate: verify_response.certificate,
+        certificate_verified: None,
+    };
+    Ok(MeteredPayment {
+        payment_header,
+        payment_payload,
+        requirements: requirements.clone(),
+        cap,
+        price_per_byte,
+        summary,
+    })
+}
@@ -0,0 +1,155 @@
+//! An in-memory [`Facilitator`] for unit tests.
+use http::HeaderValue;
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::x402::{
+    Facilitator, FacilitatorFuture, FacilitatorSupportedResponse, FacilitatorTabResponse,
+    model::{
+        FacilitatorSettleParams, FacilitatorSettleParamsV2, FacilitatorSettleResponse,
+        FacilitatorTabRequestParams, FacilitatorVerifyParams, FacilitatorVerifyParamsV2,
+        FacilitatorVerifyResponse,
+    },
+};
+
+/// The `txHash` every successful settlement answers with.
+pub(crate) const MOCK_TX_HASH: &str = "0xsettled";
+
+/// Answers every call from memory, counting how often each was made.
+///
+/// Clones, including the handles [`Facilitator::with_request_id`] returns, share the counts.
+#[derive(Clone, Default)]
+pub(crate) struct MockFacilitator {
+    /// Why `/verify` rejects a payment; accepted when unset.
+    pub invalid_reason: Option<String>,
+    /// Why `/settle` fails; settled when unset.
+    pub settle_error: Option<String>,
+    /// How long each `/verify` takes.
+    pub verify_delay: Duration,
+    pub verifies: Arc<AtomicUsize>,
+    pub settles: Arc<AtomicUsize>,
+    pub tabs: Arc<AtomicUsize>,
+}
+
+impl MockFacilitator {
+    pub fn verifies(&self) -> usize {
+        self.verifies.load(Ordering::SeqCst)
+    }
+
+    pub fn settles(&self) -> usize {
+        self.settles.load(Ordering::SeqCst)
+    }
+
+    fn answer_verify(&self) -> FacilitatorFuture<'_, FacilitatorVerifyResponse> {
+        self.verifies.fetch_add(1, Ordering::SeqCst);
+        Box::pin(async move {
+            tokio::time::sleep(self.verify_delay).await;
+            Ok(FacilitatorVerifyResponse {
+                is_valid: self.invalid_reason.is_none(),
+                invalid_reason: self.invalid_reason.clone(),
+                certificate: None,
+            })
+        })
+    }
+
+    fn answer_settle(&self) -> FacilitatorFuture<'_, FacilitatorSettleResponse> {
+        self.settles.fetch_add(1, Ordering::SeqCst);
+        let response = FacilitatorSettleResponse {
+            success: self.settle_error.is_none(),
+            error: self.settle_error.clone(),
+            tx_hash: self
+                .settle_error
+                .is_none()
+                .then(|| MOCK_TX_HASH.to_string()),
+            network_id: None,
+            certificate: None,
+        };
+        Box::pin(async move { Ok(response) })
+    }
+}
+
+impl Facilitator for MockFacilitator {
+    fn verify<'a>(
+        &'a self,
+        _request: &'a FacilitatorVerifyParams<'a>,
+    ) -> FacilitatorFuture<'a, FacilitatorVerifyResponse> {
+        self.answer_verify()
+    }
+
+    fn verify_v2<'a>(
+        &'a self,
+        _request: &'a FacilitatorVerifyParamsV2<'a>,
+    ) -> FacilitatorFuture<'a, FacilitatorVerifyResponse> {
+        self.answer_verify()
+    }
+
+    fn settle<'a>(
+        &'a self,
+        _request: &'a FacilitatorSettleParams<'a>,
+    ) -> FacilitatorFuture<'a, FacilitatorSettleResponse> {
+        self.answer_settle()
+    }
+
+    fn settle_v2<'a>(
+        &'a self,
+        _request: &'a FacilitatorSettleParamsV2<'a>,
+    ) -> FacilitatorFuture<'a, FacilitatorSettleResponse> {
+        self.answer_settle()
+    }
+
+    fn request_tab<'a>(
+        &'a self,
+        request: &'a FacilitatorTabRequestParams,
+    ) -> FacilitatorFuture<'a, FacilitatorTabResponse> {
+        let tab = self.tabs.fetch_add(1, Ordering::SeqCst) + 1;
+        Box::pin(async move {
+            Ok(FacilitatorTabResponse {
+                tab_id: tab.to_string(),
+                user_address: request.user_address.clone(),
+                recipient_address: request.recipient_address.clone(),
+                asset_address: request.erc20_token.clone(),
+                next_req_id: Some("0".to_string()),
+                start_timestamp: chrono::Utc::now().timestamp(),
+                ttl_seconds: request.ttl_seconds.unwrap_or(86400) as i64,
+            })
+        })
+    }
+
+    fn supported(&self) -> FacilitatorFuture<'_, FacilitatorSupportedResponse> {
+        Box::pin(async { Ok(FacilitatorSupportedResponse::default()) })
+    }
+
+    fn with_request_id(&self, _request_id: HeaderValue) -> Arc<dyn Facilitator> {
+        Arc::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tab_request() -> FacilitatorTabRequestParams {
+        FacilitatorTabRequestParams {
+            user_address: "0x1".into(),
+            recipient_address: "0x2".into(),
+            erc20_token: "0x3".into(),
+            ttl_seconds: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn request_id_handles_share_the_counts() {
+        let facilitator = MockFacilitator::default();
+        let handle = facilitator.with_request_id(HeaderValue::from_static("req-1"));
+
+        let tab = handle.request_tab(&tab_request()).await.unwrap();
+        assert_eq!(tab.tab_id, "1");
+        let tab = facilitator.request_tab(&tab_request()).await.unwrap();
+        assert_eq!(tab.tab_id, "2");
+    }
+}
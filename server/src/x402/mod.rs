@@ -1,94 +1,228 @@
-use base64::{Engine, prelude::BASE64_STANDARD};
-use log::{debug, info};
+use base64::{
+    Engine, alphabet,
+    engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+    prelude::BASE64_STANDARD,
+};
+use percent_encoding::percent_decode_str;
 use sdk_4mica::{U256, x402::PaymentRequirements};
 use serde_json::{Value, json};
+use tracing::{debug, info};
+use url::Url;
 
+mod async_settle;
+mod certificate;
 mod config;
+mod deferred;
+mod diagnose;
 mod facilitator;
 mod fourmica;
+mod ledger;
+mod metered;
+#[cfg(test)]
+mod mock;
 mod model;
-mod native;
+mod onchain;
+mod pending;
+mod sandbox;
+mod settlement_cache;
+mod split;
+mod tab_audit;
 
-pub use config::X402Config;
-pub use facilitator::{FacilitatorClient, FacilitatorClientError};
-pub use model::{PaymentRequirementsV2, PaymentRequiredV2, X402ResourceInfo};
+pub use async_settle::{
+    AsyncSettler, FailedSettlement, FailureHook, UnsettledPaymentView, UnsettledSnapshot,
+};
+pub use certificate::{CertificateError, OperatorKey, verify_certificate};
+pub use config::{
+    FacilitatorUrls, Network, NetworkAliasList, NetworkAliases, NetworkEntry, NetworkList,
+    SettlementMode, TabSnapshotMode, X402Config, X402Mode,
+};
+pub use deferred::{
+    DeferredPayment, DeferredRequirements, DeferredSettler, DeferredSnapshot, DeferredTabView,
+};
+pub use diagnose::{
+    DiagnosisCheck, EnvelopeSummary, FacilitatorVerdict, MatchedRequirement, PaymentDiagnosis,
+    diagnose_payment,
+};
+pub use facilitator::{
+    Facilitator, FacilitatorClient, FacilitatorClientError, FacilitatorErrorBody,
+    FacilitatorFuture, FailoverStatus, RetryPolicy, SIGNATURE_HEADER, SIGNATURE_TIMESTAMP_HEADER,
+    TabCacheEntry, request_signature,
+};
+pub use fourmica::{
+    CollateralEventView, GuaranteeView, PaymentStatusView, SnapshotSection, TabSnapshot, TabStatus,
+    TabStatusError, TabView, check_fourmica_client, fetch_tab_snapshot, fetch_tab_status,
+};
+pub use ledger::{RecipientSummary, SpendItem, SpendLedger, SpendSummary};
+pub use metered::{MeteredPayment, verify_metered_payment};
+pub use model::{
+    FacilitatorSupportedResponse, FourMicaCertificate, PaymentRequiredV2, PaymentRequirementsV2,
+    ResourceMeta, SettlementSummary, SupportedKind, X402ResourceInfo,
+};
+pub use onchain::{asset_decimals, chain_id, parse_u256_value, parse_units, rpc_client};
+pub use pending::{PendingSettlementView, PendingSettlements, RecoverySnapshot, SettlementIntent};
+pub use sandbox::{SANDBOX_ACK, SANDBOX_SCHEME, sandbox_payment_header};
+pub use settlement_cache::SettlementCache;
+pub use split::{BASIS_POINTS, RevenueSplit, SplitAmount, SplitShare};
+pub use tab_audit::{TabAudit, TabAuditSummary};
+pub use x402_common::{FacilitatorTabResponse, PaymentEnvelope, X402_VERSION};
 
 use crate::{
     error::PaymentError,
-    x402::model::{
-        FacilitatorSettleParams, FacilitatorSettleParamsV2, FacilitatorTabRequestParams,
-        FacilitatorTabResponse,
-    },
+    x402::{model::FacilitatorTabRequestParams, onchain::normalize_address},
 };
 
-pub const X402_VERSION: u64 = 1;
+/// Envelope versions accepted by [`settle_payment`]; v2 matches CAIP-2 network identifiers.
+pub const SUPPORTED_X402_VERSIONS: [u64; 2] = [1, 2];
+
+/// Deepest nesting of objects and arrays a decoded payment envelope may have.
+pub const MAX_PAYMENT_JSON_DEPTH: usize = 16;
+
+/// Standard base64 with or without padding.
+const BASE64_STANDARD_LENIENT: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// URL-safe base64 with or without padding, as some client libraries and players emit it.
+pub const BASE64_URL_SAFE_LENIENT: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
 
 pub async fn request_tab(
     user_address: String,
     payment_requirements: PaymentRequirements,
-    facilitator: &FacilitatorClient,
+    facilitator: &dyn Facilitator,
 ) -> Result<FacilitatorTabResponse, PaymentError> {
-    log::info!(
-        "Requesting tab via facilitator for user={} pay_to={} asset={}",
-        user_address,
-        payment_requirements.pay_to,
-        payment_requirements.asset
+    info!(
+        user = %user_address,
+        pay_to = %payment_requirements.pay_to,
+        asset = %payment_requirements.asset,
+        "Requesting tab via facilitator"
     );
-    let tab_request = FacilitatorTabRequestParams {
-        user_address,
-        recipient_address: payment_requirements.pay_to,
-        erc20_token: payment_requirements.asset,
-        ttl_seconds: Some(86400), // 1 day TTL
-    };
-
     facilitator
-        .request_tab(&tab_request)
+        .request_tab(&tab_request_params(user_address, &payment_requirements))
         .await
         .map_err(PaymentError::from)
 }
 
+/// The tab [`request_tab`] would answer from the facilitator client's cache, without
+/// calling the facilitator.
+pub fn cached_tab(
+    user_address: &str,
+    payment_requirements: &PaymentRequirements,
+    facilitator: &dyn Facilitator,
+) -> Option<FacilitatorTabResponse> {
+    facilitator.cached_tab(&tab_request_params(
+        user_address.to_string(),
+        payment_requirements,
+    ))
+}
+
+fn tab_request_params(
+    user_address: String,
+    payment_requirements: &PaymentRequirements,
+) -> FacilitatorTabRequestParams {
+    FacilitatorTabRequestParams {
+        user_address,
+        recipient_address: payment_requirements.pay_to.clone(),
+        erc20_token: payment_requirements.asset.clone(),
+        ttl_seconds: Some(86400), // 1 day TTL
+    }
+}
+
+/// v1 requirements of the enabled schemes for every network; `meta` describes the resource
+/// in each entry, and without a description one naming `resource` is used. Amounts are
+/// decimal strings, as the x402 spec has them; payments are still parsed in either base.
+///
+/// With a `metered_rate` and 4mica enabled, each network also offers the `X402_SCHEME_UPTO`
+/// scheme, capped at `max_amount_required` and charged that rate per byte delivered.
 pub fn build_accepted_payment_requirements(
     config: &X402Config,
     max_amount_required: U256,
     tab_endpoint: String,
     resource: Option<String>,
+    meta: &ResourceMeta,
+    metered_rate: Option<U256>,
 ) -> Vec<PaymentRequirements> {
-    let max_amount_required = format!("{:#x}", max_amount_required);
-    let description = resource
-        .as_ref()
-        .map(|r| format!("Access to resource: {}", r));
-    let exact = PaymentRequirements {
-        scheme: "exact".to_string(),
-        network: config.network.clone(),
-        max_amount_required: max_amount_required.clone(),
-        resource: resource.clone(),
-        description: description.clone(),
-        mime_type: Some("video/mp2t".to_string()),
-        output_schema: None,
-        pay_to: config.pay_to.clone(),
-        max_timeout_seconds: Some(3600),
-        asset: config.asset.clone(),
-        extra: None,
-    };
+    let max_amount_required = max_amount_required.to_string();
+    let description = meta.description.clone().or_else(|| {
+        resource
+            .as_ref()
+            .map(|r| format!("Access to resource: {}", r))
+    });
 
-    let mut requirements = vec![PaymentRequirements {
-        scheme: config.scheme_4mica.clone(),
-        network: config.network.clone(),
-        max_amount_required,
-        resource,
-        description,
-        mime_type: Some("video/mp2t".to_string()),
-        output_schema: None,
-        pay_to: config.pay_to.clone(),
-        max_timeout_seconds: Some(3600),
-        asset: config.asset.clone(),
-        extra: Some(json!({
-            "tabEndpoint": tab_endpoint,
-        })),
-    }];
+    let mut requirements = Vec::new();
+    for network in config.networks() {
+        if config.enable_4mica {
+            requirements.push(PaymentRequirements {
+                scheme: config.scheme_4mica.clone(),
+                network: network.name.clone(),
+                max_amount_required: max_amount_required.clone(),
+                resource: resource.clone(),
+                description: description.clone(),
+                mime_type: meta.mime_type.clone(),
+                output_schema: None,
+                pay_to: network.pay_to.clone(),
+                max_timeout_seconds: Some(config.max_timeout_seconds),
+                asset: network.asset.clone(),
+                extra: Some(json!({
+                    "tabEndpoint": tab_endpoint,
+                })),
+            });
+
+            if let Some(rate) = metered_rate {
+                requirements.push(PaymentRequirements {
+                    scheme: config.scheme_upto.clone(),
+                    network: network.name.clone(),
+                    max_amount_required: max_amount_required.clone(),
+                    resource: resource.clone(),
+                    description: description.clone(),
+                    mime_type: meta.mime_type.clone(),
+                    output_schema: None,
+                    pay_to: network.pay_to.clone(),
+                    max_timeout_seconds: Some(config.max_timeout_seconds),
+                    asset: network.asset.clone(),
+                    extra: Some(json!({
+                        "tabEndpoint": tab_endpoint,
+                        "pricePerByte": rate.to_string(),
+                    })),
+                });
+            }
+        }
 
-    if config.direct_settlement {
-        requirements.push(exact);
+        if config.offers_exact() {
+            requirements.push(PaymentRequirements {
+                scheme: "exact".to_string(),
+                network: network.name.clone(),
+                max_amount_required: max_amount_required.clone(),
+                resource: resource.clone(),
+                description: description.clone(),
+                mime_type: meta.mime_type.clone(),
+                output_schema: None,
+                pay_to: network.pay_to.clone(),
+                max_timeout_seconds: Some(config.max_timeout_seconds),
+                asset: network.asset.clone(),
+                extra: Some(exact_extra(&network)),
+            });
+        }
+
+        let accept_native = config.enable_exact && config.accept_native;
+        if let Some(native_price) = config.native_price.filter(|_| accept_native) {
+            requirements.push(PaymentRequirements {
+                scheme: "exact".to_string(),
+                network: network.name.clone(),
+                max_amount_required: native_price.to_string(),
+                resource: resource.clone(),
+                description: description.clone(),
+                mime_type: meta.mime_type.clone(),
+                output_schema: None,
+                pay_to: network.pay_to.clone(),
+                max_timeout_seconds: Some(config.max_timeout_seconds),
+                asset: onchain::NATIVE_ASSET.to_string(),
+                extra: None,
+            });
+        }
     }
 
     requirements
@@ -100,17 +234,48 @@ pub fn build_accepted_payment_requirements_v2(
     tab_endpoint: String,
 ) -> Vec<PaymentRequirementsV2> {
     let amount = amount.to_string();
-    vec![PaymentRequirementsV2 {
-        scheme: config.scheme_4mica.clone(),
-        network: config.network_v2.clone(),
-        amount,
-        asset: config.asset.clone(),
-        pay_to: config.pay_to.clone(),
-        max_timeout_seconds: Some(3600),
-        extra: Some(json!({
-            "tabEndpoint": tab_endpoint,
-        })),
-    }]
+    let mut requirements = Vec::new();
+    for network in config.networks() {
+        let Some(network_v2) = network.network_v2.clone() else {
+            continue;
+        };
+        if config.enable_4mica {
+            requirements.push(PaymentRequirementsV2 {
+                scheme: config.scheme_4mica.clone(),
+                network: network_v2.clone(),
+                amount: amount.clone(),
+                asset: network.asset.clone(),
+                pay_to: network.pay_to.clone(),
+                max_timeout_seconds: Some(config.max_timeout_seconds),
+                extra: Some(json!({
+                    "tabEndpoint": tab_endpoint,
+                })),
+            });
+        }
+
+        // Direct settlement is v1-only, so v2 exact payments always go through the facilitator.
+        if config.enable_exact && config.exact_facilitator {
+            requirements.push(PaymentRequirementsV2 {
+                scheme: "exact".to_string(),
+                network: network_v2,
+                amount: amount.clone(),
+                asset: network.asset.clone(),
+                pay_to: network.pay_to.clone(),
+                max_timeout_seconds: Some(config.max_timeout_seconds),
+                extra: Some(exact_extra(&network)),
+            });
+        }
+    }
+
+    requirements
+}
+
+/// EIP-712 domain of the asset, which `exact` clients need to sign `transferWithAuthorization`.
+fn exact_extra(network: &Network) -> Value {
+    json!({
+        "name": network.asset_name,
+        "version": network.asset_version,
+    })
 }
 
 pub fn build_payment_required_v2(
@@ -126,17 +291,25 @@ pub fn build_payment_required_v2(
     }
 }
 
+/// The requirement for `scheme` on `network`, where the network may be named in either
+/// style `aliases` knows. With an `asset`, only a requirement in that asset matches, which
+/// tells the ERC20 and native `exact` offers apart; without one the first match wins.
 fn find_matching_payment_requirements<'a>(
     scheme: &str,
     network: &str,
+    asset: Option<&str>,
     accepted: &'a [PaymentRequirements],
+    aliases: &NetworkAliases,
 ) -> Result<&'a PaymentRequirements, PaymentError> {
     accepted
         .iter()
-        .find(|req| req.scheme == scheme && req.network == network)
-        .ok_or_else(|| PaymentError::NoMatchingRequirements {
-            scheme: scheme.to_string(),
-            network: network.to_string(),
+        .filter(|req| {
+            asset.is_none_or(|asset| normalize_address(&req.asset) == normalize_address(asset))
+        })
+        .find(|req| req.scheme == scheme && aliases.same_chain(&req.network, network))
+        .ok_or_else(|| {
+            let offered = accepted.iter().map(|req| (&req.scheme, &req.network));
+            no_matching_requirements(scheme, network, offered, aliases)
         })
 }
 
@@ -144,22 +317,250 @@ fn find_matching_payment_requirements_v2<'a>(
     scheme: &str,
     network: &str,
     accepted: &'a [PaymentRequirementsV2],
+    aliases: &NetworkAliases,
 ) -> Result<&'a PaymentRequirementsV2, PaymentError> {
     accepted
         .iter()
-        .find(|req| req.scheme == scheme && req.network == network)
-        .ok_or_else(|| PaymentError::NoMatchingRequirements {
-            scheme: scheme.to_string(),
-            network: network.to_string(),
+        .find(|req| req.scheme == scheme && aliases.same_chain(&req.network, network))
+        .ok_or_else(|| {
+            let offered = accepted.iter().map(|req| (&req.scheme, &req.network));
+            no_matching_requirements(scheme, network, offered, aliases)
         })
 }
 
+/// A failure to match `scheme` on `network`, listing what is `offered` with every
+/// identifier of each network (`4mica-credit on polygon-amoy / eip155:80002`).
+fn no_matching_requirements<'a>(
+    scheme: &str,
+    network: &str,
+    offered: impl Iterator<Item = (&'a String, &'a String)>,
+    aliases: &NetworkAliases,
+) -> PaymentError {
+    PaymentError::NoMatchingRequirements {
+        scheme: scheme.to_string(),
+        network: network.to_string(),
+        accepted: offered
+            .map(|(scheme, network)| {
+                format!("{scheme} on {}", aliases.identifiers(network).join(" / "))
+            })
+            .collect(),
+    }
+}
+
+/// Checks that 4mica claims pay at least `required` to `pay_to` in `asset`. Addresses
+/// compare without case or `0x` prefix; claims omitting any of the three are accepted
+/// only with `lenient`.
+fn validate_claims(
+    envelope: &Value,
+    pay_to: &str,
+    asset: &str,
+    required: &str,
+    lenient: bool,
+) -> Result<(), PaymentError> {
+    let required = parse_u256_value(required).map_err(PaymentError::Other)?;
+    match extract_claim_value(envelope, "amount") {
+        Some(raw) => {
+            let provided = parse_u256_value(&raw)
+                .map_err(|e| PaymentError::Other(format!("invalid claim amount: {e}")))?;
+            if provided < required {
+                return Err(PaymentError::InsufficientAmount { required, provided });
+            }
+        }
+        None if !lenient => {
+            return Err(PaymentError::InsufficientAmount {
+                required,
+                provided: U256::ZERO,
+            });
+        }
+        None => {}
+    }
+
+    let recipient = extract_claim_value(envelope, "recipientAddress")
+        .or_else(|| extract_claim_value(envelope, "recipient_address"));
+    match recipient {
+        Some(claimed) if normalize_address(&claimed) != normalize_address(pay_to) => {
+            return Err(PaymentError::RecipientMismatch {
+                claimed,
+                expected: pay_to.to_string(),
+            });
+        }
+        None if !lenient => return Err(PaymentError::MissingClaim("recipientAddress")),
+        _ => {}
+    }
+    let claimed_asset = extract_claim_value(envelope, "assetAddress")
+        .or_else(|| extract_claim_value(envelope, "asset_address"));
+    match claimed_asset {
+        Some(claimed) if normalize_address(&claimed) != normalize_address(asset) => {
+            Err(PaymentError::AssetMismatch {
+                claimed,
+                expected: asset.to_string(),
+            })
+        }
+        None if !lenient => Err(PaymentError::MissingClaim("assetAddress")),
+        _ => Ok(()),
+    }
+}
+
+/// `payment_header` as the facilitator is sent it: re-encoded in standard base64, with a
+/// `reqId` claim copied to `req_id`, when the client sent it otherwise.
+fn normalize_payment_header(
+    payment_header: &str,
+    envelope: &mut Value,
+) -> Result<String, PaymentError> {
+    if normalize_req_id(envelope) {
+        debug!("Normalized x402 payment header: copied reqId -> req_id");
+        encode_payment_header(envelope)
+    } else if !is_standard_base64(payment_header) {
+        debug!("Normalized x402 payment header: re-encoded as standard base64");
+        encode_payment_header(envelope)
+    } else {
+        Ok(payment_header.to_string())
+    }
+}
+
+fn check_x402_version(version: u64) -> Result<(), PaymentError> {
+    if SUPPORTED_X402_VERSIONS.contains(&version) {
+        Ok(())
+    } else {
+        Err(PaymentError::UnsupportedVersion(version))
+    }
+}
+
+/// Checks that the resource the client echoed back, if any, is `resource`.
+fn check_paid_resource(envelope: &Value, resource: &str) -> Result<(), PaymentError> {
+    match extract_resource(envelope) {
+        Some(paid) if !resources_match(&paid, resource) => Err(PaymentError::ResourceMismatch {
+            paid,
+            requested: resource.to_string(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// How a payment is verified and settled, by its scheme and payload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PaymentRoute {
+    /// A `test` payment in sandbox mode, approved or declined without settling.
+    Sandbox,
+    /// Verified and settled by the facilitator, like every 4mica payment.
+    Facilitator,
+    /// An `exact` payment carrying a signed authorization the facilitator settles.
+    ExactFacilitator,
+    /// An `exact` payment carrying the hash of a transaction already sent.
+    ExactDirect,
+}
+
+/// Which [`PaymentRoute`] an envelope of `scheme` takes, or why none is enabled.
+fn payment_route(
+    envelope: &Value,
+    scheme: &str,
+    version: u64,
+    config: &X402Config,
+) -> Result<PaymentRoute, PaymentError> {
+    if config.mode == X402Mode::Sandbox && scheme.eq_ignore_ascii_case(SANDBOX_SCHEME) {
+        return Ok(PaymentRoute::Sandbox);
+    }
+    // Checked before anything else, so a hand-built envelope can't reach a disabled scheme.
+    let is_exact = scheme.eq_ignore_ascii_case("exact");
+    let enabled = if is_exact {
+        config.enable_exact
+    } else {
+        config.enable_4mica
+    };
+    if !enabled {
+        return Err(PaymentError::UnsupportedScheme(scheme.to_string()));
+    }
+    if !is_exact {
+        return Ok(PaymentRoute::Facilitator);
+    }
+    let has_tx_hash = extract_payload_value(envelope, "txHash")
+        .or_else(|| extract_payload_value(envelope, "tx_hash"))
+        .is_some();
+    if !has_tx_hash && has_exact_authorization(envelope) {
+        if !config.exact_facilitator {
+            return Err(PaymentError::Other(
+                "Facilitator exact settlement disabled; send a txHash payload instead".into(),
+            ));
+        }
+        return Ok(PaymentRoute::ExactFacilitator);
+    }
+    if !config.direct_settlement {
+        return Err(PaymentError::Other(
+            "Direct settlement disabled; exact payments not supported".into(),
+        ));
+    }
+    if version == 2 {
+        return Err(PaymentError::Other(
+            "Direct settlement does not support v2 payments".into(),
+        ));
+    }
+    Ok(PaymentRoute::ExactDirect)
+}
+
+/// Decodes a payment header in either base64 alphabet into its envelope, which must be
+/// a JSON object of bounded depth with an object `payload`.
 fn decode_payment_header(payment_header: &str) -> Result<Value, PaymentError> {
-    let bytes = BASE64_STANDARD.decode(payment_header)?;
-    let envelope = serde_json::from_slice(&bytes)?;
+    let payment_header = payment_header.trim();
+    let bytes = BASE64_STANDARD_LENIENT
+        .decode(payment_header)
+        .or_else(|e| {
+            BASE64_URL_SAFE_LENIENT
+                .decode(payment_header)
+                .map_err(|_| e)
+        })?;
+    let json = std::str::from_utf8(&bytes)
+        .map_err(|_| PaymentError::MalformedEnvelope("payment envelope is not UTF-8"))?;
+    if !json_depth_within(json.as_bytes(), MAX_PAYMENT_JSON_DEPTH) {
+        return Err(PaymentError::MalformedEnvelope(
+            "payment envelope is nested too deeply",
+        ));
+    }
+    let envelope: Value = serde_json::from_str(json)?;
+    if !envelope.get("payload").is_some_and(Value::is_object) {
+        return Err(PaymentError::MalformedEnvelope(
+            "payment payload is not a JSON object",
+        ));
+    }
     Ok(envelope)
 }
 
+/// Whether the objects and arrays in `json` nest at most `max` deep, checked in one pass
+/// before parsing so hostile input can't make the parser recurse.
+fn json_depth_within(json: &[u8], max: usize) -> bool {
+    let (mut depth, mut in_string, mut escaped) = (0usize, false, false);
+    for &byte in json {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'{' | b'[' => {
+                depth += 1;
+                if depth > max {
+                    return false;
+                }
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    true
+}
+
+/// Whether `payment_header` is padded standard base64, as facilitators expect it.
+fn is_standard_base64(payment_header: &str) -> bool {
+    payment_header.len().is_multiple_of(4)
+        && payment_header
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'+' | b'/' | b'='))
+}
+
 fn encode_payment_header(envelope: &Value) -> Result<String, PaymentError> {
     let bytes = serde_json::to_vec(envelope)?;
     Ok(BASE64_STANDARD.encode(bytes))
@@ -202,6 +603,158 @@ fn extract_claim_value(envelope: &Value, key: &str) -> Option<String> {
     })
 }
 
+fn extract_payload_value(envelope: &Value, key: &str) -> Option<String> {
+    envelope
+        .get("payload")
+        .and_then(|payload| payload.get(key))
+        .and_then(|val| val.as_str())
+        .map(str::to_string)
+}
+
+/// Whether an `exact` payload is a signed EIP-3009 authorization rather than a broadcast tx.
+fn has_exact_authorization(envelope: &Value) -> bool {
+    envelope.get("payload").is_some_and(|payload| {
+        payload.get("authorization").is_some_and(Value::is_object)
+            && payload.get("signature").is_some_and(Value::is_string)
+    })
+}
+
+fn extract_authorization_from(envelope: &Value) -> Option<String> {
+    envelope
+        .get("payload")
+        .and_then(|payload| payload.get("authorization"))
+        .and_then(|authorization| authorization.get("from"))
+        .and_then(|from| from.as_str())
+        .map(str::to_string)
+}
+
+/// The scheme a payment header pays with, without verifying it.
+pub fn payment_scheme(payment_header: &str) -> Option<String> {
+    let envelope = decode_payment_header(payment_header).ok()?;
+    extract_scheme_network(&envelope, extract_x402_version(&envelope))
+        .ok()
+        .map(|(scheme, _)| scheme)
+}
+
+/// The payer address a payment header claims, without verifying it: the 4mica
+/// `userAddress` claim, the `exact` authorization signer, or the payload `payer`/`from`.
+pub fn claimed_payer(payment_header: &str) -> Option<String> {
+    let envelope = decode_payment_header(payment_header).ok()?;
+    extract_claim_value(&envelope, "userAddress")
+        .or_else(|| extract_claim_value(&envelope, "user_address"))
+        .or_else(|| extract_authorization_from(&envelope))
+        .or_else(|| extract_payload_value(&envelope, "payer"))
+        .or_else(|| extract_payload_value(&envelope, "from"))
+}
+
+/// The tab a 4mica payment header claims to pay from, without verifying it.
+pub fn payment_tab_id(payment_header: &str) -> Option<U256> {
+    let envelope = decode_payment_header(payment_header).ok()?;
+    parse_u256_value(&fourmica::extract_tab_id(&envelope)?).ok()
+}
+
+/// What identifies the payment `payment_header` makes however it is encoded, so a payment
+/// re-sent in another base64 alphabet or with its JSON reordered is still the same one:
+/// the tx hash of a broadcast `exact` payment, the tab and request ids of a 4mica payment,
+/// the signer and nonce of an EIP-3009 authorization, else the decoded envelope itself.
+pub fn payment_identity(payment_header: &str) -> Option<String> {
+    let envelope = decode_payment_header(payment_header).ok()?;
+    if let Some(tx_hash) = extract_payload_value(&envelope, "txHash")
+        .or_else(|| extract_payload_value(&envelope, "tx_hash"))
+    {
+        return Some(format!("tx:{}", tx_hash.trim().to_ascii_lowercase()));
+    }
+    let req_id = extract_claim_value(&envelope, "req_id")
+        .or_else(|| extract_claim_value(&envelope, "reqId"));
+    if let Some(tab_id) = fourmica::extract_tab_id(&envelope)
+        && let Some(req_id) = req_id
+        && let (Ok(tab_id), Ok(req_id)) = (parse_u256_value(&tab_id), parse_u256_value(&req_id))
+    {
+        return Some(format!("tab:{tab_id:#x}:{req_id:#x}"));
+    }
+    let authorization = envelope
+        .get("payload")
+        .and_then(|payload| payload.get("authorization"));
+    if let Some(from) = extract_authorization_from(&envelope)
+        && let Some(nonce) = authorization
+            .and_then(|authorization| authorization.get("nonce"))
+            .and_then(Value::as_str)
+    {
+        return Some(format!(
+            "nonce:{}:{}",
+            normalize_address(&from),
+            nonce.trim().to_ascii_lowercase()
+        ));
+    }
+    // Object keys serialize sorted, so this is the same for any key order or spacing.
+    Some(format!("envelope:{envelope}"))
+}
+
+/// The Unix time `payment_header`'s signed validity ends, without verifying it: an `exact`
+/// authorization's `validBefore`, or a 4mica claim `timestamp` plus `maxTimeoutSeconds`.
+pub fn payment_valid_until(payment_header: &str, config: &X402Config) -> Option<i64> {
+    let envelope = decode_payment_header(payment_header).ok()?;
+    let valid_before = envelope
+        .get("payload")
+        .and_then(|payload| payload.get("authorization"))
+        .and_then(|authorization| authorization.get("validBefore"));
+    if let Some(valid_before) = valid_before {
+        return match valid_before {
+            Value::String(s) => s.parse().ok(),
+            value => value.as_i64(),
+        };
+    }
+    let signed_at: i64 = extract_claim_value(&envelope, "timestamp")?.parse().ok()?;
+    let timeout = i64::try_from(config.max_timeout_seconds).ok()?;
+    signed_at.checked_add(timeout)
+}
+
+/// The resource a client echoed back: v2 `resource.url`, or a `resource` string at the
+/// top level or in the payload.
+fn extract_resource(envelope: &Value) -> Option<String> {
+    let resource = envelope.get("resource").or_else(|| {
+        envelope
+            .get("payload")
+            .and_then(|payload| payload.get("resource"))
+    })?;
+    resource
+        .as_str()
+        .or_else(|| resource.get("url").and_then(|url| url.as_str()))
+        .map(str::to_string)
+}
+
+/// Comparable form of a resource URL: origin, decoded path, and decoded query pairs.
+#[derive(PartialEq)]
+struct ResourceKey {
+    origin: String,
+    path: String,
+    query: Vec<(String, String)>,
+}
+
+impl ResourceKey {
+    fn parse(raw: &str) -> Option<Self> {
+        let url = Url::parse(raw).ok()?;
+        Some(Self {
+            origin: url.origin().ascii_serialization(),
+            path: percent_decode_str(url.path())
+                .decode_utf8_lossy()
+                .into_owned(),
+            query: url
+                .query_pairs()
+                .map(|(key, value)| (key.into_owned(), value.into_owned()))
+                .collect(),
+        })
+    }
+}
+
+/// Compares resource URLs so that differences in percent-encoding don't matter.
+fn resources_match(a: &str, b: &str) -> bool {
+    match (ResourceKey::parse(a), ResourceKey::parse(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
 fn extract_x402_version(envelope: &Value) -> u64 {
     envelope
         .get("x402Version")
@@ -209,7 +762,10 @@ fn extract_x402_version(envelope: &Value) -> u64 {
         .unwrap_or(1)
 }
 
-fn extract_scheme_network(envelope: &Value, version: u64) -> Result<(String, String), PaymentError> {
+fn extract_scheme_network(
+    envelope: &Value,
+    version: u64,
+) -> Result<(String, String), PaymentError> {
     if version == 2 {
         let accepted = envelope
             .get("accepted")
@@ -221,7 +777,9 @@ fn extract_scheme_network(envelope: &Value, version: u64) -> Result<(String, Str
         let network = accepted
             .get("network")
             .and_then(|val| val.as_str())
-            .ok_or_else(|| PaymentError::Other("missing network in accepted requirements".into()))?;
+            .ok_or_else(|| {
+                PaymentError::Other("missing network in accepted requirements".into())
+            })?;
         return Ok((scheme.to_string(), network.to_string()));
     }
 
@@ -236,158 +794,991 @@ fn extract_scheme_network(envelope: &Value, version: u64) -> Result<(String, Str
     Ok((scheme.to_string(), network.to_string()))
 }
 
+/// Where [`settle_payment`] hands payments it only verifies.
+#[derive(Clone, Copy, Default)]
+pub struct BackgroundSettlers<'a> {
+    /// Takes 4mica payments carrying a tab id.
+    pub deferred: Option<&'a DeferredSettler>,
+    /// Takes every other facilitator payment.
+    pub queue: Option<&'a AsyncSettler>,
+}
+
+/// Settles `payment_header` for `resource`, the absolute URL being purchased.
+///
+/// Payments taken by one of the `background` settlers are only verified here; the
+/// summary then has no `tx_hash`. Without a `facilitator` only sandbox and direct `exact`
+/// payments can settle; those are read from the chain with `rpc`.
+///
+/// With `X402_REQUIRE_GUARANTEE`, a 4mica payment whose tab's guarantee headroom doesn't
+/// cover the amount is rejected before the facilitator verifies or settles it.
+#[allow(clippy::too_many_arguments)]
 pub async fn settle_payment(
     payment_header: &str,
+    resource: &str,
     accepted_payment_requirements: &[PaymentRequirements],
     accepted_payment_requirements_v2: &[PaymentRequirementsV2],
-    facilitator: &FacilitatorClient,
+    facilitator: Option<&dyn Facilitator>,
+    rpc: &reqwest::Client,
     config: &X402Config,
-) -> Result<(), PaymentError> {
-    let mut envelope = decode_payment_header(payment_header)?;
-    let mut normalized_header = payment_header.to_string();
-    if normalize_req_id(&mut envelope) {
-        normalized_header = encode_payment_header(&envelope)?;
-        debug!("Normalized x402 payment header: copied reqId -> req_id");
+    background: BackgroundSettlers<'_>,
+) -> Result<SettlementSummary, PaymentError> {
+    let mut summary = settle_payment_header(
+        payment_header,
+        resource,
+        accepted_payment_requirements,
+        accepted_payment_requirements_v2,
+        facilitator,
+        rpc,
+        config,
+        background,
+    )
+    .await?;
+    if summary.scheme.to_lowercase().contains("4mica") {
+        let claimed_amount = decode_payment_header(payment_header)
+            .ok()
+            .and_then(|envelope| extract_claim_value(&envelope, "amount"));
+        certificate::check_settlement_certificate(&mut summary, claimed_amount.as_deref(), config)
+            .await?;
     }
-    if let Ok(payload_json) = serde_json::to_string(&envelope.get("payload").cloned().unwrap_or(Value::Null)) {
-        debug!("Decoded x402 envelope payload={}", payload_json);
+    Ok(summary)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn settle_payment_header(
+    payment_header: &str,
+    resource: &str,
+    accepted_payment_requirements: &[PaymentRequirements],
+    accepted_payment_requirements_v2: &[PaymentRequirementsV2],
+    facilitator: Option<&dyn Facilitator>,
+    rpc: &reqwest::Client,
+    config: &X402Config,
+    background: BackgroundSettlers<'_>,
+) -> Result<SettlementSummary, PaymentError> {
+    let mut envelope = decode_payment_header(payment_header)?;
+    let normalized_header = normalize_payment_header(payment_header, &mut envelope)?;
+    if let Ok(payload_json) =
+        serde_json::to_string(&envelope.get("payload").cloned().unwrap_or(Value::Null))
+    {
+        debug!(payload = %payload_json, "Decoded x402 envelope payload");
     }
     let req_id = extract_claim_value(&envelope, "req_id");
     let req_id_alt = extract_claim_value(&envelope, "reqId");
     let tab_id = extract_claim_value(&envelope, "tab_id")
         .or_else(|| extract_claim_value(&envelope, "tabId"));
-    debug!(
-        "Decoded x402 claims: tab_id={:?} req_id={:?} reqId={:?}",
-        tab_id, req_id, req_id_alt
-    );
+    debug!(?tab_id, ?req_id, ?req_id_alt, "Decoded x402 claims");
     let x402_version = extract_x402_version(&envelope);
+    check_x402_version(x402_version)?;
+    check_paid_resource(&envelope, resource)?;
     let (scheme, network) = extract_scheme_network(&envelope, x402_version)?;
-    debug!(
-        "Decoded x402 envelope: version={}, scheme={}, network={}",
-        x402_version, scheme, network
-    );
+    debug!(version = x402_version, %scheme, %network, "Decoded x402 envelope");
+    let route = payment_route(&envelope, &scheme, x402_version, config)?;
+    let aliases = config.network_aliases();
+    if route == PaymentRoute::Sandbox {
+        return sandbox::settle_sandbox_payment(
+            &envelope,
+            network,
+            accepted_payment_requirements,
+            &aliases,
+        );
+    }
 
     let scheme_lower = scheme.to_lowercase();
-    if scheme_lower == "exact" {
-        if !config.direct_settlement {
-            return Err(PaymentError::Other(
-                "Direct settlement disabled; exact payments not supported".into(),
-            ));
-        }
-        if x402_version == 2 {
-            return Err(PaymentError::Other(
-                "Direct settlement does not support v2 payments".into(),
-            ));
-        }
-
-        let selected_requirement =
-            find_matching_payment_requirements(&scheme, &network, accepted_payment_requirements)?;
+    let is_exact = matches!(
+        route,
+        PaymentRoute::ExactDirect | PaymentRoute::ExactFacilitator
+    );
+    let exact_via_facilitator = route == PaymentRoute::ExactFacilitator;
+    if route == PaymentRoute::ExactDirect {
+        let asset = onchain::paid_asset(rpc, &envelope, &network, config).await?;
+        let selected_requirement = find_matching_payment_requirements(
+            &scheme,
+            &network,
+            asset.as_deref(),
+            accepted_payment_requirements,
+            &aliases,
+        )?;
 
-        return native::verify_onchain_payment(&envelope, selected_requirement, &config.rpc_url)
-            .await;
+        onchain::verify_onchain_payment(rpc, &envelope, selected_requirement, config).await?;
+        return Ok(SettlementSummary {
+            scheme,
+            network,
+            pay_to: selected_requirement.pay_to.clone(),
+            asset: selected_requirement.asset.clone(),
+            amount: selected_requirement.max_amount_required.clone(),
+            payer: extract_payload_value(&envelope, "payer")
+                .or_else(|| extract_payload_value(&envelope, "from")),
+            tab_id: None,
+            tx_hash: extract_payload_value(&envelope, "txHash")
+                .or_else(|| extract_payload_value(&envelope, "tx_hash")),
+            certificate: None,
+            certificate_verified: None,
+        });
     }
+    let Some(facilitator) = facilitator else {
+        return Err(PaymentError::Other("No facilitator is configured".into()));
+    };
 
-    if x402_version == 2 {
-        let selected_requirement =
-            find_matching_payment_requirements_v2(&scheme, &network, accepted_payment_requirements_v2)?;
-        info!(
-            "Matched v2 payment requirements: scheme={}, network={}, pay_to={}, asset={}, amount={}",
-            selected_requirement.scheme,
-            selected_requirement.network,
-            selected_requirement.pay_to,
-            selected_requirement.asset,
-            selected_requirement.amount
-        );
+    let payer = if exact_via_facilitator {
+        extract_authorization_from(&envelope)
+    } else {
+        extract_claim_value(&envelope, "userAddress")
+            .or_else(|| extract_claim_value(&envelope, "user_address"))
+    };
+    let deferred = background
+        .deferred
+        .filter(|_| !is_exact && scheme_lower.contains("4mica"))
+        .zip(tab_id.as_deref());
+    let queue = background.queue.filter(|_| deferred.is_none());
 
+    let requirements = if x402_version == 2 {
+        let selected_requirement = find_matching_payment_requirements_v2(
+            &scheme,
+            &network,
+            accepted_payment_requirements_v2,
+            &aliases,
+        )?;
         info!(
-            "Calling facilitator /settle for scheme={} network={}",
-            scheme, network
+            scheme = %selected_requirement.scheme,
+            network = %selected_requirement.network,
+            pay_to = %selected_requirement.pay_to,
+            asset = %selected_requirement.asset,
+            amount = %selected_requirement.amount,
+            "Matched v2 payment requirements"
         );
-        debug!(
-            "Sending payment header to facilitator: bytes={} normalized={}",
-            normalized_header.len(),
-            normalized_header != payment_header
+        DeferredRequirements::V2(selected_requirement.clone())
+    } else {
+        let selected_requirement = find_matching_payment_requirements(
+            &scheme,
+            &network,
+            None,
+            accepted_payment_requirements,
+            &aliases,
+        )?;
+        info!(
+            scheme = %selected_requirement.scheme,
+            network = %selected_requirement.network,
+            pay_to = %selected_requirement.pay_to,
+            asset = %selected_requirement.asset,
+            amount = %selected_requirement.max_amount_required,
+            "Matched payment requirements"
         );
-        let payment_payload = serde_json::to_value(&envelope)?;
-        let settle_response = facilitator
-            .settle_v2(&FacilitatorSettleParamsV2 {
-                x402_version: 2,
-                payment_header: &normalized_header,
-                payment_payload: Some(payment_payload),
-                payment_requirements: selected_requirement,
-            })
-            .await?;
+        DeferredRequirements::V1(selected_requirement.clone())
+    };
+    if !is_exact {
+        validate_claims(
+            &envelope,
+            requirements.pay_to(),
+            requirements.asset(),
+            requirements.amount(),
+            config.lenient_claims,
+        )?;
+        if config.require_guarantee && scheme_lower.contains("4mica") {
+            fourmica::enforce_guarantee(tab_id.as_deref(), requirements.amount(), config).await?;
+        }
+    }
 
-        if !settle_response.success {
-            return Err(PaymentError::SettlementFailed(
-                settle_response.error.unwrap_or_default(),
-            ));
+    let summary = SettlementSummary {
+        scheme,
+        network,
+        pay_to: requirements.pay_to().to_string(),
+        asset: requirements.asset().to_string(),
+        amount: requirements.amount().to_string(),
+        payer,
+        tab_id: tab_id.clone(),
+        tx_hash: None,
+        certificate: None,
+        certificate_verified: None,
+    };
+    let settlement = match (deferred, queue) {
+        (Some((settler, tab_id)), _) => Settlement::Deferred(settler, tab_id),
+        (None, Some(queue)) => Settlement::Queued(queue),
+        (None, None) => {
+            debug!(
+                bytes = normalized_header.len(),
+                normalized = normalized_header != payment_header,
+                "Sending payment header to facilitator"
+            );
+            Settlement::Now {
+                verify: exact_via_facilitator,
+            }
         }
+    };
+    let payment = DeferredPayment {
+        amount: parse_u256_value(requirements.amount()).map_err(PaymentError::Other)?,
+        payment_header: normalized_header,
+        payment_payload: serde_json::to_value(&envelope)?,
+        requirements,
+    };
+    let settled_now = matches!(settlement, Settlement::Now { .. });
+    let summary =
+        settle_with_facilitator(facilitator, resource, payment, summary, settlement).await?;
+    if settled_now && scheme_lower.contains("4mica") {
+        fourmica::log_fourmica_payment_info(&envelope, config).await;
+    }
+    Ok(summary)
+}
+
+/// How a payment the facilitator verifies gets settled.
+#[derive(Clone, Copy)]
+enum Settlement<'a> {
+    /// Added to the 4mica tab with this id, which the deferred settler settles at once.
+    Deferred(&'a DeferredSettler, &'a str),
+    /// Settled by the background worker; the request only waits for `/verify`.
+    Queued(&'a AsyncSettler),
+    /// Settled before the request is served, after `/verify` if `verify` is set.
+    Now { verify: bool },
+}
 
-        if let Some(certificate) = settle_response.certificate {
+/// Verifies `payment` with the facilitator and settles it as `settlement` says, filling in
+/// the certificate and `tx_hash` the facilitator returned on `summary`.
+async fn settle_with_facilitator(
+    facilitator: &dyn Facilitator,
+    resource: &str,
+    payment: DeferredPayment,
+    summary: SettlementSummary,
+    settlement: Settlement<'_>,
+) -> Result<SettlementSummary, PaymentError> {
+    let (scheme, network) = (&summary.scheme, &summary.network);
+    match settlement {
+        Settlement::Deferred(settler, tab_id) => {
+            info!(
+                %scheme,
+                %network,
+                tab_id,
+                "Calling facilitator /verify (deferred settlement)"
+            );
+            let certificate = deferred::verify_deferred(facilitator, &payment).await?;
+            let summary = SettlementSummary {
+                certificate,
+                ..summary
+            };
+            settler.enqueue(tab_id, resource, payment, &summary).await;
+            Ok(summary)
+        }
+        Settlement::Queued(queue) => {
+            // Held through `/verify`, so a copy of the header sent meanwhile isn't verified too.
+            let Some(_reservation) = queue.reserve(&payment.payment_header) else {
+                return Err(PaymentError::PaymentReplayed);
+            };
+            info!(%scheme, %network, "Calling facilitator /verify (settling in the background)");
+            let certificate = deferred::verify_deferred(facilitator, &payment).await?;
+            let summary = SettlementSummary {
+                certificate,
+                ..summary
+            };
+            queue.enqueue(resource, payment, summary.clone()).await;
+            Ok(summary)
+        }
+        Settlement::Now { verify } => {
+            info!(%scheme, %network, "Calling facilitator /settle");
+            if verify {
+                info!(%scheme, %network, "Calling facilitator /verify");
+                deferred::verify_deferred(facilitator, &payment).await?;
+            }
+            let settle_response = deferred::settle_deferred(facilitator, &payment).await?;
             info!(
-                "Settled payment header successfully, Certificate: {:?}",
-                certificate
+                %scheme,
+                %network,
+                tx_hash = ?settle_response.tx_hash,
+                certificate = ?settle_response.certificate,
+                "Settled payment header successfully"
             );
+            Ok(SettlementSummary {
+                tx_hash: settle_response.tx_hash,
+                certificate: settle_response.certificate,
+                ..summary
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x402::mock::{MOCK_TX_HASH, MockFacilitator};
+    use envconfig::Envconfig;
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+
+    const PAY_TO: &str = "0x00000000000000000000000000000000000000ab";
+    const PAYER: &str = "0x00000000000000000000000000000000000000ef";
+    const RESOURCE: &str = "http://localhost:3000/stream/a.ts";
+
+    fn config() -> X402Config {
+        X402Config::init_from_hashmap(&HashMap::from([
+            ("X402_PAY_TO".to_string(), PAY_TO.to_string()),
+            ("X402_TAB_SNAPSHOT".to_string(), "off".to_string()),
+        ]))
+        .unwrap()
+    }
+
+    /// A 4mica payment of `amount` from [`PAYER`], as an x402 `version` client sends it.
+    fn payment_header(config: &X402Config, version: u64, amount: &str) -> String {
+        let payload = json!({
+            "claims": {
+                "userAddress": PAYER,
+                "recipientAddress": PAY_TO,
+                "assetAddress": config.asset,
+                "amount": amount,
+                "tab_id": "7",
+                "req_id": "0",
+            },
+            "signature": "0x01",
+        });
+        let envelope = if version == 2 {
+            json!({
+                "x402Version": 2,
+                "accepted": { "scheme": config.scheme_4mica, "network": config.network_v2 },
+                "payload": payload,
+            })
         } else {
-            info!("Settled payment header successfully.");
+            json!({
+                "x402Version": 1,
+                "scheme": config.scheme_4mica,
+                "network": config.network,
+                "payload": payload,
+            })
+        };
+        encode_payment_header(&envelope).unwrap()
+    }
+
+    async fn settle(
+        config: &X402Config,
+        header: &str,
+        facilitator: &MockFacilitator,
+        background: BackgroundSettlers<'_>,
+    ) -> Result<SettlementSummary, PaymentError> {
+        let price = U256::from(100);
+        let tab_endpoint = "http://localhost:3000/tab".to_string();
+        let meta = ResourceMeta::default();
+        settle_payment(
+            header,
+            RESOURCE,
+            &build_accepted_payment_requirements(
+                config,
+                price,
+                tab_endpoint.clone(),
+                Some(RESOURCE.to_string()),
+                &meta,
+                None,
+            ),
+            &build_accepted_payment_requirements_v2(config, price, tab_endpoint),
+            Some(facilitator),
+            &reqwest::Client::new(),
+            config,
+            background,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn settles_v1_and_v2_payments() {
+        let config = config();
+        let facilitator = MockFacilitator::default();
+        for version in [1, 2] {
+            let header = payment_header(&config, version, "100");
+            let summary = settle(
+                &config,
+                &header,
+                &facilitator,
+                BackgroundSettlers::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(summary.tx_hash.as_deref(), Some(MOCK_TX_HASH));
+            assert_eq!(summary.payer.as_deref(), Some(PAYER));
+            assert_eq!(summary.tab_id.as_deref(), Some("7"));
+            assert_eq!(summary.amount, "100");
+        }
+        assert_eq!(facilitator.settles(), 2);
+        assert_eq!(facilitator.verifies(), 0);
+    }
+
+    #[tokio::test]
+    async fn failed_settlement_is_an_error() {
+        let config = config();
+        let facilitator = MockFacilitator {
+            settle_error: Some("tab closed".into()),
+            ..Default::default()
+        };
+        let header = payment_header(&config, 1, "100");
+        let result = settle(
+            &config,
+            &header,
+            &facilitator,
+            BackgroundSettlers::default(),
+        )
+        .await;
+        assert!(
+            matches!(result, Err(PaymentError::SettlementFailed(reason)) if reason == "tab closed")
+        );
+    }
+
+    #[tokio::test]
+    async fn short_claims_are_rejected_before_the_facilitator() {
+        let config = config();
+        let facilitator = MockFacilitator::default();
+        let header = payment_header(&config, 2, "99");
+        let result = settle(
+            &config,
+            &header,
+            &facilitator,
+            BackgroundSettlers::default(),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(PaymentError::InsufficientAmount { .. })
+        ));
+        assert_eq!(facilitator.settles(), 0);
+    }
+
+    #[tokio::test]
+    async fn queued_payment_is_only_verified() {
+        let config = config();
+        let facilitator = Arc::new(MockFacilitator::default());
+        let queue = AsyncSettler::spawn(facilitator.clone(), 8, Box::new(|_| {}), None);
+        let header = payment_header(&config, 1, "100");
+        let background = BackgroundSettlers {
+            deferred: None,
+            queue: Some(&queue),
+        };
+        let summary = settle(&config, &header, &facilitator, background)
+            .await
+            .unwrap();
+        assert_eq!(summary.tx_hash, None);
+        assert_eq!(facilitator.verifies(), 1);
+
+        queue.drain().await;
+        assert_eq!(facilitator.settles(), 1);
+    }
+
+    #[tokio::test]
+    async fn queued_payment_failing_verification_is_not_queued() {
+        let config = config();
+        let facilitator = Arc::new(MockFacilitator {
+            invalid_reason: Some("bad signature".into()),
+            ..Default::default()
+        });
+        let queue = AsyncSettler::spawn(facilitator.clone(), 8, Box::new(|_| {}), None);
+        let header = payment_header(&config, 1, "100");
+        let background = BackgroundSettlers {
+            deferred: None,
+            queue: Some(&queue),
+        };
+        let result = settle(&config, &header, &facilitator, background).await;
+        assert!(matches!(result, Err(PaymentError::VerificationFailed(_))));
+        queue.drain().await;
+        assert_eq!(facilitator.settles(), 0);
+    }
+
+    #[tokio::test]
+    async fn concurrent_copies_of_a_queued_payment_are_verified_once() {
+        let config = config();
+        let facilitator = Arc::new(MockFacilitator {
+            verify_delay: Duration::from_millis(50),
+            ..Default::default()
+        });
+        let queue = AsyncSettler::spawn(facilitator.clone(), 8, Box::new(|_| {}), None);
+        let header = payment_header(&config, 1, "100");
+        let background = BackgroundSettlers {
+            deferred: None,
+            queue: Some(&queue),
+        };
+        let (first, second) = tokio::join!(
+            settle(&config, &header, &facilitator, background),
+            settle(&config, &header, &facilitator, background),
+        );
+        let replayed = [&first, &second]
+            .iter()
+            .filter(|result| matches!(result, Err(PaymentError::PaymentReplayed)))
+            .count();
+        assert_eq!(replayed, 1);
+        assert!(first.is_ok() || second.is_ok());
+        assert_eq!(facilitator.verifies(), 1);
+    }
+
+    #[tokio::test]
+    async fn payment_failing_verification_may_be_sent_again() {
+        let config = config();
+        let facilitator = Arc::new(MockFacilitator {
+            invalid_reason: Some("tab not funded yet".into()),
+            ..Default::default()
+        });
+        let queue = AsyncSettler::spawn(facilitator.clone(), 8, Box::new(|_| {}), None);
+        let header = payment_header(&config, 1, "100");
+        let background = BackgroundSettlers {
+            deferred: None,
+            queue: Some(&queue),
+        };
+        for _ in 0..2 {
+            let result = settle(&config, &header, &facilitator, background).await;
+            assert!(matches!(result, Err(PaymentError::VerificationFailed(_))));
         }
+        assert_eq!(facilitator.verifies(), 2);
+    }
+
+    #[test]
+    fn valid_until_comes_from_the_signed_payload() {
+        let config = config();
+        let exact = encode_payment_header(&json!({
+            "scheme": "exact",
+            "network": "polygon-amoy",
+            "payload": {
+                "authorization": { "from": PAYER, "validBefore": "1700000300" },
+                "signature": "0x01",
+            },
+        }))
+        .unwrap();
+        assert_eq!(payment_valid_until(&exact, &config), Some(1_700_000_300));
+
+        let fourmica = encode_payment_header(&json!({
+            "scheme": "4mica-credit",
+            "network": "polygon-amoy",
+            "payload": { "claims": { "timestamp": 1_700_000_000 } },
+        }))
+        .unwrap();
+        assert_eq!(payment_valid_until(&fourmica, &config), Some(1_700_000_300));
+
+        let unsigned = payment_header(&config, 1, "100");
+        assert_eq!(payment_valid_until(&unsigned, &config), None);
+    }
+
+    fn exact_config() -> X402Config {
+        X402Config::init_from_hashmap(&HashMap::from([
+            ("X402_PAY_TO".to_string(), PAY_TO.to_string()),
+            ("X402_TAB_SNAPSHOT".to_string(), "off".to_string()),
+            ("X402_EXACT_FACILITATOR".to_string(), "true".to_string()),
+        ]))
+        .unwrap()
+    }
+
+    #[test]
+    fn v1_requirements_are_pinned() {
+        let requirements = build_accepted_payment_requirements(
+            &exact_config(),
+            U256::from(1_000_000u64),
+            "http://localhost:3000/tab".to_string(),
+            Some(RESOURCE.to_string()),
+            &ResourceMeta::default(),
+            None,
+        );
+        assert_eq!(
+            serde_json::to_value(&requirements).unwrap(),
+            json!([
+                {
+                    "scheme": "4mica-credit",
+                    "network": "polygon-amoy",
+                    "maxAmountRequired": "1000000",
+                    "resource": RESOURCE,
+                    "description": "Access to resource: http://localhost:3000/stream/a.ts",
+                    "mimeType": null,
+                    "outputSchema": null,
+                    "payTo": PAY_TO,
+                    "maxTimeoutSeconds": 300,
+                    "asset": "0x41E94Eb019C0762f9Bfcf9Fb1E58725BfB0e7582",
+                    "extra": { "tabEndpoint": "http://localhost:3000/tab" },
+                },
+                {
+                    "scheme": "exact",
+                    "network": "polygon-amoy",
+                    "maxAmountRequired": "1000000",
+                    "resource": RESOURCE,
+                    "description": "Access to resource: http://localhost:3000/stream/a.ts",
+                    "mimeType": null,
+                    "outputSchema": null,
+                    "payTo": PAY_TO,
+                    "maxTimeoutSeconds": 300,
+                    "asset": "0x41E94Eb019C0762f9Bfcf9Fb1E58725BfB0e7582",
+                    "extra": { "name": "USDC", "version": "2" },
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn v2_requirements_are_pinned() {
+        let requirements = build_accepted_payment_requirements_v2(
+            &exact_config(),
+            U256::from(1_000_000u64),
+            "http://localhost:3000/tab".to_string(),
+        );
+        assert_eq!(
+            serde_json::to_value(&requirements).unwrap(),
+            json!([
+                {
+                    "scheme": "4mica-credit",
+                    "network": "eip155:80002",
+                    "amount": "1000000",
+                    "asset": "0x41E94Eb019C0762f9Bfcf9Fb1E58725BfB0e7582",
+                    "payTo": PAY_TO,
+                    "maxTimeoutSeconds": 300,
+                    "extra": { "tabEndpoint": "http://localhost:3000/tab" },
+                },
+                {
+                    "scheme": "exact",
+                    "network": "eip155:80002",
+                    "amount": "1000000",
+                    "asset": "0x41E94Eb019C0762f9Bfcf9Fb1E58725BfB0e7582",
+                    "payTo": PAY_TO,
+                    "maxTimeoutSeconds": 300,
+                    "extra": { "name": "USDC", "version": "2" },
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn requirements_carry_each_networks_asset_metadata() {
+        let config = X402Config::init_from_hashmap(&HashMap::from([
+            ("X402_PAY_TO".to_string(), PAY_TO.to_string()),
+            ("X402_EXACT_FACILITATOR".to_string(), "true".to_string()),
+            ("X402_ENABLE_4MICA".to_string(), "false".to_string()),
+            ("X402_ASSET_DECIMALS".to_string(), "6".to_string()),
+            (
+                "X402_NETWORKS".to_string(),
+                json!([
+                    { "name": "polygon-amoy", "networkV2": "eip155:80002" },
+                    {
+                        "name": "base",
+                        "asset": "0x00000000000000000000000000000000000000cd",
+                        "assetName": "Token",
+                        "assetVersion": "1",
+                        "assetDecimals": 18,
+                    },
+                ])
+                .to_string(),
+            ),
+        ]))
+        .unwrap();
+        let networks = config.networks();
+        assert_eq!(networks[0].asset_decimals, Some(6));
+        assert_eq!(networks[1].asset_decimals, Some(18));
 
-        if scheme.to_lowercase().contains("4mica") {
-            fourmica::log_fourmica_payment_info(&envelope, config).await;
+        let requirements = build_accepted_payment_requirements(
+            &config,
+            U256::from(5u64),
+            "http://localhost:3000/tab".to_string(),
+            None,
+            &ResourceMeta::default(),
+            None,
+        );
+        let extras: Vec<_> = requirements
+            .iter()
+            .map(|requirement| requirement.extra.clone().unwrap())
+            .collect();
+        assert_eq!(
+            extras,
+            [
+                json!({ "name": "USDC", "version": "2" }),
+                json!({ "name": "Token", "version": "1" }),
+            ]
+        );
+    }
+
+    #[test]
+    fn v1_and_v2_headers_round_trip() {
+        let config = config();
+        for version in SUPPORTED_X402_VERSIONS {
+            let header = payment_header(&config, version, "100");
+            let envelope = decode_payment_header(&header).unwrap();
+            assert_eq!(extract_x402_version(&envelope), version);
+            check_x402_version(version).unwrap();
+            assert_eq!(
+                extract_scheme_network(&envelope, version).unwrap(),
+                if version == 2 {
+                    (config.scheme_4mica.clone(), config.network_v2.clone())
+                } else {
+                    (config.scheme_4mica.clone(), config.network.clone())
+                }
+            );
+            let reencoded = encode_payment_header(&envelope).unwrap();
+            assert_eq!(decode_payment_header(&reencoded).unwrap(), envelope);
         }
+    }
 
-        return Ok(());
+    #[test]
+    fn unsupported_version_names_the_supported_ones() {
+        let header = encode_payment_header(&json!({
+            "x402Version": 3,
+            "payload": {},
+        }))
+        .unwrap();
+        let envelope = decode_payment_header(&header).unwrap();
+        let err = check_x402_version(extract_x402_version(&envelope)).unwrap_err();
+        assert!(matches!(err, PaymentError::UnsupportedVersion(3)));
+        assert_eq!(
+            err.to_string(),
+            "Unsupported x402 version 3; supported versions: 1, 2"
+        );
     }
 
-    let selected_requirement =
-        find_matching_payment_requirements(&scheme, &network, accepted_payment_requirements)?;
-    info!(
-        "Matched payment requirements: scheme={}, network={}, pay_to={}, asset={}, max_amount_required={}",
-        selected_requirement.scheme,
-        selected_requirement.network,
-        selected_requirement.pay_to,
-        selected_requirement.asset,
-        selected_requirement.max_amount_required
-    );
+    #[test]
+    fn every_scheme_carries_the_resource_meta() {
+        let meta = ResourceMeta {
+            description: Some("HLS segment chunk_0042.ts, 4.0s".to_string()),
+            mime_type: Some("video/mp2t".to_string()),
+        };
+        let requirements = build_accepted_payment_requirements(
+            &exact_config(),
+            U256::from(100u64),
+            "http://localhost:3000/tab".to_string(),
+            Some(RESOURCE.to_string()),
+            &meta,
+            None,
+        );
+        assert_eq!(requirements.len(), 2);
+        for requirement in requirements {
+            let json = serde_json::to_value(&requirement).unwrap();
+            assert_eq!(json["description"], "HLS segment chunk_0042.ts, 4.0s");
+            assert_eq!(json["mimeType"], "video/mp2t");
+            assert_eq!(json["maxTimeoutSeconds"], 300);
+        }
+    }
 
-    info!(
-        "Calling facilitator /settle for scheme={} network={}",
-        scheme, network
-    );
-    debug!(
-        "Sending payment header to facilitator: bytes={} normalized={}",
-        normalized_header.len(),
-        normalized_header != payment_header
-    );
-    let payment_payload = serde_json::to_value(&envelope)?;
-    let settle_response = facilitator
-        .settle(&FacilitatorSettleParams {
-            x402_version: X402_VERSION,
-            payment_header: &normalized_header,
-            payment_payload: Some(payment_payload),
-            payment_requirements: selected_requirement,
-        })
-        .await?;
+    fn claims(recipient: Option<&str>, asset: Option<&str>, amount: Option<&str>) -> Value {
+        let mut claims = serde_json::Map::new();
+        for (key, value) in [
+            ("recipientAddress", recipient),
+            ("assetAddress", asset),
+            ("amount", amount),
+        ] {
+            if let Some(value) = value {
+                claims.insert(key.to_string(), json!(value));
+            }
+        }
+        json!({ "payload": { "claims": claims } })
+    }
 
-    if !settle_response.success {
-        return Err(PaymentError::SettlementFailed(
-            settle_response.error.unwrap_or_default(),
+    const ASSET: &str = "0x41E94Eb019C0762f9Bfcf9Fb1E58725BfB0e7582";
+
+    #[test]
+    fn claim_addresses_compare_without_case_or_prefix() {
+        for (recipient, asset) in [
+            (PAY_TO, ASSET),
+            ("0x00000000000000000000000000000000000000AB", ASSET),
+            (
+                PAY_TO.trim_start_matches("0x"),
+                "41e94eb019c0762f9bfcf9fb1e58725bfb0e7582",
+            ),
+            (PAY_TO, "0x41E94EB019C0762F9BFCF9FB1E58725BFB0E7582"),
+        ] {
+            let envelope = claims(Some(recipient), Some(asset), Some("100"));
+            validate_claims(&envelope, PAY_TO, ASSET, "100", false).unwrap();
+        }
+    }
+
+    #[test]
+    fn claims_for_another_recipient_or_asset_are_rejected() {
+        let other = "0x00000000000000000000000000000000000000ac";
+        let envelope = claims(Some(other), Some(ASSET), Some("100"));
+        assert!(matches!(
+            validate_claims(&envelope, PAY_TO, ASSET, "100", true),
+            Err(PaymentError::RecipientMismatch { .. })
+        ));
+        let envelope = claims(Some(PAY_TO), Some(other), Some("100"));
+        assert!(matches!(
+            validate_claims(&envelope, PAY_TO, ASSET, "100", true),
+            Err(PaymentError::AssetMismatch { .. })
         ));
     }
 
-    if let Some(certificate) = settle_response.certificate {
-        info!(
-            "Settled payment header successfully, Certificate: {:?}",
-            certificate
+    #[test]
+    fn missing_claims_are_accepted_only_when_lenient() {
+        let envelope = claims(None, Some(ASSET), Some("100"));
+        assert!(matches!(
+            validate_claims(&envelope, PAY_TO, ASSET, "100", false),
+            Err(PaymentError::MissingClaim("recipientAddress"))
+        ));
+        let envelope = claims(Some(PAY_TO), None, Some("100"));
+        assert!(matches!(
+            validate_claims(&envelope, PAY_TO, ASSET, "100", false),
+            Err(PaymentError::MissingClaim("assetAddress"))
+        ));
+
+        let envelope = claims(None, None, None);
+        validate_claims(&envelope, PAY_TO, ASSET, "100", true).unwrap();
+    }
+
+    #[tokio::test]
+    async fn claims_for_another_recipient_never_reach_the_facilitator() {
+        let config = config();
+        let facilitator = MockFacilitator::default();
+        let header = encode_payment_header(&json!({
+            "x402Version": 1,
+            "scheme": config.scheme_4mica,
+            "network": config.network,
+            "payload": {
+                "claims": {
+                    "userAddress": PAYER,
+                    "recipientAddress": "0x00000000000000000000000000000000000000ac",
+                    "assetAddress": config.asset,
+                    "amount": "100",
+                },
+                "signature": "0x01",
+            },
+        }))
+        .unwrap();
+        let result = settle(
+            &config,
+            &header,
+            &facilitator,
+            BackgroundSettlers::default(),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(PaymentError::RecipientMismatch { .. })
+        ));
+        assert_eq!(facilitator.verifies(), 0);
+        assert_eq!(facilitator.settles(), 0);
+    }
+
+    #[test]
+    fn headers_decode_in_either_alphabet() {
+        // `?` and `>` in the JSON put `/` and `+` in the standard encoding.
+        let envelope = json!({ "x402Version": 1, "payload": { "note": "a?b>c???>>>" } });
+        let json = envelope.to_string();
+        let standard = BASE64_STANDARD.encode(&json);
+        let url_safe = base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(&json);
+        assert_ne!(standard.trim_end_matches('='), url_safe);
+        assert_eq!(decode_payment_header(&standard).unwrap(), envelope);
+        assert_eq!(decode_payment_header(&url_safe).unwrap(), envelope);
+        assert_eq!(
+            decode_payment_header(&format!(" {standard}\n")).unwrap(),
+            envelope
         );
-    } else {
-        info!("Settled payment header successfully.");
     }
 
-    if scheme_lower.contains("4mica") {
-        fourmica::log_fourmica_payment_info(&envelope, config).await;
+    #[test]
+    fn malformed_headers_are_rejected_cleanly() {
+        let encode = |json: &str| BASE64_STANDARD.encode(json);
+        let nested = format!(
+            "{{\"payload\":{}{}}}",
+            "[".repeat(MAX_PAYMENT_JSON_DEPTH),
+            "]".repeat(MAX_PAYMENT_JSON_DEPTH)
+        );
+        for header in [
+            encode(&nested),
+            encode(r#"{"payload":"not an object"}"#),
+            encode(r#"{"x402Version":1}"#),
+            BASE64_STANDARD.encode([0xff, 0xfe, 0x7b]),
+        ] {
+            assert!(
+                matches!(
+                    decode_payment_header(&header),
+                    Err(PaymentError::MalformedEnvelope(_))
+                ),
+                "{header}"
+            );
+        }
+        assert!(matches!(
+            decode_payment_header("not base64!"),
+            Err(PaymentError::Base64Decode(_))
+        ));
+        assert!(matches!(
+            decode_payment_header(&encode("{not json")),
+            Err(PaymentError::JsonParse(_))
+        ));
+        assert!(decode_payment_header("").is_err());
+    }
+
+    #[test]
+    fn depth_is_counted_outside_strings_only() {
+        assert!(json_depth_within(br#"{"a":[{"b":1}]}"#, 3));
+        assert!(!json_depth_within(br#"{"a":[{"b":1}]}"#, 2));
+        assert!(json_depth_within(br#"{"a":"[[[[{{{{"}"#, 1));
+        assert!(json_depth_within(br#"{"a":"\"[[[["}"#, 1));
+        // Deep enough to overflow a recursive parser, yet checked in one quick pass.
+        let hostile = "[".repeat(1_000_000);
+        assert!(!json_depth_within(
+            hostile.as_bytes(),
+            MAX_PAYMENT_JSON_DEPTH
+        ));
+    }
+
+    #[test]
+    fn networks_match_in_either_style_and_any_case() {
+        let config = config();
+        let aliases = config.network_aliases();
+        let price = U256::from(100);
+        let tab_endpoint = "http://localhost:3000/tab".to_string();
+        let meta = ResourceMeta::default();
+        let accepted = build_accepted_payment_requirements(
+            &config,
+            price,
+            tab_endpoint.clone(),
+            None,
+            &meta,
+            None,
+        );
+        let accepted_v2 = build_accepted_payment_requirements_v2(&config, price, tab_endpoint);
+        let scheme = &config.scheme_4mica;
+        for network in [
+            "polygon-amoy",
+            "eip155:80002",
+            "POLYGON-AMOY",
+            "EIP155:80002",
+        ] {
+            let matched =
+                find_matching_payment_requirements(scheme, network, None, &accepted, &aliases);
+            assert_eq!(matched.unwrap().network, "polygon-amoy", "{network}");
+            let matched =
+                find_matching_payment_requirements_v2(scheme, network, &accepted_v2, &aliases);
+            assert_eq!(matched.unwrap().network, "eip155:80002", "{network}");
+        }
     }
 
-    Ok(())
+    #[test]
+    fn another_chain_is_told_every_accepted_identifier() {
+        let config = config();
+        let aliases = config.network_aliases();
+        let accepted_v2 = build_accepted_payment_requirements_v2(
+            &config,
+            U256::from(100),
+            "http://localhost:3000/tab".to_string(),
+        );
+        let err = find_matching_payment_requirements_v2(
+            &config.scheme_4mica,
+            "eip155:137",
+            &accepted_v2,
+            &aliases,
+        )
+        .unwrap_err();
+        match &err {
+            PaymentError::NoMatchingRequirements { accepted, .. } => {
+                let expected = format!("{} on eip155:80002 / polygon-amoy", config.scheme_4mica);
+                assert!(accepted.contains(&expected), "{accepted:?}");
+            }
+            other => panic!("{other}"),
+        }
+        assert_eq!(err.reason_code(), "invalid_payment_requirements");
+        assert!(!aliases.same_chain("polygon", "polygon-amoy"));
+        assert!(aliases.same_chain("eip155:137", "EIP155:137"));
+    }
+
+    #[test]
+    fn configured_aliases_name_the_same_chain() {
+        let config = X402Config::init_from_hashmap(&HashMap::from([
+            ("X402_PAY_TO".to_string(), PAY_TO.to_string()),
+            (
+                "X402_NETWORK_ALIASES".to_string(),
+                "amoy=eip155:80002, Amoy-Testnet=polygon-amoy".to_string(),
+            ),
+        ]))
+        .unwrap();
+        let aliases = config.network_aliases();
+        // An alias of the v1 name resolves to its chain, not to the name.
+        for network in ["amoy", "AMOY", "amoy-testnet"] {
+            assert!(aliases.same_chain(network, "eip155:80002"), "{network}");
+        }
+        assert_eq!(
+            aliases.identifiers("polygon-amoy"),
+            ["polygon-amoy", "amoy", "amoy-testnet", "eip155:80002"]
+        );
+
+        for raw in ["amoy", "=eip155:80002", "amoy=", "amoy=eip155:80002,bad"] {
+            assert!(raw.parse::<NetworkAliasList>().is_err(), "{raw:?}");
+        }
+        assert!(" , ".parse::<NetworkAliasList>().unwrap().0.is_empty());
+    }
 }
@@ -12,6 +12,13 @@ pub struct X402ResourceInfo {
     pub mime_type: Option<String>,
 }
 
+/// What is being sold, shown by wallets next to an advertised payment.
+#[derive(Debug, Clone, Default)]
+pub struct ResourceMeta {
+    pub description: Option<String>,
+    pub mime_type: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PaymentRequirementsV2 {
@@ -87,6 +94,41 @@ pub struct FacilitatorSettleResponse {
     pub certificate: Option<FourMicaCertificate>,
 }
 
+/// One payment kind listed by the facilitator's `GET /supported`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SupportedKind {
+    pub scheme: String,
+    pub network: String,
+    #[serde(default)]
+    pub x402_version: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FacilitatorSupportedResponse {
+    #[serde(default)]
+    pub kinds: Vec<SupportedKind>,
+}
+
+/// What was paid for by a successful `settle_payment` call.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SettlementSummary {
+    pub scheme: String,
+    pub network: String,
+    pub pay_to: String,
+    pub asset: String,
+    pub amount: String,
+    pub payer: Option<String>,
+    pub tab_id: Option<String>,
+    pub tx_hash: Option<String>,
+    pub certificate: Option<FourMicaCertificate>,
+    /// Whether `certificate` was checked against the operator key and the payment; unset
+    /// when no check ran.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub certificate_verified: Option<bool>,
+}
+
 #[derive(Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct FacilitatorTabRequestParams {
@@ -95,23 +137,3 @@ pub struct FacilitatorTabRequestParams {
     pub erc20_token: String,
     pub ttl_seconds: Option<u64>,
 }
-
-#[derive(Clone, Debug, Deserialize, Serialize)]
-#[serde(rename_all = "camelCase")]
-pub struct FacilitatorTabResponse {
-    pub tab_id: String,
-    pub user_address: String,
-    pub recipient_address: String,
-    pub asset_address: String,
-    #[serde(
-        default,
-        skip_serializing_if = "Option::is_none",
-        alias = "nextReqId",
-        alias = "next_req_id",
-        alias = "reqId",
-        alias = "req_id"
-    )]
-    pub next_req_id: Option<String>,
-    pub start_timestamp: i64,
-    pub ttl_seconds: i64,
-}
@@ -0,0 +1,1064 @@
+use chrono::Utc;
+use parking_lot::Mutex;
+use reqwest::Client;
+use sdk_4mica::{U256, x402::PaymentRequirements};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, warn};
+use url::Url;
+
+use crate::{error::PaymentError, x402::config::X402Config};
+
+/// Asset address of the chain's native coin in payment requirements.
+pub(crate) const NATIVE_ASSET: &str = "0x0000000000000000000000000000000000000000";
+const ERC20_TRANSFER_TOPIC: &str =
+    "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+
+/// Selector of ERC-20 `decimals()`.
+const DECIMALS_SELECTOR: &str = "0x313ce567";
+/// Assets whose decimals are known without asking the chain: USDC on Polygon Amoy.
+const KNOWN_DECIMALS: [(&str, u8); 1] = [("41e94eb019c0762f9bfcf9fb1e58725bfb0e7582", 6)];
+
+const RPC_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long an endpoint that just failed is tried only after the healthy ones.
+const RPC_COOLDOWN: Duration = Duration::from_secs(30);
+/// JSON-RPC error codes that report a failing node rather than an answer.
+const RPC_NODE_ERROR_CODES: [i64; 2] = [-32603, -32005];
+
+static RPC_HEALTH: OnceLock<Mutex<HashMap<String, EndpointHealth>>> = OnceLock::new();
+
+/// Failure score of one RPC endpoint, reset by its next success.
+#[derive(Debug, Default)]
+struct EndpointHealth {
+    failures: u32,
+    last_failure: Option<Instant>,
+}
+
+impl EndpointHealth {
+    fn demoted(&self) -> bool {
+        self.last_failure
+            .is_some_and(|at| at.elapsed() < RPC_COOLDOWN.saturating_mul(self.failures.min(10)))
+    }
+}
+
+fn rpc_health() -> &'static Mutex<HashMap<String, EndpointHealth>> {
+    RPC_HEALTH.get_or_init(Mutex::default)
+}
+
+/// `endpoints` in the order to try them: as configured, except that endpoints that
+/// failed within their cooldown (which grows with consecutive failures) go last.
+fn endpoint_order(endpoints: &[String]) -> Vec<&str> {
+    let health = rpc_health().lock();
+    let mut ordered: Vec<(bool, &str)> = endpoints
+        .iter()
+        .map(|url| {
+            (
+                health.get(url).is_some_and(EndpointHealth::demoted),
+                url.as_str(),
+            )
+        })
+        .collect();
+    ordered.sort_by_key(|(demoted, _)| *demoted);
+    ordered.into_iter().map(|(_, url)| url).collect()
+}
+
+fn record_endpoint(url: &str, ok: bool) {
+    let mut health = rpc_health().lock();
+    if ok {
+        health.remove(url);
+    } else {
+        let entry = health.entry(url.to_string()).or_default();
+        entry.failures = entry.failures.saturating_add(1);
+        entry.last_failure = Some(Instant::now());
+    }
+}
+
+/// Host (and port) of an endpoint, safe to show to clients: RPC URLs often embed API keys.
+fn endpoint_label(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(parsed) => match (parsed.host_str(), parsed.port()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            (Some(host), None) => host.to_string(),
+            _ => "<invalid url>".into(),
+        },
+        Err(_) => "<invalid url>".into(),
+    }
+}
+
+/// An RPC client with the timeouts of `config`. Build one and share it, so exact-scheme
+/// payments reuse pooled connections.
+pub fn rpc_client(config: &X402Config) -> Client {
+    let timeout = Duration::from_secs(config.rpc_timeout_seconds);
+    Client::builder()
+        .connect_timeout(RPC_CONNECT_TIMEOUT.min(timeout))
+        .timeout(timeout)
+        .build()
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonRpcError {
+    code: i64,
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonRpcResponse<T> {
+    result: Option<T>,
+    error: Option<JsonRpcError>,
+}
+
+/// Result of `eth_getTransactionReceipt`; `block_number` is unset while the transaction is pending.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RpcReceipt {
+    pub status: Option<String>,
+    pub block_number: Option<String>,
+    #[serde(default)]
+    pub logs: Vec<RpcLog>,
+}
+
+/// Result of `eth_getBlockByNumber`, without its transactions.
+#[derive(Debug, Deserialize)]
+pub(crate) struct RpcBlock {
+    pub timestamp: Option<String>,
+}
+
+/// Result of `eth_getTransactionByHash`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RpcTransaction {
+    pub to: Option<String>,
+    pub value: Option<String>,
+}
+
+/// An event log in a transaction receipt.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RpcLog {
+    pub address: String,
+    pub topics: Vec<String>,
+    pub data: String,
+}
+
+/// Why a single endpoint did not answer a call.
+enum RpcFailure {
+    /// The endpoint is unreachable or broken; another endpoint may answer.
+    Unavailable(String),
+    /// The endpoint answered, e.g. that the transaction doesn't exist.
+    Definitive(PaymentError),
+}
+
+/// Calls `method` on the first of `endpoints` that answers, mapping JSON-RPC errors and
+/// empty results to [`PaymentError::Onchain`].
+///
+/// Connection errors, timeouts, non-2xx responses, unparseable bodies, and JSON-RPC
+/// errors reporting a failing node move on to the next endpoint; see [`endpoint_order`].
+pub(crate) async fn rpc_call<T: for<'de> Deserialize<'de>>(
+    client: &Client,
+    endpoints: &[String],
+    method: &str,
+    params: Vec<Value>,
+) -> Result<T, PaymentError> {
+    rpc_call_optional(client, endpoints, method, params)
+        .await?
+        .ok_or_else(|| PaymentError::Onchain(format!("rpc {method} returned no result")))
+}
+
+/// [`rpc_call`] for methods that answer `null` for what they don't know yet, such as the
+/// receipt of a pending transaction.
+async fn rpc_call_optional<T: for<'de> Deserialize<'de>>(
+    client: &Client,
+    endpoints: &[String],
+    method: &str,
+    params: Vec<Value>,
+) -> Result<Option<T>, PaymentError> {
+    let body = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": method,
+        "params": params,
+    });
+    let mut attempts = Vec::new();
+    for url in endpoint_order(endpoints) {
+        match rpc_call_once(client, url, &body).await {
+            Ok(result) => {
+                record_endpoint(url, true);
+                return Ok(result);
+            }
+            Err(RpcFailure::Definitive(e)) => {
+                record_endpoint(url, true);
+                return Err(e);
+            }
+            Err(RpcFailure::Unavailable(reason)) => {
+                let label = endpoint_label(url);
+                warn!(endpoint = %label, method, "RPC endpoint failed: {}", reason);
+                record_endpoint(url, false);
+                attempts.push(format!("{label}: {reason}"));
+            }
+        }
+    }
+    if attempts.is_empty() {
+        return Err(PaymentError::Onchain("no RPC endpoint configured".into()));
+    }
+    Err(PaymentError::Onchain(format!(
+        "rpc {method} failed on every endpoint ({})",
+        attempts.join("; ")
+    )))
+}
+
+async fn rpc_call_once<T: for<'de> Deserialize<'de>>(
+    client: &Client,
+    url: &str,
+    body: &Value,
+) -> Result<Option<T>, RpcFailure> {
+    let resp = client.post(url).json(body).send().await.map_err(|e| {
+        if e.is_timeout() {
+            RpcFailure::Unavailable("timed out".into())
+        } else {
+            RpcFailure::Unavailable(format!("request failed: {}", e.without_url()))
+        }
+    })?;
+    let status = resp.status();
+    if !status.is_success() {
+        return Err(RpcFailure::Unavailable(format!("HTTP {status}")));
+    }
+    let parsed: JsonRpcResponse<T> = resp.json().await.map_err(|e| {
+        RpcFailure::Unavailable(format!("response parse failed: {}", e.without_url()))
+    })?;
+    if let Some(err) = parsed.error {
+        let message = format!("rpc error {}: {}", err.code, err.message);
+        return Err(if RPC_NODE_ERROR_CODES.contains(&err.code) {
+            RpcFailure::Unavailable(message)
+        } else {
+            RpcFailure::Definitive(PaymentError::Onchain(message))
+        });
+    }
+    Ok(parsed.result)
+}
+
+/// Lowercases an address and strips its `0x` prefix, for comparisons.
+pub(crate) fn normalize_address(addr: &str) -> String {
+    addr.trim_start_matches("0x").to_lowercase()
+}
+
+fn normalize_topic(topic: &str) -> String {
+    let stripped = topic.trim_start_matches("0x").to_lowercase();
+    format!("0x{stripped}")
+}
+
+/// The address in an indexed 32-byte log topic, normalized like [`normalize_address`].
+pub(crate) fn parse_topic_address(topic: &str) -> Option<String> {
+    let stripped = topic.trim_start_matches("0x");
+    if stripped.len() != 64 {
+        return None;
+    }
+    Some(stripped[24..].to_lowercase())
+}
+
+/// Parses a `0x`-prefixed hex or plain decimal integer.
+pub fn parse_u256_value(raw: &str) -> Result<U256, String> {
+    let trimmed = raw.trim();
+    if trimmed.is_empty() {
+        return Err("empty numeric value".into());
+    }
+    if let Some(stripped) = trimmed.strip_prefix("0x") {
+        U256::from_str_radix(stripped, 16).map_err(|e| format!("invalid hex value {trimmed}: {e}"))
+    } else {
+        U256::from_str(trimmed).map_err(|e| format!("invalid decimal value {trimmed}: {e}"))
+    }
+}
+
+/// Converts a decimal amount such as `0.05` into the asset's smallest unit, given the
+/// asset's `decimals`. The conversion is exact: an amount with more significant
+/// fractional digits than `decimals` is rejected rather than rounded.
+pub fn parse_units(raw: &str, decimals: u8) -> Result<U256, String> {
+    let trimmed = raw.trim();
+    let (whole, fraction) = trimmed.split_once('.').unwrap_or((trimmed, ""));
+    let is_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    if (whole.is_empty() && fraction.is_empty()) || !is_digits(whole) || !is_digits(fraction) {
+        return Err(format!("invalid decimal amount {trimmed:?}"));
+    }
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.len() > usize::from(decimals) {
+        return Err(format!(
+            "amount {trimmed} has more than {decimals} fractional digits"
+        ));
+    }
+    let digits = format!("{whole}{fraction:0<width$}", width = usize::from(decimals));
+    let digits = digits.trim_start_matches('0');
+    if digits.is_empty() {
+        return Ok(U256::from(0));
+    }
+    U256::from_str_radix(digits, 10).map_err(|e| format!("amount {trimmed} is too large: {e}"))
+}
+
+/// The chain id the RPC endpoints of `network` report with `eth_chainId`.
+pub async fn chain_id(
+    client: &Client,
+    config: &X402Config,
+    network: &str,
+) -> Result<u64, PaymentError> {
+    let result: String =
+        rpc_call(client, &config.rpc_urls_for(network), "eth_chainId", vec![]).await?;
+    let chain_id = parse_onchain_u256(&result)?;
+    u64::try_from(chain_id)
+        .map_err(|_| PaymentError::Onchain(format!("chain id {chain_id} is out of range")))
+}
+
+/// Decimals of the ERC-20 `asset`: the configured value for `network`, a known value for
+/// bundled assets, else its `decimals()` read with `eth_call` from the RPC endpoints of
+/// `network`.
+pub async fn asset_decimals(
+    client: &Client,
+    config: &X402Config,
+    network: &str,
+    asset: &str,
+) -> Result<u8, PaymentError> {
+    let aliases = config.network_aliases();
+    let configured = config.networks().into_iter().find_map(|entry| {
+        (aliases.same_chain(&entry.name, network) && entry.asset.eq_ignore_ascii_case(asset))
+            .then_some(entry.asset_decimals)
+            .flatten()
+    });
+    if let Some(decimals) = configured {
+        return Ok(decimals);
+    }
+    let normalized = normalize_address(asset);
+    if let Some((_, decimals)) = KNOWN_DECIMALS
+        .iter()
+        .find(|(known, _)| *known == normalized)
+    {
+        return Ok(*decimals);
+    }
+    let result: String = rpc_call(
+        client,
+        &config.rpc_urls_for(network),
+        "eth_call",
+        vec![
+            json!({ "to": asset, "data": DECIMALS_SELECTOR }),
+            json!("latest"),
+        ],
+    )
+    .await?;
+    let decimals = parse_onchain_u256(&result)?;
+    u8::try_from(decimals)
+        .map_err(|_| PaymentError::Onchain(format!("asset {asset} reports {decimals} decimals")))
+}
+
+/// [`parse_u256_value`] for values read from the chain.
+fn parse_onchain_u256(raw: &str) -> Result<U256, PaymentError> {
+    parse_u256_value(raw).map_err(PaymentError::Onchain)
+}
+
+/// Whether `asset` is [`NATIVE_ASSET`], the chain's native coin.
+pub(crate) fn is_native_asset(asset: &str) -> bool {
+    normalize_address(asset) == normalize_address(NATIVE_ASSET)
+}
+
+/// Whether a receipt `status` reports success; a missing or unparseable status does not.
+pub(crate) fn is_success_status(status: Option<&str>) -> bool {
+    status
+        .and_then(|s| parse_u256_value(s).ok())
+        .map(|val| val > U256::from(0))
+        .unwrap_or(false)
+}
+
+/// Checks that the transaction sent at least `required_amount` of the native coin to `pay_to`.
+pub(crate) async fn validate_native_transfer(
+    client: &Client,
+    endpoints: &[String],
+    tx_hash: &str,
+    pay_to: &str,
+    required_amount: U256,
+) -> Result<(), PaymentError> {
+    let tx: RpcTransaction = rpc_call(
+        client,
+        endpoints,
+        "eth_getTransactionByHash",
+        vec![json!(tx_hash)],
+    )
+    .await?;
+    let to_addr = tx
+        .to
+        .as_deref()
+        .map(normalize_address)
+        .ok_or_else(|| PaymentError::Onchain("transaction missing recipient".into()))?;
+    if to_addr != pay_to {
+        return Err(PaymentError::Onchain(format!(
+            "transaction recipient mismatch: expected {pay_to}, got {to_addr}"
+        )));
+    }
+    let value = tx.value.as_deref().unwrap_or("0x0");
+    let amount = parse_onchain_u256(value)?;
+    if amount < required_amount {
+        return Err(PaymentError::Onchain(format!(
+            "transaction value {amount:?} below required {required_amount:?}"
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that `payer` paid `pay_to` at least `required_amount` of `asset`, summed over
+/// every `Transfer` log from `payer` to `pay_to`, since routers and smart-contract wallets
+/// may forward a payment in parts. Transfers to `pay_to` from anyone else don't count, even
+/// in a transaction the payer sent; addresses are expected normalized.
+pub(crate) async fn validate_erc20_transfer(
+    receipt: &RpcReceipt,
+    asset: &str,
+    payer: &str,
+    pay_to: &str,
+    required_amount: U256,
+) -> Result<(), PaymentError> {
+    let transfer_topic = normalize_topic(ERC20_TRANSFER_TOPIC);
+    let mut found = false;
+    let mut total = U256::from(0);
+    for log in &receipt.logs {
+        if normalize_address(&log.address) != asset {
+            continue;
+        }
+        if log.topics.len() < 3 {
+            continue;
+        }
+        if normalize_topic(&log.topics[0]) != transfer_topic {
+            continue;
+        }
+        let (Some(from_addr), Some(to_addr)) = (
+            parse_topic_address(&log.topics[1]),
+            parse_topic_address(&log.topics[2]),
+        ) else {
+            continue;
+        };
+        if from_addr != payer || to_addr != pay_to {
+            continue;
+        }
+        let value = match parse_u256_value(&log.data) {
+            Ok(value) => value,
+            Err(reason) => {
+                warn!(asset, data = %log.data, "Skipping malformed transfer log: {}", reason);
+                continue;
+            }
+        };
+        found = true;
+        total = total
+            .checked_add(value)
+            .ok_or_else(|| PaymentError::Onchain("erc20 transfer total overflows".into()))?;
+    }
+    if !found {
+        return Err(PaymentError::Onchain(
+            "erc20 transfer from payer to pay_to not found in transaction logs".into(),
+        ));
+    }
+    if total < required_amount {
+        return Err(PaymentError::InsufficientAmount {
+            required: required_amount,
+            provided: total,
+        });
+    }
+    Ok(())
+}
+
+/// Rejects transactions mined fewer than `required` blocks ago (the mining block counts
+/// as the first confirmation).
+pub(crate) async fn check_confirmations(
+    client: &Client,
+    endpoints: &[String],
+    block_number: &str,
+    required: u64,
+) -> Result<(), PaymentError> {
+    // A mined receipt always has at least one confirmation.
+    if required <= 1 {
+        return Ok(());
+    }
+    let tx_block = parse_onchain_u256(block_number)?;
+    let head: String = rpc_call(client, endpoints, "eth_blockNumber", vec![]).await?;
+    let head = parse_onchain_u256(&head)?;
+    let have = if head >= tx_block {
+        (head - tx_block).saturating_add(U256::from(1))
+    } else {
+        // The provider's head lags the node that served the receipt.
+        U256::from(0)
+    };
+    let have = u64::try_from(have).unwrap_or(u64::MAX);
+    if have < required {
+        return Err(PaymentError::InsufficientConfirmations {
+            have,
+            need: required,
+        });
+    }
+    Ok(())
+}
+
+/// How far in the future a block's timestamp may be, for clocks that disagree.
+const BLOCK_CLOCK_SKEW_SECONDS: u64 = 120;
+
+/// The timestamp, in Unix seconds, of the block numbered `block_number`.
+pub(crate) async fn block_timestamp(
+    client: &Client,
+    endpoints: &[String],
+    block_number: &str,
+) -> Result<u64, PaymentError> {
+    let block: Option<RpcBlock> = rpc_call_optional(
+        client,
+        endpoints,
+        "eth_getBlockByNumber",
+        vec![json!(block_number), json!(false)],
+    )
+    .await?;
+    let block =
+        block.ok_or_else(|| PaymentError::Onchain(format!("block {block_number} not found")))?;
+    let timestamp = block
+        .timestamp
+        .ok_or_else(|| PaymentError::Onchain(format!("block {block_number} has no timestamp")))?;
+    let timestamp = parse_onchain_u256(&timestamp)?;
+    u64::try_from(timestamp)
+        .map_err(|_| PaymentError::Onchain(format!("block {block_number} timestamp out of range")))
+}
+
+/// Rejects transactions mined more than `max_age` seconds before `now`, or further in the
+/// future than [`BLOCK_CLOCK_SKEW_SECONDS`].
+pub(crate) fn check_transaction_age(
+    mined_at: u64,
+    now: u64,
+    max_age: u64,
+) -> Result<(), PaymentError> {
+    if mined_at > now.saturating_add(BLOCK_CLOCK_SKEW_SECONDS) {
+        return Err(PaymentError::Onchain(format!(
+            "transaction block timestamp {mined_at} is in the future"
+        )));
+    }
+    let age = now.saturating_sub(mined_at);
+    if age > max_age {
+        return Err(PaymentError::TransactionTooOld { age, max_age });
+    }
+    Ok(())
+}
+
+/// The mined receipt of `tx_hash` and its block number, with the number of queries made.
+///
+/// A receipt that is missing or not yet mined is asked for again every
+/// `X402_TX_POLL_INTERVAL_MS` until `X402_TX_WAIT_SECONDS` have passed. The caller holds a
+/// settlement slot meanwhile, so pending transactions wait within the settlement limit.
+async fn wait_for_receipt(
+    client: &Client,
+    endpoints: &[String],
+    tx_hash: &str,
+    config: &X402Config,
+) -> Result<(RpcReceipt, String, u32), PaymentError> {
+    let deadline = Instant::now() + Duration::from_secs(config.tx_wait_seconds);
+    let interval = Duration::from_millis(config.tx_poll_interval_ms.max(1));
+    let mut polls = 0;
+    loop {
+        polls += 1;
+        let receipt: Option<RpcReceipt> = rpc_call_optional(
+            client,
+            endpoints,
+            "eth_getTransactionReceipt",
+            vec![json!(tx_hash)],
+        )
+        .await?;
+        if let Some(mut receipt) = receipt
+            && let Some(block_number) = receipt.block_number.take()
+        {
+            return Ok((receipt, block_number, polls));
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(PaymentError::Onchain(
+                "transaction not yet finalized on-chain".into(),
+            ));
+        }
+        debug!(tx_hash, polls, "Transaction not mined yet; polling again");
+        tokio::time::sleep(interval.min(deadline - now)).await;
+    }
+}
+
+/// The asset an `exact` payment was made in, when that decides between the ERC20 and
+/// native offers: the payload's `asset` if it declares one, else the native coin when
+/// `X402_ACCEPT_NATIVE` is on and the transaction sends value. `None` means the ERC20 one.
+pub(crate) async fn paid_asset(
+    client: &Client,
+    envelope: &Value,
+    network: &str,
+    config: &X402Config,
+) -> Result<Option<String>, PaymentError> {
+    let payload = envelope.get("payload");
+    let declared = ["asset", "assetAddress"]
+        .iter()
+        .find_map(|key| payload?.get(*key)?.as_str());
+    if let Some(asset) = declared {
+        return Ok(Some(asset.to_string()));
+    }
+    let tx_hash = payload
+        .and_then(|payload| payload.get("txHash").or_else(|| payload.get("tx_hash")))
+        .and_then(|v| v.as_str());
+    let Some(tx_hash) = tx_hash.filter(|_| config.accept_native) else {
+        return Ok(None);
+    };
+    let tx: Option<RpcTransaction> = rpc_call_optional(
+        client,
+        &config.rpc_urls_for(network),
+        "eth_getTransactionByHash",
+        vec![json!(tx_hash)],
+    )
+    .await?;
+    let sends_value = tx
+        .and_then(|tx| tx.value)
+        .and_then(|value| parse_u256_value(&value).ok())
+        .is_some_and(|value| value > U256::ZERO);
+    Ok(sends_value.then(|| NATIVE_ASSET.to_string()))
+}
+
+/// Verifies the transaction named by the envelope's `txHash` against `requirements`:
+/// mined recently with enough confirmations, successful, and paying the required amount.
+pub(crate) async fn verify_onchain_payment(
+    client: &Client,
+    envelope: &Value,
+    requirements: &PaymentRequirements,
+    config: &X402Config,
+) -> Result<(), PaymentError> {
+    let tx_hash = envelope
+        .get("payload")
+        .and_then(|payload| payload.get("txHash").or_else(|| payload.get("tx_hash")))
+        .and_then(|v| v.as_str())
+        .ok_or(PaymentError::MissingTxHash)?;
+    let endpoints = config.rpc_urls_for(&requirements.network);
+
+    let started = Instant::now();
+    let (receipt, block_number, polls) =
+        wait_for_receipt(client, &endpoints, tx_hash, config).await?;
+    check_confirmations(client, &endpoints, &block_number, config.min_confirmations).await?;
+    if let Some(max_age) = config
+        .max_tx_age_seconds
+        .or(requirements.max_timeout_seconds)
+    {
+        let mined_at = block_timestamp(client, &endpoints, &block_number).await?;
+        let now = u64::try_from(Utc::now().timestamp()).unwrap_or_default();
+        check_transaction_age(mined_at, now, max_age)?;
+    }
+    if !is_success_status(receipt.status.as_deref()) {
+        return Err(PaymentError::Onchain("transaction reverted".into()));
+    }
+
+    let required_amount = parse_onchain_u256(&requirements.max_amount_required)?;
+    let pay_to = normalize_address(&requirements.pay_to);
+    let asset = normalize_address(&requirements.asset);
+
+    if is_native_asset(&asset) {
+        validate_native_transfer(client, &endpoints, tx_hash, &pay_to, required_amount).await?;
+    } else {
+        let payer = ["payer", "from"]
+            .iter()
+            .find_map(|key| envelope.get("payload")?.get(*key)?.as_str())
+            .map(normalize_address)
+            .ok_or(PaymentError::MissingPayer)?;
+        validate_erc20_transfer(&receipt, &asset, &payer, &pay_to, required_amount).await?;
+    }
+
+    info!(
+        tx_hash,
+        pay_to = %requirements.pay_to,
+        asset = %requirements.asset,
+        amount = %requirements.max_amount_required,
+        polls,
+        elapsed_ms = started.elapsed().as_millis() as u64,
+        "On-chain payment settled"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ASSET: &str = "41e94eb019c0762f9bfcf9fb1e58725bfb0e7582";
+    const OTHER_ASSET: &str = "0000000000000000000000000000000000000eee";
+    const PAYER: &str = "00000000000000000000000000000000000000cd";
+    const PAY_TO: &str = "00000000000000000000000000000000000000ab";
+    const STRANGER: &str = "00000000000000000000000000000000000000ef";
+
+    fn topic(address: &str) -> String {
+        format!("0x{address:0>64}")
+    }
+
+    fn transfer(asset: &str, from: &str, to: &str, amount: u64) -> serde_json::Value {
+        json!({
+            "address": format!("0x{asset}"),
+            "topics": [ERC20_TRANSFER_TOPIC, topic(from), topic(to)],
+            "data": format!("0x{amount:064x}"),
+        })
+    }
+
+    fn receipt_with(logs: Vec<serde_json::Value>) -> RpcReceipt {
+        serde_json::from_value(json!({
+            "status": "0x1",
+            "from": format!("0x{PAYER}"),
+            "blockNumber": "0x10",
+            "logs": logs,
+        }))
+        .unwrap()
+    }
+
+    async fn validate(receipt: &RpcReceipt, required: u64) -> Result<(), PaymentError> {
+        validate_erc20_transfer(receipt, ASSET, PAYER, PAY_TO, U256::from(required)).await
+    }
+
+    #[tokio::test]
+    async fn partial_transfers_pass_in_aggregate() {
+        let receipt = receipt_with(vec![
+            transfer(ASSET, PAYER, PAY_TO, 60),
+            transfer(ASSET, PAYER, PAY_TO, 40),
+        ]);
+        validate(&receipt, 100).await.unwrap();
+        assert!(matches!(
+            validate(&receipt, 101).await,
+            Err(PaymentError::InsufficientAmount { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn transfers_of_other_tokens_are_ignored() {
+        let receipt = receipt_with(vec![
+            transfer(ASSET, PAYER, PAY_TO, 60),
+            transfer(OTHER_ASSET, PAYER, PAY_TO, 1000),
+        ]);
+        assert!(matches!(
+            validate(&receipt, 100).await,
+            Err(PaymentError::InsufficientAmount { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn transfers_from_someone_else_are_not_the_payers() {
+        // The payer sent the transaction, but the tokens reaching `pay_to` are not theirs.
+        let receipt = receipt_with(vec![
+            transfer(ASSET, STRANGER, PAY_TO, 100),
+            transfer(ASSET, PAYER, STRANGER, 100),
+        ]);
+        assert!(matches!(
+            validate(&receipt, 100).await,
+            Err(PaymentError::Onchain(_))
+        ));
+
+        let receipt = receipt_with(vec![
+            transfer(ASSET, STRANGER, PAY_TO, 100),
+            transfer(ASSET, PAYER, PAY_TO, 10),
+        ]);
+        assert!(matches!(
+            validate(&receipt, 100).await,
+            Err(PaymentError::InsufficientAmount { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn malformed_transfer_data_is_skipped() {
+        let mut malformed = transfer(ASSET, PAYER, PAY_TO, 0);
+        malformed["data"] = json!("0xnot-a-number");
+        let receipt = receipt_with(vec![malformed, transfer(ASSET, PAYER, PAY_TO, 100)]);
+        validate(&receipt, 100).await.unwrap();
+    }
+
+    #[test]
+    fn receipt_fixture_parses() {
+        // Trimmed from a Polygon Amoy USDC transfer.
+        let receipt: RpcReceipt = serde_json::from_value(json!({
+            "blockHash": "0x5b1c02f2e3a0c1d6bbf1c4d7e8e1f3a1b6c0d9e8f7a6b5c4d3e2f1a0b9c8d7e6",
+            "blockNumber": "0x1a2b3c",
+            "contractAddress": null,
+            "cumulativeGasUsed": "0x2dc6c0",
+            "effectiveGasPrice": "0x6fc23ac00",
+            "from": format!("0x{PAYER}"),
+            "gasUsed": "0xf618",
+            "logs": [transfer(ASSET, PAYER, PAY_TO, 1_000_000)],
+            "logsBloom": "0x00",
+            "status": "0x1",
+            "to": format!("0x{ASSET}"),
+            "transactionHash": "0x9f8e7d6c5b4a39281706f5e4d3c2b1a09f8e7d6c5b4a39281706f5e4d3c2b1a0",
+            "transactionIndex": "0x3",
+            "type": "0x2",
+        }))
+        .unwrap();
+        assert!(is_success_status(receipt.status.as_deref()));
+        assert_eq!(receipt.block_number.as_deref(), Some("0x1a2b3c"));
+        assert_eq!(receipt.logs.len(), 1);
+        assert_eq!(
+            parse_topic_address(&receipt.logs[0].topics[2]).as_deref(),
+            Some(PAY_TO)
+        );
+
+        // A pending transaction has no block yet, and may come without logs.
+        let pending: RpcReceipt =
+            serde_json::from_value(json!({ "status": null, "blockNumber": null })).unwrap();
+        assert_eq!(pending.block_number, None);
+        assert!(pending.logs.is_empty());
+    }
+
+    #[test]
+    fn numbers_parse_as_hex_or_decimal() {
+        assert_eq!(parse_u256_value("0x10"), Ok(U256::from(16)));
+        assert_eq!(parse_u256_value("0xFF"), Ok(U256::from(255)));
+        assert_eq!(parse_u256_value("16"), Ok(U256::from(16)));
+        assert_eq!(parse_u256_value(" 42 "), Ok(U256::from(42)));
+        assert_eq!(
+            parse_u256_value(&format!("0x{:064x}", 7)),
+            Ok(U256::from(7))
+        );
+        assert_eq!(parse_u256_value(&U256::MAX.to_string()), Ok(U256::MAX));
+
+        assert!(parse_u256_value("").is_err());
+        assert!(parse_u256_value("  ").is_err());
+        assert!(parse_u256_value("0xzz").is_err());
+        assert!(parse_u256_value("1.5").is_err());
+        assert!(parse_u256_value("-1").is_err());
+        assert!(parse_u256_value(&format!("0x1{:064x}", 0)).is_err());
+    }
+
+    #[test]
+    fn only_a_nonzero_status_is_success() {
+        assert!(is_success_status(Some("0x1")));
+        assert!(is_success_status(Some("1")));
+        assert!(!is_success_status(Some("0x0")));
+        assert!(!is_success_status(Some("0")));
+        assert!(!is_success_status(Some("")));
+        assert!(!is_success_status(Some("0x")));
+        assert!(!is_success_status(Some("success")));
+        assert!(!is_success_status(None));
+    }
+
+    #[test]
+    fn addresses_compare_without_prefix_or_case() {
+        assert_eq!(
+            normalize_address("0x41E94Eb019C0762f9Bfcf9Fb1E58725BfB0e7582"),
+            ASSET
+        );
+        assert_eq!(parse_topic_address(&topic(PAYER)).as_deref(), Some(PAYER));
+        assert_eq!(parse_topic_address("0x1234"), None);
+        assert!(is_native_asset(NATIVE_ASSET));
+        assert!(is_native_asset("0000000000000000000000000000000000000000"));
+        assert!(!is_native_asset(ASSET));
+    }
+
+    /// Answers every `rpc_method` call `node` receives with `result`.
+    async fn answer(node: &wiremock::MockServer, rpc_method: &str, result: serde_json::Value) {
+        use wiremock::{
+            Mock, ResponseTemplate,
+            matchers::{body_partial_json, method},
+        };
+
+        Mock::given(method("POST"))
+            .and(body_partial_json(json!({ "method": rpc_method })))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({ "jsonrpc": "2.0", "id": 1, "result": result })),
+            )
+            .mount(node)
+            .await;
+    }
+
+    /// A node answering a receipt holding `logs`, mined in block 0x10, at a head ten
+    /// blocks later.
+    async fn node_with(logs: Vec<serde_json::Value>) -> wiremock::MockServer {
+        let node = wiremock::MockServer::start().await;
+        let receipt = json!({ "status": "0x1", "blockNumber": "0x10", "logs": logs });
+        answer(&node, "eth_getTransactionReceipt", receipt).await;
+        answer(&node, "eth_blockNumber", json!("0x1a")).await;
+        node
+    }
+
+    async fn verify_transfer(
+        node: &wiremock::MockServer,
+        payload: serde_json::Value,
+        settings: &[(&str, &str)],
+    ) -> Result<(), PaymentError> {
+        use envconfig::Envconfig;
+
+        let mut values = HashMap::from([
+            ("X402_PAY_TO".to_string(), format!("0x{PAY_TO}")),
+            ("X402_RPC_URL".to_string(), node.uri()),
+        ]);
+        values.extend(
+            settings
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string())),
+        );
+        let config = X402Config::init_from_hashmap(&values).unwrap();
+        let requirements = PaymentRequirements {
+            scheme: "exact".to_string(),
+            network: config.network.clone(),
+            max_amount_required: "100".to_string(),
+            resource: None,
+            description: None,
+            mime_type: None,
+            output_schema: None,
+            pay_to: format!("0x{PAY_TO}"),
+            max_timeout_seconds: None,
+            asset: format!("0x{ASSET}"),
+            extra: None,
+        };
+        let envelope = json!({ "x402Version": 1, "payload": payload });
+        verify_onchain_payment(&Client::new(), &envelope, &requirements, &config).await
+    }
+
+    #[tokio::test]
+    async fn transfer_from_the_declared_payer_is_accepted() {
+        let node = node_with(vec![transfer(ASSET, PAYER, PAY_TO, 100)]).await;
+        verify_transfer(
+            &node,
+            json!({ "txHash": "0x01", "payer": format!("0x{PAYER}") }),
+            &[],
+        )
+        .await
+        .unwrap();
+        verify_transfer(
+            &node,
+            json!({ "txHash": "0x01", "from": PAYER.to_uppercase() }),
+            &[],
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn transfer_to_pay_to_from_someone_else_is_rejected() {
+        // Someone else's payment to the merchant can't be claimed by pointing at it.
+        let node = node_with(vec![transfer(ASSET, STRANGER, PAY_TO, 100)]).await;
+        assert!(matches!(
+            verify_transfer(
+                &node,
+                json!({ "txHash": "0x01", "payer": format!("0x{PAYER}") }),
+                &[]
+            )
+            .await,
+            Err(PaymentError::Onchain(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn erc20_payment_without_a_payer_is_rejected() {
+        let node = node_with(vec![transfer(ASSET, PAYER, PAY_TO, 100)]).await;
+        assert!(matches!(
+            verify_transfer(&node, json!({ "txHash": "0x01" }), &[]).await,
+            Err(PaymentError::MissingPayer)
+        ));
+    }
+
+    #[test]
+    fn transaction_age_is_bounded_both_ways() {
+        let now = 1_700_000_000;
+        check_transaction_age(now - 60, now, 300).unwrap();
+        check_transaction_age(now - 300, now, 300).unwrap();
+        assert!(matches!(
+            check_transaction_age(now - 301, now, 300),
+            Err(PaymentError::TransactionTooOld {
+                age: 301,
+                max_age: 300
+            })
+        ));
+        // A block stamped slightly ahead of this clock is skew, not fraud.
+        check_transaction_age(now + BLOCK_CLOCK_SKEW_SECONDS, now, 300).unwrap();
+        assert!(matches!(
+            check_transaction_age(now + BLOCK_CLOCK_SKEW_SECONDS + 1, now, 300),
+            Err(PaymentError::Onchain(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn block_timestamp_comes_from_the_block() {
+        let node = wiremock::MockServer::start().await;
+        answer(
+            &node,
+            "eth_getBlockByNumber",
+            json!({ "number": "0x10", "timestamp": "0x6553f100" }),
+        )
+        .await;
+        let endpoints = [node.uri()];
+        assert_eq!(
+            block_timestamp(&Client::new(), &endpoints, "0x10")
+                .await
+                .unwrap(),
+            1_700_000_000
+        );
+    }
+
+    #[tokio::test]
+    async fn block_without_a_timestamp_is_an_error() {
+        for block in [
+            json!({ "number": "0x10" }),
+            json!({ "timestamp": "0xzz" }),
+            json!(null),
+        ] {
+            let node = wiremock::MockServer::start().await;
+            answer(&node, "eth_getBlockByNumber", block.clone()).await;
+            let endpoints = [node.uri()];
+            assert!(
+                matches!(
+                    block_timestamp(&Client::new(), &endpoints, "0x10").await,
+                    Err(PaymentError::Onchain(_))
+                ),
+                "{block}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn stale_transfer_is_rejected() {
+        let node = node_with(vec![transfer(ASSET, PAYER, PAY_TO, 100)]).await;
+        let mined_at = Utc::now().timestamp() - 3600;
+        answer(
+            &node,
+            "eth_getBlockByNumber",
+            json!({ "timestamp": format!("{mined_at:#x}") }),
+        )
+        .await;
+        let payload = json!({ "txHash": "0x01", "payer": format!("0x{PAYER}") });
+        assert!(matches!(
+            verify_transfer(
+                &node,
+                payload.clone(),
+                &[("X402_MAX_TX_AGE_SECONDS", "600")]
+            )
+            .await,
+            Err(PaymentError::TransactionTooOld { .. })
+        ));
+        verify_transfer(&node, payload, &[("X402_MAX_TX_AGE_SECONDS", "7200")])
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn parse_units_scales_exactly() {
+        assert_eq!(parse_units("0.05", 6), Ok(U256::from(50_000)));
+        assert_eq!(
+            parse_units("1", 18),
+            Ok(U256::from(1_000_000_000_000_000_000u64))
+        );
+        assert_eq!(parse_units("1.500000", 6), Ok(U256::from(1_500_000)));
+        assert_eq!(parse_units(" 2. ", 6), Ok(U256::from(2_000_000)));
+        assert_eq!(parse_units(".5", 1), Ok(U256::from(5)));
+        assert_eq!(parse_units("0.000", 6), Ok(U256::ZERO));
+    }
+
+    #[test]
+    fn parse_units_rejects_what_it_cannot_convert_exactly() {
+        let err = parse_units("0.0000001", 6).unwrap_err();
+        assert!(err.contains("more than 6 fractional digits"), "{err}");
+        assert!(parse_units("1.5", 0).is_err());
+        for raw in [".", "", "-1", "1e3", "1.2.3", "0x10", "1,5"] {
+            let err = parse_units(raw, 6).unwrap_err();
+            assert!(err.starts_with("invalid decimal amount"), "{raw:?}: {err}");
+        }
+        let err = parse_units(&"9".repeat(80), 0).unwrap_err();
+        assert!(err.contains("too large"), "{err}");
+        assert_eq!(parse_units(&U256::MAX.to_string(), 0), Ok(U256::MAX));
+        // Fits as written, not once scaled by the decimals.
+        let err = parse_units(&"2".repeat(60), 18).unwrap_err();
+        assert!(err.contains("too large"), "{err}");
+    }
+}
@@ -0,0 +1,479 @@
+use chrono::Utc;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use crate::{
+    io::FsyncPolicy,
+    x402::{
+        DeferredPayment, Facilitator, FailedSettlement, FailureHook, SettlementSummary,
+        deferred::settle_verified,
+    },
+};
+
+/// Settle attempts per recovered payment before it is marked failed.
+const MAX_ATTEMPTS: u32 = 5;
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(2);
+/// Lines appended before the journal is rewritten with only what is still open.
+const COMPACT_EVERY: usize = 10_000;
+
+/// A payment that was served before its settlement was final, as journaled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SettlementIntent {
+    /// Hex SHA-256 of the payment header.
+    pub id: String,
+    /// Unix timestamp in seconds.
+    pub timestamp: i64,
+    pub resource: String,
+    pub summary: SettlementSummary,
+    pub payment: DeferredPayment,
+}
+
+/// One journal line.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "camelCase")]
+enum Entry {
+    Intent(Box<SettlementIntent>),
+    Settled { id: String },
+    Failed { id: String, error: String },
+}
+
+struct Open {
+    intent: Box<SettlementIntent>,
+    /// Set once the payment gave up settling.
+    error: Option<String>,
+    /// Whether the recovery task still has to settle it.
+    recovering: bool,
+}
+
+/// One journaled payment, as listed by `GET /admin/recovery`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingSettlementView {
+    pub id: String,
+    pub resource: String,
+    pub scheme: String,
+    pub network: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payer: Option<String>,
+    pub amount: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tab_id: Option<String>,
+    /// Unix timestamp in seconds of when the payment was served.
+    pub timestamp: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl From<&Open> for PendingSettlementView {
+    fn from(open: &Open) -> Self {
+        let intent = &open.intent;
+        Self {
+            id: intent.id.clone(),
+            resource: intent.resource.clone(),
+            scheme: intent.summary.scheme.clone(),
+            network: intent.summary.network.clone(),
+            payer: intent.summary.payer.clone(),
+            amount: intent.summary.amount.clone(),
+            tab_id: intent.summary.tab_id.clone(),
+            timestamp: intent.timestamp,
+            error: open.error.clone(),
+        }
+    }
+}
+
+/// Payments left over from an earlier run that are still being settled, plus every
+/// journaled payment whose settlement gave up.
+#[derive(Debug, Serialize)]
+pub struct RecoverySnapshot {
+    pub recovering: Vec<PendingSettlementView>,
+    pub failed: Vec<PendingSettlementView>,
+}
+
+enum Command {
+    Append(Entry, Option<oneshot::Sender<()>>),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Write-ahead journal (`X402_PENDING_SETTLEMENTS_PATH`) of payments served before their
+/// settlement is final, so a crash between serving and settling doesn't lose them.
+///
+/// Each payment is appended as an intent before it is served and closed once it settles
+/// or gives up, as JSON lines written by one thread. Appends that arrive together share
+/// one write and, with `X402_PENDING_SETTLEMENTS_FSYNC=always`, one sync. Intents still
+/// open on startup are settled again by [`PendingSettlements::recover`]; failed ones are
+/// kept for `GET /admin/recovery`.
+pub struct PendingSettlements {
+    tx: mpsc::UnboundedSender<Command>,
+    open: Arc<Mutex<HashMap<String, Open>>>,
+}
+
+impl PendingSettlements {
+    /// Replays `path`, rewrites it with only the intents still open, and starts the
+    /// writer. Returns the journal and the intents left unsettled by the previous run.
+    /// Unparseable lines, such as one torn by a crash, are skipped with a warning.
+    pub fn open(
+        path: impl AsRef<Path>,
+        fsync: FsyncPolicy,
+    ) -> io::Result<(Arc<Self>, Vec<SettlementIntent>)> {
+        let path = path.as_ref().to_path_buf();
+        let mut open = HashMap::new();
+        if path.exists() {
+            for (index, line) in BufReader::new(File::open(&path)?).lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<Entry>(&line) {
+                    Ok(entry) => apply(&mut open, entry),
+                    Err(e) => warn!("Skipping pending settlements line {}: {}", index + 1, e),
+                }
+            }
+        }
+        let mut leftover: Vec<SettlementIntent> = open
+            .values_mut()
+            .filter(|open| open.error.is_none())
+            .map(|open| {
+                open.recovering = true;
+                (*open.intent).clone()
+            })
+            .collect();
+        leftover.sort_by_key(|intent| intent.timestamp);
+
+        let mut writer = Writer {
+            path,
+            fsync,
+            file: None,
+            appended: 0,
+        };
+        writer.compact(&open)?;
+        let open = Arc::new(Mutex::new(open));
+        let (tx, mut rx) = mpsc::unbounded_channel::<Command>();
+        thread::Builder::new()
+            .name("pending-settlements".into())
+            .spawn({
+                let open = open.clone();
+                move || {
+                    while let Some(command) = rx.blocking_recv() {
+                        let mut batch = vec![command];
+                        while let Ok(command) = rx.try_recv() {
+                            batch.push(command);
+                        }
+                        writer.write(batch, &open);
+                    }
+                }
+            })?;
+        Ok((Arc::new(Self { tx, open }), leftover))
+    }
+
+    /// Journals that `payment` is served for `resource` before it settles, returning once
+    /// the intent is written (and synced, with `always`). A write failure is logged; the
+    /// payment is still served.
+    pub async fn record(
+        &self,
+        resource: &str,
+        summary: &SettlementSummary,
+        payment: &DeferredPayment,
+    ) {
+        let intent = SettlementIntent {
+            id: intent_id(&payment.payment_header),
+            timestamp: Utc::now().timestamp(),
+            resource: resource.to_string(),
+            summary: summary.clone(),
+            payment: payment.clone(),
+        };
+        let (reply, rx) = oneshot::channel();
+        if self
+            .tx
+            .send(Command::Append(
+                Entry::Intent(Box::new(intent)),
+                Some(reply),
+            ))
+            .is_ok()
+        {
+            let _ = rx.await;
+        }
+    }
+
+    /// Closes the intent of a payment that settled.
+    pub fn settled(&self, payment_header: &str) {
+        let id = intent_id(payment_header);
+        let _ = self.tx.send(Command::Append(Entry::Settled { id }, None));
+    }
+
+    /// Marks the intent of a payment whose settlement gave up, keeping it for inspection
+    /// instead of retrying it on the next start.
+    pub fn failed(&self, payment_header: &str, error: &str) {
+        let entry = Entry::Failed {
+            id: intent_id(payment_header),
+            error: error.to_string(),
+        };
+        let _ = self.tx.send(Command::Append(entry, None));
+    }
+
+    /// Waits until everything journaled so far has been written and synced.
+    pub async fn flush(&self) {
+        let (reply, rx) = oneshot::channel();
+        if self.tx.send(Command::Flush(reply)).is_ok() {
+            let _ = rx.await;
+        }
+    }
+
+    pub fn snapshot(&self) -> RecoverySnapshot {
+        let open = self.open.lock();
+        let view = |recovering: bool| {
+            let mut views: Vec<PendingSettlementView> = open
+                .values()
+                .filter(|open| {
+                    if recovering {
+                        open.recovering
+                    } else {
+                        open.error.is_some()
+                    }
+                })
+                .map(Into::into)
+                .collect();
+            views.sort_by_key(|view| view.timestamp);
+            views
+        };
+        RecoverySnapshot {
+            recovering: view(true),
+            failed: view(false),
+        }
+    }
+
+    /// Settles the `leftover` intents one at a time from a background task, each through
+    /// `facilitator` (and so its retries and failover) with exponential backoff between
+    /// attempts. After [`MAX_ATTEMPTS`] an intent is marked failed and handed to
+    /// `on_failure`. Stops when `shutdown` is cancelled; intents not yet settled stay open
+    /// for the next start.
+    pub fn recover(
+        self: &Arc<Self>,
+        facilitator: Arc<dyn Facilitator>,
+        leftover: Vec<SettlementIntent>,
+        on_failure: FailureHook,
+        shutdown: CancellationToken,
+    ) {
+        if leftover.is_empty() {
+            return;
+        }
+        info!(
+            "Recovering {} payments served before their settlement finished",
+            leftover.len()
+        );
+        let journal = self.clone();
+        tokio::spawn(async move {
+            for intent in leftover {
+                let settled = tokio::select! {
+                    settled = journal.recover_one(facilitator.as_ref(), &intent) => settled,
+                    _ = shutdown.cancelled() => break,
+                };
+                if let Some(failed) = settled {
+                    on_failure(failed);
+                }
+            }
+        });
+    }
+
+    async fn recover_one(
+        &self,
+        facilitator: &dyn Facilitator,
+        intent: &SettlementIntent,
+    ) -> Option<FailedSettlement> {
+        let mut attempts = 0;
+        let error = loop {
+            attempts += 1;
+            let error = match settle_verified(facilitator, &intent.payment).await {
+                Ok(tx_hash) => {
+                    info!(
+                        resource = %intent.resource,
+                        id = %intent.id,
+                        ?tx_hash,
+                        attempts,
+                        "Settled recovered payment"
+                    );
+                    self.settled(&intent.payment.payment_header);
+                    return None;
+                }
+                Err(e) => e,
+            };
+            if attempts >= MAX_ATTEMPTS {
+                break error;
+            }
+            let delay = RETRY_BASE_DELAY * 2u32.pow(attempts - 1);
+            warn!(
+                resource = %intent.resource,
+                id = %intent.id,
+                attempt = attempts,
+                error = %error,
+                "Recovered payment settlement failed; retrying in {:?}",
+                delay
+            );
+            tokio::time::sleep(delay).await;
+        };
+
+        error!(
+            resource = %intent.resource,
+            id = %intent.id,
+            attempts,
+            error = %error,
+            "Giving up on recovered payment settlement"
+        );
+        self.failed(&intent.payment.payment_header, &error.to_string());
+        Some(FailedSettlement {
+            resource: intent.resource.clone(),
+            summary: intent.summary.clone(),
+            attempts,
+            error,
+        })
+    }
+}
+
+/// Keys a payment's intent: its header is too sensitive and too long to repeat.
+fn intent_id(payment_header: &str) -> String {
+    format!("{:x}", Sha256::digest(payment_header.as_bytes()))
+}
+
+fn apply(open: &mut HashMap<String, Open>, entry: Entry) {
+    match entry {
+        Entry::Intent(intent) => {
+            open.insert(
+                intent.id.clone(),
+                Open {
+                    intent,
+                    error: None,
+                    recovering: false,
+                },
+            );
+        }
+        Entry::Settled { id } => {
+            open.remove(&id);
+        }
+        Entry::Failed { id, error } => {
+            if let Some(open) = open.get_mut(&id) {
+                open.error = Some(error);
+                open.recovering = false;
+            }
+        }
+    }
+}
+
+struct Writer {
+    path: PathBuf,
+    fsync: FsyncPolicy,
+    file: Option<File>,
+    /// Lines appended since the journal was last rewritten.
+    appended: usize,
+}
+
+impl Writer {
+    /// Appends a batch of entries with one write (and sync), then answers its waiters.
+    fn write(&mut self, batch: Vec<Command>, open: &Mutex<HashMap<String, Open>>) {
+        let mut lines = Vec::new();
+        let mut replies = Vec::new();
+        for command in batch {
+            let entry = match command {
+                Command::Append(entry, reply) => {
+                    replies.extend(reply);
+                    entry
+                }
+                Command::Flush(reply) => {
+                    replies.push(reply);
+                    continue;
+                }
+            };
+            match serde_json::to_vec(&entry) {
+                Ok(line) => {
+                    lines.extend_from_slice(&line);
+                    lines.push(b'\n');
+                    self.appended += 1;
+                }
+                Err(e) => warn!("Failed to serialize pending settlement: {}", e),
+            }
+            apply(&mut open.lock(), entry);
+        }
+
+        if !lines.is_empty()
+            && let Err(e) = self.append(&lines)
+        {
+            error!(
+                "Failed to write pending settlements {}: {}",
+                self.path.display(),
+                e
+            );
+            // Reopen on the next batch; the path may have become writable again.
+            self.file = None;
+        }
+        if self.appended >= COMPACT_EVERY
+            && let Err(e) = self.compact(&open.lock())
+        {
+            warn!(
+                "Failed to compact pending settlements {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+        for reply in replies {
+            let _ = reply.send(());
+        }
+    }
+
+    fn append(&mut self, lines: &[u8]) -> io::Result<()> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            ),
+        };
+        file.write_all(lines)?;
+        if self.fsync == FsyncPolicy::Always {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the journal with the intents still `open`, through a synced temporary
+    /// file so a crash leaves either the old journal or the new one.
+    fn compact(&mut self, open: &HashMap<String, Open>) -> io::Result<()> {
+        let mut rewritten = self.path.as_os_str().to_owned();
+        rewritten.push(".tmp");
+        let rewritten = PathBuf::from(rewritten);
+        let mut file = File::create(&rewritten)?;
+        let mut opened: Vec<&Open> = open.values().collect();
+        opened.sort_by_key(|open| open.intent.timestamp);
+        for open in opened {
+            let mut entries = vec![Entry::Intent(open.intent.clone())];
+            if let Some(error) = &open.error {
+                entries.push(Entry::Failed {
+                    id: open.intent.id.clone(),
+                    error: error.clone(),
+                });
+            }
+            for entry in entries {
+                serde_json::to_writer(&mut file, &entry)?;
+                file.write_all(b"\n")?;
+            }
+        }
+        file.sync_all()?;
+        fs::rename(&rewritten, &self.path)?;
+        self.file = None;
+        self.appended = 0;
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+use sdk_4mica::x402::PaymentRequirements;
+use serde_json::{Value, json};
+use tracing::warn;
+
+use crate::{
+    error::PaymentError,
+    x402::{NetworkAliases, SettlementSummary, X402_VERSION, encode_payment_header},
+};
+
+/// Scheme of the payments `X402_MODE=sandbox` approves or declines without settling.
+pub const SANDBOX_SCHEME: &str = "test";
+
+/// The `X402_SANDBOX_ACK` value sandbox mode requires, so it is never enabled by accident.
+pub const SANDBOX_ACK: &str = "I_UNDERSTAND";
+
+/// A base64 `test` payment for `network`, approved or declined as `approve` says, as a
+/// player would send it in `X-PAYMENT`; `resource` binds it to one URL.
+pub fn sandbox_payment_header(
+    network: &str,
+    resource: Option<&str>,
+    approve: bool,
+) -> Result<String, PaymentError> {
+    let mut envelope = json!({
+        "x402Version": X402_VERSION,
+        "scheme": SANDBOX_SCHEME,
+        "network": network,
+        "payload": { "approve": approve },
+    });
+    if let Some(resource) = resource {
+        envelope["resource"] = json!(resource);
+    }
+    encode_payment_header(&envelope)
+}
+
+/// Settles a `test` envelope on `network` by its `payload.approve`, as if it paid the v1
+/// requirement for that network (or the first one) in full.
+pub(super) fn settle_sandbox_payment(
+    envelope: &Value,
+    network: String,
+    accepted: &[PaymentRequirements],
+    aliases: &NetworkAliases,
+) -> Result<SettlementSummary, PaymentError> {
+    let payload = envelope.get("payload");
+    let approve = payload
+        .and_then(|payload| payload.get("approve"))
+        .and_then(Value::as_bool)
+        .ok_or(PaymentError::MalformedEnvelope(
+            "sandbox payload.approve must be a boolean",
+        ))?;
+    if !approve {
+        warn!(%network, "SANDBOX payment declined");
+        return Err(PaymentError::VerificationFailed("sandbox_declined".into()));
+    }
+    let requirements = accepted
+        .iter()
+        .find(|requirements| aliases.same_chain(&requirements.network, &network))
+        .or_else(|| accepted.first())
+        .ok_or_else(|| PaymentError::NoMatchingRequirements {
+            scheme: SANDBOX_SCHEME.to_string(),
+            network: network.clone(),
+            accepted: Vec::new(),
+        })?;
+    warn!(
+        %network,
+        amount = %requirements.max_amount_required,
+        "SANDBOX payment approved; nothing was settled"
+    );
+    Ok(SettlementSummary {
+        scheme: SANDBOX_SCHEME.to_string(),
+        network,
+        pay_to: requirements.pay_to.clone(),
+        asset: requirements.asset.clone(),
+        amount: requirements.max_amount_required.clone(),
+        payer: payload
+            .and_then(|payload| payload.get("payer"))
+            .and_then(Value::as_str)
+            .map(str::to_string),
+        tab_id: None,
+        tx_hash: None,
+        certificate: None,
+        certificate_verified: None,
+    })
+}
@@ -0,0 +1,289 @@
+use chrono::Utc;
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::OnceCell;
+use tracing::info;
+
+use crate::{error::PaymentError, x402::SettlementSummary};
+
+type HeaderHash = [u8; 32];
+
+/// Hash of the [payment identity](super::payment_identity) of `payment_header`, or of the
+/// header itself when it doesn't decode.
+pub(crate) fn payment_key(payment_header: &str) -> HeaderHash {
+    match super::payment_identity(payment_header) {
+        Some(identity) => Sha256::digest(identity.as_bytes()).into(),
+        None => Sha256::digest(payment_header.trim().as_bytes()).into(),
+    }
+}
+
+#[derive(Default)]
+struct CachedSettlement {
+    outcome: OnceCell<Settled>,
+}
+
+struct Settled {
+    at: Instant,
+    /// When the entry may be forgotten.
+    keep_until: Instant,
+    resource: String,
+    summary: SettlementSummary,
+}
+
+/// Remembers successful settlements by [payment identity](super::payment_identity) so
+/// retried requests carrying the same payment, however encoded, are treated as the same
+/// purchase instead of settling twice.
+///
+/// Within `window` of the settlement a duplicate for the same resource reuses the
+/// cached result; after that, or for any other resource, the header is rejected as a
+/// replay. A header is remembered for `retention`, or until its signed validity ends if
+/// that is later, so it can't be accepted again while it would still pass verification.
+/// Failures are not cached so a client can retry a header that did not settle.
+pub struct SettlementCache {
+    window: Duration,
+    retention: Duration,
+    entries: Mutex<HashMap<HeaderHash, Arc<CachedSettlement>>>,
+}
+
+impl SettlementCache {
+    pub fn new(window: Duration, retention: Duration) -> Self {
+        Self {
+            window,
+            retention: retention.max(window),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Runs `settle` unless this payment already settled. Concurrent calls with the
+    /// same payment share a single settlement. `valid_until` is the Unix time the header's
+    /// signed validity ends, when it names one.
+    pub async fn settle_once<F, Fut>(
+        &self,
+        payment_header: &str,
+        resource: &str,
+        valid_until: Option<i64>,
+        settle: F,
+    ) -> Result<SettlementSummary, PaymentError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<SettlementSummary, PaymentError>>,
+    {
+        let key = payment_key(payment_header);
+        let entry = {
+            let mut entries = self.entries.lock();
+            self.prune(&mut entries);
+            entries.entry(key).or_default().clone()
+        };
+
+        if let Some(settled) = entry.outcome.get() {
+            if settled.at.elapsed() <= self.window && settled.resource == resource {
+                info!("Reusing cached settlement for duplicate payment header");
+                return Ok(settled.summary.clone());
+            }
+            return Err(PaymentError::PaymentReplayed);
+        }
+
+        let settled = entry
+            .outcome
+            .get_or_try_init(|| async {
+                settle().await.map(|summary| {
+                    let at = Instant::now();
+                    let validity = valid_until
+                        .and_then(|until| until.checked_sub(Utc::now().timestamp()))
+                        .and_then(|seconds| u64::try_from(seconds).ok())
+                        .map_or(Duration::ZERO, Duration::from_secs);
+                    Settled {
+                        at,
+                        keep_until: at + self.retention.max(validity),
+                        resource: resource.to_string(),
+                        summary,
+                    }
+                })
+            })
+            .await?;
+        // A concurrent caller may have settled this header for a different resource.
+        if settled.resource != resource {
+            return Err(PaymentError::PaymentReplayed);
+        }
+        Ok(settled.summary.clone())
+    }
+
+    fn prune(&self, entries: &mut HashMap<HeaderHash, Arc<CachedSettlement>>) {
+        entries.retain(|_, entry| match entry.outcome.get() {
+            Some(settled) => Instant::now() <= settled.keep_until,
+            // Unsettled entries only matter while a caller is still settling them.
+            None => Arc::strong_count(entry) > 1,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn summary() -> SettlementSummary {
+        SettlementSummary {
+            scheme: "4mica-credit".into(),
+            network: "polygon-amoy".into(),
+            pay_to: "0x00000000000000000000000000000000000000ab".into(),
+            asset: "0x00000000000000000000000000000000000000cd".into(),
+            amount: "100".into(),
+            payer: None,
+            tab_id: None,
+            tx_hash: None,
+            certificate: None,
+            certificate_verified: None,
+        }
+    }
+
+    async fn settle(
+        cache: &SettlementCache,
+        resource: &str,
+        valid_until: Option<i64>,
+        settles: &AtomicUsize,
+    ) -> Result<SettlementSummary, PaymentError> {
+        cache
+            .settle_once("header", resource, valid_until, || async {
+                settles.fetch_add(1, Ordering::SeqCst);
+                Ok(summary())
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn duplicate_within_the_window_reuses_the_settlement() {
+        let cache = SettlementCache::new(Duration::from_secs(60), Duration::from_secs(300));
+        let settles = AtomicUsize::new(0);
+        settle(&cache, "/a.ts", None, &settles).await.unwrap();
+        settle(&cache, "/a.ts", None, &settles).await.unwrap();
+        assert_eq!(settles.load(Ordering::SeqCst), 1);
+
+        let other = settle(&cache, "/b.ts", None, &settles).await;
+        assert!(matches!(other, Err(PaymentError::PaymentReplayed)));
+    }
+
+    #[tokio::test]
+    async fn header_is_forgotten_after_the_retention() {
+        let cache = SettlementCache::new(Duration::ZERO, Duration::ZERO);
+        let settles = AtomicUsize::new(0);
+        settle(&cache, "/a.ts", None, &settles).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        settle(&cache, "/a.ts", None, &settles).await.unwrap();
+        assert_eq!(settles.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn header_is_kept_while_its_signed_validity_lasts() {
+        let cache = SettlementCache::new(Duration::ZERO, Duration::ZERO);
+        let settles = AtomicUsize::new(0);
+        let valid_until = Some(Utc::now().timestamp() + 60);
+        settle(&cache, "/a.ts", valid_until, &settles)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let replay = settle(&cache, "/a.ts", valid_until, &settles).await;
+        assert!(matches!(replay, Err(PaymentError::PaymentReplayed)));
+        assert_eq!(settles.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn concurrent_duplicates_share_one_settlement() {
+        let cache = SettlementCache::new(Duration::from_secs(60), Duration::from_secs(300));
+        let settles = AtomicUsize::new(0);
+        let slow_settle = || async {
+            settles.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(summary())
+        };
+        let (first, second) = tokio::join!(
+            cache.settle_once("header", "/a.ts", None, slow_settle),
+            cache.settle_once("header", "/a.ts", None, slow_settle),
+        );
+        first.unwrap();
+        second.unwrap();
+        assert_eq!(settles.load(Ordering::SeqCst), 1);
+    }
+
+    /// `envelope` in each encoding `decode_payment_header` accepts.
+    fn encodings(envelope: &str) -> Vec<String> {
+        use base64::{
+            Engine,
+            prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD},
+        };
+        vec![
+            BASE64_STANDARD.encode(envelope),
+            BASE64_URL_SAFE_NO_PAD.encode(envelope),
+            format!("  {}\n", BASE64_STANDARD.encode(envelope)),
+        ]
+    }
+
+    #[tokio::test]
+    async fn re_encoded_payment_is_the_same_payment() {
+        let payments = [
+            // The same transfer, with its keys reordered, spaced, or its hash in capitals.
+            [
+                r#"{"x402Version":1,"scheme":"exact","payload":{"txHash":"0xab"}}"#,
+                r#"{ "payload": {"txHash": "0xAB"}, "scheme": "exact", "x402Version": 1 }"#,
+            ],
+            // The same 4mica request, however its ids are written.
+            [
+                r#"{"payload":{"claims":{"tab_id":"7","req_id":"0"},"signature":"0x01"}}"#,
+                r#"{"payload":{"claims":{"tabId":"0x7","reqId":0},"signature":"0x02"}}"#,
+            ],
+            // The same EIP-3009 authorization.
+            [
+                r#"{"payload":{"authorization":{"from":"0xAbC","nonce":"0x01"}}}"#,
+                r#"{"payload":{"authorization":{"nonce":"0x01","from":"0xabc"}}}"#,
+            ],
+        ];
+        for [envelope, variant] in payments {
+            let cache = SettlementCache::new(Duration::ZERO, Duration::from_secs(300));
+            let settles = AtomicUsize::new(0);
+            let settle = |header: String| {
+                let cache = &cache;
+                let settles = &settles;
+                async move {
+                    cache
+                        .settle_once(&header, "/a.ts", None, || async {
+                            settles.fetch_add(1, Ordering::SeqCst);
+                            Ok(summary())
+                        })
+                        .await
+                }
+            };
+            settle(encodings(envelope).remove(0)).await.unwrap();
+            for header in encodings(envelope).into_iter().chain(encodings(variant)) {
+                let replay = settle(header).await;
+                assert!(
+                    matches!(replay, Err(PaymentError::PaymentReplayed)),
+                    "{envelope}"
+                );
+            }
+            assert_eq!(settles.load(Ordering::SeqCst), 1);
+        }
+    }
+
+    #[test]
+    fn distinct_payments_have_distinct_keys() {
+        let key = |envelope: &str| payment_key(&encodings(envelope).remove(0));
+        assert_ne!(
+            key(r#"{"payload":{"claims":{"tab_id":"7","req_id":"0"}}}"#),
+            key(r#"{"payload":{"claims":{"tab_id":"7","req_id":"1"}}}"#)
+        );
+        assert_ne!(
+            key(r#"{"payload":{"txHash":"0xab"}}"#),
+            key(r#"{"payload":{"txHash":"0xac"}}"#)
+        );
+        assert_ne!(key(r#"{"payload":{"a":1}}"#), key(r#"{"payload":{"a":2}}"#));
+        // A header that doesn't decode is keyed as written.
+        assert_eq!(payment_key("not base64!"), payment_key(" not base64! "));
+        assert_ne!(payment_key("not base64!"), payment_key("not base64?"));
+    }
+}
@@ -0,0 +1,93 @@
+use sdk_4mica::U256;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Basis points in a whole; a split's shares must add up to this.
+pub const BASIS_POINTS: u32 = 10_000;
+
+/// One recipient's share of revenue.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SplitShare {
+    pub address: String,
+    pub basis_points: u32,
+}
+
+/// What one recipient is owed out of a settled amount.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SplitAmount {
+    pub recipient: String,
+    /// Decimal amount in the asset's smallest unit.
+    pub amount: String,
+}
+
+/// `X402_PAY_TO_SPLIT`: comma-separated `address:basis_points` shares, e.g.
+/// `0xCreator:8500,0xPlatform:1500`.
+///
+/// The configured split is bookkeeping: payments still go to `X402_PAY_TO` in full, and
+/// the shares recorded per settlement tell what to pay out off-line.
+#[derive(Debug, Clone)]
+pub struct RevenueSplit(pub Vec<SplitShare>);
+
+impl FromStr for RevenueSplit {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let shares =
+            raw.split(',')
+                .map(str::trim)
+                .filter(|share| !share.is_empty())
+                .map(|share| {
+                    let (address, basis_points) = share.rsplit_once(':').ok_or_else(|| {
+                        format!("split share {share:?} is not address:basis_points")
+                    })?;
+                    let basis_points = basis_points.trim().parse().map_err(|e| {
+                        format!("invalid basis points in split share {share:?}: {e}")
+                    })?;
+                    Ok(SplitShare {
+                        address: address.trim().to_string(),
+                        basis_points,
+                    })
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+        if shares.is_empty() {
+            return Err("revenue split is empty".into());
+        }
+        Ok(Self(shares))
+    }
+}
+
+impl RevenueSplit {
+    /// Sum of the shares' basis points, [`BASIS_POINTS`] for a valid split.
+    pub fn total_basis_points(&self) -> u64 {
+        self.0
+            .iter()
+            .map(|share| u64::from(share.basis_points))
+            .sum()
+    }
+
+    /// `amount` divided by the shares, in order. Each share is rounded down and the
+    /// first recipient also gets the remainder, so the amounts always add up to `amount`.
+    pub fn divide(&self, amount: U256) -> Vec<(String, U256)> {
+        let whole = U256::from(BASIS_POINTS);
+        let (quotient, remainder) = (amount / whole, amount % whole);
+        let mut amounts: Vec<(String, U256)> = self
+            .0
+            .iter()
+            .map(|share| {
+                let bps = U256::from(share.basis_points);
+                // amount * bps / 10000, without overflowing on large amounts.
+                let part = quotient * bps + remainder * bps / whole;
+                (share.address.to_lowercase(), part)
+            })
+            .collect();
+        let assigned = amounts
+            .iter()
+            .skip(1)
+            .fold(U256::from(0), |sum, (_, part)| sum.saturating_add(*part));
+        if let Some((_, first)) = amounts.first_mut() {
+            *first = amount.saturating_sub(assigned);
+        }
+        amounts
+    }
+}
@@ -0,0 +1,212 @@
+use chrono::Utc;
+use futures_util::{StreamExt, stream};
+use parking_lot::Mutex;
+use sdk_4mica::{Client as FourMicaClient, U256};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::x402::{
+    FacilitatorTabResponse, X402Config, fourmica::build_fourmica_client, onchain::parse_u256_value,
+};
+
+/// Most tabs the registry keeps; past it, the one expiring soonest makes room.
+const MAX_TRACKED_TABS: usize = 10_000;
+/// Tabs looked up through the SDK at once.
+const LOOKUP_CONCURRENCY: usize = 8;
+/// Longest one tab's lookups may take before it counts as unknown.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What the SDK said about a tracked tab at the last audit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TabState {
+    Open,
+    /// Its user has paid some of it back.
+    Paid,
+    /// Past its TTL without a payment.
+    Expired,
+    /// The lookup failed, or the SDK doesn't know the tab.
+    Unknown,
+}
+
+/// The outcome of one audit of the tabs `POST /tab` issued.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TabAuditSummary {
+    pub open: usize,
+    pub paid: usize,
+    pub expired: usize,
+    pub unknown: usize,
+    /// Tabs still tracked once those past their TTL were dropped.
+    pub tracked: usize,
+    /// Unix time the audit finished.
+    pub checked_at: i64,
+    pub duration_ms: u64,
+}
+
+impl TabAuditSummary {
+    fn count(&mut self, state: TabState) {
+        match state {
+            TabState::Open => self.open += 1,
+            TabState::Paid => self.paid += 1,
+            TabState::Expired => self.expired += 1,
+            TabState::Unknown => self.unknown += 1,
+        }
+    }
+}
+
+/// Tracks the tabs this server opened and audits them through the 4mica SDK every
+/// interval. A tab past its TTL is reported by one more audit, then dropped.
+pub struct TabAudit {
+    config: X402Config,
+    /// Unix time each tracked tab expires at.
+    tabs: Mutex<HashMap<U256, i64>>,
+    latest: Mutex<Option<TabAuditSummary>>,
+}
+
+impl TabAudit {
+    /// Starts auditing every `interval`, first right away, until `shutdown` is cancelled.
+    pub fn spawn(config: X402Config, interval: Duration, shutdown: CancellationToken) -> Arc<Self> {
+        let audit = Arc::new(Self {
+            config,
+            tabs: Mutex::new(HashMap::new()),
+            latest: Mutex::new(None),
+        });
+        tokio::spawn({
+            let audit = audit.clone();
+            async move {
+                let mut ticks = tokio::time::interval(interval);
+                ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                loop {
+                    tokio::select! {
+                        _ = ticks.tick() => {}
+                        _ = shutdown.cancelled() => break,
+                    }
+                    let summary = audit.run().await;
+                    *audit.latest.lock() = Some(summary);
+                }
+            }
+        });
+        audit
+    }
+
+    /// Starts tracking a tab the facilitator opened.
+    pub fn track(&self, tab: &FacilitatorTabResponse) {
+        let tab_id = match parse_u256_value(&tab.tab_id) {
+            Ok(tab_id) => tab_id,
+            Err(e) => {
+                warn!(tab_id = %tab.tab_id, error = %e, "[4mica] Not auditing tab");
+                return;
+            }
+        };
+        let expires_at = tab.start_timestamp.saturating_add(tab.ttl_seconds);
+        let mut tabs = self.tabs.lock();
+        if tabs.len() >= MAX_TRACKED_TABS && !tabs.contains_key(&tab_id) {
+            let now = Utc::now().timestamp();
+            tabs.retain(|_, expires_at| *expires_at > now);
+            if tabs.len() >= MAX_TRACKED_TABS
+                && let Some(soonest) = tabs
+                    .iter()
+                    .min_by_key(|(_, expires_at)| **expires_at)
+                    .map(|(tab_id, _)| *tab_id)
+            {
+                tabs.remove(&soonest);
+            }
+        }
+        tabs.insert(tab_id, expires_at);
+    }
+
+    /// Tabs currently tracked.
+    pub fn tracked(&self) -> usize {
+        self.tabs.lock().len()
+    }
+
+    /// The last finished audit, if any.
+    pub fn latest(&self) -> Option<TabAuditSummary> {
+        *self.latest.lock()
+    }
+
+    async fn run(&self) -> TabAuditSummary {
+        let started = Instant::now();
+        let now = Utc::now().timestamp();
+        let tabs: Vec<U256> = self.tabs.lock().keys().copied().collect();
+        let mut summary = TabAuditSummary::default();
+        // Nothing to look up, so no SDK client to build.
+        let client = if tabs.is_empty() {
+            None
+        } else {
+            Some(build_fourmica_client(&self.config).await)
+        };
+        match client {
+            None => {}
+            Some(Some(client)) => {
+                let mut lookups = stream::iter(tabs)
+                    .map(|tab_id| async move {
+                        tokio::time::timeout(LOOKUP_TIMEOUT, tab_state(client, tab_id, now))
+                            .await
+                            .unwrap_or(TabState::Unknown)
+                    })
+                    .buffer_unordered(LOOKUP_CONCURRENCY);
+                while let Some(state) = lookups.next().await {
+                    summary.count(state);
+                }
+            }
+            Some(None) => {
+                warn!("[4mica] Tab audit found the SDK client unavailable");
+                summary.unknown = tabs.len();
+            }
+        }
+
+        let mut tabs = self.tabs.lock();
+        tabs.retain(|_, expires_at| *expires_at > now);
+        summary.tracked = tabs.len();
+        drop(tabs);
+        summary.checked_at = Utc::now().timestamp();
+        summary.duration_ms = started.elapsed().as_millis() as u64;
+        info!(
+            open = summary.open,
+            paid = summary.paid,
+            expired = summary.expired,
+            unknown = summary.unknown,
+            tracked = summary.tracked,
+            "[4mica] Tab audit"
+        );
+        summary
+    }
+}
+
+async fn tab_state(client: &FourMicaClient, tab_id: U256, now: i64) -> TabState {
+    let (tab, payment_status) = tokio::join!(
+        client.recipient.get_tab(tab_id),
+        client.recipient.get_tab_payment_status(tab_id),
+    );
+    let lookup = tab.map_err(|e| e.to_string()).and_then(|tab| {
+        payment_status
+            .map(|status| (tab, status))
+            .map_err(|e| e.to_string())
+    });
+    match lookup {
+        Ok((Some(tab), status)) => {
+            let expires_at = tab.start_timestamp.saturating_add(tab.ttl_seconds);
+            if status.paid > U256::ZERO {
+                TabState::Paid
+            } else if expires_at <= now {
+                TabState::Expired
+            } else {
+                TabState::Open
+            }
+        }
+        Ok((None, _)) => TabState::Unknown,
+        Err(e) => {
+            let tab_id = format!("0x{tab_id:x}");
+            debug!(tab_id = %tab_id, error = %e, "[4mica] Tab audit lookup failed");
+            TabState::Unknown
+        }
+    }
+}
@@ -267,6 +267,7 @@ export const settleDirectPayment = async (
       network,
       payload: {
         txHash,
+        payer: await signer.getAddress(),
         payTo,
         asset: assetAddr,
         amount: amountRaw.toString(),